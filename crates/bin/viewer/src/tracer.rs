@@ -11,7 +11,7 @@ mod ui;
 use camera_controller::CameraController;
 use image_io::*;
 use loading_screen::LoadingScreen;
-use scene_importer::ImportHandler;
+use scene_importer::{ImportHandler, ImportedCamera};
 use scene_renderer::SceneRenderer;
 use texture_data::{create_solid_color_texture, TextureBlock, TextureData};
 use ui::*;
@@ -58,6 +58,7 @@ pub struct Tracer {
     render_start: Instant,
     pool: threadpool::ThreadPool,
     camera: camera_controller::CameraController,
+    scene_cameras: Vec<ImportedCamera>,
     tracing_renderable: renderable::Renderable,
     texture: TextureData,
     tracer: Arc<RwLock<pathtracer::Tracer>>,
@@ -74,22 +75,17 @@ pub struct Tracer {
     has_albedo: bool,
     normals: TextureData,
     has_normals: bool,
+    // Light splatted to the image during the current frame.
+    splats: Vec<Vector3>,
+    has_splats: bool,
     tracing_output: TracingOutput,
     pending_action: VecDeque<Box<Action>>,
 }
 
 impl Tracer {
     pub fn new(display: &glium::Display, width: u32, height: u32) -> Tracer {
-        let tracer_settings = pathtracer::TracerSettings::from_file(SETTINGS_JSON).unwrap_or(
-            pathtracer::TracerSettings {
-                max_scatter_depth: 5,
-                shadow_rays: true,
-                random_light_sample: false,
-                t_min: 0.001,
-                t_max: 100000.0,
-                min_bounces: 3,
-            },
-        );
+        let tracer_settings =
+            pathtracer::TracerSettings::from_file(SETTINGS_JSON).unwrap_or_default();
 
         let mut camera = CameraController::new(
             tracer_settings.t_min,
@@ -142,6 +138,7 @@ impl Tracer {
             render_start: Instant::now(),
             pool,
             camera,
+            scene_cameras: vec![],
             preview,
             default_texture,
             loading_screen: LoadingScreen::new(display),
@@ -158,6 +155,8 @@ impl Tracer {
             has_albedo: false,
             normals,
             has_normals: false,
+            splats: vec![Vector3::zero(); (width * height) as usize],
+            has_splats: false,
             tracing_output: TracingOutput::Output,
             pending_action: VecDeque::new(),
         }
@@ -189,7 +188,12 @@ impl Tracer {
                             let color =
                                 match thread_cancel.load(std::sync::atomic::Ordering::Acquire) {
                                     true => math::Vector3::zero(),
-                                    false => thread_tracer.read().unwrap().trace(norm_x, norm_y),
+                                    false => thread_tracer.read().unwrap().trace(
+                                        norm_x,
+                                        norm_y,
+                                        (1. / width, 1. / height),
+                                        &mut block.splats,
+                                    ),
                                 };
 
                             block.set(block_x, block_y, color);
@@ -216,6 +220,9 @@ impl Tracer {
         normals: bool,
     ) -> u32 {
         let mut spawned = 0;
+        // Lens cameras are expensive to set up, blocks share one.
+        let sharp_camera: Arc<dyn camera::Camera + Send + Sync> =
+            Arc::from(self.camera.tracer_sharp_camera());
 
         for grid_y in 0..grid_height {
             for grid_x in 0..grid_width {
@@ -224,8 +231,9 @@ impl Tracer {
                 let thread_cancel = self.cancel.clone();
 
                 let (width, height) = (self.width as f32, self.height as f32);
-                let tracer_camera = self.camera.tracer_simple_camera();
+                let tracer_camera = sharp_camera.clone();
                 let (t_min, t_max) = (self.tracer_settings.t_min, self.tracer_settings.t_max);
+                let max_transparent_hits = self.tracer_settings.max_transparent_hits;
 
                 let work = move || {
                     let mut block = TextureBlock::new(BLOCK_WIDTH, BLOCK_HEIGHT);
@@ -244,12 +252,17 @@ impl Tracer {
                             {
                                 true => (math::Vector3::zero(), math::Vector3::zero()),
                                 false => {
+                                    let sampler = UniformSampler::new();
                                     match thread_tracer.read().unwrap().scene().hit(
-                                        &tracer_camera.ray(norm_x, norm_y, &UniformSampler::new()),
+                                        &tracer_camera.ray(norm_x, norm_y, &sampler),
                                         t_min,
                                         t_max,
+                                        max_transparent_hits,
+                                        &sampler,
                                     ) {
-                                        Some(hit) => (hit.material.base_color(hit.uv), hit.normal),
+                                        Some(hit) => {
+                                            (hit.material.base_color(&hit.tex_coord), hit.normal)
+                                        }
                                         None => (math::Vector3::zero(), math::Vector3::zero()),
                                     }
                                 }
@@ -315,6 +328,7 @@ impl Tracer {
         self.reset_tracing();
         self.has_albedo = false;
         self.has_normals = false;
+        self.scene_cameras = handler.cameras();
 
         let (renderables, textures, texture_mapping) =
             handler.generate(display, consts::SCENE_VS, consts::SCENE_FS);
@@ -489,6 +503,8 @@ impl Tracer {
         if self.reset() {
             self.begin_tracing();
             self.texture.clear();
+            self.splats.fill(Vector3::zero());
+            self.has_splats = false;
 
             self.tracer
                 .write()
@@ -510,13 +526,43 @@ impl Tracer {
 
         self.frame_start = Instant::now();
         self.average.next_frame();
+        self.tracer
+            .write()
+            .unwrap()
+            .begin_pass(self.average.sample() as u32);
 
         let spawned = self.spawn_threads(self.width / BLOCK_WIDTH, self.height / BLOCK_HEIGHT);
         self.pending = (spawned, spawned);
     }
 
+    /// Adds light splatted during the finished frame to the average, splats of a frame estimate
+    /// the whole image.
+    fn apply_splats(&mut self) {
+        if !self.has_splats {
+            return;
+        }
+
+        let scale = 1. / self.average.sample() as f32;
+        for (color, splat) in self.texture.data.iter_mut().zip(self.splats.iter_mut()) {
+            *color += *splat * scale;
+            *splat = Vector3::zero();
+        }
+
+        self.texture.reset();
+        self.has_splats = false;
+    }
+
     fn update_raytracing_texture(&mut self) {
         for (_, (x, y, block)) in self.rx.try_iter().enumerate().take(UPDATE_COUNT) {
+            if let TracingOutput::Output = self.tracing_output {
+                for splat in &block.splats {
+                    let splat_x = ((splat.x * self.width as f32) as u32).min(self.width - 1);
+                    let splat_y = ((splat.y * self.height as f32) as u32).min(self.height - 1);
+                    self.splats[(splat_x + splat_y * self.width) as usize] += splat.color;
+                    self.has_splats = true;
+                }
+            }
+
             let mut local_data = Vec::with_capacity((block.width * block.height * 4) as usize);
 
             for block_y in 0..block.height {
@@ -557,6 +603,10 @@ impl Tracer {
 
             self.pending.0 -= 1;
         }
+
+        if self.pending.0 == 0 {
+            self.apply_splats();
+        }
     }
 
     pub fn update(&mut self, display: &glium::Display) {
@@ -2,8 +2,23 @@ use ::pathtracer::*;
 
 use ::cgmath::*;
 
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
 
+use super::scene_importer::{ImportedCamera, Projection};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum CameraKind {
+    Simple,
+    Aperture,
+    Orthographic,
+    Equirectangular,
+    Cubemap,
+    Fisheye,
+    Lens,
+}
+
 pub struct CameraController {
     pub yaw: f32,
     pub pitch: f32,
@@ -15,7 +30,25 @@ pub struct CameraController {
     pub v_fov: f32,
     pub aperture: f32,
     pub focus_distance: f32,
-    pub simple_camera: bool,
+    pub kind: CameraKind,
+    /// Height of the image of the orthographic camera in world units.
+    pub ortho_height: f32,
+    /// Field of view of the fisheye camera along the diagonal in degrees.
+    pub fisheye_fov: f32,
+    /// Number of diaphragm blades shaping the aperture, a disk below three.
+    pub blades: u32,
+    /// Rotation of the diaphragm blades in degrees.
+    pub blade_rotation: f32,
+    /// Aperture mask replacing the diaphragm blades.
+    pub aperture_mask: Option<Arc<camera::ApertureMask>>,
+    /// Strength of cat's eye vignetting of the aperture camera.
+    pub cat_eye: f32,
+    /// Elements of the lens of the lens camera.
+    pub lens_elements: Vec<camera::LensElement>,
+    /// Diameter of the aperture stop of the lens camera in millimetres.
+    pub lens_aperture: f32,
+    /// Diagonal of the film of the lens camera in millimetres.
+    pub film_diagonal: f32,
     pub speed: f32,
 }
 
@@ -29,6 +62,14 @@ struct CameraStore {
     focus_distance: f32,
     simple_camera: bool,
     speed: Option<f32>,
+    kind: Option<CameraKind>,
+    ortho_height: Option<f32>,
+    fisheye_fov: Option<f32>,
+    blades: Option<u32>,
+    blade_rotation: Option<f32>,
+    cat_eye: Option<f32>,
+    lens_aperture: Option<f32>,
+    film_diagonal: Option<f32>,
 }
 
 impl CameraController {
@@ -44,7 +85,16 @@ impl CameraController {
             v_fov,
             aperture: 1.,
             focus_distance: 1.,
-            simple_camera: true,
+            kind: CameraKind::Simple,
+            ortho_height: 10.,
+            fisheye_fov: 180.,
+            blades: 0,
+            blade_rotation: 0.,
+            aperture_mask: None,
+            cat_eye: 0.,
+            lens_elements: camera::DOUBLE_GAUSS.to_vec(),
+            lens_aperture: 17.1,
+            film_diagonal: 43.27,
             speed: 10.,
         }
     }
@@ -58,9 +108,68 @@ impl CameraController {
     }
 
     pub fn tracer_camera(&self) -> Box<dyn camera::Camera + Send + Sync> {
-        match self.simple_camera {
-            true => self.tracer_simple_camera(),
-            false => self.tracer_focus_camera(),
+        let pos = self.position;
+        let look_at = self.lookat();
+        let (position, look_at, up) = (
+            math::Vector3::new(pos.x, pos.y, pos.z),
+            math::Vector3::new(look_at.x, look_at.y, look_at.z),
+            math::Vector3::new(0., 1., 0.),
+        );
+
+        match self.kind {
+            CameraKind::Simple => self.tracer_simple_camera(),
+            CameraKind::Aperture => self.tracer_focus_camera(),
+            CameraKind::Orthographic => Box::new(camera::OrthographicCamera::look_at(
+                position,
+                look_at,
+                up,
+                self.ortho_height,
+                self.aspect_ratio,
+            )),
+            CameraKind::Equirectangular => Box::new(camera::EquirectangularCamera::look_at(
+                position, look_at, up,
+            )),
+            CameraKind::Cubemap => Box::new(camera::CubemapCamera::look_at(position, look_at, up)),
+            CameraKind::Fisheye => Box::new(camera::FisheyeCamera::look_at(
+                position,
+                look_at,
+                up,
+                self.fisheye_fov,
+                self.aspect_ratio,
+            )),
+            CameraKind::Lens => Box::new(camera::RealisticCamera::look_at(
+                position,
+                look_at,
+                up,
+                &camera::Lens {
+                    elements: self.lens_elements.clone(),
+                    aperture_diameter: self.lens_aperture,
+                    aperture: self.tracer_aperture(),
+                    focus_distance: self.focus_distance,
+                    film_diagonal: self.film_diagonal,
+                },
+                self.aspect_ratio,
+            )),
+        }
+    }
+
+    /// Aperture of the aperture and lens cameras, the mask replaces the diaphragm blades.
+    fn tracer_aperture(&self) -> camera::Aperture {
+        match (&self.aperture_mask, self.blades) {
+            (Some(mask), _) => camera::Aperture::Mask(mask.clone()),
+            (None, blades) if blades >= 3 => camera::Aperture::Polygon {
+                blades,
+                rotation: self.blade_rotation,
+            },
+            _ => camera::Aperture::Disk,
+        }
+    }
+
+    /// Camera without depth of field, for rendering albedo and normals.
+    pub fn tracer_sharp_camera(&self) -> Box<dyn camera::Camera + Send + Sync> {
+        match self.kind {
+            CameraKind::Aperture => self.tracer_simple_camera(),
+            _ => self.tracer_camera(),
         }
     }
 
@@ -81,7 +190,7 @@ impl CameraController {
         let pos = self.position;
         let look_at = self.lookat();
 
-        Box::new(camera::ApertureCamera::look_at(
+        let mut focus_camera = camera::ApertureCamera::look_at(
             math::Vector3::new(pos.x, pos.y, pos.z),
             math::Vector3::new(look_at.x, look_at.y, look_at.z),
             math::Vector3::new(0., 1., 0.),
@@ -89,7 +198,11 @@ impl CameraController {
             self.aspect_ratio,
             self.aperture,
             self.focus_distance,
-        ))
+        );
+        focus_camera.aperture = self.tracer_aperture();
+        focus_camera.cat_eye = self.cat_eye;
+
+        Box::new(focus_camera)
     }
 
     pub fn gl_camera(&self) -> [[f32; 4]; 4] {
@@ -102,8 +215,20 @@ impl CameraController {
             self.up,
         );
 
-        let projection_matrix =
-            perspective(Deg(self.v_fov), self.aspect_ratio, self.z_near, self.z_far);
+        let projection_matrix = match self.kind {
+            CameraKind::Orthographic => {
+                let (height, width) = (self.ortho_height, self.ortho_height * self.aspect_ratio);
+                ortho(
+                    -width / 2.,
+                    width / 2.,
+                    -height / 2.,
+                    height / 2.,
+                    self.z_near,
+                    self.z_far,
+                )
+            }
+            _ => perspective(Deg(self.v_fov), self.aspect_ratio, self.z_near, self.z_far),
+        };
 
         (projection_matrix * view_matrix).into()
     }
@@ -131,11 +256,30 @@ impl CameraController {
 
     pub fn reset(&mut self) {
         self.position = Vector3::zero();
-        self.simple_camera = true;
+        self.kind = CameraKind::Simple;
         self.yaw = 0.;
         self.pitch = 0.;
     }
 
+    /// Moves the camera to the camera imported with the scene and takes over its projection.
+    pub fn apply(&mut self, camera: &ImportedCamera) {
+        let forward = camera.forward;
+        self.position = Vector3::new(camera.position.x, camera.position.y, camera.position.z);
+        self.yaw = forward.x.atan2(forward.z);
+        self.pitch = -forward.y.clamp(-1., 1.).asin();
+
+        match camera.projection {
+            Projection::Orthographic { height } => {
+                self.kind = CameraKind::Orthographic;
+                self.ortho_height = height;
+            }
+            Projection::Perspective { v_fov } => {
+                self.kind = CameraKind::Simple;
+                self.v_fov = v_fov.to_degrees();
+            }
+        }
+    }
+
     pub fn save(&self, file: &str) -> Result<(), std::io::Error> {
         let store = CameraStore {
             position: (self.position.x, self.position.y, self.position.z),
@@ -144,8 +288,16 @@ impl CameraController {
             v_fov: self.v_fov,
             aperture: self.aperture,
             focus_distance: self.focus_distance,
-            simple_camera: self.simple_camera,
+            simple_camera: self.kind == CameraKind::Simple,
             speed: Some(self.speed),
+            kind: Some(self.kind),
+            ortho_height: Some(self.ortho_height),
+            fisheye_fov: Some(self.fisheye_fov),
+            blades: Some(self.blades),
+            blade_rotation: Some(self.blade_rotation),
+            cat_eye: Some(self.cat_eye),
+            lens_aperture: Some(self.lens_aperture),
+            film_diagonal: Some(self.film_diagonal),
         };
 
         let file = std::fs::File::create(file)?;
@@ -164,7 +316,18 @@ impl CameraController {
                 self.v_fov = store.v_fov;
                 self.aperture = store.aperture;
                 self.focus_distance = store.focus_distance;
-                self.simple_camera = store.simple_camera;
+                // Cameras stored before other kinds existed only tell if they are simple.
+                self.kind = store.kind.unwrap_or(match store.simple_camera {
+                    true => CameraKind::Simple,
+                    false => CameraKind::Aperture,
+                });
+                self.ortho_height = store.ortho_height.unwrap_or(10.);
+                self.fisheye_fov = store.fisheye_fov.unwrap_or(180.);
+                self.blades = store.blades.unwrap_or(0);
+                self.blade_rotation = store.blade_rotation.unwrap_or(0.);
+                self.cat_eye = store.cat_eye.unwrap_or(0.);
+                self.lens_aperture = store.lens_aperture.unwrap_or(17.1);
+                self.film_diagonal = store.film_diagonal.unwrap_or(43.27);
                 self.speed = store.speed.unwrap_or(10.);
                 Ok(())
             }
@@ -35,6 +35,20 @@ struct MeshInfo {
     indices: Vec<u32>,
 }
 
+#[derive(Clone, Copy)]
+pub enum Projection {
+    Orthographic { height: f32 },
+    Perspective { v_fov: f32 },
+}
+
+/// Camera defined in the scene, placed by a node referencing it.
+#[derive(Clone, Copy)]
+pub struct ImportedCamera {
+    pub projection: Projection,
+    pub position: Vector3,
+    pub forward: Vector3,
+}
+
 pub struct ImportHandler {
     //display: &'a glium::Display,
     // pub renderables: Vec<renderable::Renderable>,
@@ -42,6 +56,7 @@ pub struct ImportHandler {
     meshes: Vec<MeshInfo>,
     textures: Vec<TextureInfo>,
     texture_mapping: Vec<i32>,
+    cameras: Vec<(Projection, Option<(Vector3, Vector3)>)>,
     // vs: &'a str,
     // fs: &'a str,
 }
@@ -52,9 +67,24 @@ impl ImportHandler {
             meshes: vec![],
             textures: vec![],
             texture_mapping: vec![],
+            cameras: vec![],
         }
     }
 
+    /// Returns cameras of the scene which are placed by some node.
+    pub fn cameras(&self) -> Vec<ImportedCamera> {
+        self.cameras
+            .iter()
+            .filter_map(|(projection, placement)| {
+                placement.map(|(position, forward)| ImportedCamera {
+                    projection: *projection,
+                    position,
+                    forward,
+                })
+            })
+            .collect()
+    }
+
     pub fn generate(
         self,
         display: &glium::Display,
@@ -120,27 +150,29 @@ impl SceneImportHandler for ImportHandler {
         self.texture_mapping.push(material_index);
     }
 
-    fn handle_ortho_camera(&mut self, width: f32, height: f32, near: f32, far: f32) {
-        println!(
-            "TODO: ortographic camera: width={width}, height={height}, near={near}, far={far}"
-        );
+    // Aspect ratio and clipping planes are given by the window and tracer settings.
+    fn handle_ortho_camera(&mut self, _width: f32, height: f32, _near: f32, _far: f32) {
+        self.cameras.push((
+            Projection::Orthographic {
+                height: 2. * height,
+            },
+            None,
+        ));
     }
 
-    fn handle_perspective_camera(&mut self, v_fov: f32, aspect_ratio: f32, near: f32, far: f32) {
-        println!(
-            "TODO: perspective camera: v_fov={v_fov}, aspect={aspect_ratio}, near={near}, far={far}"
-        );
+    fn handle_perspective_camera(&mut self, v_fov: f32, _aspect_ratio: f32, _near: f32, _far: f32) {
+        self.cameras.push((Projection::Perspective { v_fov }, None));
     }
 
     fn handle_camera_transform(
         &mut self,
         camera_index: usize,
-        translate: &[f32; 3],
-        rotate: &[f32; 4],
+        position: Vector3,
+        forward: Vector3,
+        _up: Vector3,
     ) {
-        println!(
-            "Camera transform[{camera_index}] = {:?};{:?}",
-            translate, rotate
-        );
+        if let Some((_, placement)) = self.cameras.get_mut(camera_index) {
+            *placement = Some((position, forward));
+        }
     }
 }
@@ -1,4 +1,5 @@
 use pathtracer::math::{EnhancedVector, Vector3};
+use pathtracer::pathtracer::Splat;
 
 use glium::texture::*;
 
@@ -14,6 +15,8 @@ pub struct TextureBlock {
     pub width: u32,
     pub height: u32,
     pub data: Vec<Vector3>,
+    /// Light arriving to other parts of the image, found while tracing the block.
+    pub splats: Vec<Splat>,
 }
 
 struct VectorWrapper<'a> {
@@ -59,6 +62,7 @@ impl TextureBlock {
             width,
             height,
             data: vec![Vector3::zero(); (width * height) as usize],
+            splats: vec![],
         }
     }
 
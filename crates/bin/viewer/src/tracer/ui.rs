@@ -1,12 +1,16 @@
+use super::camera_controller::CameraKind;
 use super::image_io::*;
 use super::Tracer;
 
+use pathtracer::camera::{ApertureMask, LensElement, DOUBLE_GAUSS};
+use pathtracer::pathtracer::{IntegratorType, LightSelection};
 use pathtracer::random::UniformSampler;
 
 use imgui::*;
 
 use native_dialog::{MessageDialog, MessageType};
 
+use std::sync::Arc;
 use std::time::Instant;
 
 pub const CAMERA_JSON: &str = "camera.json";
@@ -64,13 +68,20 @@ fn update_mouse(tracer: &mut Tracer, io: &imgui::Io) {
             .camera
             .tracer_simple_camera()
             .ray(x, 1. - y, &UniformSampler::new());
+        let max_transparent_hits = tracer.tracer_settings.max_transparent_hits;
 
         let distance = tracer
             .tracer
             .read()
             .unwrap()
             .scene()
-            .hit(&ray, 0.001, std::f32::INFINITY)
+            .hit(
+                &ray,
+                0.001,
+                std::f32::INFINITY,
+                max_transparent_hits,
+                &UniformSampler::new(),
+            )
             .map_or(0., |hit| hit.t);
 
         if distance > 0. {
@@ -194,6 +205,57 @@ fn menu_camera(ui: &Ui, tracer: &mut Tracer) {
             let _ = tracer.camera.save(CAMERA_JSON);
         }
 
+        if !tracer.scene_cameras.is_empty() {
+            if let Some(_menu) = ui.begin_menu("Scene cameras") {
+                let selected = (0..tracer.scene_cameras.len())
+                    .filter(|index| ui.selectable(format!("Camera {}", index)))
+                    .last();
+
+                if let Some(index) = selected {
+                    tracer.camera.apply(&tracer.scene_cameras[index]);
+                    tracer.reset_tracing();
+                }
+            }
+        }
+
+        ui.separator();
+
+        if ui.selectable("Load aperture mask...") {
+            let path = get_open_file_name("Image", &["png", "jpg", "exr", "hdr"]);
+            if let Some(path) = path {
+                match ApertureMask::load(&path) {
+                    Ok(mask) => {
+                        tracer.camera.aperture_mask = Some(Arc::new(mask));
+                        tracer.reset_tracing();
+                    }
+                    Err(err) => show_error(&format!("Failed to load aperture mask: {:?}", err)),
+                }
+            }
+        }
+
+        if ui.selectable("Clear aperture mask") {
+            tracer.camera.aperture_mask = None;
+            tracer.reset_tracing();
+        }
+
+        if ui.selectable("Load lens...") {
+            let path = get_open_file_name("Lens", &["dat", "txt"]);
+            if let Some(path) = path {
+                match LensElement::load(&path) {
+                    Ok(elements) => {
+                        tracer.camera.lens_elements = elements;
+                        tracer.reset_tracing();
+                    }
+                    Err(err) => show_error(&format!("Failed to load lens: {:?}", err)),
+                }
+            }
+        }
+
+        if ui.selectable("Double Gauss lens") {
+            tracer.camera.lens_elements = DOUBLE_GAUSS.to_vec();
+            tracer.reset_tracing();
+        }
+
         ui.separator();
 
         ui.slider("Movement speed", 0.01, 5., &mut tracer.camera.speed);
@@ -203,10 +265,17 @@ fn menu_camera(ui: &Ui, tracer: &mut Tracer) {
         let mut modified = false;
 
         modified = ui.slider("Vertical fov", 30., 160., &mut tracer.camera.v_fov) || modified;
-        modified =
-            ui.radio_button("Simple camera", &mut tracer.camera.simple_camera, true) || modified;
-        modified =
-            ui.radio_button("Aperture camera", &mut tracer.camera.simple_camera, false) || modified;
+        for (label, kind) in [
+            ("Simple camera", CameraKind::Simple),
+            ("Aperture camera", CameraKind::Aperture),
+            ("Orthographic camera", CameraKind::Orthographic),
+            ("Equirectangular camera", CameraKind::Equirectangular),
+            ("Cube map camera", CameraKind::Cubemap),
+            ("Fisheye camera", CameraKind::Fisheye),
+            ("Lens camera", CameraKind::Lens),
+        ] {
+            modified = ui.radio_button(label, &mut tracer.camera.kind, kind) || modified;
+        }
         modified = ui.slider("Aperture", 0.1, 10., &mut tracer.camera.aperture) || modified;
         modified = ui.slider(
             "Focus distance",
@@ -214,6 +283,33 @@ fn menu_camera(ui: &Ui, tracer: &mut Tracer) {
             100000.,
             &mut tracer.camera.focus_distance,
         ) || modified;
+        modified = ui.slider(
+            "Orthographic height",
+            0.1,
+            1000.,
+            &mut tracer.camera.ortho_height,
+        ) || modified;
+        modified = ui.slider("Fisheye fov", 90., 360., &mut tracer.camera.fisheye_fov) || modified;
+        modified = ui.slider("Aperture blades", 0, 16, &mut tracer.camera.blades) || modified;
+        modified = ui.slider(
+            "Blade rotation",
+            0.,
+            360.,
+            &mut tracer.camera.blade_rotation,
+        ) || modified;
+        modified = ui.slider("Cat's eye", 0., 2., &mut tracer.camera.cat_eye) || modified;
+        modified = ui.slider(
+            "Lens aperture (mm)",
+            1.,
+            50.,
+            &mut tracer.camera.lens_aperture,
+        ) || modified;
+        modified = ui.slider(
+            "Film diagonal (mm)",
+            10.,
+            100.,
+            &mut tracer.camera.film_diagonal,
+        ) || modified;
 
         if modified {
             tracer.reset_tracing();
@@ -238,12 +334,92 @@ fn menu_settings(ui: &Ui, tracer: &mut Tracer) {
             "Random light sample",
             &mut tracer.tracer_settings.random_light_sample,
         ) || modified;
+        modified = ui.radio_button(
+            "Uniform lights",
+            &mut tracer.tracer_settings.light_selection,
+            LightSelection::Uniform,
+        ) || modified;
+        modified = ui.radio_button(
+            "Lights by power",
+            &mut tracer.tracer_settings.light_selection,
+            LightSelection::Power,
+        ) || modified;
+        modified = ui.radio_button(
+            "Light tree",
+            &mut tracer.tracer_settings.light_selection,
+            LightSelection::Tree,
+        ) || modified;
+        modified = ui.checkbox("Spectral", &mut tracer.tracer_settings.spectral) || modified;
+        modified =
+            ui.checkbox("Path guiding", &mut tracer.tracer_settings.path_guiding) || modified;
+        modified = ui.radio_button(
+            "Path tracing",
+            &mut tracer.tracer_settings.integrator,
+            IntegratorType::Path,
+        ) || modified;
+        modified = ui.radio_button(
+            "Bidirectional",
+            &mut tracer.tracer_settings.integrator,
+            IntegratorType::Bidirectional,
+        ) || modified;
+        modified = ui.radio_button(
+            "Photon mapping",
+            &mut tracer.tracer_settings.integrator,
+            IntegratorType::PhotonMapping,
+        ) || modified;
+        if tracer.tracer_settings.integrator == IntegratorType::PhotonMapping {
+            ui.text_wrapped(
+                "Only point and directional lights emit photons, emissive surfaces and the \
+                environment are found through smooth surfaces alone.",
+            );
+        }
+        modified = ui.radio_button(
+            "Metropolis",
+            &mut tracer.tracer_settings.integrator,
+            IntegratorType::Metropolis,
+        ) || modified;
+        modified = ui.slider(
+            "Photons",
+            1000,
+            1000000,
+            &mut tracer.tracer_settings.photons,
+        ) || modified;
+        modified = ui.slider(
+            "Photon radius",
+            0.0005,
+            0.05,
+            &mut tracer.tracer_settings.photon_radius,
+        ) || modified;
+        modified = ui.slider(
+            "Markov chains",
+            1,
+            10000,
+            &mut tracer.tracer_settings.markov_chains,
+        ) || modified;
+        modified = ui.slider(
+            "Large steps",
+            0.,
+            1.,
+            &mut tracer.tracer_settings.large_step_probability,
+        ) || modified;
         modified = ui.slider(
             "Bounces",
             1,
             10,
             &mut tracer.tracer_settings.max_scatter_depth,
         ) || modified;
+        modified = ui.slider(
+            "Transparent hits",
+            0,
+            64,
+            &mut tracer.tracer_settings.max_transparent_hits,
+        ) || modified;
+        modified = ui.slider(
+            "Walk steps",
+            0,
+            4096,
+            &mut tracer.tracer_settings.max_walk_steps,
+        ) || modified;
 
         if modified {
             tracer.reset_tracing();
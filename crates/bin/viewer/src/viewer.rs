@@ -9,7 +9,54 @@ use std::time::Instant;
 const WIDTH: u32 = 1024;
 const HEIGHT: u32 = 768;
 
+/// Lints given scene files, returns process exit code.
+fn check_scenes(paths: &[String]) -> i32 {
+    if paths.is_empty() {
+        println!("Usage: viewer --check <scene.json>...");
+        return 2;
+    }
+
+    let mut failed = false;
+
+    for path in paths {
+        match pathtracer::validation::check_file(std::path::Path::new(path)) {
+            Ok(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    println!("{}", diagnostic);
+                }
+
+                let errors = diagnostics
+                    .iter()
+                    .filter(|d| d.severity == pathtracer::validation::Severity::Error)
+                    .count();
+                println!(
+                    "{}: {} error(s), {} warning(s)",
+                    path,
+                    errors,
+                    diagnostics.len() - errors
+                );
+
+                failed = failed || errors > 0;
+            }
+            Err(err) => {
+                println!("{}: failed to read: {:?}", path, err);
+                failed = true;
+            }
+        }
+    }
+
+    match failed {
+        true => 1,
+        false => 0,
+    }
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--check") {
+        std::process::exit(check_scenes(&args[1..]));
+    }
+
     let win = window::Window::new("rust-path-tracer", WIDTH as i32, HEIGHT as i32);
     let window::Window {
         event_loop,
@@ -0,0 +1,519 @@
+use cgmath::dot;
+
+use crate::brdf::*;
+use crate::light::{Attenuable, Light};
+use crate::math::*;
+use crate::pathtracer::{Integrator, Splat, Tracer};
+use crate::random::{Sampler, UniformSampler};
+use crate::ray::Ray;
+
+// Bidirectional path tracer.
+// Source: "Robust Monte Carlo Methods for Light Transport Simulation" by Veach, chapter 10
+// Source: https://pbr-book.org/3ed-2018/Light_Transport_III_Bidirectional_Methods/Bidirectional_Path_Tracing
+// Every vertex of a camera subpath is connected to every vertex of a light subpath and the
+// strategies are weighted by the balance heuristic. Lights are points and directions which paths
+// cannot hit, so emissive surfaces and the environment are found by camera subpaths alone. Media
+// are not sampled, paths pass through them unattenuated. Connections pass through thin-walled
+// transmissive and alpha blended surfaces like shadow rays of the path tracer.
+pub struct Bidirectional<'a> {
+    tracer: &'a Tracer,
+}
+
+#[derive(Copy, Clone)]
+enum Transport {
+    Radiance,   //< Camera subpaths
+    Importance, //< Light subpaths
+}
+
+struct Surface {
+    hit: Hit,
+    material: ResolvedMaterial,
+    wo: Vector3, //< Direction of the incident ray
+    transport: Transport,
+}
+
+enum VertexKind<'a> {
+    /// Orthographic cameras sample ray origins per area of the image, see `Camera::importance_pdf`.
+    Camera {
+        orthographic: bool,
+    },
+    Light(&'a Light),
+    Surface(Box<Surface>),
+}
+
+struct Vertex<'a> {
+    kind: VertexKind<'a>,
+    position: Vector3,
+    beta: Vector3,
+    /// Densities of sampling the vertex along the subpath and in the opposite direction, per area.
+    pdf_fwd: f32,
+    pdf_rev: f32,
+}
+
+// Returns unit direction between vertices, directional lights lie infinitely far.
+fn direction(from: &Vertex, to: &Vertex) -> Vector3 {
+    match (&from.kind, &to.kind) {
+        (VertexKind::Light(Light::Directional(light)), _) => -light.dir,
+        (_, VertexKind::Light(Light::Directional(light))) => light.dir,
+        _ => (to.position - from.position).unit(),
+    }
+}
+
+// Factor making scattering of importance with shading normals adjoint to scattering of radiance.
+// Source: "Non-symmetric Scattering in Light Transport Algorithms" by Veach
+pub(crate) fn shading_correction(
+    hit: &Hit,
+    material: &ResolvedMaterial,
+    wo: Vector3,
+    wi: Vector3,
+) -> f32 {
+    let numerator = dot(wo, material.shading_normal).abs() * dot(wi, hit.normal).abs();
+    let denominator = dot(wo, hit.normal).abs() * dot(wi, material.shading_normal).abs();
+
+    match denominator > 0. {
+        true => numerator / denominator,
+        false => 0.,
+    }
+}
+
+impl Vertex<'_> {
+    /// Converts density of sampling `next` from this vertex per solid angle to density per area.
+    ///
+    /// Directional lights sample positions on a disk perpendicular to their direction, their
+    /// density is per area of the disk, as well as densities of orthographic cameras.
+    fn to_area(&self, pdf: f32, next: &Vertex) -> f32 {
+        let pdf = match (&self.kind, &next.kind) {
+            (_, VertexKind::Light(Light::Directional(_))) => return pdf,
+            (VertexKind::Light(Light::Directional(_)), _) => pdf,
+            (VertexKind::Camera { orthographic: true }, _) => pdf,
+            _ => pdf / (next.position - self.position).squared_length(),
+        };
+
+        match &next.kind {
+            VertexKind::Surface(surface) => {
+                pdf * dot(surface.hit.normal, direction(self, next)).abs()
+            }
+            _ => pdf,
+        }
+    }
+
+    /// Returns density of sampling `next` from this vertex reached from `prev`, per area.
+    fn pdf(&self, tracer: &Tracer, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let pdf = match &self.kind {
+            VertexKind::Camera { .. } => tracer
+                .camera()
+                .importance_pdf(&Ray::new(self.position, direction(self, next))),
+            VertexKind::Light(Light::Point(point)) => 1. / point.solid_angle(),
+            VertexKind::Light(Light::Directional(_)) => {
+                let (_, radius) = tracer.scene().bounding_sphere();
+                ONE_OVER_PI / (radius * radius)
+            }
+            VertexKind::Surface(surface) => match prev {
+                Some(prev) => surface.hit.material.brdf.pdf(
+                    &direction(self, next),
+                    &direction(prev, self),
+                    &surface.material,
+                ),
+                None => 0.,
+            },
+        };
+
+        self.to_area(pdf, next)
+    }
+
+    /// Returns scattering of light towards `next` from the incident ray, with cosine to the normal.
+    fn eval(&self, next: &Vertex) -> Vector3 {
+        match &self.kind {
+            VertexKind::Surface(surface) => {
+                let Surface {
+                    hit,
+                    material,
+                    wo,
+                    transport,
+                } = surface.as_ref();
+                let wi = direction(self, next);
+                let value = hit.material.brdf.eval(&wi, wo, material);
+
+                match transport {
+                    Transport::Radiance => value,
+                    Transport::Importance => value * shading_correction(hit, material, -*wo, wi),
+                }
+            }
+            _ => Vector3::zero(),
+        }
+    }
+}
+
+impl<'a> Bidirectional<'a> {
+    pub fn new(tracer: &'a Tracer) -> Self {
+        Self { tracer }
+    }
+
+    /// Extends the subpath by vertices found along the ray, the first of them is reached with
+    /// throughput `beta` and given density per solid angle.
+    ///
+    /// Returns radiance emitted by surfaces and the environment along camera subpaths.
+    fn random_walk(
+        &self,
+        ray: Ray,
+        beta: Vector3,
+        pdf: f32,
+        transport: Transport,
+        sampler: &UniformSampler,
+        path: &mut Vec<Vertex<'a>>,
+    ) -> Vector3 {
+        let settings = self.tracer.settings();
+        let scene = self.tracer.scene();
+
+        let (mut ray, mut beta, mut pdf) = (ray, beta, pdf);
+        let mut color = Vector3::zero();
+
+        loop {
+            let hit = match scene.hit(
+                &ray,
+                settings.t_min,
+                settings.t_max,
+                settings.max_transparent_hits,
+                sampler,
+            ) {
+                Some(hit) => hit,
+                None => {
+                    if let Transport::Radiance = transport {
+                        color += beta.mul(scene.environment(&ray));
+                    }
+                    break;
+                }
+            };
+
+            let prev = path
+                .last()
+                .expect("subpath should start at camera or light");
+            if let VertexKind::Light(light) = prev.kind {
+                beta = beta.mul(light.emitted(&prev.position, &hit.position));
+            }
+
+            let material = hit.resolve_material();
+            if let Transport::Radiance = transport {
+                color += beta.mul(material.emissive);
+            }
+
+            let bounce = path.len() as u32;
+            let scattered = match bounce < settings.max_scatter_depth {
+                true => self
+                    .tracer
+                    .russian_roulette(bounce, luminance(beta), sampler)
+                    .and_then(|survival| {
+                        self.scatter(&hit, &material, &ray, transport, sampler)
+                            .map(|(wi, weight, pdf, pdf_rev)| (wi, weight / survival, pdf, pdf_rev))
+                    }),
+                false => None,
+            };
+
+            let differentials = scattered.and_then(|(wi, ..)| match transport {
+                Transport::Radiance => hit.scatter_differentials(&ray, &material, wi),
+                Transport::Importance => None,
+            });
+
+            let mut vertex = Vertex {
+                position: hit.position,
+                kind: VertexKind::Surface(Box::new(Surface {
+                    hit,
+                    material,
+                    wo: ray.direction,
+                    transport,
+                })),
+                beta,
+                pdf_fwd: 0.,
+                pdf_rev: 0.,
+            };
+            vertex.pdf_fwd = prev.to_area(pdf, &vertex);
+
+            let (wi, weight, next_pdf, pdf_rev) = match scattered {
+                Some(scattered) => scattered,
+                None => {
+                    path.push(vertex);
+                    break;
+                }
+            };
+
+            let prev = path.last_mut().unwrap();
+            prev.pdf_rev = vertex.to_area(pdf_rev, prev);
+
+            ray = Ray {
+                differentials,
+                ..Ray::new(vertex.position, wi)
+            };
+            beta = beta.mul(weight);
+            pdf = next_pdf;
+            path.push(vertex);
+        }
+
+        color
+    }
+
+    /// Samples direction of the ray leaving the surface, returns it with weight of the throughput
+    /// and densities of sampling it and of sampling the incident ray in reverse.
+    fn scatter(
+        &self,
+        hit: &Hit,
+        material: &ResolvedMaterial,
+        ray: &Ray,
+        transport: Transport,
+        sampler: &UniformSampler,
+    ) -> Option<(Vector3, Vector3, f32, f32)> {
+        let brdf = &(*hit.material.brdf);
+        let wo = ray.direction;
+
+        // Probability of the selected BRDF is included in its pdf.
+        let brdf_type = match sampler.next_float() < brdf.probability(&-wo, material) {
+            true => BrdfType::Specular,
+            false => BrdfType::Diffuse,
+        };
+
+        let wi = brdf.sample(brdf_type, &wo, material, sampler)?.unit();
+        let pdf = brdf.pdf(&wi, &wo, material);
+        if pdf <= 0. {
+            return None;
+        }
+
+        let weight = brdf.eval(&wi, &wo, material) / pdf;
+        let weight = match transport {
+            Transport::Radiance => weight,
+            Transport::Importance => weight * shading_correction(hit, material, -wo, wi),
+        };
+
+        Some((wi, weight, pdf, brdf.pdf(&-wo, &-wi, material)))
+    }
+
+    /// Traces subpath from a randomly selected light.
+    fn light_subpath(&self, sampler: &UniformSampler, path: &mut Vec<Vertex<'a>>) {
+        let scene = self.tracer.scene();
+        let lights = scene.lights();
+        let selection = self.tracer.settings().light_selection;
+        let (index, light_probability) = match scene
+            .light_sampler()
+            .sample_emitting(selection, sampler.next_float())
+        {
+            Some(selected) => selected,
+            None => return,
+        };
+        let light = &lights[index];
+        // Contributions of lights are averaged as in the path tracer.
+        let scale = 1. / (light_probability * lights.len() as f32);
+
+        let emission = match light.sample_emission(scene.bounding_sphere(), sampler) {
+            Some(emission) => emission,
+            None => return,
+        };
+
+        path.push(Vertex {
+            kind: VertexKind::Light(light),
+            position: emission.ray.origin,
+            beta: to_v3(scale),
+            pdf_fwd: light_probability * emission.pdf_position,
+            pdf_rev: 0.,
+        });
+
+        self.random_walk(
+            emission.ray,
+            to_v3(scale / emission.pdf),
+            emission.pdf,
+            Transport::Importance,
+            sampler,
+            path,
+        );
+    }
+
+    /// Returns transmittance of surfaces along the segment between vertices, zero if it is blocked.
+    fn transmittance(&self, from: &Vertex, to: &Vertex, sampler: &UniformSampler) -> Vector3 {
+        let settings = self.tracer.settings();
+        let distance = match to.kind {
+            VertexKind::Light(Light::Directional(_)) => settings.t_max,
+            _ => (to.position - from.position).length() - settings.t_min,
+        };
+
+        self.tracer
+            .surface_transmittance(from.position, direction(from, to), distance, sampler)
+            .unwrap_or(Vector3::zero())
+    }
+
+    /// Returns contribution of the path connecting first `s` vertices of the light subpath and
+    /// first `t` vertices of the camera subpath, at least two of them.
+    fn connect(
+        &self,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        s: usize,
+        t: usize,
+        sampler: &UniformSampler,
+    ) -> Vector3 {
+        let (qs, pt) = (&light_path[s - 1], &camera_path[t - 1]);
+
+        let contribution = match &qs.kind {
+            // Light arriving from the light, same as in the path tracer.
+            VertexKind::Light(light) => pt
+                .beta
+                .mul(pt.eval(qs))
+                .mul(light.intensity_at(&pt.position))
+                .mul(qs.beta),
+            _ => {
+                pt.beta.mul(pt.eval(qs)).mul(qs.eval(pt)).mul(qs.beta)
+                    / (qs.position - pt.position).squared_length()
+            }
+        };
+
+        if contribution == Vector3::zero() {
+            return Vector3::zero();
+        }
+
+        let contribution = contribution.mul(self.transmittance(pt, qs, sampler));
+        if contribution == Vector3::zero() {
+            return Vector3::zero();
+        }
+
+        contribution * self.mis_weight(light_path, camera_path, s, t)
+    }
+
+    /// Returns light arriving to the lens from the last of first `s` vertices of the light subpath.
+    fn splat(&self, light_path: &[Vertex], s: usize, sampler: &UniformSampler) -> Option<Splat> {
+        let qs = &light_path[s - 1];
+        let sample = self
+            .tracer
+            .camera()
+            .sample_importance(&qs.position, sampler)?;
+
+        let camera = Vertex {
+            kind: VertexKind::Camera {
+                orthographic: self.tracer.camera().orthographic(),
+            },
+            position: sample.lens,
+            beta: to_v3(sample.importance / sample.pdf),
+            pdf_fwd: 0.,
+            pdf_rev: 0.,
+        };
+
+        let contribution = qs.beta.mul(qs.eval(&camera)).mul(camera.beta);
+        if contribution == Vector3::zero() {
+            return None;
+        }
+
+        let contribution = contribution.mul(self.transmittance(qs, &camera, sampler));
+        if contribution == Vector3::zero() {
+            return None;
+        }
+
+        Some(Splat {
+            x: sample.image.0,
+            y: sample.image.1,
+            color: contribution * self.mis_weight(light_path, std::slice::from_ref(&camera), s, 1),
+        })
+    }
+
+    /// Returns balance heuristic weight of the strategy connecting given numbers of vertices.
+    ///
+    /// Ratios of densities of sampling the path by other strategies and by this one are summed
+    /// up, strategies which would connect from the light vertex itself are impossible, as well as
+    /// strategies connecting to lenses which do not sample importance.
+    fn mis_weight(&self, light_path: &[Vertex], camera_path: &[Vertex], s: usize, t: usize) -> f32 {
+        let (qs, pt) = (&light_path[s - 1], &camera_path[t - 1]);
+        let qs_minus = s.checked_sub(2).map(|i| &light_path[i]);
+        let pt_minus = t.checked_sub(2).map(|i| &camera_path[i]);
+
+        // Reverse densities of vertices around the connection are given by the other subpath.
+        let densities = |path: &[Vertex]| -> Vec<(f32, f32)> {
+            path.iter()
+                .map(|vertex| (vertex.pdf_rev, vertex.pdf_fwd))
+                .collect()
+        };
+        let mut light = densities(&light_path[..s]);
+        let mut camera = densities(&camera_path[..t]);
+
+        camera[t - 1].0 = qs.pdf(self.tracer, qs_minus, pt);
+        if let Some(pt_minus) = pt_minus {
+            camera[t - 2].0 = pt.pdf(self.tracer, Some(qs), pt_minus);
+        }
+        light[s - 1].0 = pt.pdf(self.tracer, pt_minus, qs);
+        if let Some(qs_minus) = qs_minus {
+            light[s - 2].0 = qs.pdf(self.tracer, Some(pt), qs_minus);
+        }
+
+        let remap = |pdf: f32| match pdf > 0. {
+            true => pdf,
+            false => 1.,
+        };
+        let ratios = |densities: &[(f32, f32)]| {
+            densities
+                .iter()
+                .rev()
+                .scan(1., |ratio, (pdf_rev, pdf_fwd)| {
+                    *ratio *= remap(*pdf_rev) / remap(*pdf_fwd);
+                    Some(*ratio)
+                })
+                .sum::<f32>()
+        };
+
+        // Strategies connecting to the lens are impossible for cameras not sampling importance.
+        let camera = match self.tracer.camera().samples_importance() {
+            true => &camera[1..],
+            false => &camera[t.min(2)..],
+        };
+        let sum = ratios(camera) + ratios(&light[1..]);
+        match sum.is_finite() {
+            true => 1. / (1. + sum),
+            false => 0.,
+        }
+    }
+}
+
+impl Integrator for Bidirectional<'_> {
+    fn radiance(&self, x: f32, y: f32, pixel_size: (f32, f32), splats: &mut Vec<Splat>) -> Vector3 {
+        let sampler = UniformSampler::new();
+        let camera = self.tracer.camera();
+        let max_vertices = self.tracer.settings().max_scatter_depth as usize + 1;
+
+        let (ray, weight) = self.tracer.camera_ray(x, y, pixel_size, &sampler);
+
+        let mut camera_path = Vec::with_capacity(max_vertices);
+        camera_path.push(Vertex {
+            kind: VertexKind::Camera {
+                orthographic: camera.orthographic(),
+            },
+            position: ray.origin,
+            beta: Vector3::one() * weight,
+            pdf_fwd: 1.,
+            pdf_rev: 0.,
+        });
+
+        // Rays blocked by the lens end at the camera, while light is still traced towards it.
+        let mut color = match weight > 0. {
+            true => self.random_walk(
+                ray,
+                Vector3::one() * weight,
+                camera.importance_pdf(&ray),
+                Transport::Radiance,
+                &sampler,
+                &mut camera_path,
+            ),
+            false => Vector3::zero(),
+        };
+
+        let mut light_path = Vec::with_capacity(max_vertices);
+        self.light_subpath(&sampler, &mut light_path);
+
+        // Paths are at most as long as paths of the path tracer with a shadow ray from the last bounce.
+        for t in 1..=camera_path.len() {
+            for s in 1..=light_path.len() {
+                if s + t < 3 || s + t > max_vertices + 1 {
+                    continue;
+                }
+
+                match t {
+                    1 => splats.extend(self.splat(&light_path, s, &sampler)),
+                    _ => color += self.connect(&light_path, &camera_path, s, t, &sampler),
+                }
+            }
+        }
+
+        color
+    }
+}
@@ -1,11 +1,14 @@
 use crate::material::*;
-use crate::math::{EnhancedVector, Vector3};
-use crate::random::UniformSampler;
+use crate::math::{reflect, refract, to_v3, EnhancedVector, Vector3};
+use crate::random::Sampler;
+use crate::ray::{Ray, RayDifferentials};
+use crate::spectrum::upsample;
 
-use cgmath::Matrix3;
+use cgmath::{dot, Matrix3};
 
 use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct ResolvedMaterial {
     pub base_color: Vector3,
     pub emissive: Vector3,
@@ -13,23 +16,139 @@ pub struct ResolvedMaterial {
     pub shading_normal: Vector3, // Interpolation between vertex normals (flat shading + normal map).
     pub metalness: f32,
     pub roughness: f32,
+    pub transmission: f32,
+    pub subsurface: f32, // Part of the remaining diffuse base entering the volume instead.
+    pub ior: f32,
+    pub conductor: Option<(Vector3, Vector3)>, // Real and imaginary part of complex IOR per channel.
+    pub specular: f32,
+    pub specular_color: Vector3,
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    pub sheen_color: Vector3,
+    pub sheen_roughness: f32,
+    pub anisotropy: f32,
+    pub anisotropy_direction: Vector3, // Not orthogonalized against shading normal.
+    pub iridescence: f32,
+    pub iridescence_ior: f32,
+    pub iridescence_thickness: f32,
+    pub thin_walled: bool,
     pub single_sided: bool,
 }
 
+// Cosine between sampled and ideal direction above which scattering is treated as smooth.
+const SMOOTH_SCATTER_COSINE: f32 = 0.99;
+
 pub struct Hit {
     pub position: Vector3,
     pub material: Arc<Material>,
     pub t: f32,
-    pub uv: (f32, f32),
+    pub tex_coord: TexCoord,
     pub normal: Vector3,
     pub tangent: Vector3,
     pub bitangent: Vector3,
+    /// Derivatives of position with respect to coordinates of each UV set.
+    pub dpdu: [Vector3; UV_SETS],
+    pub dpdv: [Vector3; UV_SETS],
+    /// Offsets of the position towards neighbouring pixels, if the ray has differentials.
+    pub position_differentials: Option<(Vector3, Vector3)>,
 }
 
 impl Hit {
+    /// Estimates footprint of the pixel on the surface and in texture space.
+    ///
+    /// Offset rays are intersected with the tangent plane and the offsets projected onto dp/du and dp/dv.
+    pub fn compute_differentials(&mut self, ray: &Ray) {
+        let differentials = match ray.differentials {
+            Some(differentials) => differentials,
+            None => return,
+        };
+
+        let n = self.normal;
+        let offset = |origin: Vector3, direction: Vector3| {
+            let t = dot(n, self.position - origin) / dot(n, direction);
+            origin + direction * t - self.position
+        };
+
+        let dpdx = offset(differentials.rx_origin, differentials.rx_direction);
+        let dpdy = offset(differentials.ry_origin, differentials.ry_direction);
+        let finite = |v: Vector3| v.x.is_finite() && v.y.is_finite() && v.z.is_finite();
+        if !finite(dpdx) || !finite(dpdy) {
+            return;
+        }
+        self.position_differentials = Some((dpdx, dpdy));
+
+        // Least squares solution of dpdu * du + dpdv * dv = dp.
+        for (set, coord) in self.tex_coord.sets.iter_mut().enumerate() {
+            let (dpdu, dpdv) = (self.dpdu[set], self.dpdv[set]);
+            let (a, b, c) = (dot(dpdu, dpdu), dot(dpdu, dpdv), dot(dpdv, dpdv));
+            let det = a * c - b * b;
+            let solve = |dp: Vector3| {
+                let (pu, pv) = (dot(dpdu, dp), dot(dpdv, dp));
+                ((c * pu - b * pv) / det, (a * pv - b * pu) / det)
+            };
+
+            let (duv_dx, duv_dy) = (solve(dpdx), solve(dpdy));
+            if [duv_dx.0, duv_dx.1, duv_dy.0, duv_dy.1]
+                .iter()
+                .all(|d| d.is_finite())
+            {
+                coord.duv_dx = duv_dx;
+                coord.duv_dy = duv_dy;
+            }
+        }
+    }
+
+    /// Returns differentials of the scattered ray in direction wi for smooth reflection and refraction.
+    ///
+    /// Rougher scattering spreads the footprint too much to be tracked. Curvature of the surface is
+    /// ignored, offset rays scatter about the same normal.
+    pub fn scatter_differentials(
+        &self,
+        ray: &Ray,
+        material: &ResolvedMaterial,
+        wi: Vector3,
+    ) -> Option<RayDifferentials> {
+        let differentials = ray.differentials?;
+        let (dpdx, dpdy) = self.position_differentials?;
+
+        let d = ray.direction.unit();
+        let n = match dot(d, material.shading_normal) < 0. {
+            true => material.shading_normal,
+            false => -material.shading_normal,
+        };
+        let eta = match dot(d, material.geometry_normal) < 0. {
+            true => 1. / material.ior,
+            false => material.ior,
+        };
+
+        let smooth = |ideal: Option<Vector3>| {
+            ideal.is_some_and(|ideal| dot(wi.unit(), ideal) > SMOOTH_SCATTER_COSINE)
+        };
+        let reflected = |direction: Vector3| Some(reflect(direction.unit(), n));
+        let transmitted = |direction: Vector3| match material.thin_walled {
+            true => Some(direction.unit()),
+            false => refract(&direction, &n, eta).map(|t| t.unit()),
+        };
+
+        let scatter: &dyn Fn(Vector3) -> Option<Vector3> = if smooth(reflected(d)) {
+            &reflected
+        } else if material.transmission > 0. && smooth(transmitted(d)) {
+            &transmitted
+        } else {
+            return None;
+        };
+
+        Some(RayDifferentials {
+            rx_origin: self.position + dpdx,
+            rx_direction: scatter(differentials.rx_direction)?,
+            ry_origin: self.position + dpdy,
+            ry_direction: scatter(differentials.ry_direction)?,
+        })
+    }
+
     pub fn resolve_material(&self) -> ResolvedMaterial {
-        let base_color = self.material.base_color(self.uv);
-        let emissive = self.material.emissive_color(self.uv);
+        let base_color = self.material.base_color(&self.tex_coord);
+        let emissive = self.material.emissive_color(&self.tex_coord);
         // TODO: tangent space...
         let geometry_normal = self.normal;
 
@@ -37,15 +156,27 @@ impl Hit {
         let shading_normal = match self.material.has_normal() {
             true => {
                 let btn = Matrix3::from_cols(self.bitangent, self.tangent, self.normal);
-                let mat_norm = self.material.normal(self.uv);
+                let mat_norm = self.material.normal(&self.tex_coord);
                 (btn * mat_norm).unit()
             }
             false => self.normal,
         };
 
-        let metalness = self.material.metalness(self.uv);
-        let roughness = self.material.roughness(self.uv);
-        let single_sided = self.material.single_sided;
+        let metalness = self.material.metalness(&self.tex_coord);
+        let roughness = self.material.roughness(&self.tex_coord);
+        let transmission = self.material.transmission(&self.tex_coord);
+        let (subsurface, _) = self.material.subsurface(&self.tex_coord);
+        let ior = self.material.ior;
+        let conductor = self.material.conductor.as_ref().map(|ior| ior.rgb());
+        let (specular, specular_color) = self.material.specular(&self.tex_coord);
+        let (clearcoat, clearcoat_roughness) = self.material.clearcoat(&self.tex_coord);
+        let (sheen_color, sheen_roughness) = self.material.sheen(&self.tex_coord);
+        let (anisotropy, direction) = self.material.anisotropy(&self.tex_coord);
+        let anisotropy_direction = self.tangent * direction.0 + self.bitangent * direction.1;
+        let (iridescence, iridescence_thickness) = self.material.iridescence(&self.tex_coord);
+        let iridescence_ior = self.material.iridescence.ior;
+        let thin_walled = self.material.is_thin_walled();
+        let single_sided = self.material.is_single_sided();
 
         ResolvedMaterial {
             base_color,
@@ -54,11 +185,57 @@ impl Hit {
             shading_normal,
             metalness,
             roughness,
+            transmission,
+            subsurface,
+            ior,
+            conductor,
+            specular,
+            specular_color,
+            clearcoat,
+            clearcoat_roughness,
+            sheen_color,
+            sheen_roughness,
+            anisotropy,
+            anisotropy_direction,
+            iridescence,
+            iridescence_ior,
+            iridescence_thickness,
+            thin_walled,
             single_sided,
         }
     }
 }
 
+/// Surfaces without a diffuse base smoother than this reflect or refract a sharp image.
+const SMOOTH_ROUGHNESS: f32 = 0.2;
+
+impl ResolvedMaterial {
+    /// Returns whether the surface reflects or refracts a sharp image, which estimates of light
+    /// arriving from neighbouring directions would blur.
+    pub fn is_smooth(&self) -> bool {
+        self.roughness < SMOOTH_ROUGHNESS && (self.metalness >= 1. || self.transmission >= 1.)
+    }
+
+    /// Returns the material seen by light of given wavelength in nanometers, its colors are
+    /// upsampled to spectra and its IOR is taken at the wavelength.
+    pub fn at_wavelength(&self, material: &Material, lambda: f32) -> ResolvedMaterial {
+        let gray = |color: Vector3| to_v3(upsample(color, lambda));
+
+        ResolvedMaterial {
+            base_color: gray(self.base_color),
+            emissive: gray(self.emissive),
+            specular_color: gray(self.specular_color),
+            sheen_color: gray(self.sheen_color),
+            ior: material.ior_at(lambda),
+            conductor: material.conductor.as_ref().map(|ior| {
+                let (eta, k) = ior.at(lambda);
+                (to_v3(eta), to_v3(k))
+            }),
+            ..self.clone()
+        }
+    }
+}
+
 pub enum BrdfType {
     Diffuse,
     Specular,
@@ -71,17 +248,122 @@ pub trait Brdf {
         brdf_type: BrdfType,
         wo: &Vector3,
         material: &ResolvedMaterial,
-        sampler: &UniformSampler,
+        sampler: &dyn Sampler,
     ) -> Option<Vector3>;
 
     /// Returns attenuation given incident vector wi, outgoing vector wo, normal at incident point and hit record.
     fn eval(&self, wi: &Vector3, wo: &Vector3, material: &ResolvedMaterial) -> Vector3;
 
-    /// Returns probabilistic density function of material.
-    fn pdf(&self, wi: &Vector3, normal: &Vector3) -> f32;
+    /// Returns probability density of sampling incident vector wi given outgoing vector wo.
+    fn pdf(&self, wi: &Vector3, wo: &Vector3, material: &ResolvedMaterial) -> f32;
 
-    /// Return probability of selecting specular and diffuse BRDF.
+    /// Return probability of selecting specular over diffuse BRDF, 'pdf' must account for it.
     fn probability(&self, _v: &Vector3, _material: &ResolvedMaterial) -> f32 {
         0.5
     }
 }
+
+/// Fixtures and checks shared by tests of BRDFs.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::{Brdf, BrdfType, ResolvedMaterial};
+    use crate::math::*;
+    use crate::random::{Sampler, UniformSampler};
+
+    /// Returns white dielectric material of a surface facing the Z axis, without any layers.
+    pub(crate) fn material() -> ResolvedMaterial {
+        ResolvedMaterial {
+            base_color: Vector3::one(),
+            emissive: Vector3::zero(),
+            geometry_normal: Vector3::new(0., 0., 1.),
+            shading_normal: Vector3::new(0., 0., 1.),
+            metalness: 0.,
+            roughness: 0.5,
+            transmission: 0.,
+            subsurface: 0.,
+            ior: 1.5,
+            conductor: None,
+            specular: 1.,
+            specular_color: Vector3::one(),
+            clearcoat: 0.,
+            clearcoat_roughness: 0.,
+            sheen_color: Vector3::zero(),
+            sheen_roughness: 0.,
+            anisotropy: 0.,
+            anisotropy_direction: Vector3::new(1., 0., 0.),
+            iridescence: 0.,
+            iridescence_ior: 1.3,
+            iridescence_thickness: 0.,
+            thin_walled: false,
+            single_sided: false,
+        }
+    }
+
+    /// Samples directions with the BRDF selected by its probability, returns the rate of
+    /// successful samples and the estimate of the integral of eval.
+    pub(crate) fn sample_integral(
+        brdf: &dyn Brdf,
+        wo: &Vector3,
+        material: &ResolvedMaterial,
+        count: usize,
+        sampler: &UniformSampler,
+    ) -> (f32, Vector3) {
+        let probability = brdf.probability(&-*wo, material);
+
+        let mut sampled = 0;
+        let mut integral = Vector3::zero();
+        for _ in 0..count {
+            let brdf_type = match sampler.next_float() < probability {
+                true => BrdfType::Specular,
+                false => BrdfType::Diffuse,
+            };
+
+            if let Some(wi) = brdf.sample(brdf_type, wo, material, sampler) {
+                sampled += 1;
+                integral += brdf.eval(&wi, wo, material) / brdf.pdf(&wi, wo, material);
+            }
+        }
+
+        (sampled as f32 / count as f32, integral / count as f32)
+    }
+
+    /// Checks that directions are sampled at the rate given by the integral of pdf over the
+    /// sphere, returns estimates of the integral of eval by sampling and by quadrature.
+    pub(crate) fn sampling_matches_pdf(
+        brdf: &dyn Brdf,
+        wo: &Vector3,
+        material: &ResolvedMaterial,
+    ) -> (Vector3, Vector3) {
+        let sampler = UniformSampler::new();
+        let (sampled_rate, sampled_integral) =
+            sample_integral(brdf, wo, material, 100000, &sampler);
+
+        // Integrals of pdf and eval over the sphere by midpoint rule in spherical coordinates.
+        let (mut pdf_integral, mut uniform_integral) = (0., Vector3::zero());
+        let (rows, columns) = (800, 800);
+        for row in 0..rows {
+            let theta = (row as f32 + 0.5) / rows as f32 * std::f32::consts::PI;
+            let solid_angle =
+                theta.sin() * std::f32::consts::PI / rows as f32 * TWO_PI / columns as f32;
+
+            for column in 0..columns {
+                let phi = (column as f32 + 0.5) / columns as f32 * TWO_PI;
+                let wi = Vector3::new(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                );
+                pdf_integral += brdf.pdf(&wi, wo, material) * solid_angle;
+                uniform_integral += brdf.eval(&wi, wo, material) * solid_angle;
+            }
+        }
+
+        assert!(
+            (sampled_rate - pdf_integral).abs() < 0.03,
+            "{} {}",
+            sampled_rate,
+            pdf_integral
+        );
+        (sampled_integral, uniform_integral)
+    }
+}
@@ -0,0 +1,296 @@
+use crate::brdf::*;
+use crate::math::*;
+use crate::microfacet::*;
+use crate::random::Sampler;
+
+use cgmath::dot;
+
+// Rough dielectric BSDF, GGX microfacet reflection and refraction.
+// Source: "Microfacet Models for Refraction through Rough Surfaces" by Walter et al.
+// Light which is not transmitted is scattered by Lambertian base (KHR_materials_transmission),
+// thin-walled surfaces transmit light without bending it. Subsurface scattering refracts part of
+// the base into the volume, where the medium colors it.
+pub struct Dielectric {}
+
+// Fraction of light refracted when entering the surface and its tint.
+fn refracted(material: &ResolvedMaterial) -> (f32, Vector3) {
+    let subsurface = (1. - material.transmission) * material.subsurface;
+    (
+        material.transmission + subsurface,
+        material.base_color * material.transmission + Vector3::one() * subsurface,
+    )
+}
+
+// Scattering configuration, normal always faces the viewer.
+struct Frame {
+    v: Vector3,
+    n: Vector3,
+    eta: f32, //< IOR behind the surface over IOR in front of it
+    alpha_squared: f32,
+    inside: bool,
+}
+
+impl Frame {
+    fn new(wo: &Vector3, material: &ResolvedMaterial) -> Frame {
+        let v = -*wo;
+        let back_side = dot(v, material.geometry_normal) < 0.;
+        let inside = back_side && !material.thin_walled;
+
+        let alpha = (material.roughness * material.roughness).max(MIN_ALPHA);
+
+        Frame {
+            v,
+            n: match back_side {
+                true => -material.shading_normal,
+                false => material.shading_normal,
+            },
+            eta: match inside {
+                true => 1. / material.ior,
+                false => material.ior,
+            },
+            alpha_squared: alpha * alpha,
+            inside,
+        }
+    }
+
+    fn g1(&self, n_dot_s: f32) -> f32 {
+        smith_g1_ggx(self.alpha_squared, n_dot_s * n_dot_s)
+    }
+
+    // Density of visible normals, PDF of sampling h by 'sample_ggx_vndf'.
+    fn vndf(&self, h: Vector3) -> f32 {
+        let n_dot_v = dot(self.n, self.v);
+        self.g1(n_dot_v) * dot(self.v, h).max(0.) * ggx_d(self.alpha_squared, dot(self.n, h))
+            / n_dot_v
+    }
+
+    // Probability of sampling diffuse base instead of the specular lobes.
+    fn diffuse_probability(&self, material: &ResolvedMaterial) -> f32 {
+        match self.inside {
+            true => 0.,
+            false => 0.5 * (1. - refracted(material).0),
+        }
+    }
+
+    // Mirrors direction to the other side of the surface.
+    fn mirror(&self, l: Vector3) -> Vector3 {
+        l - 2. * dot(l, self.n) * self.n
+    }
+
+    // Half vector of refraction, oriented along the normal.
+    fn refraction_half_vector(&self, l: Vector3) -> Vector3 {
+        let h = (self.v + l * self.eta).unit();
+        match dot(h, self.n) < 0. {
+            true => -h,
+            false => h,
+        }
+    }
+}
+
+impl Dielectric {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Brdf for Dielectric {
+    fn sample(
+        &self,
+        _brdf_type: BrdfType,
+        wo: &Vector3,
+        material: &ResolvedMaterial,
+        sampler: &dyn Sampler,
+    ) -> Option<Vector3> {
+        let frame = Frame::new(wo, material);
+        if dot(frame.n, frame.v) <= 0. {
+            return None;
+        }
+
+        let q_rotation_to_z = get_rotation_to_z_axis(frame.n);
+        let to_world = |local| rotate_point(invert_rotation(q_rotation_to_z), local).unit();
+
+        if sampler.next_float() < frame.diffuse_probability(material) {
+            let (l_local, _) = sample_hemisphere(sampler);
+            return Some(to_world(l_local));
+        }
+
+        let alpha = frame.alpha_squared.sqrt();
+        let v_local = rotate_point(q_rotation_to_z, frame.v);
+        let u = (sampler.next_float(), sampler.next_float());
+        let h = to_world(sample_ggx_vndf(v_local, (alpha, alpha), u));
+
+        let reflected = reflect(-frame.v, h);
+        if sampler.next_float() < fresnel_dielectric(dot(frame.v, h), frame.eta) {
+            return Some(reflected).filter(|l| dot(*l, frame.n) > 0.);
+        }
+
+        let transmitted = match material.thin_walled {
+            true => Some(frame.mirror(reflected)),
+            false => refract(&-frame.v, &h, 1. / frame.eta),
+        };
+        transmitted.filter(|l| dot(*l, frame.n) < 0.)
+    }
+
+    fn eval(&self, wi: &Vector3, wo: &Vector3, material: &ResolvedMaterial) -> Vector3 {
+        let frame = Frame::new(wo, material);
+        let (v, n, l) = (frame.v, frame.n, *wi);
+
+        let n_dot_v = dot(n, v);
+        let n_dot_l = dot(n, l);
+        if n_dot_v <= 0. || n_dot_l == 0. {
+            return Vector3::zero();
+        }
+
+        // Reflection.
+        if n_dot_l > 0. {
+            let h = (v + l).unit();
+            let f = fresnel_dielectric(dot(v, h), frame.eta);
+            let specular =
+                f * ggx_d(frame.alpha_squared, dot(n, h)) * frame.g1(n_dot_v) * frame.g1(n_dot_l)
+                    / (4. * n_dot_v);
+
+            let diffuse = match frame.inside {
+                true => Vector3::zero(),
+                false => {
+                    material.base_color
+                        * ((1. - refracted(material).0)
+                            * (1. - fresnel_dielectric(n_dot_v, frame.eta))
+                            * ONE_OVER_PI
+                            * n_dot_l)
+                }
+            };
+
+            return to_v3(specular) + diffuse;
+        }
+
+        // Transmission is tinted when entering the material.
+        let tint = match frame.inside {
+            true => Vector3::one(),
+            false => refracted(material).1,
+        };
+
+        if material.thin_walled {
+            let h = (v + frame.mirror(l)).unit();
+            let f = fresnel_dielectric(dot(v, h), frame.eta);
+            let specular = (1. - f)
+                * ggx_d(frame.alpha_squared, dot(n, h))
+                * frame.g1(n_dot_v)
+                * frame.g1(-n_dot_l)
+                / (4. * n_dot_v);
+
+            return tint * specular;
+        }
+
+        let h = frame.refraction_half_vector(l);
+        let v_dot_h = dot(v, h);
+        let l_dot_h = dot(l, h);
+        if v_dot_h <= 0. || l_dot_h >= 0. {
+            return Vector3::zero();
+        }
+
+        // Radiance is scaled by 1 / eta^2 when crossing the boundary, so it cancels eta^2 of the BTDF.
+        let f = fresnel_dielectric(v_dot_h, frame.eta);
+        let denominator = v_dot_h + frame.eta * l_dot_h;
+        let specular = (1. - f)
+            * ggx_d(frame.alpha_squared, dot(n, h))
+            * frame.g1(n_dot_v)
+            * frame.g1(-n_dot_l)
+            * v_dot_h
+            * -l_dot_h
+            / (n_dot_v * denominator * denominator);
+
+        tint * specular
+    }
+
+    fn pdf(&self, wi: &Vector3, wo: &Vector3, material: &ResolvedMaterial) -> f32 {
+        let frame = Frame::new(wo, material);
+        let (v, n, l) = (frame.v, frame.n, *wi);
+
+        let n_dot_l = dot(n, l);
+        if dot(n, v) <= 0. || n_dot_l == 0. {
+            return 0.;
+        }
+
+        let diffuse_probability = frame.diffuse_probability(material);
+        let specular_probability = 1. - diffuse_probability;
+
+        if n_dot_l > 0. {
+            let h = (v + l).unit();
+            let f = fresnel_dielectric(dot(v, h), frame.eta);
+            let specular = f * frame.vndf(h) / (4. * dot(v, h));
+
+            return specular_probability * specular + diffuse_probability * n_dot_l * ONE_OVER_PI;
+        }
+
+        if material.thin_walled {
+            let h = (v + frame.mirror(l)).unit();
+            let f = fresnel_dielectric(dot(v, h), frame.eta);
+
+            return specular_probability * (1. - f) * frame.vndf(h) / (4. * dot(v, h));
+        }
+
+        let h = frame.refraction_half_vector(l);
+        let v_dot_h = dot(v, h);
+        let l_dot_h = dot(l, h);
+        if v_dot_h <= 0. || l_dot_h >= 0. {
+            return 0.;
+        }
+
+        let f = fresnel_dielectric(v_dot_h, frame.eta);
+        let denominator = v_dot_h + frame.eta * l_dot_h;
+        let jacobian = frame.eta * frame.eta * -l_dot_h / (denominator * denominator);
+
+        specular_probability * (1. - f) * frame.vndf(h) * jacobian
+    }
+
+    fn probability(&self, _v: &Vector3, _material: &ResolvedMaterial) -> f32 {
+        // All lobes are chosen by 'sample' itself, 'pdf' accounts for them.
+        1.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dielectric;
+    use crate::brdf::testing::{material, sampling_matches_pdf};
+    use crate::brdf::ResolvedMaterial;
+    use crate::math::*;
+
+    fn dielectric(roughness: f32, transmission: f32, thin_walled: bool) -> ResolvedMaterial {
+        ResolvedMaterial {
+            roughness,
+            transmission,
+            thin_walled,
+            ..material()
+        }
+    }
+
+    #[test]
+    fn test_sampling_matches_pdf() {
+        for (material, wo) in [
+            (
+                dielectric(0.6, 1., false),
+                Vector3::new(0.3, 0., -1.).unit(),
+            ),
+            (
+                dielectric(0.6, 1., false),
+                Vector3::new(0.3, 0.2, 1.).unit(),
+            ),
+            (
+                dielectric(0.5, 0.5, true),
+                Vector3::new(0.5, 0., -1.).unit(),
+            ),
+            (
+                ResolvedMaterial {
+                    subsurface: 0.7,
+                    ..dielectric(0.4, 0.2, false)
+                },
+                Vector3::new(0.4, 0., -1.).unit(),
+            ),
+        ] {
+            let (sampled_integral, uniform_integral) =
+                sampling_matches_pdf(&Dielectric::new(), &wo, &material);
+            assert!((sampled_integral.x - uniform_integral.x).abs() < 0.03);
+        }
+    }
+}
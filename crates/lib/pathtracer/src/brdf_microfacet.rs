@@ -6,10 +6,127 @@ use cgmath::dot;
 
 use crate::microfacet::*;
 
+// Clearcoat is a thin dielectric layer with IOR 1.5.
+const CLEARCOAT_F0: f32 = 0.04;
+
 // Microfacet based BRDF.
 // Source: https://github.com/boksajak/referencePT/blob/master/shaders/brdf.h
+// Layered as in glTF material model, clearcoat on top of sheen on top of specular and diffuse.
+// Source: https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html#appendix-b-brdf-implementation
 pub struct MicrofacetBrdf {}
 
+// Energy reflected by the lobes towards the viewer, used to select lobe to sample.
+struct LobeWeights {
+    coat: f32,
+    specular: f32,
+    diffuse: f32, //< Lambertian diffuse, sheen and multiple scattering, all sampled from cosine-weighted hemisphere
+}
+
+impl LobeWeights {
+    fn new(v: Vector3, material: &ResolvedMaterial) -> LobeWeights {
+        let n = material.shading_normal;
+        let data = prepare_brdf_data(n, n /* unused L vector */, v, material);
+
+        let fresnel = saturate(luminance(eval_specular_fresnel(
+            material,
+            data.specular_f0,
+            dot(n, v).max(0.),
+        )));
+
+        let sheen = max_component(material.sheen_color)
+            * sheen_albedo(data.n_dot_v, material.sheen_roughness);
+        let coat = clearcoat_fresnel(material, dot(material.geometry_normal, v).max(0.));
+
+        LobeWeights {
+            coat,
+            specular: (1. - coat) * (1. - sheen) * fresnel,
+            diffuse: (1. - coat)
+                * ((1. - sheen)
+                    * (luminance(data.diffuse_reflectance) * (1. - fresnel)
+                        + luminance(multiple_scattering_albedo(&data)))
+                    + sheen),
+        }
+    }
+
+    // Probability of sampling one of the specular lobes over diffuse ones.
+    fn specular_probability(&self) -> f32 {
+        let specular = self.coat + self.specular;
+
+        match (specular > 0., self.diffuse > 0.) {
+            // Clamp probability to avoid undersampling of less prominent BRDF
+            (true, true) => clamp(specular / (specular + self.diffuse), 0.1, 0.9),
+            (true, false) => 1.,
+            _ => 0.,
+        }
+    }
+
+    // Probability of sampling clearcoat over base specular lobe.
+    fn coat_probability(&self) -> f32 {
+        match self.coat > 0. {
+            true => self.coat / (self.coat + self.specular),
+            false => 0.,
+        }
+    }
+}
+
+#[inline]
+fn max_component(v: Vector3) -> f32 {
+    v.x.max(v.y).max(v.z)
+}
+
+// Part of light reflected by clearcoat, it does not reach the layers below.
+fn clearcoat_fresnel(material: &ResolvedMaterial, n_dot_s: f32) -> f32 {
+    material.clearcoat * eval_fresnel(to_v3(CLEARCOAT_F0), Vector3::one(), n_dot_s).x
+}
+
+// Base specular lobe, anisotropic along the tangent of the frame.
+fn base_lobe(material: &ResolvedMaterial) -> (TangentFrame, (f32, f32)) {
+    let alpha = (material.roughness * material.roughness).max(MIN_ALPHA);
+    (
+        TangentFrame::new(material.shading_normal, material.anisotropy_direction),
+        anisotropic_alpha(alpha, material.anisotropy),
+    )
+}
+
+fn clearcoat_alpha(material: &ResolvedMaterial) -> f32 {
+    (material.clearcoat_roughness * material.clearcoat_roughness).max(MIN_ALPHA)
+}
+
+fn clearcoat_lobe(material: &ResolvedMaterial) -> (TangentFrame, (f32, f32)) {
+    let alpha = clearcoat_alpha(material);
+    (
+        TangentFrame::new(material.geometry_normal, material.anisotropy_direction),
+        (alpha, alpha),
+    )
+}
+
+// Samples GGX reflection of view direction about normal of given frame.
+fn sample_reflection(
+    v: Vector3,
+    (frame, alpha_2d): (TangentFrame, (f32, f32)),
+    sampler: &dyn Sampler,
+) -> Option<Vector3> {
+    let v_local = frame.to_local(v);
+    if v_local.z <= 0. {
+        return None;
+    }
+
+    let u = (sampler.next_float(), sampler.next_float());
+    let h_local = sample_ggx_vndf(v_local, alpha_2d, u);
+    let l_local = reflect(-v_local, h_local);
+
+    Some(frame.to_world(l_local).unit())
+}
+
+fn reflection_pdf(v: Vector3, l: Vector3, (frame, alpha_2d): (TangentFrame, (f32, f32))) -> f32 {
+    let (v_local, l_local) = (frame.to_local(v), frame.to_local(l));
+    if v_local.z <= 0. || l_local.z <= 0. {
+        return 0.;
+    }
+
+    ggx_vndf_reflection_pdf(alpha_2d, v_local, (v_local + l_local).unit())
+}
+
 impl MicrofacetBrdf {
     pub fn new() -> Self {
         Self {}
@@ -22,7 +139,7 @@ impl Brdf for MicrofacetBrdf {
         brdf_type: BrdfType,
         wo: &Vector3,
         material: &ResolvedMaterial,
-        sampler: &UniformSampler,
+        sampler: &dyn Sampler,
     ) -> Option<Vector3> {
         let v = -*wo;
 
@@ -31,128 +148,180 @@ impl Brdf for MicrofacetBrdf {
             return None;
         }
 
-        // Transform view direction into local space of our sampling routines
-        // (local space is oriented so that its positive Z axis points along the shading normal)
-        let q_rotation_to_z = get_rotation_to_z_axis(material.shading_normal);
-        let v_local = rotate_point(q_rotation_to_z, v);
-        let n_local = Vector3::new(0., 0., 1.);
-
-        let (ray_direction_local, sample_weight) = match brdf_type {
+        let ray_direction = match brdf_type {
             BrdfType::Diffuse => {
                 // Sample diffuse ray using cosine-weighted hemisphere sampling
+                let q_rotation_to_z = get_rotation_to_z_axis(material.shading_normal);
                 let (ray_direction_local, _) = sample_hemisphere(sampler);
-                let data = prepare_brdf_data(n_local, ray_direction_local, v_local, material);
-
-                // Function 'diffuseTerm' is predivided by PDF of sampling the cosine weighted hemisphere
-                let sample_weight = data.diffuse_reflectance; // diffuse_term(data) = 1.0 for Lambertian;
-
-                // #if COMBINE_BRDFS_WITH_FRESNEL
-                // Sample a half-vector of specular BRDF. Note that we're reusing random variable 'u' here, but correctly it should be an new independent random number
-                let u = (sampler.next_float(), sampler.next_float());
-                let h_specular = sample_ggx_vndf(v_local, (data.alpha, data.alpha), u);
-
-                // Clamp HdotL to small value to prevent numerical instability. Assume that rays incident from below the hemisphere have been filtered
-                let v_dot_h = 0.00001f32.max(1.0f32.min(dot(v_local, h_specular)));
-
-                let diff = Vector3::one()
-                    - eval_fresnel(
-                        data.specular_f0,
-                        to_v3(shadowed_f90(data.specular_f0)),
-                        v_dot_h,
-                    );
-
-                (ray_direction_local, sample_weight.mul(diff))
-                // #endif
+                rotate_point(invert_rotation(q_rotation_to_z), ray_direction_local).unit()
             }
             BrdfType::Specular => {
-                let data = prepare_brdf_data(
-                    n_local,
-                    Vector3::new(0., 0., 1.), /* unused L vector */
-                    v_local,
-                    material,
-                );
-
-                sample_specular_microfacet(
-                    v_local,
-                    data.alpha,
-                    data.alpha_squared,
-                    data.specular_f0,
-                    (sampler.next_float(), sampler.next_float()),
-                )
+                let weights = LobeWeights::new(v, material);
+                let lobe = match sampler.next_float() < weights.coat_probability() {
+                    true => clearcoat_lobe(material),
+                    false => base_lobe(material),
+                };
+
+                sample_reflection(v, lobe, sampler)?
             }
         };
 
-        // Prevent tracing direction with no contribution
-        if luminance(sample_weight) == 0. {
-            return None;
-        }
-
-        // Transform sampled direction Llocal back to V vector space
-        let ray_direction =
-            rotate_point(invert_rotation(q_rotation_to_z), ray_direction_local).unit();
-
         // Prevent tracing direction "under" the hemisphere (behind the triangle)
         if cgmath::dot(material.geometry_normal, ray_direction) <= 0. {
             return None;
         }
 
         Some(ray_direction)
-        // None
     }
 
     fn eval(&self, wi: &Vector3, wo: &Vector3, material: &ResolvedMaterial) -> Vector3 {
         // Prepare data needed for BRDF evaluation - unpack material properties and evaluate commonly used terms (e.g. Fresnel, NdotL, ...)
         let n = material.shading_normal;
+        let (v, l) = (-*wo, *wi);
 
-        let data = prepare_brdf_data(n, *wi, -*wo, material);
+        let data = prepare_brdf_data(n, l, v, material);
 
         // Ignore V and L rays "below" the hemisphere
-        if data.v_backfacing || data.l_backfacing {
-            return Vector3::zero();
+        let base = if data.v_backfacing || data.l_backfacing {
+            Vector3::zero()
+        } else {
+            // Eval specular and diffuse BRDFs
+            let specular = eval_microfacet(&data);
+            let diffuse = eval_lambertian(&data);
+
+            // Specular is already multiplied by F, just attenuate diffuse
+            // Energy lost by single scattering specular is added back by the compensation lobe
+            let base =
+                (Vector3::one() - data.f).mul(diffuse) + specular + eval_multiple_scattering(&data);
+
+            // Sheen covers the base, which receives only light not reflected by the fibers
+            let sheen = material.sheen_color
+                * (charlie_d(material.sheen_roughness, data.n_dot_h)
+                    * sheen_v(data.n_dot_l, data.n_dot_v)
+                    * data.n_dot_l);
+            let sheen_albedo = sheen_albedo(data.n_dot_v, material.sheen_roughness)
+                .max(sheen_albedo(data.n_dot_l, material.sheen_roughness));
+
+            sheen + base * (1. - max_component(material.sheen_color) * sheen_albedo)
+        };
+
+        if material.clearcoat == 0. {
+            return base;
+        }
+
+        // Clearcoat is not affected by normal map of the base
+        let n = material.geometry_normal;
+        let (n_dot_v, n_dot_l) = (dot(n, v), dot(n, l));
+        if n_dot_v <= 0. {
+            return base;
         }
 
-        // Eval specular and diffuse BRDFs
-        let specular = eval_microfacet(&data);
-        let diffuse = eval_lambertian(&data);
-
-        // Combine specular and diffuse layers
-        // #if COMBINE_BRDFS_WITH_FRESNEL
-        // Specular is already multiplied by F, just attenuate diffuse
-        (Vector3::one() - data.f).mul(diffuse) + specular
-        // #else
-        // 	return diffuse + specular;
-        // #endif
+        let coat = match n_dot_l > 0. {
+            true => {
+                let alpha = clearcoat_alpha(material);
+                let alpha_squared = alpha * alpha;
+                let h = (v + l).unit();
+
+                clearcoat_fresnel(material, dot(v, h))
+                    * ggx_d(alpha_squared, dot(n, h))
+                    * smith_g2_height_correlated_ggx_lagarde(alpha_squared, n_dot_l, n_dot_v)
+                    * n_dot_l
+            }
+            false => 0.,
+        };
+
+        base * (1. - clearcoat_fresnel(material, n_dot_v)) + to_v3(coat)
     }
 
-    fn pdf(&self, _wi: &Vector3, _normal: &Vector3) -> f32 {
-        1.
+    fn pdf(&self, wi: &Vector3, wo: &Vector3, material: &ResolvedMaterial) -> f32 {
+        let n = material.shading_normal;
+        let (v, l) = (-*wo, *wi);
+
+        if dot(n, v) <= 0. {
+            return 0.;
+        }
+
+        let weights = LobeWeights::new(v, material);
+        let specular_probability = weights.specular_probability();
+        let coat_probability = weights.coat_probability();
+
+        let specular = lerp_scalar(
+            reflection_pdf(v, l, base_lobe(material)),
+            reflection_pdf(v, l, clearcoat_lobe(material)),
+            coat_probability,
+        );
+        let diffuse = dot(n, l).max(0.) * ONE_OVER_PI;
+
+        lerp_scalar(diffuse, specular, specular_probability)
     }
 
     fn probability(&self, v: &Vector3, material: &ResolvedMaterial) -> f32 {
-        // Evaluate Fresnel term using the shading normal
-        // Note: we use the shading normal instead of the microfacet normal (half-vector) for Fresnel term here. That's suboptimal for rough surfaces at grazing angles, but half-vector is yet unknown at this point
-        let specular_f0 = luminance(base_color_to_specular_f0(
-            material.base_color,
-            material.metalness,
-        ));
-        let diffuse_reflectance = luminance(base_color_to_diffuse_reflectance(
-            material.base_color,
-            material.metalness,
-        ));
-        let fresnel = saturate(luminance(eval_fresnel(
-            to_v3(specular_f0),
-            to_v3(shadowed_f90(to_v3(specular_f0))),
-            cgmath::dot(*v, material.shading_normal).max(0.),
-        )));
+        // Approximate relative contribution of BRDFs using the Fresnel term at the shading normal
+        // Note: half-vector is yet unknown at this point, which is suboptimal for rough surfaces at grazing angles
+        LobeWeights::new(*v, material).specular_probability()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MicrofacetBrdf;
+    use crate::brdf::testing::{material, sample_integral, sampling_matches_pdf};
+    use crate::brdf::ResolvedMaterial;
+    use crate::math::*;
+    use crate::random::UniformSampler;
 
-        // Approximate relative contribution of BRDFs using the Fresnel term
-        let specular = fresnel;
-        let diffuse = diffuse_reflectance * (1.0 - fresnel); //< If diffuse term is weighted by Fresnel, apply it here as well
+    fn layered(metalness: f32, clearcoat: f32, sheen: f32, anisotropy: f32) -> ResolvedMaterial {
+        ResolvedMaterial {
+            base_color: Vector3::new(0.8, 0.5, 0.3),
+            metalness,
+            clearcoat,
+            clearcoat_roughness: 0.3,
+            sheen_color: to_v3(sheen),
+            sheen_roughness: 0.5,
+            anisotropy,
+            anisotropy_direction: Vector3::new(1., 1., 0.),
+            thin_walled: true,
+            ..material()
+        }
+    }
+
+    #[test]
+    fn test_sampling_matches_pdf() {
+        let wo = Vector3::new(0.5, 0.2, -1.).unit();
+
+        for material in [
+            layered(0., 0., 0., 0.),
+            layered(1., 0., 0., 0.),
+            layered(1., 0., 0., 0.8),
+            layered(0., 1., 0., 0.5),
+            layered(0., 0.5, 1., 0.),
+        ] {
+            let (sampled_integral, uniform_integral) =
+                sampling_matches_pdf(&MicrofacetBrdf::new(), &wo, &material);
+            assert!((sampled_integral - uniform_integral).length() < 0.03);
+            assert!(uniform_integral.x <= 1.);
+        }
+    }
+
+    #[test]
+    fn test_white_furnace() {
+        let sampler = UniformSampler::new();
+        let brdf = MicrofacetBrdf::new();
+
+        // White conductor reflects all light for any roughness and view direction.
+        for roughness in [0., 0.1, 0.25, 0.5, 0.75, 1.] {
+            let material = ResolvedMaterial {
+                base_color: Vector3::one(),
+                roughness,
+                ..layered(1., 0., 0., 0.)
+            };
 
-        // Return probability of selecting specular BRDF over diffuse BRDF
-        let p = specular / (specular + diffuse).max(0.0001);
+            for n_dot_v in [0.2f32, 0.5, 0.9] {
+                let wo = -Vector3::new((1. - n_dot_v * n_dot_v).sqrt(), 0., n_dot_v);
+                let (_, albedo) = sample_integral(&brdf, &wo, &material, 20000, &sampler);
 
-        // Clamp probability to avoid undersampling of less prominent BRDF
-        clamp(p, 0.1, 0.9)
+                assert!((albedo.x - 1.).abs() < 0.03);
+            }
+        }
     }
 }
@@ -1,9 +1,132 @@
+use crate::lens::{LensSystem, PupilBounds};
 use crate::math::*;
 use crate::random::*;
 use crate::ray::*;
 
+pub use crate::lens::{Aperture, ApertureMask, Lens, LensElement, DOUBLE_GAUSS};
+
 pub trait Camera {
-    fn ray(&self, x: f32, y: f32, sampler: &UniformSampler) -> Ray;
+    fn ray(&self, x: f32, y: f32, sampler: &dyn Sampler) -> Ray;
+
+    /// Returns ray with differentials towards neighbouring pixels, given size of a pixel in normalized coordinates.
+    fn ray_differential(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        sampler: &dyn Sampler,
+    ) -> Ray;
+
+    /// Samples ray with differentials and its weight, zero if the lens blocks the ray.
+    ///
+    /// Integrators trace these rays, `ray` and `ray_differential` ignore the weight.
+    fn sample_ray(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        sampler: &dyn Sampler,
+    ) -> (Ray, f32) {
+        (self.ray_differential(x, y, pixel_size, sampler), 1.)
+    }
+
+    /// Samples a point of the lens seen from given point, none if the point is not in the image.
+    fn sample_importance(&self, point: &Vector3, sampler: &dyn Sampler)
+        -> Option<ImportanceSample>;
+
+    /// Returns probability density of the direction of given ray from the lens per solid angle.
+    ///
+    /// Orthographic cameras return density of the origin of the ray per area of the image.
+    fn importance_pdf(&self, ray: &Ray) -> f32;
+
+    /// Returns true if all rays are parallel, see `importance_pdf`.
+    fn orthographic(&self) -> bool {
+        false
+    }
+
+    /// Returns false if points of the scene cannot be connected to the lens, `sample_importance`
+    /// then never returns a sample.
+    fn samples_importance(&self) -> bool {
+        true
+    }
+}
+
+/// Connection of a point of the scene to the lens, used to trace light towards the camera.
+pub struct ImportanceSample {
+    /// Normalized coordinates of the point in the image.
+    pub image: (f32, f32),
+    pub lens: Vector3,
+    /// Importance emitted by the lens towards the point.
+    pub importance: f32,
+    /// Probability density of the lens point per solid angle seen from the point.
+    pub pdf: f32,
+}
+
+/// Image plane at given distance in front of the lens, spanned by its horizontal and vertical extent.
+struct ImagePlane {
+    lower_left: Vector3,
+    horizontal: Vector3,
+    vertical: Vector3,
+    distance: f32,
+}
+
+impl ImagePlane {
+    fn forward(&self) -> Vector3 {
+        self.vertical.cross(self.horizontal).unit()
+    }
+
+    /// Area of the image moved to unit distance from the lens.
+    fn area(&self) -> f32 {
+        self.horizontal.length() * self.vertical.length() / (self.distance * self.distance)
+    }
+
+    /// Returns normalized image coordinates of the point where the ray from the lens crosses the
+    /// plane and cosine of the ray to the optical axis.
+    fn project(&self, origin: Vector3, direction: Vector3) -> Option<((f32, f32), f32)> {
+        let cosine = cgmath::dot(direction, self.forward());
+        if cosine <= 0. {
+            return None;
+        }
+
+        let offset = origin + direction * (self.distance / cosine) - self.lower_left;
+        let x = cgmath::dot(offset, self.horizontal) / self.horizontal.squared_length();
+        let y = cgmath::dot(offset, self.vertical) / self.vertical.squared_length();
+
+        match (0. ..=1.).contains(&x) && (0. ..=1.).contains(&y) {
+            true => Some(((x, y), cosine)),
+            false => None,
+        }
+    }
+
+    /// Importance of a lens of given area and density of sampling the point per solid angle.
+    ///
+    /// Importance is normalized over the whole image, so that light arriving to all pixels by a
+    /// single light path estimates the image.
+    fn importance(
+        &self,
+        lens: Vector3,
+        lens_area: f32,
+        point: &Vector3,
+    ) -> Option<ImportanceSample> {
+        let to_point = *point - lens;
+        let direction = to_point.unit();
+        let (image, cosine) = self.project(lens, direction)?;
+
+        let cosine_2 = cosine * cosine;
+        Some(ImportanceSample {
+            image,
+            lens,
+            importance: 1. / (self.area() * lens_area * cosine_2 * cosine_2),
+            pdf: to_point.squared_length() / (cosine * lens_area),
+        })
+    }
+
+    fn pdf(&self, ray: &Ray) -> f32 {
+        match self.project(ray.origin, ray.direction.unit()) {
+            Some((_, cosine)) => 1. / (self.area() * cosine * cosine * cosine),
+            None => 0.,
+        }
+    }
 }
 
 pub struct SimpleCamera {
@@ -14,6 +137,15 @@ pub struct SimpleCamera {
 }
 
 impl SimpleCamera {
+    fn image_plane(&self) -> ImagePlane {
+        ImagePlane {
+            lower_left: self.lower_left,
+            horizontal: self.horizontal,
+            vertical: self.vertical,
+            distance: 1.,
+        }
+    }
+
     pub fn look_at(
         position: Vector3,
         look_at: Vector3,
@@ -44,16 +176,54 @@ impl SimpleCamera {
 }
 
 impl Camera for SimpleCamera {
-    fn ray(&self, x: f32, y: f32, _sampler: &UniformSampler) -> Ray {
+    fn ray(&self, x: f32, y: f32, _sampler: &dyn Sampler) -> Ray {
+        Ray::new(
+            self.position,
+            self.lower_left + self.horizontal * x + self.vertical * y - self.position,
+        )
+    }
+
+    fn ray_differential(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        sampler: &dyn Sampler,
+    ) -> Ray {
+        let ray = self.ray(x, y, sampler);
         Ray {
-            origin: self.position,
-            direction: self.lower_left + self.horizontal * x + self.vertical * y - self.position,
+            differentials: Some(RayDifferentials {
+                rx_origin: ray.origin,
+                rx_direction: ray.direction + self.horizontal * pixel_size.0,
+                ry_origin: ray.origin,
+                ry_direction: ray.direction + self.vertical * pixel_size.1,
+            }),
+            ..ray
         }
     }
+
+    fn sample_importance(
+        &self,
+        point: &Vector3,
+        _sampler: &dyn Sampler,
+    ) -> Option<ImportanceSample> {
+        // Pinhole is a single point, its area does not scale the importance.
+        self.image_plane().importance(self.position, 1., point)
+    }
+
+    fn importance_pdf(&self, ray: &Ray) -> f32 {
+        self.image_plane().pdf(ray)
+    }
 }
 
+/// Thin lens camera focused at given distance, with bokeh shaped by its aperture.
 pub struct ApertureCamera {
     pub position: Vector3,
+    /// Opening of the lens, a disk unless set otherwise.
+    pub aperture: Aperture,
+    /// Shift of the opening of the lens barrel seen from corners of the image in units of the
+    /// lens radius, clipping the bokeh into cat's eyes. Zero disables the vignetting.
+    pub cat_eye: f32,
 
     u: Vector3,
     v: Vector3,
@@ -61,9 +231,19 @@ pub struct ApertureCamera {
     lower_left: Vector3,
     horizontal: Vector3,
     vertical: Vector3,
+    focus_distance: f32,
 }
 
 impl ApertureCamera {
+    fn image_plane(&self) -> ImagePlane {
+        ImagePlane {
+            lower_left: self.lower_left,
+            horizontal: self.horizontal,
+            vertical: self.vertical,
+            distance: self.focus_distance,
+        }
+    }
+
     pub fn look_at(
         position: Vector3,
         look_at: Vector3,
@@ -89,26 +269,875 @@ impl ApertureCamera {
 
         Self {
             position,
+            aperture: Aperture::Disk,
+            cat_eye: 0.,
             u,
             v,
             lens_radius,
             lower_left,
             horizontal,
             vertical,
+            focus_distance,
+        }
+    }
+
+    /// Returns point of the lens at given point of the aperture, in units of the lens radius.
+    fn lens_point(&self, aperture: Vector3) -> Vector3 {
+        self.position + (self.u * aperture.x + self.v * aperture.y) * self.lens_radius
+    }
+
+    fn lens_ray(&self, x: f32, y: f32, aperture: Vector3) -> Ray {
+        let lens = self.lens_point(aperture);
+        Ray::new(
+            lens,
+            self.lower_left + self.horizontal * x + self.vertical * y - lens,
+        )
+    }
+
+    // Offset rays start at the same point of the lens and converge in the focal plane.
+    fn with_differentials(&self, ray: Ray, pixel_size: (f32, f32)) -> Ray {
+        Ray {
+            differentials: Some(RayDifferentials {
+                rx_origin: ray.origin,
+                rx_direction: ray.direction + self.horizontal * pixel_size.0,
+                ry_origin: ray.origin,
+                ry_direction: ray.direction + self.vertical * pixel_size.1,
+            }),
+            ..ray
+        }
+    }
+
+    /// Returns transmission of the lens barrel at given point of the aperture seen from given
+    /// image point, the barrel is a disk of the lens radius shifted towards the image point.
+    fn vignetting(&self, x: f32, y: f32, aperture: Vector3) -> f32 {
+        let (width, height) = (self.horizontal.length(), self.vertical.length());
+        let scale = 2. * self.cat_eye / (width * width + height * height).sqrt();
+        let shift = ((x - 0.5) * width * scale, (y - 0.5) * height * scale);
+
+        let (dx, dy) = (aperture.x - shift.0, aperture.y - shift.1);
+        match dx * dx + dy * dy <= 1. {
+            true => 1.,
+            false => 0.,
         }
     }
 }
 
 impl Camera for ApertureCamera {
-    fn ray(&self, x: f32, y: f32, sampler: &UniformSampler) -> Ray {
-        let random = self.lens_radius * unit_disk(sampler);
-        let offset = self.u * random.x + self.v * random.y;
+    fn ray(&self, x: f32, y: f32, sampler: &dyn Sampler) -> Ray {
+        self.lens_ray(x, y, self.aperture.sample(sampler).0)
+    }
+
+    fn ray_differential(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        sampler: &dyn Sampler,
+    ) -> Ray {
+        self.with_differentials(self.ray(x, y, sampler), pixel_size)
+    }
+
+    // Apertures are sampled proportionally to their transmission, only the barrel blocks rays.
+    fn sample_ray(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        sampler: &dyn Sampler,
+    ) -> (Ray, f32) {
+        let (aperture, _) = self.aperture.sample(sampler);
+        let ray = self.lens_ray(x, y, aperture);
+
+        (
+            self.with_differentials(ray, pixel_size),
+            self.vignetting(x, y, aperture),
+        )
+    }
+
+    fn sample_importance(
+        &self,
+        point: &Vector3,
+        sampler: &dyn Sampler,
+    ) -> Option<ImportanceSample> {
+        if self.lens_radius <= 0. {
+            return self.image_plane().importance(self.position, 1., point);
+        }
+
+        // Transmission of the sampled point cancels out with density of sampling it.
+        let (aperture, _) = self.aperture.sample(sampler);
+        let lens_area = self.lens_radius * self.lens_radius * self.aperture.area();
+        let mut sample =
+            self.image_plane()
+                .importance(self.lens_point(aperture), lens_area, point)?;
+
+        sample.importance *= self.vignetting(sample.image.0, sample.image.1, aperture);
+        Some(sample)
+    }
+
+    fn importance_pdf(&self, ray: &Ray) -> f32 {
+        self.image_plane().pdf(ray)
+    }
+}
+
+/// Camera with parallel rays leaving the image plane.
+pub struct OrthographicCamera {
+    lower_left: Vector3,
+    horizontal: Vector3,
+    vertical: Vector3,
+    forward: Vector3,
+}
+
+impl OrthographicCamera {
+    /// Creates camera whose image plane has given height in world units and lies at `position`.
+    pub fn look_at(
+        position: Vector3,
+        look_at: Vector3,
+        up: Vector3,
+        height: f32,
+        aspect_ratio: f32,
+    ) -> Self {
+        let w = (position - look_at).unit();
+        let u = up.cross(w).unit();
+        let v = w.cross(u);
+
+        let vertical = v * height;
+        let horizontal = u * height * aspect_ratio;
+
+        Self {
+            lower_left: position - horizontal / 2. - vertical / 2.,
+            horizontal,
+            vertical,
+            forward: -w,
+        }
+    }
+
+    fn area(&self) -> f32 {
+        self.horizontal.length() * self.vertical.length()
+    }
+
+    /// Returns normalized image coordinates of the point projected along the rays and its distance
+    /// from the image plane.
+    fn project(&self, point: &Vector3) -> Option<((f32, f32), f32)> {
+        let offset = *point - self.lower_left;
+        let x = cgmath::dot(offset, self.horizontal) / self.horizontal.squared_length();
+        let y = cgmath::dot(offset, self.vertical) / self.vertical.squared_length();
+
+        match (0. ..=1.).contains(&x) && (0. ..=1.).contains(&y) {
+            true => Some(((x, y), cgmath::dot(offset, self.forward))),
+            false => None,
+        }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn ray(&self, x: f32, y: f32, _sampler: &dyn Sampler) -> Ray {
+        Ray::new(
+            self.lower_left + self.horizontal * x + self.vertical * y,
+            self.forward,
+        )
+    }
+
+    // Offset rays are parallel and start at the neighbouring pixels.
+    fn ray_differential(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        sampler: &dyn Sampler,
+    ) -> Ray {
+        let ray = self.ray(x, y, sampler);
+        Ray {
+            differentials: Some(RayDifferentials {
+                rx_origin: ray.origin + self.horizontal * pixel_size.0,
+                rx_direction: ray.direction,
+                ry_origin: ray.origin + self.vertical * pixel_size.1,
+                ry_direction: ray.direction,
+            }),
+            ..ray
+        }
+    }
+
+    fn sample_importance(
+        &self,
+        point: &Vector3,
+        _sampler: &dyn Sampler,
+    ) -> Option<ImportanceSample> {
+        let (image, distance) = self.project(point)?;
+        if distance <= 0. {
+            return None;
+        }
+
+        // Light reaches the image only along the rays, the lens point is not sampled.
+        Some(ImportanceSample {
+            image,
+            lens: *point - self.forward * distance,
+            importance: 1. / self.area(),
+            pdf: 1.,
+        })
+    }
+
+    fn importance_pdf(&self, ray: &Ray) -> f32 {
+        match cgmath::dot(ray.direction, self.forward) > 0. && self.project(&ray.origin).is_some() {
+            true => 1. / self.area(),
+            false => 0.,
+        }
+    }
+
+    fn orthographic(&self) -> bool {
+        true
+    }
+}
+
+/// Point from which panoramic and lens cameras look, with axes to the right, up and forward.
+struct Panorama {
+    position: Vector3,
+    right: Vector3,
+    up: Vector3,
+    forward: Vector3,
+}
+
+impl Panorama {
+    fn look_at(position: Vector3, look_at: Vector3, up: Vector3) -> Self {
+        let forward = (look_at - position).unit();
+        let right = forward.cross(up).unit();
+
+        Self {
+            position,
+            right,
+            up: right.cross(forward),
+            forward,
+        }
+    }
+
+    fn to_world(&self, local: Vector3) -> Vector3 {
+        self.right * local.x + self.up * local.y + self.forward * local.z
+    }
+
+    fn to_local(&self, direction: Vector3) -> Vector3 {
+        Vector3::new(
+            cgmath::dot(direction, self.right),
+            cgmath::dot(direction, self.up),
+            cgmath::dot(direction, self.forward),
+        )
+    }
+
+    /// Returns ray towards local direction of the image point given by `direction`, with
+    /// differentials towards neighbouring pixels.
+    fn ray(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        direction: impl Fn(f32, f32) -> Vector3,
+    ) -> Ray {
+        Ray {
+            differentials: Some(RayDifferentials {
+                rx_origin: self.position,
+                rx_direction: self.to_world(direction(x + pixel_size.0, y)),
+                ry_origin: self.position,
+                ry_direction: self.to_world(direction(x, y + pixel_size.1)),
+            }),
+            ..Ray::new(self.position, self.to_world(direction(x, y)))
+        }
+    }
+
+    /// Connects the point to the lens, `project` returns image coordinates of a local direction
+    /// and density of sampling it per solid angle.
+    ///
+    /// Without an image plane, the pinhole is treated as a unit area facing each point.
+    fn importance(
+        &self,
+        point: &Vector3,
+        project: impl Fn(Vector3) -> Option<((f32, f32), f32)>,
+    ) -> Option<ImportanceSample> {
+        let to_point = *point - self.position;
+        let (image, pdf) = project(self.to_local(to_point.unit()))?;
+
+        Some(ImportanceSample {
+            image,
+            lens: self.position,
+            importance: pdf,
+            pdf: to_point.squared_length(),
+        })
+    }
+
+    fn pdf(&self, ray: &Ray, project: impl Fn(Vector3) -> Option<((f32, f32), f32)>) -> f32 {
+        project(self.to_local(ray.direction.unit())).map_or(0., |(_, pdf)| pdf)
+    }
+}
+
+/// Camera seeing all directions, horizontal image axis is the longitude and vertical the latitude.
+pub struct EquirectangularCamera {
+    panorama: Panorama,
+}
 
+impl EquirectangularCamera {
+    /// Creates camera with the center of the image in direction of `look_at`.
+    pub fn look_at(position: Vector3, look_at: Vector3, up: Vector3) -> Self {
+        Self {
+            panorama: Panorama::look_at(position, look_at, up),
+        }
+    }
+
+    fn direction(x: f32, y: f32) -> Vector3 {
+        let phi = TWO_PI * (x - 0.5);
+        let theta = std::f32::consts::PI * (1. - y);
+
+        Vector3::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            theta.sin() * phi.cos(),
+        )
+    }
+
+    fn project(local: Vector3) -> Option<((f32, f32), f32)> {
+        let sin_theta = (local.x * local.x + local.z * local.z).sqrt();
+        if sin_theta <= 0. {
+            return None;
+        }
+
+        let phi = local.x.atan2(local.z);
+        let theta = local.y.clamp(-1., 1.).acos();
+        let image = (phi / TWO_PI + 0.5, 1. - theta * std::f32::consts::FRAC_1_PI);
+
+        Some((image, 1. / (TWO_PI * std::f32::consts::PI * sin_theta)))
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn ray(&self, x: f32, y: f32, _sampler: &dyn Sampler) -> Ray {
+        Ray::new(
+            self.panorama.position,
+            self.panorama.to_world(Self::direction(x, y)),
+        )
+    }
+
+    fn ray_differential(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        _sampler: &dyn Sampler,
+    ) -> Ray {
+        self.panorama.ray(x, y, pixel_size, Self::direction)
+    }
+
+    fn sample_importance(
+        &self,
+        point: &Vector3,
+        _sampler: &dyn Sampler,
+    ) -> Option<ImportanceSample> {
+        self.panorama.importance(point, Self::project)
+    }
+
+    fn importance_pdf(&self, ray: &Ray) -> f32 {
+        self.panorama.pdf(ray, Self::project)
+    }
+}
+
+/// Faces of the cube map given by local axes to the right, up and forward.
+///
+/// The image has faces looking left, forward and right in the upper row, back, up and down in
+/// the lower row.
+const CUBE_FACES: [[[f32; 3]; 3]; 6] = [
+    [[0., 0., 1.], [0., 1., 0.], [-1., 0., 0.]],
+    [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+    [[0., 0., -1.], [0., 1., 0.], [1., 0., 0.]],
+    [[-1., 0., 0.], [0., 1., 0.], [0., 0., -1.]],
+    [[1., 0., 0.], [0., 0., -1.], [0., 1., 0.]],
+    [[1., 0., 0.], [0., 0., 1.], [0., -1., 0.]],
+];
+
+/// Camera seeing all directions through six faces of a cube with 90° field of view.
+pub struct CubemapCamera {
+    panorama: Panorama,
+}
+
+impl CubemapCamera {
+    /// Creates camera with the center of the forward face in direction of `look_at`.
+    pub fn look_at(position: Vector3, look_at: Vector3, up: Vector3) -> Self {
+        Self {
+            panorama: Panorama::look_at(position, look_at, up),
+        }
+    }
+
+    fn face(index: usize) -> [Vector3; 3] {
+        CUBE_FACES[index].map(|axis| Vector3::new(axis[0], axis[1], axis[2]))
+    }
+
+    fn direction(x: f32, y: f32) -> Vector3 {
+        let column = (3. * x).floor().clamp(0., 2.);
+        let row = (2. * y).floor().clamp(0., 1.);
+        let [right, up, forward] = Self::face(3 * (1 - row as usize) + column as usize);
+
+        let (s, t) = (3. * x - column, 2. * y - row);
+        forward + right * (2. * s - 1.) + up * (2. * t - 1.)
+    }
+
+    fn project(local: Vector3) -> Option<((f32, f32), f32)> {
+        let (index, cosine) = (0..CUBE_FACES.len())
+            .map(|index| (index, cgmath::dot(local, Self::face(index)[2])))
+            .fold((0, f32::MIN), |max, face| match face.1 > max.1 {
+                true => face,
+                false => max,
+            });
+        let [right, up, _] = Self::face(index);
+
+        let s = (cgmath::dot(local, right) / cosine + 1.) / 2.;
+        let t = (cgmath::dot(local, up) / cosine + 1.) / 2.;
+        let (column, row) = ((index % 3) as f32, (1 - index / 3) as f32);
+        let image = ((column + s) / 3., (row + t) / 2.);
+
+        // Each face covers a sixth of the image and an area of four at unit distance.
+        Some((image, 1. / (24. * cosine * cosine * cosine)))
+    }
+}
+
+impl Camera for CubemapCamera {
+    fn ray(&self, x: f32, y: f32, _sampler: &dyn Sampler) -> Ray {
+        Ray::new(
+            self.panorama.position,
+            self.panorama.to_world(Self::direction(x, y)),
+        )
+    }
+
+    fn ray_differential(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        _sampler: &dyn Sampler,
+    ) -> Ray {
+        self.panorama.ray(x, y, pixel_size, Self::direction)
+    }
+
+    fn sample_importance(
+        &self,
+        point: &Vector3,
+        _sampler: &dyn Sampler,
+    ) -> Option<ImportanceSample> {
+        self.panorama.importance(point, Self::project)
+    }
+
+    fn importance_pdf(&self, ray: &Ray) -> f32 {
+        self.panorama.pdf(ray, Self::project)
+    }
+}
+
+/// Camera with equidistant fisheye projection, where distance from the center of the image is
+/// proportional to the angle from the optical axis.
+pub struct FisheyeCamera {
+    panorama: Panorama,
+    max_angle: f32,
+    aspect_ratio: f32,
+}
+
+impl FisheyeCamera {
+    /// Creates camera with given field of view in degrees along the diagonal of the image, up
+    /// to 360°.
+    pub fn look_at(
+        position: Vector3,
+        look_at: Vector3,
+        up: Vector3,
+        fov: f32,
+        aspect_ratio: f32,
+    ) -> Self {
+        Self {
+            panorama: Panorama::look_at(position, look_at, up),
+            max_angle: fov.to_radians().min(TWO_PI) / 2.,
+            aspect_ratio,
+        }
+    }
+
+    /// Distance from the center to a corner of the image, in units of the image height.
+    fn half_diagonal(&self) -> f32 {
+        (self.aspect_ratio * self.aspect_ratio + 1.).sqrt() / 2.
+    }
+
+    fn direction(&self, x: f32, y: f32) -> Vector3 {
+        let (u, v) = ((x - 0.5) * self.aspect_ratio, y - 0.5);
+        let radius = (u * u + v * v).sqrt();
+        if radius <= 0. {
+            return Vector3::new(0., 0., 1.);
+        }
+
+        let theta = radius / self.half_diagonal() * self.max_angle;
+        let sin_theta = theta.sin();
+        Vector3::new(sin_theta * u / radius, sin_theta * v / radius, theta.cos())
+    }
+
+    fn project(&self, local: Vector3) -> Option<((f32, f32), f32)> {
+        let theta = local.z.clamp(-1., 1.).acos();
+        if theta > self.max_angle {
+            return None;
+        }
+
+        let sin_theta = (local.x * local.x + local.y * local.y).sqrt();
+        let scale = self.half_diagonal() / self.max_angle;
+        let (u, v) = match sin_theta > 0. {
+            true => (
+                local.x / sin_theta * theta * scale,
+                local.y / sin_theta * theta * scale,
+            ),
+            false => (0., 0.),
+        };
+
+        let (x, y) = (u / self.aspect_ratio + 0.5, v + 0.5);
+        if !(0. ..=1.).contains(&x) || !(0. ..=1.).contains(&y) {
+            return None;
+        }
+
+        // Area of the image per solid angle, theta / sin(theta) tends to one on the axis.
+        let stretch = match sin_theta > 0. {
+            true => theta / sin_theta,
+            false => 1.,
+        };
+        Some(((x, y), scale * scale * stretch / self.aspect_ratio))
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn ray(&self, x: f32, y: f32, _sampler: &dyn Sampler) -> Ray {
+        Ray::new(
+            self.panorama.position,
+            self.panorama.to_world(self.direction(x, y)),
+        )
+    }
+
+    fn ray_differential(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        _sampler: &dyn Sampler,
+    ) -> Ray {
+        self.panorama
+            .ray(x, y, pixel_size, |x, y| self.direction(x, y))
+    }
+
+    fn sample_importance(
+        &self,
+        point: &Vector3,
+        _sampler: &dyn Sampler,
+    ) -> Option<ImportanceSample> {
+        self.panorama.importance(point, |local| self.project(local))
+    }
+
+    fn importance_pdf(&self, ray: &Ray) -> f32 {
+        self.panorama.pdf(ray, |local| self.project(local))
+    }
+}
+
+/// Number of segments of the film radius with their own bounds of the exit pupil.
+const PUPIL_SEGMENTS: usize = 16;
+
+/// Number of times a ray blocked by the lens is resampled by `ray` and `ray_differential`.
+const LENS_ATTEMPTS: usize = 16;
+
+/// Camera tracing rays from the film through spherical surfaces of a lens, whose focusing,
+/// vignetting, distortion and breathing follow from the lens itself.
+///
+/// Scene units are taken as metres. Light cannot be traced towards the film, so the camera does
+/// not sample importance.
+// Source: https://pbr-book.org/3ed-2018/Camera_Models/Realistic_Cameras
+pub struct RealisticCamera {
+    panorama: Panorama,
+    lens: LensSystem,
+    /// Width and height of the film in metres.
+    film: (f32, f32),
+    pupil: Vec<PupilBounds>,
+    /// Average weight of rays reaching the center of the film, weights are relative to it.
+    exposure: f32,
+}
+
+impl RealisticCamera {
+    /// Creates camera with the film at `position` and the lens towards `look_at`.
+    pub fn look_at(
+        position: Vector3,
+        look_at: Vector3,
+        up: Vector3,
+        lens: &Lens,
+        aspect_ratio: f32,
+    ) -> Self {
+        let system = LensSystem::new(
+            &lens.elements,
+            lens.aperture_diameter,
+            lens.aperture.clone(),
+            lens.focus_distance,
+        );
+
+        let diagonal = lens.film_diagonal * 0.001;
+        let height = diagonal / (aspect_ratio * aspect_ratio + 1.).sqrt();
+        let pupil = system.exit_pupil(diagonal / 2., PUPIL_SEGMENTS);
+
+        let mut camera = Self {
+            panorama: Panorama::look_at(position, look_at, up),
+            lens: system,
+            film: (aspect_ratio * height, height),
+            pupil,
+            exposure: 1.,
+        };
+
+        let grid = 64;
+        let bounds = camera.pupil[0];
+        let total = (0..grid * grid)
+            .filter_map(|i| {
+                let u = ((i % grid) as f32 + 0.5) / grid as f32;
+                let v = ((i / grid) as f32 + 0.5) / grid as f32;
+                let (x, y) = bounds.point(u, v);
+                camera.trace(Vector3::zero(), Vector3::new(x, y, camera.lens.rear_z()))
+            })
+            .map(|(_, weight)| weight * bounds.area())
+            .sum::<f32>();
+
+        if total > 0. {
+            camera.exposure = total / (grid * grid) as f32;
+        }
+        camera
+    }
+
+    /// Returns point of the film seeing given image point, the lens flips the image.
+    fn film_point(&self, x: f32, y: f32) -> Vector3 {
+        Vector3::new((0.5 - x) * self.film.0, (0.5 - y) * self.film.1, 0.)
+    }
+
+    /// Samples point of the rear element through which light may reach the film point, returns it
+    /// with area of the sampled bounds.
+    fn sample_rear(&self, film: Vector3, sampler: &dyn Sampler) -> (Vector3, f32) {
+        let radius = (film.x * film.x + film.y * film.y).sqrt();
+        let film_radius = 0.5 * (self.film.0 * self.film.0 + self.film.1 * self.film.1).sqrt();
+        let segment =
+            ((radius / film_radius * PUPIL_SEGMENTS as f32) as usize).min(PUPIL_SEGMENTS - 1);
+
+        let bounds = self.pupil[segment];
+        let (x, y) = bounds.point(sampler.next_float(), sampler.next_float());
+
+        // Bounds are given for points of the film along the X axis, they are rotated around.
+        let (sin, cos) = match radius > 0. {
+            true => (film.y / radius, film.x / radius),
+            false => (0., 1.),
+        };
+        (
+            Vector3::new(cos * x - sin * y, sin * x + cos * y, self.lens.rear_z()),
+            bounds.area(),
+        )
+    }
+
+    /// Traces ray from the film point through the point of the rear element, returns the ray
+    /// leaving the lens in the world and its weight per area of the rear element.
+    fn trace(&self, film: Vector3, rear: Vector3) -> Option<(Ray, f32)> {
+        let direction = (rear - film).unit();
+        let (ray, transmission) = self.lens.trace_from_film(&Ray::new(film, direction))?;
+
+        let cosine_2 = direction.z * direction.z;
+        Some((
+            Ray::new(
+                self.panorama.position + self.panorama.to_world(ray.origin),
+                self.panorama.to_world(ray.direction),
+            ),
+            transmission * cosine_2 * cosine_2,
+        ))
+    }
+}
+
+impl Camera for RealisticCamera {
+    // Rays are resampled until they pass through the lens.
+    fn ray(&self, x: f32, y: f32, sampler: &dyn Sampler) -> Ray {
         Ray {
-            origin: self.position + offset,
-            direction: self.lower_left + self.horizontal * x + self.vertical * y
-                - self.position
-                - offset,
+            differentials: None,
+            ..self.ray_differential(x, y, (0., 0.), sampler)
         }
     }
+
+    fn ray_differential(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        sampler: &dyn Sampler,
+    ) -> Ray {
+        (0..LENS_ATTEMPTS)
+            .map(|_| self.sample_ray(x, y, pixel_size, sampler))
+            .find(|(_, weight)| *weight > 0.)
+            .map_or(
+                Ray::new(self.panorama.position, self.panorama.forward),
+                |(ray, _)| ray,
+            )
+    }
+
+    // Offset rays pass through the same point of the rear element.
+    fn sample_ray(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        sampler: &dyn Sampler,
+    ) -> (Ray, f32) {
+        let film = self.film_point(x, y);
+        let (rear, area) = self.sample_rear(film, sampler);
+
+        let (ray, weight) = match self.trace(film, rear) {
+            Some(traced) => traced,
+            None => return (Ray::new(self.panorama.position, self.panorama.forward), 0.),
+        };
+
+        let offset = |x, y| self.trace(self.film_point(x, y), rear);
+        let differentials = match (offset(x + pixel_size.0, y), offset(x, y + pixel_size.1)) {
+            (Some((rx, _)), Some((ry, _))) => Some(RayDifferentials {
+                rx_origin: rx.origin,
+                rx_direction: rx.direction,
+                ry_origin: ry.origin,
+                ry_direction: ry.direction,
+            }),
+            _ => None,
+        };
+
+        (
+            Ray {
+                differentials,
+                ..ray
+            },
+            weight * area / self.exposure,
+        )
+    }
+
+    fn sample_importance(
+        &self,
+        _point: &Vector3,
+        _sampler: &dyn Sampler,
+    ) -> Option<ImportanceSample> {
+        None
+    }
+
+    fn importance_pdf(&self, _ray: &Ray) -> f32 {
+        0.
+    }
+
+    fn samples_importance(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_importance() {
+        let sampler = UniformSampler::new();
+        let (position, look_at, up) = (
+            Vector3::new(1., 2., 3.),
+            Vector3::new(0., 0., 0.),
+            Vector3::new(0., 1., 0.),
+        );
+
+        let mut bokeh = ApertureCamera::look_at(position, look_at, up, 60., 1.5, 0.2, 2.);
+        bokeh.aperture = Aperture::Polygon {
+            blades: 6,
+            rotation: 10.,
+        };
+        bokeh.cat_eye = 0.5;
+
+        let points = [(0.5, 0.5), (0.1, 0.8), (0.95, 0.05)];
+        // The center of the image lies on a seam between faces of the cube map.
+        let cube_points = [(0.5, 0.6), (0.1, 0.8), (0.95, 0.05)];
+
+        let cameras: [(Box<dyn Camera>, _); 7] = [
+            (
+                Box::new(SimpleCamera::look_at(position, look_at, up, 60., 1.5)),
+                points,
+            ),
+            (
+                Box::new(ApertureCamera::look_at(
+                    position, look_at, up, 60., 1.5, 0.2, 2.,
+                )),
+                points,
+            ),
+            (Box::new(bokeh), points),
+            (
+                Box::new(OrthographicCamera::look_at(position, look_at, up, 3., 1.5)),
+                points,
+            ),
+            (
+                Box::new(EquirectangularCamera::look_at(position, look_at, up)),
+                points,
+            ),
+            (
+                Box::new(CubemapCamera::look_at(position, look_at, up)),
+                cube_points,
+            ),
+            (
+                Box::new(FisheyeCamera::look_at(position, look_at, up, 200., 1.5)),
+                points,
+            ),
+        ];
+
+        for (camera, points) in cameras {
+            // Points in focus project back to the image point of the ray.
+            for (x, y) in points {
+                let ray = camera.ray(x, y, &sampler);
+                let sample = camera
+                    .sample_importance(&ray.point_at(1.), &sampler)
+                    .unwrap();
+
+                assert!((sample.image.0 - x).abs() < 1e-4);
+                assert!((sample.image.1 - y).abs() < 1e-4);
+            }
+
+            if camera.orthographic() {
+                continue;
+            }
+
+            // Density of ray directions integrates to one over the sphere.
+            let steps = 200000;
+            let integral = (0..steps)
+                .map(|_| {
+                    let direction = unit_sphere(&sampler).unit();
+                    camera.importance_pdf(&Ray::new(position, direction))
+                })
+                .sum::<f32>()
+                * 4.
+                * std::f32::consts::PI
+                / steps as f32;
+
+            assert!((integral - 1.).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn test_realistic_camera() {
+        let sampler = UniformSampler::new();
+        let (position, forward, up) = (
+            Vector3::new(1., 2., 3.),
+            Vector3::new(0., 0., -1.),
+            Vector3::new(0., 1., 0.),
+        );
+        let lens = Lens {
+            focus_distance: 2.,
+            ..Lens::default()
+        };
+        let camera = RealisticCamera::look_at(position, position + forward, up, &lens, 1.5);
+
+        // Rays from the center of the image converge at the focus distance from the film.
+        let focus = position + forward * lens.focus_distance;
+        let steps = 10000;
+        let mut total = 0.;
+        for _ in 0..steps {
+            let (ray, weight) = camera.sample_ray(0.5, 0.5, (0.001, 0.001), &sampler);
+            total += weight;
+
+            if weight > 0. {
+                let distance = (focus - ray.origin).cross(ray.direction.unit()).length();
+                assert!(distance < 0.01);
+            }
+        }
+
+        // Weights are relative to light reaching the center of the film.
+        assert!((total / steps as f32 - 1.).abs() < 0.05);
+
+        // The image flipped by the lens is flipped back, its right side looks to the right.
+        let ray = camera.ray(0.9, 0.5, &sampler);
+        assert!(ray.direction.x > 0.);
+        assert!(!camera.samples_importance());
+    }
 }
@@ -0,0 +1,463 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::math::*;
+use crate::random::Sampler;
+use crate::scene::Scene;
+
+// Path guiding by a spatial binary tree of directional quadtrees (SD-tree).
+// Source: "Practical Path Guiding for Efficient Light-Transport Simulation" by Müller et al.
+// Leaves of the spatial tree split regions of the scene, each of them learns distribution of light
+// arriving to its surfaces in a quadtree over directions. Every pass records light found by paths
+// in one tree while sampling from the tree learned before, and whenever the number of passes
+// doubles the recorded tree replaces the sampled one. Leaves which recorded many paths split and
+// quadtrees subdivide directions receiving much light.
+
+/// Ratio of guided samples in the mixture with sampling of the BRDF.
+pub(crate) const GUIDED_FRACTION: f32 = 0.5;
+
+/// Number of recorded paths of the first pass for which a spatial leaf splits, it grows with the
+/// square root of the number of passes.
+const SPATIAL_THRESHOLD: f32 = 12000.;
+
+/// Fraction of the light of a quadtree a node receives for subdivision.
+const FLUX_THRESHOLD: f32 = 0.01;
+
+const MAX_SPATIAL_DEPTH: u32 = 32;
+const MAX_DIRECTIONAL_DEPTH: u32 = 20;
+
+/// Float with atomic addition, paths of all threads record into shared trees.
+#[derive(Default)]
+struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, value: f32) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f32::from_bits(bits) + value).to_bits())
+            });
+    }
+}
+
+// Maps direction to the square of cylindrical coordinates, which preserves area.
+fn to_square(direction: &Vector3) -> (f32, f32) {
+    let direction = direction.unit();
+    let cos_theta = clamp(direction.z, -1., 1.);
+    let phi = direction.y.atan2(direction.x);
+
+    ((cos_theta + 1.) * 0.5, (phi / TWO_PI).rem_euclid(1.))
+}
+
+fn from_square((u, v): (f32, f32)) -> Vector3 {
+    let cos_theta = 2. * u - 1.;
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let phi = TWO_PI * v;
+
+    Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+// Returns quadrant of the point and maps the point to coordinates within the quadrant.
+fn quadrant(point: &mut (f32, f32)) -> usize {
+    let x = usize::from(point.0 >= 0.5);
+    let y = usize::from(point.1 >= 0.5);
+    *point = (2. * point.0 - x as f32, 2. * point.1 - y as f32);
+    x + 2 * y
+}
+
+#[derive(Default)]
+struct QuadNode {
+    /// Light arriving through the quadrants, divided by densities of the recorded directions.
+    sums: [AtomicF32; 4],
+    /// Index of the node subdividing every quadrant, zero for leaves.
+    children: [u32; 4],
+}
+
+impl Clone for QuadNode {
+    fn clone(&self) -> Self {
+        Self {
+            sums: std::array::from_fn(|quadrant| AtomicF32::new(self.sums[quadrant].load())),
+            children: self.children,
+        }
+    }
+}
+
+/// Distribution of directions in proportion to light arriving from them.
+#[derive(Clone)]
+pub(crate) struct DTree {
+    nodes: Vec<QuadNode>,
+}
+
+impl DTree {
+    fn new() -> Self {
+        Self {
+            nodes: vec![QuadNode::default()],
+        }
+    }
+
+    fn sums(&self, node: usize) -> [f32; 4] {
+        std::array::from_fn(|quadrant| self.nodes[node].sums[quadrant].load())
+    }
+
+    fn total(&self) -> f32 {
+        self.sums(0).iter().sum()
+    }
+
+    fn child(&self, node: usize, quadrant: usize) -> Option<usize> {
+        match self.nodes[node].children[quadrant] {
+            0 => None,
+            child => Some(child as usize),
+        }
+    }
+
+    fn record(&self, direction: &Vector3, value: f32) {
+        let mut point = to_square(direction);
+        let mut node = 0;
+        loop {
+            let quadrant = quadrant(&mut point);
+            self.nodes[node].sums[quadrant].add(value);
+            match self.child(node, quadrant) {
+                Some(child) => node = child,
+                None => return,
+            }
+        }
+    }
+
+    /// Samples direction, nodes which received no light are sampled uniformly.
+    pub(crate) fn sample(&self, sampler: &impl Sampler) -> Vector3 {
+        let mut u = (sampler.next_float(), sampler.next_float());
+        let (mut node, mut origin, mut size) = (0, (0., 0.), 1.);
+
+        loop {
+            let sums = self.sums(node);
+            let total: f32 = sums.iter().sum();
+            if total <= 0. {
+                break;
+            }
+
+            // Column by its part of the light, then row within the column, reusing the numbers.
+            let left = (sums[0] + sums[2]) / total;
+            let x = match u.0 < left {
+                true => {
+                    u.0 /= left;
+                    0
+                }
+                false => {
+                    u.0 = (u.0 - left) / (1. - left);
+                    1
+                }
+            };
+            let bottom = sums[x] / (sums[x] + sums[x + 2]);
+            let y = match u.1 < bottom {
+                true => {
+                    u.1 /= bottom;
+                    0
+                }
+                false => {
+                    u.1 = (u.1 - bottom) / (1. - bottom);
+                    1
+                }
+            };
+
+            size *= 0.5;
+            origin = (origin.0 + x as f32 * size, origin.1 + y as f32 * size);
+            match self.child(node, x + 2 * y) {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+
+        from_square((
+            origin.0 + clamp(u.0, 0., 1.) * size,
+            origin.1 + clamp(u.1, 0., 1.) * size,
+        ))
+    }
+
+    /// Returns probability density of sampling the direction per solid angle.
+    pub(crate) fn pdf(&self, direction: &Vector3) -> f32 {
+        let mut point = to_square(direction);
+        let (mut node, mut pdf) = (0, 0.25 * ONE_OVER_PI);
+
+        loop {
+            let sums = self.sums(node);
+            let total: f32 = sums.iter().sum();
+            if total <= 0. {
+                return pdf;
+            }
+
+            let quadrant = quadrant(&mut point);
+            pdf *= 4. * sums[quadrant] / total;
+            match self.child(node, quadrant) {
+                Some(child) => node = child,
+                None => return pdf,
+            }
+        }
+    }
+
+    /// Returns empty tree subdivided where this one received more than a fraction of its light.
+    fn refined(&self) -> DTree {
+        let total = self.total();
+        let mut tree = DTree::new();
+        if total <= 0. {
+            return tree;
+        }
+
+        // Quadrants without a node of their own spread their light evenly.
+        let mut stack = vec![(Some(0), 0, 1, total)];
+        while let Some((source, target, depth, light)) = stack.pop() {
+            let sums = match source {
+                Some(source) => self.sums(source),
+                None => [light * 0.25; 4],
+            };
+
+            for (quadrant, sum) in sums.iter().enumerate() {
+                if depth < MAX_DIRECTIONAL_DEPTH && *sum / total > FLUX_THRESHOLD {
+                    let child = tree.nodes.len();
+                    tree.nodes.push(QuadNode::default());
+                    tree.nodes[target].children[quadrant] = child as u32;
+
+                    let source = source.and_then(|source| self.child(source, quadrant));
+                    stack.push((source, child, depth + 1, *sum));
+                }
+            }
+        }
+
+        tree
+    }
+}
+
+struct Leaf {
+    sampling: DTree,
+    recording: DTree,
+    /// Number of paths recorded in this iteration.
+    samples: AtomicU32,
+    depth: u32,
+}
+
+impl Leaf {
+    /// Returns one of the halves of the leaf, sharing its distributions.
+    fn half(&self) -> Leaf {
+        Leaf {
+            sampling: self.sampling.clone(),
+            recording: self.recording.clone(),
+            samples: AtomicU32::new(self.samples.load(Ordering::Relaxed) / 2),
+            depth: self.depth + 1,
+        }
+    }
+}
+
+enum SpatialNode {
+    /// Node split in the middle of its bounds along the axis.
+    Inner {
+        axis: usize,
+        children: [usize; 2],
+    },
+    Leaf(Leaf),
+}
+
+/// Vertex of a path scattered in a direction sampled from the mixture of guiding and the BRDF.
+pub(crate) struct GuidedVertex {
+    pub position: Vector3,
+    pub direction: Vector3,
+    pub pdf: f32,
+    /// Light found by the path before and throughput after scattering at the vertex.
+    pub color: Vector3,
+    pub throughput: Vector3,
+}
+
+/// Distributions of arriving light learned in regions of the scene.
+pub(crate) struct GuidingField {
+    nodes: Vec<SpatialNode>,
+    bounds: (Vector3, Vector3),
+    /// Number of refinements, each of them follows twice as many passes as the previous one.
+    iteration: u32,
+}
+
+impl GuidingField {
+    pub(crate) fn new(scene: &Scene) -> Self {
+        let (center, radius) = scene.bounding_sphere();
+
+        Self {
+            nodes: vec![SpatialNode::Leaf(Leaf {
+                sampling: DTree::new(),
+                recording: DTree::new(),
+                samples: AtomicU32::new(0),
+                depth: 0,
+            })],
+            bounds: (center - to_v3(radius), center + to_v3(radius)),
+            iteration: 0,
+        }
+    }
+
+    fn leaf(&self, position: &Vector3) -> &Leaf {
+        let (mut min, mut max) = self.bounds;
+        let mut index = 0;
+
+        loop {
+            match &self.nodes[index] {
+                SpatialNode::Leaf(leaf) => return leaf,
+                SpatialNode::Inner { axis, children } => {
+                    let middle = (min[*axis] + max[*axis]) * 0.5;
+                    if position[*axis] < middle {
+                        max[*axis] = middle;
+                        index = children[0];
+                    } else {
+                        min[*axis] = middle;
+                        index = children[1];
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns distribution of light arriving around the position, none until it was learned.
+    pub(crate) fn guide(&self, position: &Vector3) -> Option<&DTree> {
+        let leaf = self.leaf(position);
+        match leaf.sampling.total() > 0. {
+            true => Some(&leaf.sampling),
+            false => None,
+        }
+    }
+
+    /// Records light arriving to the vertices of a path which found light of given color.
+    pub(crate) fn record(&self, vertices: &[GuidedVertex], color: Vector3) {
+        let divide = |light: f32, throughput: f32| match throughput > 0. {
+            true => light / throughput,
+            false => 0.,
+        };
+
+        for vertex in vertices {
+            // Light found after the vertex was scaled by its throughput.
+            let light = color - vertex.color;
+            let arriving = Vector3::new(
+                divide(light.x, vertex.throughput.x),
+                divide(light.y, vertex.throughput.y),
+                divide(light.z, vertex.throughput.z),
+            );
+
+            let leaf = self.leaf(&vertex.position);
+            leaf.samples.fetch_add(1, Ordering::Relaxed);
+
+            let value = luminance(arriving) / vertex.pdf;
+            if value.is_finite() && value > 0. {
+                leaf.recording.record(&vertex.direction, value);
+            }
+        }
+    }
+
+    /// Replaces sampled distributions by the recorded ones, refines the recording trees and
+    /// splits leaves which recorded many paths.
+    pub(crate) fn refine(&mut self) {
+        for node in &mut self.nodes {
+            if let SpatialNode::Leaf(leaf) = node {
+                // Regions no path reached keep what they learned before.
+                if leaf.recording.total() > 0. {
+                    leaf.sampling = std::mem::replace(&mut leaf.recording, DTree::new());
+                }
+                leaf.recording = leaf.sampling.refined();
+            }
+        }
+
+        // Halves are visited again, so that leaves split until few enough paths are left in them.
+        let threshold = SPATIAL_THRESHOLD * 2f32.powi(self.iteration as i32).sqrt();
+        let mut index = 0;
+        while index < self.nodes.len() {
+            let halves = match &self.nodes[index] {
+                SpatialNode::Leaf(leaf)
+                    if leaf.samples.load(Ordering::Relaxed) as f32 > threshold
+                        && leaf.depth < MAX_SPATIAL_DEPTH =>
+                {
+                    Some(((leaf.depth % 3) as usize, leaf.half(), leaf.half()))
+                }
+                _ => None,
+            };
+
+            if let Some((axis, first, second)) = halves {
+                let children = [self.nodes.len(), self.nodes.len() + 1];
+                self.nodes.push(SpatialNode::Leaf(first));
+                self.nodes.push(SpatialNode::Leaf(second));
+                self.nodes[index] = SpatialNode::Inner { axis, children };
+            }
+            index += 1;
+        }
+
+        for node in &mut self.nodes {
+            if let SpatialNode::Leaf(leaf) = node {
+                *leaf.samples.get_mut() = 0;
+            }
+        }
+        self.iteration += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::UniformSampler;
+
+    #[test]
+    fn test_square_mapping() {
+        for direction in [
+            Vector3::new(0., 0., 1.),
+            Vector3::new(0.6, -0.8, 0.),
+            Vector3::new(-0.48, -0.6, 0.64),
+        ] {
+            let mapped = from_square(to_square(&direction));
+            assert!((mapped - direction).length() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_learned_distribution() {
+        let sampler = UniformSampler::new();
+        let bright = Vector3::new(0.3, 0.2, 0.93).unit();
+
+        let record = |tree: &DTree| {
+            for _ in 0..10000 {
+                // Most of the light arrives from a cap around the bright direction.
+                let direction = loop {
+                    let direction = unit_sphere_direction(&sampler);
+                    if sampler.next_float() < 0.01 || cgmath::dot(direction, bright) > 0.9 {
+                        break direction;
+                    }
+                };
+                tree.record(&direction, 1.);
+            }
+        };
+
+        // Light recorded in a refined tree is learned in finer detail.
+        let coarse = DTree::new();
+        record(&coarse);
+        let tree = coarse.refined();
+        record(&tree);
+        assert!(tree.nodes.len() > 1);
+
+        // Density integrates to one and samples follow the light.
+        let count = 100000;
+        let mut integral = 0.;
+        let mut close = 0;
+        for _ in 0..count {
+            let direction = unit_sphere_direction(&sampler);
+            integral += tree.pdf(&direction) * 4. / ONE_OVER_PI / count as f32;
+
+            let sampled = tree.sample(&sampler);
+            assert!(tree.pdf(&sampled) > 0.);
+            if cgmath::dot(sampled, bright) > 0.9 {
+                close += 1;
+            }
+        }
+        assert!((integral - 1.).abs() < 0.05);
+        assert!(close > count / 2);
+    }
+
+    fn unit_sphere_direction(sampler: &UniformSampler) -> Vector3 {
+        from_square((sampler.next_float(), sampler.next_float()))
+    }
+}
@@ -2,10 +2,18 @@ use std::collections::HashMap;
 use std::path::Path;
 use std::sync::Arc;
 
-use crate::brdf_lambert::{Dielectric, Lambertian};
+use crate::brdf::Brdf;
+use crate::brdf_dielectric::Dielectric;
+use crate::brdf_lambert::Lambertian;
 use crate::brdf_microfacet::MicrofacetBrdf;
-
-use crate::material::{AlphaMode, Filtering, Material, Sampler, Texture, TextureSampler, WrapMode};
+use crate::import_scene::MeshMaterials;
+use crate::light::{Cone, Directional, Falloff, Light, Point};
+
+use crate::material::{
+    AlphaMode, Anisotropy, Clearcoat, ColorSpace, Filtering, Iridescence, Material, MipFiltering,
+    Sampler, Scattering, Sheen, Specular, Subsurface, Texels, Texture, TextureSampler,
+    TextureTransform, WrapMode, UV_SETS,
+};
 use crate::math::{EnhancedVector, Vector3};
 use crate::mesh::{Mesh, Triangle, Vertex};
 use crate::scene::SceneImportHandler;
@@ -15,13 +23,14 @@ fn handle_material<H>(handler: &mut H, material: &Material)
 where
     H: SceneImportHandler,
 {
-    let albedo = material.albedo_texture.as_ref().map(|texture| {
-        (
-            texture.texture.width,
-            texture.texture.height,
-            &texture.texture.rgba[..],
-        )
-    });
+    let level = material
+        .albedo_texture
+        .as_ref()
+        .map(|texture| &texture.texture.levels[0]);
+    let rgba = level.map(|level| level.to_rgba8());
+    let albedo = level
+        .zip(rgba.as_deref())
+        .map(|(level, rgba)| (level.width, level.height, rgba));
 
     handler.handle_material(material.albedo_factor, albedo);
 }
@@ -76,6 +85,14 @@ fn to_sampler(sampler: &gltf::texture::Sampler) -> Sampler {
         _ => Filtering::Nearest,
     };
 
+    let mip_filtering = match sampler.min_filter() {
+        Some(gltf::texture::MinFilter::Nearest) | Some(gltf::texture::MinFilter::Linear) => {
+            MipFiltering::Disabled
+        }
+        Some(gltf::texture::MinFilter::LinearMipmapLinear) | None => MipFiltering::Ewa,
+        Some(_) => MipFiltering::Trilinear,
+    };
+
     let wrap_s = match sampler.wrap_s() {
         gltf::texture::WrappingMode::ClampToEdge => WrapMode::Clamp,
         gltf::texture::WrappingMode::MirroredRepeat => WrapMode::MirroredRepeat,
@@ -90,139 +107,421 @@ fn to_sampler(sampler: &gltf::texture::Sampler) -> Sampler {
 
     Sampler {
         filtering,
+        mip_filtering,
         wrap_s,
         wrap_t,
     }
 }
 
-#[inline]
-fn from_u16(lsb: u8, msb: u8) -> u8 {
-    (255. * (((lsb as u16) | ((msb as u16) << 8)) as f32 / 165535.)) as u8
+// Expands channels of pixels to RGBA given source channel of each output one, alpha defaults to opaque.
+fn expand<T: Copy>(pixels: &[T], channels: usize, map: [Option<usize>; 4], opaque: T) -> Vec<T> {
+    pixels
+        .chunks_exact(channels)
+        .flat_map(|pixel| map.map(|channel| channel.map_or(opaque, |c| pixel[c])))
+        .collect()
 }
 
-fn to_texture(texture: &gltf::image::Data) -> Texture {
+fn to_texture(image: &gltf::image::Data, color_space: ColorSpace) -> Texture {
     use gltf::image::Format;
 
-    let reader = match texture.format {
-        Format::R8 => |data: &Vec<u8>, i| {
-            let rgba = (data[i], 0, 0, 255);
-            (1, rgba)
-        },
-        Format::R8G8 => |data: &Vec<u8>, i| {
-            let rgba = (data[i], data[i + 1], 0, 255);
-            (2, rgba)
-        },
-        Format::R8G8B8 => |data: &Vec<u8>, i| {
-            let rgba = (data[i], data[i + 1], data[i + 2], 255);
-            (3, rgba)
-        },
-        Format::R8G8B8A8 => |data: &Vec<u8>, i| {
-            let rgba = (data[i], data[i + 1], data[i + 2], data[i + 3]);
-            (4, rgba)
-        },
-        Format::B8G8R8 => |data: &Vec<u8>, i| {
-            let rgba = (data[i + 2], data[i + 1], data[i], 255);
-            (3, rgba)
-        },
-        Format::B8G8R8A8 => |data: &Vec<u8>, i| {
-            let rgba = (data[i + 2], data[i + 1], data[i], data[i + 3]);
-            (4, rgba)
-        },
-        Format::R16 => |data: &Vec<u8>, i| {
-            let rgba = (from_u16(data[i], data[i + 1]), 0u8, 0u8, 255u8);
-            (2, rgba)
-        },
-        Format::R16G16 => |data: &Vec<u8>, i| {
-            let rgba = (
-                from_u16(data[i], data[i + 1]),
-                from_u16(data[i + 2], data[i + 3]),
-                0u8,
-                255u8,
-            );
-            (4, rgba)
-        },
-        Format::R16G16B16 => |data: &Vec<u8>, i| {
-            let rgba = (
-                from_u16(data[i], data[i + 1]),
-                from_u16(data[i + 2], data[i + 3]),
-                from_u16(data[i + 4], data[i + 5]),
-                255u8,
-            );
-            (6, rgba)
-        },
-        Format::R16G16B16A16 => |data: &Vec<u8>, i| {
-            let rgba = (
-                from_u16(data[i], data[i + 1]),
-                from_u16(data[i + 2], data[i + 3]),
-                from_u16(data[i + 4], data[i + 5]),
-                from_u16(data[i + 6], data[i + 7]),
-            );
-            (8, rgba)
-        },
+    // Single channel images are grayscale.
+    let (channels, map) = match image.format {
+        Format::R8 | Format::R16 => (1, [Some(0), Some(0), Some(0), None]),
+        Format::R8G8 | Format::R16G16 => (2, [Some(0), Some(0), Some(0), Some(1)]),
+        Format::R8G8B8 | Format::R16G16B16 => (3, [Some(0), Some(1), Some(2), None]),
+        Format::R8G8B8A8 | Format::R16G16B16A16 => (4, [Some(0), Some(1), Some(2), Some(3)]),
+        Format::B8G8R8 => (3, [Some(2), Some(1), Some(0), None]),
+        Format::B8G8R8A8 => (4, [Some(2), Some(1), Some(0), Some(3)]),
     };
 
-    let pixel_size = 4u8;
-    let mut rgba: Vec<u8> = vec![0; (texture.width * texture.height * pixel_size as u32) as usize];
-    let mut position = 0;
-    let mut output = 0;
+    let texels = match image.format {
+        Format::R16 | Format::R16G16 | Format::R16G16B16 | Format::R16G16B16A16 => {
+            let values: Vec<u16> = image
+                .pixels
+                .chunks_exact(2)
+                .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+                .collect();
+            Texels::U16(expand(&values, channels, map, u16::MAX))
+        }
+        _ => Texels::U8(expand(&image.pixels, channels, map, u8::MAX)),
+    };
 
-    loop {
-        let (offset, pixels) = reader(&texture.pixels, position);
-        position += offset;
+    Texture::new(image.width, image.height, texels, color_space)
+}
 
-        rgba[output] = pixels.0;
-        rgba[output + 1] = pixels.1;
-        rgba[output + 2] = pixels.2;
-        rgba[output + 3] = pixels.3;
+// Images are converted on first use, once for each color space of the roles they are used in.
+struct Textures<'a> {
+    images: &'a [gltf::image::Data],
+    converted: HashMap<(usize, ColorSpace), Arc<Texture>>,
+}
 
-        output += 4;
+impl<'a> Textures<'a> {
+    fn new(images: &'a [gltf::image::Data]) -> Self {
+        Textures {
+            images,
+            converted: HashMap::new(),
+        }
+    }
 
-        if position >= texture.pixels.len() {
-            break;
+    fn sampler(
+        &mut self,
+        info: &gltf::Texture,
+        color_space: ColorSpace,
+        tex_coord: u32,
+        transform: TextureTransform,
+    ) -> TextureSampler {
+        let index = info.source().index();
+        let image = &self.images[index];
+        let texture = self
+            .converted
+            .entry((index, color_space))
+            .or_insert_with(|| Arc::new(to_texture(image, color_space)))
+            .clone();
+
+        let tex_coord = match (tex_coord as usize) < UV_SETS {
+            true => tex_coord as usize,
+            false => {
+                println!("Unsupported texture coordinate set {}, using 0", tex_coord);
+                0
+            }
+        };
+
+        TextureSampler {
+            texture,
+            sampler: to_sampler(&info.sampler()),
+            tex_coord,
+            transform,
         }
     }
 
-    Texture {
-        width: texture.width,
-        height: texture.height,
-        rgba,
-        pixel_size,
+    fn info_sampler(
+        &mut self,
+        info: &gltf::texture::Info,
+        color_space: ColorSpace,
+    ) -> TextureSampler {
+        let (tex_coord, transform) = match info.texture_transform() {
+            Some(transform) => (
+                transform.tex_coord().unwrap_or(info.tex_coord()),
+                TextureTransform {
+                    offset: (transform.offset()[0], transform.offset()[1]),
+                    rotation: transform.rotation(),
+                    scale: (transform.scale()[0], transform.scale()[1]),
+                },
+            ),
+            None => (info.tex_coord(), TextureTransform::default()),
+        };
+
+        self.sampler(&info.texture(), color_space, tex_coord, transform)
+    }
+}
+
+// Names of material properties the dielectric BRDF has no lobe for.
+fn ignored_by_dielectric(material: &Material) -> Vec<&'static str> {
+    [
+        ("metalness", material.metalic > 0.),
+        (
+            "specular",
+            material.specular.factor != 1. || material.specular.color != Vector3::one(),
+        ),
+        ("clearcoat", material.clearcoat.factor > 0.),
+        ("sheen", material.sheen.color != Vector3::zero()),
+        ("anisotropy", material.anisotropy.strength != 0.),
+        ("iridescence", material.iridescence.factor > 0.),
+    ]
+    .into_iter()
+    .filter(|(_, set)| *set)
+    .map(|(name, _)| name)
+    .collect()
+}
+
+/// Chooses BRDF matching material properties.
+///
+/// Transmissive materials use the dielectric BRDF alone, their metalness and specular,
+/// clearcoat, sheen, anisotropy and iridescence layers are ignored with a warning.
+pub fn create_brdf(material: &Material) -> Box<dyn Brdf + Sync + Send + 'static> {
+    if material.is_transmissive() {
+        let ignored = ignored_by_dielectric(material);
+        if !ignored.is_empty() {
+            println!(
+                "Transmissive material does not support {}, ignoring",
+                ignored.join(", ")
+            );
+        }
+        Box::new(Dielectric::new())
+    } else {
+        Box::new(MicrofacetBrdf::new())
+    }
+}
+
+/// Returns materials in raw JSON, the gltf crate does not parse all of their extensions.
+fn raw_materials(filename: &Path) -> Result<Vec<serde_json::Value>, Error> {
+    let data = std::fs::read(filename)?;
+    let root: serde_json::Value = match gltf::Glb::from_slice(&data) {
+        Ok(glb) => serde_json::from_slice(&glb.json)?,
+        Err(_) => serde_json::from_slice(&data)?,
+    };
+
+    let materials = match root.get("materials").and_then(|m| m.as_array()) {
+        Some(materials) => materials,
+        None => return Ok(vec![]),
+    };
+
+    Ok(materials.clone())
+}
+
+fn json_number(value: &serde_json::Value, key: &str, default: f32) -> f32 {
+    value
+        .get(key)
+        .and_then(|v| v.as_f64())
+        .map_or(default, |v| v as f32)
+}
+
+fn json_numbers(value: &serde_json::Value, key: &str) -> Option<Vec<f32>> {
+    value.get(key).and_then(|v| v.as_array()).map(|c| {
+        c.iter()
+            .filter_map(|v| v.as_f64().map(|v| v as f32))
+            .collect()
+    })
+}
+
+fn json_color(value: &serde_json::Value, key: &str, default: Vector3) -> Vector3 {
+    match json_numbers(value, key) {
+        Some(c) if c.len() == 3 => Vector3::from_slice(&c),
+        _ => default,
     }
 }
 
-pub fn to_texture_sampler(textures: &[Arc<Texture>], info: &gltf::Texture) -> TextureSampler {
-    TextureSampler {
-        texture: textures[info.source().index()].clone(),
-        sampler: to_sampler(&info.sampler()),
+fn json_pair(value: &serde_json::Value, key: &str, default: (f32, f32)) -> (f32, f32) {
+    match json_numbers(value, key) {
+        Some(c) if c.len() == 2 => (c[0], c[1]),
+        _ => default,
     }
 }
 
-pub fn load_material(material: &gltf::Material, textures: &[Arc<Texture>]) -> Material {
+fn json_texture(
+    value: &serde_json::Value,
+    key: &str,
+    document: &gltf::Document,
+    textures: &mut Textures,
+    color_space: ColorSpace,
+) -> Option<TextureSampler> {
+    let info = value.get(key)?;
+    let index = info.get("index")?.as_u64()? as usize;
+    let tex_coord = info.get("texCoord").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+
+    let transform = info
+        .get("extensions")
+        .and_then(|extensions| extensions.get("KHR_texture_transform"));
+    let (tex_coord, transform) = match transform {
+        Some(transform) => (
+            transform
+                .get("texCoord")
+                .and_then(|v| v.as_u64())
+                .map_or(tex_coord, |v| v as u32),
+            TextureTransform {
+                offset: json_pair(transform, "offset", (0., 0.)),
+                rotation: json_number(transform, "rotation", 0.),
+                scale: json_pair(transform, "scale", (1., 1.)),
+            },
+        ),
+        None => (tex_coord, TextureTransform::default()),
+    };
+
+    document
+        .textures()
+        .nth(index)
+        .map(|texture| textures.sampler(&texture, color_space, tex_coord, transform))
+}
+
+fn load_material(
+    material: &gltf::Material,
+    document: &gltf::Document,
+    textures: &mut Textures,
+    raw: &serde_json::Value,
+) -> Material {
     let pbr = material.pbr_metallic_roughness();
+    let extensions = raw.get("extensions").unwrap_or(&serde_json::Value::Null);
 
     // Albedo.
     let albedo_factor = Vector3::from_slice(&pbr.base_color_factor()[0..3]);
     let albedo_texture = pbr
         .base_color_texture()
-        .map(|info| to_texture_sampler(textures, &info.texture()));
+        .map(|info| textures.info_sampler(&info, ColorSpace::Srgb));
 
-    // Emissive.
-    let emitted_factor = Vector3::from_slice(&material.emissive_factor()[0..3]);
+    // Emissive, KHR_materials_emissive_strength scales the factor above one.
+    let emissive_strength = extensions
+        .get("KHR_materials_emissive_strength")
+        .map_or(1., |strength| json_number(strength, "emissiveStrength", 1.));
+    let emitted_factor = Vector3::from_slice(&material.emissive_factor()[0..3]) * emissive_strength;
     let emitted_texture = material
         .emissive_texture()
-        .map(|info| to_texture_sampler(textures, &info.texture()));
+        .map(|info| textures.info_sampler(&info, ColorSpace::Srgb));
 
-    // Normal.
-    let normal_texture = material
-        .normal_texture()
-        .map(|info| to_texture_sampler(textures, &info.texture()));
+    // Normal, the gltf crate does not expose texture transform of normal textures.
+    let normal_texture = json_texture(raw, "normalTexture", document, textures, ColorSpace::Linear);
 
     // Metalic + roughness.
     let metalic = pbr.metallic_factor();
     let roughness = pbr.roughness_factor();
     let metalic_roughness_texture = pbr
         .metallic_roughness_texture()
-        .map(|info| to_texture_sampler(textures, &info.texture()));
+        .map(|info| textures.info_sampler(&info, ColorSpace::Linear));
+
+    // Transmission, KHR_materials_transmission.
+    let (transmission, transmission_texture) = match material.transmission() {
+        Some(transmission) => (
+            transmission.transmission_factor(),
+            transmission
+                .transmission_texture()
+                .map(|info| textures.info_sampler(&info, ColorSpace::Linear)),
+        ),
+        None => (0., None),
+    };
+
+    // Volume, KHR_materials_volume.
+    let (thickness, attenuation_color, attenuation_distance) = match material.volume() {
+        Some(volume) => (
+            volume.thickness_factor(),
+            Vector3::from_slice(&volume.attenuation_color()),
+            volume.attenuation_distance(),
+        ),
+        None => (0., Vector3::one(), f32::INFINITY),
+    };
+
+    // Subsurface scattering, KHR_materials_diffuse_transmission inside of a volume. The walk
+    // spans the attenuation distance scaled by its color, the thickness if it is not given.
+    let subsurface = match extensions.get("KHR_materials_diffuse_transmission") {
+        Some(transmission) => Subsurface {
+            factor: json_number(transmission, "diffuseTransmissionFactor", 0.),
+            texture: json_texture(
+                transmission,
+                "diffuseTransmissionTexture",
+                document,
+                textures,
+                ColorSpace::Linear,
+            ),
+            color: json_color(
+                transmission,
+                "diffuseTransmissionColorFactor",
+                Vector3::one(),
+            ),
+            color_texture: json_texture(
+                transmission,
+                "diffuseTransmissionColorTexture",
+                document,
+                textures,
+                ColorSpace::Srgb,
+            ),
+            mean_free_path: match attenuation_distance.is_finite() {
+                true => attenuation_color * attenuation_distance,
+                false => Vector3::one() * thickness,
+            },
+            asymmetry: 0.,
+        },
+        None => Subsurface::default(),
+    };
+
+    // Specular, KHR_materials_specular.
+    let specular = match material.specular() {
+        Some(specular) => Specular {
+            factor: specular.specular_factor(),
+            texture: specular
+                .specular_texture()
+                .map(|info| textures.info_sampler(&info, ColorSpace::Linear)),
+            color: Vector3::from_slice(&specular.specular_color_factor()),
+            color_texture: specular
+                .specular_color_texture()
+                .map(|info| textures.info_sampler(&info, ColorSpace::Srgb)),
+        },
+        None => Specular::default(),
+    };
+
+    // Clearcoat, KHR_materials_clearcoat.
+    let clearcoat = match extensions.get("KHR_materials_clearcoat") {
+        Some(clearcoat) => Clearcoat {
+            factor: json_number(clearcoat, "clearcoatFactor", 0.),
+            texture: json_texture(
+                clearcoat,
+                "clearcoatTexture",
+                document,
+                textures,
+                ColorSpace::Linear,
+            ),
+            roughness: json_number(clearcoat, "clearcoatRoughnessFactor", 0.),
+            roughness_texture: json_texture(
+                clearcoat,
+                "clearcoatRoughnessTexture",
+                document,
+                textures,
+                ColorSpace::Linear,
+            ),
+        },
+        None => Clearcoat::default(),
+    };
+
+    // Sheen, KHR_materials_sheen.
+    let sheen = match extensions.get("KHR_materials_sheen") {
+        Some(sheen) => Sheen {
+            color: json_color(sheen, "sheenColorFactor", Vector3::zero()),
+            color_texture: json_texture(
+                sheen,
+                "sheenColorTexture",
+                document,
+                textures,
+                ColorSpace::Srgb,
+            ),
+            roughness: json_number(sheen, "sheenRoughnessFactor", 0.),
+            roughness_texture: json_texture(
+                sheen,
+                "sheenRoughnessTexture",
+                document,
+                textures,
+                ColorSpace::Linear,
+            ),
+        },
+        None => Sheen::default(),
+    };
+
+    // Anisotropy, KHR_materials_anisotropy.
+    let anisotropy = match extensions.get("KHR_materials_anisotropy") {
+        Some(anisotropy) => Anisotropy {
+            strength: json_number(anisotropy, "anisotropyStrength", 0.),
+            rotation: json_number(anisotropy, "anisotropyRotation", 0.),
+            texture: json_texture(
+                anisotropy,
+                "anisotropyTexture",
+                document,
+                textures,
+                ColorSpace::Linear,
+            ),
+        },
+        None => Anisotropy::default(),
+    };
+
+    // Iridescence, KHR_materials_iridescence.
+    let iridescence = match extensions.get("KHR_materials_iridescence") {
+        Some(iridescence) => Iridescence {
+            factor: json_number(iridescence, "iridescenceFactor", 0.),
+            texture: json_texture(
+                iridescence,
+                "iridescenceTexture",
+                document,
+                textures,
+                ColorSpace::Linear,
+            ),
+            ior: json_number(iridescence, "iridescenceIor", 1.3),
+            thickness_minimum: json_number(iridescence, "iridescenceThicknessMinimum", 100.),
+            thickness_maximum: json_number(iridescence, "iridescenceThicknessMaximum", 400.),
+            thickness_texture: json_texture(
+                iridescence,
+                "iridescenceThicknessTexture",
+                document,
+                textures,
+                ColorSpace::Linear,
+            ),
+        },
+        None => Iridescence::default(),
+    };
 
     let single_sided = !material.double_sided();
 
@@ -242,6 +541,23 @@ pub fn load_material(material: &gltf::Material, textures: &[Arc<Texture>]) -> Ma
         metalic,
         roughness,
         metalic_roughness_texture,
+        ior: material.ior().unwrap_or(1.5),
+        dispersion: extensions
+            .get("KHR_materials_dispersion")
+            .map_or(0., |dispersion| json_number(dispersion, "dispersion", 0.)),
+        conductor: None,
+        transmission,
+        transmission_texture,
+        thickness,
+        attenuation_color,
+        attenuation_distance,
+        scattering: Scattering::default(),
+        subsurface,
+        specular,
+        clearcoat,
+        sheen,
+        anisotropy,
+        iridescence,
         single_sided,
         brdf: Box::new(MicrofacetBrdf::new()),
     }
@@ -276,10 +592,13 @@ where
         None => vec![],
     };
 
-    let coords = match reader.read_tex_coords(0) {
-        Some(iter) => iter.into_f32().collect(),
-        None => vec![],
-    };
+    let uv_sets: Vec<Vec<[f32; 2]>> = (0..UV_SETS as u32)
+        .map(|set| match reader.read_tex_coords(set) {
+            Some(iter) => iter.into_f32().collect(),
+            None => vec![],
+        })
+        .collect();
+    let coords = &uv_sets[0];
     // println!("  ------ coord count: {}", coords.len());
 
     let indices = match reader.read_indices() {
@@ -295,15 +614,14 @@ where
 
         for (i, index) in triangle.iter().enumerate() {
             let vert = vertices[*index];
-            let coord = if coords.is_empty() {
-                [0.; 2]
-            } else {
-                coords[*index]
-            };
+            let uv = std::array::from_fn(|set| match uv_sets[set].get(*index) {
+                Some(coord) => (coord[0], coord[1]),
+                None => (0., 0.),
+            });
 
             vertex[i] = Vertex {
                 pos: Vector3::new(vert[0], vert[1], vert[2]),
-                uv: (coord[0], coord[1]),
+                uv,
             };
         }
 
@@ -316,7 +634,7 @@ where
             material_index,
             transformation,
             &vertices,
-            &coords,
+            coords,
             &normals,
             &indices,
         );
@@ -325,40 +643,86 @@ where
     Mesh::new(mesh_triangles, material, transformation)
 }
 
+/// Converts light of the KHR_lights_punctual extension, lights shine along their negative Z axis.
+fn load_light(
+    light: &gltf::khr_lights_punctual::Light,
+    transformation: cgmath::Matrix4<f32>,
+) -> Light {
+    let color = Vector3::from_slice(&light.color());
+    let position = (transformation * Vector3::zero().extend(1.)).truncate();
+    let forward = (transformation * Vector3::new(0., 0., -1.).extend(0.))
+        .truncate()
+        .unit();
+    let range = light.range().unwrap_or(f32::INFINITY);
+
+    let point = |cone| {
+        Light::Point(Point {
+            position,
+            color,
+            intensity: light.intensity(),
+            range,
+            range_squared: range * range,
+            falloff: Falloff::InverseSquare,
+            profile: None,
+            cone,
+        })
+    };
+
+    match light.kind() {
+        gltf::khr_lights_punctual::Kind::Directional => Light::Directional(Directional {
+            dir: -forward,
+            color,
+            intensity: light.intensity(),
+        }),
+        gltf::khr_lights_punctual::Kind::Point => point(None),
+        gltf::khr_lights_punctual::Kind::Spot {
+            inner_cone_angle,
+            outer_cone_angle,
+        } => point(Some(Cone::new(forward, inner_cone_angle, outer_cone_angle))),
+    }
+}
+
+/// Loads meshes and lights of the file.
+///
+/// Cameras of the file are numbered after `cameras` reported by previously loaded files, the count
+/// is advanced by cameras of this file.
 pub fn load<H>(
     filename: &Path,
     transformation: cgmath::Matrix4<f32>,
+    mesh_materials: &MeshMaterials,
+    cameras: &mut usize,
     handler: &mut Option<&mut H>,
-) -> Result<Vec<Mesh>, Error>
+) -> Result<(Vec<Mesh>, Vec<Light>), Error>
 where
     H: SceneImportHandler,
 {
     println!("Loading gltf {:?}...", filename);
 
     let (gltf, buffers, gltf_textures) = gltf::import(filename)?;
+    let raw_materials = raw_materials(filename)?;
 
-    let textures: Vec<Arc<Texture>> = gltf_textures
-        .iter()
-        .map(|gltf_texture| Arc::new(to_texture(gltf_texture)))
-        .collect();
+    let mut textures = Textures::new(&gltf_textures);
 
     let mut meshes: Vec<Mesh> = vec![];
+    let mut lights: Vec<Light> = vec![];
     let mut materials: Vec<Arc<Material>> = vec![];
     let mut material_cache: HashMap<usize, usize> = HashMap::new();
 
-    let dummy_material = Arc::new(Material {
-        alpha_mode: AlphaMode::Opaque,
-        albedo_factor: Vector3::one(),
-        albedo_texture: None,
-        emitted_factor: Vector3::zero(),
-        emitted_texture: None,
-        normal_texture: None,
-        metalic: 0.,
-        roughness: 0.,
-        metalic_roughness_texture: None,
-        single_sided: false,
-        brdf: Box::new(Dielectric::new(1.5)),
-    });
+    // Primitives without a glTF material take the mesh material, glass if there is none.
+    let mut dummy_material = match mesh_materials.has_mesh_material() {
+        true => Material::default(),
+        false => Material {
+            roughness: 0.,
+            transmission: 1.,
+            thickness: 1.,
+            ..Material::default()
+        },
+    };
+
+    mesh_materials.apply(None, &mut dummy_material);
+    dummy_material.brdf = create_brdf(&dummy_material);
+
+    let dummy_material = Arc::new(dummy_material);
 
     if handler.is_some() {
         for camera in gltf.cameras() {
@@ -393,14 +757,30 @@ where
 
             // Camera.
             if let Some(camera) = node.camera() {
-                let camera_index = camera.index();
+                let camera_index = *cameras + camera.index();
 
                 if handler.is_some() {
+                    // Cameras look along their negative Z axis with Y up.
+                    let world = transformation * node_transform;
+                    let position = (world * Vector3::zero().extend(1.)).truncate();
+                    let forward = (world * Vector3::new(0., 0., -1.).extend(0.)).truncate();
+                    let up = (world * Vector3::new(0., 1., 0.).extend(0.)).truncate();
+
                     let handler = handler.as_mut().unwrap();
-                    handler.handle_camera_transform(camera_index, &translate, &rotate);
+                    handler.handle_camera_transform(
+                        camera_index,
+                        position,
+                        forward.unit(),
+                        up.unit(),
+                    );
                 }
             }
 
+            // Light.
+            if let Some(light) = node.light() {
+                lights.push(load_light(&light, transformation * node_transform));
+            }
+
             // Meshes.
             if let Some(mesh) = node.mesh() {
                 for primitive in mesh.primitives() {
@@ -409,10 +789,17 @@ where
                             Some(index) => (*index as i32, materials[*index].clone()),
                             None => {
                                 let material_index = materials.len();
-                                materials.push(Arc::new(load_material(
+                                let mut material = load_material(
                                     &primitive.material(),
-                                    &textures,
-                                )));
+                                    &gltf,
+                                    &mut textures,
+                                    raw_materials
+                                        .get(source_material)
+                                        .unwrap_or(&serde_json::Value::Null),
+                                );
+                                mesh_materials.apply(primitive.material().name(), &mut material);
+                                material.brdf = create_brdf(&material);
+                                materials.push(Arc::new(material));
                                 material_cache.insert(source_material, material_index);
                                 let material = materials[material_index].clone();
 
@@ -439,5 +826,7 @@ where
         }
     }
 
-    Ok(meshes)
+    *cameras += gltf.cameras().len();
+
+    Ok((meshes, lights))
 }
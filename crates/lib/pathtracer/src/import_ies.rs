@@ -0,0 +1,123 @@
+use std::path::Path;
+
+use crate::light::Profile;
+use crate::Error;
+
+/// Photometric type of the profile whose vertical angles are measured from the axis.
+const TYPE_C: f32 = 1.;
+
+/// Loads photometric profile from a file of the IES LM-63 format.
+///
+/// Only type C photometry is supported, intensities are normalized to the brightest direction
+/// so the candela multiplier and lumens of the lamp do not matter. Tilt of the lamp is ignored.
+pub fn load_profile(filename: &Path) -> Result<Profile, Error> {
+    println!("Loading profile {:?}...", filename);
+    read_profile(filename)
+}
+
+/// Reads photometric profile without reporting it, scene validation checks profiles this way.
+pub(crate) fn read_profile(filename: &Path) -> Result<Profile, Error> {
+    let text = std::fs::read_to_string(filename)?;
+    parse(&text).map_err(|message| Error::FormatError(format!("{:?}: {}", filename, message)))
+}
+
+fn parse(text: &str) -> Result<Profile, String> {
+    // Keywords of the header end with the tilt line, numbers follow.
+    let mut lines = text.lines();
+    let tilt = lines
+        .by_ref()
+        .map(str::trim)
+        .find_map(|line| line.strip_prefix("TILT="))
+        .ok_or("missing TILT line")?;
+
+    let rest: Vec<&str> = lines.collect();
+    let mut numbers = rest
+        .iter()
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|token| !token.is_empty())
+        .map(|token| {
+            token
+                .parse::<f32>()
+                .map_err(|_| format!("invalid number '{}'", token))
+        });
+    let mut next = || numbers.next().unwrap_or(Err("unexpected end".to_string()));
+
+    match tilt.trim() {
+        "NONE" => {}
+        "INCLUDE" => {
+            // Lamp to luminaire geometry, followed by tilt angles and their multipliers.
+            next()?;
+            let count = next()? as usize;
+            for _ in 0..2 * count {
+                next()?;
+            }
+        }
+        _ => return Err("tilt files are not supported".to_string()),
+    }
+
+    let (_lamps, _lumens, _multiplier) = (next()?, next()?, next()?);
+    let (vertical_count, horizontal_count) = (next()? as usize, next()? as usize);
+    let photometric_type = next()?;
+    // Units, dimensions of the luminaire, ballast factor, future use and input watts.
+    for _ in 0..7 {
+        next()?;
+    }
+
+    if photometric_type != TYPE_C {
+        return Err("only type C photometry is supported".to_string());
+    }
+    if vertical_count == 0 || horizontal_count == 0 {
+        return Err("profile has no angles".to_string());
+    }
+
+    let mut read = |count: usize| (0..count).map(|_| next()).collect::<Result<Vec<_>, _>>();
+    let vertical = read(vertical_count)?;
+    let horizontal = read(horizontal_count)?;
+    let mut values = read(vertical_count * horizontal_count)?;
+
+    let ascending = |angles: &[f32]| angles.windows(2).all(|pair| pair[0] <= pair[1]);
+    if !ascending(&vertical) || !ascending(&horizontal) {
+        return Err("angles are not ascending".to_string());
+    }
+
+    let max = values.iter().copied().fold(0., f32::max);
+    if max <= 0. {
+        return Err("profile emits no light".to_string());
+    }
+    for value in &mut values {
+        *value = value.max(0.) / max;
+    }
+
+    Ok(Profile {
+        vertical,
+        horizontal,
+        values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::{EnhancedVector, Vector3};
+
+    #[test]
+    fn test_profile() {
+        let text = "IESNA:LM-63-2002\n[TEST] test\nTILT=NONE\n\
+            1 1000 1 3 2 1 1 0 0 0\n1 1 100\n\
+            0 45 90\n0 90\n\
+            200 100 0\n100, 50, 0\n";
+        let profile = parse(text).unwrap();
+        assert_eq!(profile.values, vec![1., 0.5, 0., 0.5, 0.25, 0.]);
+
+        // Axis points to the nadir, horizontal angles are mirrored in quadrants.
+        let intensity = |x: f32, y: f32, z: f32| profile.intensity(&Vector3::new(x, y, z).unit());
+        assert_eq!(intensity(0., 0., 1.), 1.);
+        assert!((intensity(1., 0., 1.) - 0.5).abs() < 1e-5);
+        assert!((intensity(0., -1., 1.) - 0.25).abs() < 1e-5);
+        assert!((intensity(-1., 1., 2f32.sqrt()) - 0.375).abs() < 1e-5);
+        assert_eq!(intensity(0., 0., -1.), 0.);
+
+        assert!(parse("TILT=NONE\n1 1000 1 3 2 1 1 0 0 0\n1 1 100\n0 45 90\n0 90\n1").is_err());
+        assert!(parse("1 1000 1 1 1 1 1 0 0 0\n1 1 100\n0\n0\n1").is_err());
+    }
+}
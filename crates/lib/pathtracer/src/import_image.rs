@@ -0,0 +1,32 @@
+use std::path::Path;
+
+use image::DynamicImage;
+
+use crate::material::{ColorSpace, Texels, Texture};
+use crate::Error;
+
+/// Loads image file as RGBA texture, keeping precision of 16-bit and floating point images.
+///
+/// Floating point images (HDR, EXR) are always linear.
+pub fn load_texture(filename: &Path, color_space: ColorSpace) -> Result<Texture, Error> {
+    println!("Loading texture {:?}...", filename);
+
+    let image = image::open(filename)?;
+    let (width, height) = (image.width(), image.height());
+
+    let (texels, color_space) = match image {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => (
+            Texels::F32(image.into_rgba32f().into_raw()),
+            ColorSpace::Linear,
+        ),
+        DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_) => {
+            (Texels::U16(image.into_rgba16().into_raw()), color_space)
+        }
+        _ => (Texels::U8(image.into_rgba8().into_raw()), color_space),
+    };
+
+    Ok(Texture::new(width, height, texels, color_space))
+}
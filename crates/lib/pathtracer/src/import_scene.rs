@@ -1,15 +1,34 @@
 use cgmath::One;
 use serde::*;
 
-use std::fs::File;
-use std::path::Path;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use crate::env;
-use crate::light::{Directional, Light, Point};
+use crate::import_gltf;
+use crate::import_ies;
+use crate::import_image;
+use crate::import_volume;
+use crate::light::{Cone, Directional, Falloff, Light, Point};
+use crate::material::{
+    ColorSpace, Filtering, Material, MipFiltering, Sampler, Texture, TextureSampler,
+    TextureTransform, WrapMode,
+};
 use crate::math::*;
+use crate::medium::Medium;
+use crate::primitive::Shape;
+use crate::spectrum::ComplexIor;
+use crate::validation::{self, Diagnostic};
+use crate::volume::Volume;
 use crate::Error;
 
-#[derive(Serialize, Deserialize)]
+/// Version of the scene description schema written by this version of the tracer.
+pub const SCENE_VERSION: u32 = 3;
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct TransformationDescription {
     pub translate: Option<(f32, f32, f32)>,
     pub scale: Option<(f32, f32, f32)>,
@@ -37,11 +56,353 @@ impl TransformationDescription {
     }
 }
 
+/// Textures loaded from files, decoded for color space of the roles they are used in.
+pub type SceneTextures = HashMap<(String, ColorSpace), Arc<Texture>>;
+
+/// Material definition, properties which are not set keep values of the material it is applied to.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MaterialDescription {
+    pub(crate) base_color: Option<(f32, f32, f32)>,
+    pub(crate) base_color_texture: Option<String>,
+    pub(crate) metalness: Option<f32>,
+    pub(crate) roughness: Option<f32>,
+    pub(crate) metallic_roughness_texture: Option<String>,
+    pub(crate) emissive: Option<(f32, f32, f32)>,
+    pub(crate) emissive_texture: Option<String>,
+    pub(crate) normal_texture: Option<String>,
+    pub(crate) ior: Option<f32>,
+    pub(crate) dispersion: Option<f32>,
+    pub(crate) conductor: Option<ConductorDescription>,
+    pub(crate) transmission: Option<f32>,
+    pub(crate) thickness: Option<f32>,
+    pub(crate) attenuation_color: Option<(f32, f32, f32)>,
+    pub(crate) attenuation_distance: Option<f32>,
+    pub(crate) scattering: Option<(f32, f32, f32)>,
+    pub(crate) scattering_asymmetry: Option<f32>,
+    pub(crate) subsurface: Option<f32>,
+    pub(crate) subsurface_color: Option<(f32, f32, f32)>,
+    pub(crate) subsurface_color_texture: Option<String>,
+    pub(crate) subsurface_radius: Option<(f32, f32, f32)>, //< Mean free path per color channel.
+    pub(crate) subsurface_asymmetry: Option<f32>,
+    pub(crate) specular: Option<f32>,
+    pub(crate) specular_color: Option<(f32, f32, f32)>,
+    pub(crate) clearcoat: Option<f32>,
+    pub(crate) clearcoat_roughness: Option<f32>,
+    pub(crate) sheen_color: Option<(f32, f32, f32)>,
+    pub(crate) sheen_roughness: Option<f32>,
+    pub(crate) anisotropy: Option<f32>,
+    pub(crate) anisotropy_rotation: Option<f32>, //< Degrees, counter-clockwise from the tangent.
+    pub(crate) iridescence: Option<f32>,
+    pub(crate) iridescence_ior: Option<f32>,
+    pub(crate) iridescence_thickness: Option<f32>, //< Nanometers.
+    pub(crate) double_sided: Option<bool>,
+}
+
+impl MaterialDescription {
+    fn merge(&mut self, other: &MaterialDescription) {
+        self.base_color = other.base_color.or(self.base_color);
+        self.base_color_texture = other
+            .base_color_texture
+            .clone()
+            .or_else(|| self.base_color_texture.take());
+        self.metalness = other.metalness.or(self.metalness);
+        self.roughness = other.roughness.or(self.roughness);
+        self.metallic_roughness_texture = other
+            .metallic_roughness_texture
+            .clone()
+            .or_else(|| self.metallic_roughness_texture.take());
+        self.emissive = other.emissive.or(self.emissive);
+        self.emissive_texture = other
+            .emissive_texture
+            .clone()
+            .or_else(|| self.emissive_texture.take());
+        self.normal_texture = other
+            .normal_texture
+            .clone()
+            .or_else(|| self.normal_texture.take());
+        self.ior = other.ior.or(self.ior);
+        self.dispersion = other.dispersion.or(self.dispersion);
+        self.conductor = other.conductor.clone().or_else(|| self.conductor.take());
+        self.transmission = other.transmission.or(self.transmission);
+        self.thickness = other.thickness.or(self.thickness);
+        self.attenuation_color = other.attenuation_color.or(self.attenuation_color);
+        self.attenuation_distance = other.attenuation_distance.or(self.attenuation_distance);
+        self.scattering = other.scattering.or(self.scattering);
+        self.scattering_asymmetry = other.scattering_asymmetry.or(self.scattering_asymmetry);
+        self.subsurface = other.subsurface.or(self.subsurface);
+        self.subsurface_color = other.subsurface_color.or(self.subsurface_color);
+        self.subsurface_color_texture = other
+            .subsurface_color_texture
+            .clone()
+            .or_else(|| self.subsurface_color_texture.take());
+        self.subsurface_radius = other.subsurface_radius.or(self.subsurface_radius);
+        self.subsurface_asymmetry = other.subsurface_asymmetry.or(self.subsurface_asymmetry);
+        self.specular = other.specular.or(self.specular);
+        self.specular_color = other.specular_color.or(self.specular_color);
+        self.clearcoat = other.clearcoat.or(self.clearcoat);
+        self.clearcoat_roughness = other.clearcoat_roughness.or(self.clearcoat_roughness);
+        self.sheen_color = other.sheen_color.or(self.sheen_color);
+        self.sheen_roughness = other.sheen_roughness.or(self.sheen_roughness);
+        self.anisotropy = other.anisotropy.or(self.anisotropy);
+        self.anisotropy_rotation = other.anisotropy_rotation.or(self.anisotropy_rotation);
+        self.iridescence = other.iridescence.or(self.iridescence);
+        self.iridescence_ior = other.iridescence_ior.or(self.iridescence_ior);
+        self.iridescence_thickness = other.iridescence_thickness.or(self.iridescence_thickness);
+        self.double_sided = other.double_sided.or(self.double_sided);
+    }
+
+    /// Returns paths of textures with color spaces of their roles.
+    pub(crate) fn textures(&self) -> impl Iterator<Item = (&String, ColorSpace)> {
+        [
+            (&self.base_color_texture, ColorSpace::Srgb),
+            (&self.metallic_roughness_texture, ColorSpace::Linear),
+            (&self.emissive_texture, ColorSpace::Srgb),
+            (&self.normal_texture, ColorSpace::Linear),
+            (&self.subsurface_color_texture, ColorSpace::Srgb),
+        ]
+        .into_iter()
+        .filter_map(|(path, color_space)| path.as_ref().map(|path| (path, color_space)))
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        for texture in [
+            &mut self.base_color_texture,
+            &mut self.metallic_roughness_texture,
+            &mut self.emissive_texture,
+            &mut self.normal_texture,
+            &mut self.subsurface_color_texture,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *texture = base.join(&*texture).to_string_lossy().to_string();
+        }
+    }
+
+    pub fn apply(&self, material: &mut Material, textures: &SceneTextures) {
+        let texture = |path: &Option<String>, color_space| {
+            path.as_ref()
+                .and_then(|path| textures.get(&(path.clone(), color_space)))
+                .map(|texture| TextureSampler {
+                    texture: texture.clone(),
+                    sampler: Sampler {
+                        filtering: Filtering::Linear,
+                        mip_filtering: MipFiltering::Ewa,
+                        wrap_s: WrapMode::Repeat,
+                        wrap_t: WrapMode::Repeat,
+                    },
+                    tex_coord: 0,
+                    transform: TextureTransform::default(),
+                })
+        };
+
+        if let Some(c) = self.base_color {
+            material.albedo_factor = Vector3::new(c.0, c.1, c.2);
+        }
+        if let Some(sampler) = texture(&self.base_color_texture, ColorSpace::Srgb) {
+            material.albedo_texture = Some(sampler);
+        }
+        if let Some(metalness) = self.metalness {
+            material.metalic = metalness;
+        }
+        if let Some(roughness) = self.roughness {
+            material.roughness = roughness;
+        }
+        if let Some(sampler) = texture(&self.metallic_roughness_texture, ColorSpace::Linear) {
+            material.metalic_roughness_texture = Some(sampler);
+        }
+        if let Some(c) = self.emissive {
+            material.emitted_factor = Vector3::new(c.0, c.1, c.2);
+        }
+        if let Some(sampler) = texture(&self.emissive_texture, ColorSpace::Srgb) {
+            material.emitted_texture = Some(sampler);
+        }
+        if let Some(sampler) = texture(&self.normal_texture, ColorSpace::Linear) {
+            material.normal_texture = Some(sampler);
+        }
+        if let Some(ior) = self.ior {
+            material.ior = ior;
+        }
+        if let Some(dispersion) = self.dispersion {
+            material.dispersion = dispersion;
+        }
+        if let Some(conductor) = &self.conductor {
+            material.conductor = conductor.to_complex_ior();
+        }
+        if let Some(transmission) = self.transmission {
+            material.transmission = transmission;
+        }
+        if let Some(thickness) = self.thickness {
+            material.thickness = thickness;
+        }
+        if let Some(c) = self.attenuation_color {
+            material.attenuation_color = Vector3::new(c.0, c.1, c.2);
+        }
+        if let Some(distance) = self.attenuation_distance {
+            material.attenuation_distance = distance;
+        }
+        if let Some(c) = self.scattering {
+            material.scattering.coefficient = Vector3::new(c.0, c.1, c.2);
+        }
+        if let Some(asymmetry) = self.scattering_asymmetry {
+            material.scattering.asymmetry = asymmetry;
+        }
+        if let Some(subsurface) = self.subsurface {
+            material.subsurface.factor = subsurface;
+        }
+        if let Some(c) = self.subsurface_color {
+            material.subsurface.color = Vector3::new(c.0, c.1, c.2);
+        }
+        if let Some(sampler) = texture(&self.subsurface_color_texture, ColorSpace::Srgb) {
+            material.subsurface.color_texture = Some(sampler);
+        }
+        if let Some(r) = self.subsurface_radius {
+            material.subsurface.mean_free_path = Vector3::new(r.0, r.1, r.2);
+        }
+        if let Some(asymmetry) = self.subsurface_asymmetry {
+            material.subsurface.asymmetry = asymmetry;
+        }
+        if let Some(specular) = self.specular {
+            material.specular.factor = specular;
+        }
+        if let Some(c) = self.specular_color {
+            material.specular.color = Vector3::new(c.0, c.1, c.2);
+        }
+        if let Some(clearcoat) = self.clearcoat {
+            material.clearcoat.factor = clearcoat;
+        }
+        if let Some(roughness) = self.clearcoat_roughness {
+            material.clearcoat.roughness = roughness;
+        }
+        if let Some(c) = self.sheen_color {
+            material.sheen.color = Vector3::new(c.0, c.1, c.2);
+        }
+        if let Some(roughness) = self.sheen_roughness {
+            material.sheen.roughness = roughness;
+        }
+        if let Some(anisotropy) = self.anisotropy {
+            material.anisotropy.strength = anisotropy;
+        }
+        if let Some(rotation) = self.anisotropy_rotation {
+            material.anisotropy.rotation = rotation.to_radians();
+        }
+        if let Some(iridescence) = self.iridescence {
+            material.iridescence.factor = iridescence;
+        }
+        if let Some(ior) = self.iridescence_ior {
+            material.iridescence.ior = ior;
+        }
+        if let Some(thickness) = self.iridescence_thickness {
+            material.iridescence.thickness_maximum = thickness;
+            material.iridescence.thickness_texture = None;
+        }
+        if let Some(double_sided) = self.double_sided {
+            material.single_sided = !double_sided;
+        }
+    }
+}
+
+/// Conductor given by name of a measured metal or by samples of wavelength in nanometers, real
+/// and imaginary part of its IOR.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ConductorDescription {
+    Named(String),
+    Measured(Vec<(f32, f32, f32)>),
+}
+
+impl ConductorDescription {
+    fn to_complex_ior(&self) -> Option<ComplexIor> {
+        match self {
+            ConductorDescription::Named(name) => ComplexIor::named(name),
+            ConductorDescription::Measured(samples) if samples.is_empty() => None,
+            ConductorDescription::Measured(samples) => {
+                let mut samples = samples.clone();
+                samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+                Some(ComplexIor { samples })
+            }
+        }
+    }
+}
+
+/// Material given either by name of a scene material or inline.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum MaterialReference {
+    Named(String),
+    Inline(Box<MaterialDescription>),
+}
+
+impl MaterialReference {
+    /// Named material replaces current one, inline definition is merged into current definition,
+    /// a named one is resolved against given materials first.
+    fn merge(
+        &mut self,
+        other: &MaterialReference,
+        materials: &Option<HashMap<String, MaterialDescription>>,
+    ) {
+        match (&mut *self, other) {
+            (MaterialReference::Inline(current), MaterialReference::Inline(other)) => {
+                current.merge(other)
+            }
+            (MaterialReference::Named(name), MaterialReference::Inline(other)) => {
+                // Undefined names are reported elsewhere, the reference is kept for that.
+                if let Some(named) = materials.as_ref().and_then(|materials| materials.get(name)) {
+                    let mut material = named.clone();
+                    material.merge(other);
+                    *self = MaterialReference::Inline(Box::new(material));
+                }
+            }
+            (current, other) => *current = other.clone(),
+        }
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        if let MaterialReference::Inline(material) = self {
+            material.resolve_paths(base);
+        }
+    }
+
+    pub(crate) fn name(&self) -> Option<&str> {
+        match self {
+            MaterialReference::Named(name) => Some(name),
+            MaterialReference::Inline(_) => None,
+        }
+    }
+}
+
+/// Materials assigned to single mesh, resolved against scene materials.
+pub struct MeshMaterials<'a> {
+    mesh: Option<&'a MaterialDescription>,
+    by_name: HashMap<&'a str, &'a MaterialDescription>,
+    textures: &'a SceneTextures,
+}
+
+impl<'a> MeshMaterials<'a> {
+    /// Returns true if the whole mesh is assigned a material.
+    pub fn has_mesh_material(&self) -> bool {
+        self.mesh.is_some()
+    }
+
+    /// Applies mesh material and material assigned to glTF material of given name.
+    pub fn apply(&self, gltf_name: Option<&str>, material: &mut Material) {
+        if let Some(description) = self.mesh {
+            description.apply(material, self.textures);
+        }
+
+        if let Some(description) = gltf_name.and_then(|name| self.by_name.get(name)) {
+            description.apply(material, self.textures);
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct MeshDescription {
-    name: String,
-    path: String,
-    transformation: Option<TransformationDescription>,
+    pub(crate) name: String,
+    pub(crate) path: String,
+    pub(crate) transformation: Option<TransformationDescription>,
+    pub(crate) material: Option<MaterialReference>,
+    pub(crate) gltf_materials: Option<HashMap<String, MaterialReference>>,
 }
 
 impl MeshDescription {
@@ -54,42 +415,245 @@ impl MeshDescription {
     pub fn path(&self) -> &str {
         &self.path
     }
+
+    /// All material references of this mesh.
+    pub(crate) fn material_references(&self) -> impl Iterator<Item = &MaterialReference> {
+        self.material.iter().chain(
+            self.gltf_materials
+                .iter()
+                .flat_map(|materials| materials.values()),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum ShapeDescription {
+    Sphere { radius: f32 },
+    Quad { width: f32, depth: f32 },
+    Disk { radius: f32 },
+    Cylinder { radius: f32, height: f32 },
+    Plane,
+}
+
+impl ShapeDescription {
+    pub fn to_shape(&self) -> Shape {
+        match *self {
+            ShapeDescription::Sphere { radius } => Shape::Sphere { radius },
+            ShapeDescription::Quad { width, depth } => Shape::Quad {
+                half_width: width / 2.,
+                half_depth: depth / 2.,
+            },
+            ShapeDescription::Disk { radius } => Shape::Disk { radius },
+            ShapeDescription::Cylinder { radius, height } => Shape::Cylinder {
+                radius,
+                half_height: height / 2.,
+            },
+            ShapeDescription::Plane => Shape::Plane,
+        }
+    }
+}
+
+/// Analytic shape placed in the scene, flat shapes face +Y before transformation.
+#[derive(Serialize, Deserialize)]
+pub struct PrimitiveDescription {
+    pub(crate) name: Option<String>,
+    pub(crate) shape: ShapeDescription,
+    pub(crate) transformation: Option<TransformationDescription>,
+    pub(crate) material: Option<MaterialReference>,
+}
+
+impl PrimitiveDescription {
+    pub fn transformation(&self) -> cgmath::Matrix4<f32> {
+        self.transformation
+            .as_ref()
+            .map_or(cgmath::Matrix4::one(), |t| t.to_matrix())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
-struct DirLightDescription {
-    dir: (f32, f32, f32),
-    color: (f32, f32, f32),
-    intensity: f32,
+pub(crate) struct BoundsDescription {
+    pub(crate) min: (f32, f32, f32),
+    pub(crate) max: (f32, f32, f32),
+}
+
+/// Heterogeneous medium given by voxel grids, which fill the bounding box in local space.
+///
+/// Density of the grid multiplied by the scale is the extinction coefficient per unit of
+/// distance, resolution is required for raw grid files.
+#[derive(Serialize, Deserialize)]
+pub struct VolumeDescription {
+    pub(crate) name: Option<String>,
+    pub(crate) density: String,
+    pub(crate) resolution: Option<(u32, u32, u32)>,
+    pub(crate) bounds: BoundsDescription,
+    pub(crate) transformation: Option<TransformationDescription>,
+    pub(crate) density_scale: Option<f32>,
+    pub(crate) albedo: Option<(f32, f32, f32)>,
+    pub(crate) asymmetry: Option<f32>,
+    pub(crate) emission: Option<String>,
+    pub(crate) emission_color: Option<(f32, f32, f32)>,
+}
+
+impl VolumeDescription {
+    pub fn transformation(&self) -> cgmath::Matrix4<f32> {
+        self.transformation
+            .as_ref()
+            .map_or(cgmath::Matrix4::one(), |t| t.to_matrix())
+    }
+
+    pub fn to_volume(&self) -> Result<Volume, Error> {
+        let color = |c: (f32, f32, f32)| Vector3::new(c.0, c.1, c.2);
+
+        let density = import_volume::load_grid(Path::new(&self.density), self.resolution)?;
+        let emission = match &self.emission {
+            Some(path) => Some((
+                import_volume::load_grid(Path::new(path), self.resolution)?,
+                self.emission_color.map_or(Vector3::one(), color),
+            )),
+            None => None,
+        };
+
+        Ok(Volume::new(
+            density,
+            self.density_scale.unwrap_or(1.),
+            self.albedo.map_or(Vector3::one(), color),
+            self.asymmetry.unwrap_or(0.),
+            emission,
+            (color(self.bounds.min), color(self.bounds.max)),
+            self.transformation(),
+        ))
+    }
+}
+
+/// Luminous efficacy converting watts to lumens, as used for glTF lights.
+const LUMENS_PER_WATT: f32 = 683.;
+
+/// Unit of light intensity, glTF specifies candela for point lights and lux for directional ones.
+///
+/// Watts are converted by luminous efficacy of 683 lm/W, lumens and watts of point lights are
+/// spread evenly over the sphere.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub(crate) enum LightUnit {
+    Candela,
+    Lumens,
+    Lux,
+    Watts,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DirLightDescription {
+    pub(crate) name: Option<String>,
+    pub(crate) dir: (f32, f32, f32),
+    pub(crate) color: (f32, f32, f32),
+    pub(crate) intensity: f32,
+    /// Lux if missing, or watts per square meter.
+    pub(crate) unit: Option<LightUnit>,
 }
 
 impl DirLightDescription {
     fn to_light(&self) -> Light {
+        let intensity = match self.unit {
+            Some(LightUnit::Watts) => self.intensity * LUMENS_PER_WATT,
+            _ => self.intensity,
+        };
+
         Light::Directional(Directional {
-            dir: Vector3::new(self.dir.0, self.dir.1, self.dir.2),
+            dir: Vector3::new(self.dir.0, self.dir.1, self.dir.2).unit(),
             color: Vector3::new(self.color.0, self.color.1, self.color.2),
-            intensity: self.intensity,
+            intensity,
         })
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub(crate) enum FalloffDescription {
+    InverseSquare,
+    Range,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PointLightDescription {
+    pub(crate) name: Option<String>,
+    pub(crate) position: (f32, f32, f32),
+    pub(crate) color: (f32, f32, f32),
+    pub(crate) intensity: f32,
+    /// Candela if missing, lumens or watts.
+    pub(crate) unit: Option<LightUnit>,
+    /// Distance at which the light is cut off, required by range falloff.
+    pub(crate) range: Option<f32>,
+    /// Inverse square if missing, scenes before version 3 are migrated to range falloff.
+    pub(crate) falloff: Option<FalloffDescription>,
+    /// Path to an IES profile giving only the shape of the distribution of light, `intensity`
+    /// is emitted in its brightest direction. Candela multiplier and lumens of the file are
+    /// ignored.
+    pub(crate) profile: Option<String>,
+    /// Axis of the profile, direction of the cone or pointing down if missing. Horizontal
+    /// angles of the profile start towards the X axis.
+    pub(crate) profile_axis: Option<(f32, f32, f32)>,
+    /// Makes the light a spot light emitting into the cone.
+    pub(crate) cone: Option<ConeDescription>,
+}
+
+/// Cone of a spot light, angles from its direction are in degrees.
 #[derive(Serialize, Deserialize)]
-struct PointLightDescription {
-    position: (f32, f32, f32),
-    color: (f32, f32, f32),
-    intensity: f32,
-    range: f32,
+pub(crate) struct ConeDescription {
+    pub(crate) direction: (f32, f32, f32),
+    /// Intensity fades out from the inner angle, zero if missing, to the outer one.
+    pub(crate) inner_angle: Option<f32>,
+    pub(crate) outer_angle: f32,
+}
+
+impl ConeDescription {
+    fn to_cone(&self) -> Cone {
+        Cone::new(
+            Vector3::new(self.direction.0, self.direction.1, self.direction.2),
+            self.inner_angle.unwrap_or(0.).to_radians(),
+            self.outer_angle.to_radians(),
+        )
+    }
 }
 
 impl PointLightDescription {
-    fn to_light(&self) -> Light {
-        Light::Point(Point {
+    fn to_light(&self) -> Result<Light, Error> {
+        // Power of spot lights is spread over their cone.
+        let cone = self.cone.as_ref().map(ConeDescription::to_cone);
+        let solid_angle = cone.map_or(4. * PI, |cone| cone.solid_angle());
+        let intensity = match self.unit {
+            Some(LightUnit::Lumens) => self.intensity / solid_angle,
+            Some(LightUnit::Watts) => self.intensity * LUMENS_PER_WATT / solid_angle,
+            _ => self.intensity,
+        };
+        let range = self.range.unwrap_or(f32::INFINITY);
+        let falloff = match self.falloff {
+            Some(FalloffDescription::Range) => Falloff::Range,
+            _ => Falloff::InverseSquare,
+        };
+
+        let profile = match &self.profile {
+            Some(path) => {
+                let axis = match (self.profile_axis, cone) {
+                    (Some(axis), _) => Vector3::new(axis.0, axis.1, axis.2).unit(),
+                    (None, Some(cone)) => cone.axis,
+                    (None, None) => Vector3::new(0., -1., 0.),
+                };
+                Some((
+                    Arc::new(import_ies::load_profile(Path::new(path))?),
+                    TangentFrame::new(axis, Vector3::new(1., 0., 0.)),
+                ))
+            }
+            None => None,
+        };
+
+        Ok(Light::Point(Point {
             position: Vector3::new(self.position.0, self.position.1, self.position.2),
             color: Vector3::new(self.color.0, self.color.1, self.color.2),
-            intensity: self.intensity,
-            range: self.range,
-            range_squared: self.range * self.range,
-        })
+            intensity,
+            range,
+            range_squared: range * range,
+            falloff,
+            profile,
+            cone,
+        }))
     }
 }
 
@@ -99,30 +663,515 @@ pub enum EnvironmentDescription {
     Gradient((f32, f32, f32), (f32, f32, f32)),
 }
 
+/// Homogeneous medium filling the whole scene, coefficients are per unit of distance.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct FogDescription {
+    pub(crate) absorption: Option<(f32, f32, f32)>,
+    pub(crate) scattering: Option<(f32, f32, f32)>,
+    pub(crate) asymmetry: Option<f32>,
+}
+
+impl FogDescription {
+    fn to_medium(&self) -> Medium {
+        let color =
+            |c: Option<(f32, f32, f32)>| c.map_or(Vector3::zero(), |c| Vector3::new(c.0, c.1, c.2));
+
+        Medium {
+            absorption: color(self.absorption),
+            scattering: color(self.scattering),
+            asymmetry: self.asymmetry.unwrap_or(0.),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct IncludeDescription {
+    pub(crate) path: String,
+    pub(crate) variables: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Changes mesh, primitive or light declared (possibly in an included file) under given name.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct OverrideDescription {
+    pub(crate) name: String,
+    pub(crate) transformation: Option<TransformationDescription>,
+    pub(crate) material: Option<MaterialReference>,
+    pub(crate) color: Option<(f32, f32, f32)>,
+    pub(crate) intensity: Option<f32>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SceneDescription {
-    meshes: Vec<MeshDescription>,
-    dir_lights: Option<Vec<DirLightDescription>>,
-    point_lights: Option<Vec<PointLightDescription>>,
-    env: Option<EnvironmentDescription>,
+    pub(crate) version: Option<u32>,
+    pub(crate) include: Option<Vec<IncludeDescription>>,
+    pub(crate) overrides: Option<Vec<OverrideDescription>>,
+    pub(crate) materials: Option<HashMap<String, MaterialDescription>>,
+    #[serde(default)]
+    pub(crate) meshes: Vec<MeshDescription>,
+    #[serde(default)]
+    pub(crate) primitives: Vec<PrimitiveDescription>,
+    #[serde(default)]
+    pub(crate) volumes: Vec<VolumeDescription>,
+    pub(crate) dir_lights: Option<Vec<DirLightDescription>>,
+    pub(crate) point_lights: Option<Vec<PointLightDescription>>,
+    pub(crate) env: Option<EnvironmentDescription>,
+    pub(crate) fog: Option<FogDescription>,
+}
+
+/// Upgrades a raw scene description to the current schema version.
+///
+/// Files without a `version` key predate schema versioning and are treated as version 0. Mesh
+/// paths of scenes before version 2 are made absolute against `base`, the working directory for
+/// the loaded scene and the directory of the including scene for included files.
+pub fn migrate(
+    mut value: serde_json::Value,
+    base: &Path,
+) -> Result<(serde_json::Value, u32), Error> {
+    let version = match value.get("version") {
+        None => 0,
+        Some(version) => match version.as_u64().map(u32::try_from) {
+            Some(Ok(version)) => version,
+            Some(Err(_)) => {
+                return Err(Error::FormatError(format!(
+                    "scene version {} is out of range",
+                    version
+                )))
+            }
+            None => {
+                return Err(Error::FormatError(
+                    "'version' must be an integer".to_string(),
+                ))
+            }
+        },
+    };
+
+    if version > SCENE_VERSION {
+        return Err(Error::FormatError(format!(
+            "scene version {} is newer than supported version {}",
+            version, SCENE_VERSION
+        )));
+    }
+
+    // Version 0 -> 1: identical layout, only the version stamp is added.
+
+    // Version 1 -> 2: mesh paths were resolved against the working directory, now they are
+    // relative to the scene file. Make them absolute to keep pointing to the same files.
+    if version < 2 {
+        if let Some(meshes) = value.get_mut("meshes").and_then(|m| m.as_array_mut()) {
+            for mesh in meshes {
+                if let Some(path) = mesh.get_mut("path") {
+                    if let Some(relative) = path.as_str() {
+                        *path = serde_json::Value::from(base.join(relative).to_string_lossy());
+                    }
+                }
+            }
+        }
+    }
+
+    // Version 2 -> 3: point lights fall off with squared distance, lights of older scenes keep
+    // their constant intensity fading out towards the range.
+    if version < 3 {
+        for light in value
+            .get_mut("point_lights")
+            .and_then(|lights| lights.as_array_mut())
+            .into_iter()
+            .flatten()
+        {
+            if let Some(light) = light.as_object_mut() {
+                light.insert("falloff".to_string(), serde_json::Value::from("Range"));
+            }
+        }
+    }
+
+    if let Some(object) = value.as_object_mut() {
+        object.insert(
+            "version".to_string(),
+            serde_json::Value::from(SCENE_VERSION),
+        );
+    }
+
+    Ok((value, version))
 }
 
 impl SceneDescription {
+    /// Makes all file paths of this description relative to `base` directory.
+    pub(crate) fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &mut String| *path = base.join(&*path).to_string_lossy().to_string();
+
+        for include in self.include.iter_mut().flatten() {
+            resolve(&mut include.path);
+        }
+        for material in self.materials.iter_mut().flat_map(|m| m.values_mut()) {
+            material.resolve_paths(base);
+        }
+        for mesh in &mut self.meshes {
+            resolve(&mut mesh.path);
+
+            for material in mesh.material.iter_mut().chain(
+                mesh.gltf_materials
+                    .iter_mut()
+                    .flat_map(|materials| materials.values_mut()),
+            ) {
+                material.resolve_paths(base);
+            }
+        }
+        for volume in &mut self.volumes {
+            resolve(&mut volume.density);
+            if let Some(emission) = &mut volume.emission {
+                resolve(emission);
+            }
+        }
+        for material in self
+            .primitives
+            .iter_mut()
+            .filter_map(|primitive| primitive.material.as_mut())
+        {
+            material.resolve_paths(base);
+        }
+        for item in self.overrides.iter_mut().flatten() {
+            if let Some(material) = &mut item.material {
+                material.resolve_paths(base);
+            }
+        }
+        for light in self.point_lights.iter_mut().flatten() {
+            if let Some(profile) = &mut light.profile {
+                resolve(profile);
+            }
+        }
+    }
+
+    /// Returns name of the first of given material references which is not defined.
+    fn undefined_material<'a>(&self, references: &[&'a MaterialReference]) -> Option<&'a str> {
+        references
+            .iter()
+            .filter_map(|reference| reference.name())
+            .find(|name| match &self.materials {
+                Some(materials) => !materials.contains_key(*name),
+                None => true,
+            })
+    }
+
+    /// Loads all textures referenced by material definitions.
+    pub fn load_textures(&self) -> Result<SceneTextures, Error> {
+        let mut textures = HashMap::new();
+
+        let inline = self
+            .meshes
+            .iter()
+            .flat_map(|mesh| mesh.material_references())
+            .chain(self.primitives.iter().filter_map(|p| p.material.as_ref()))
+            .filter_map(|reference| match reference {
+                MaterialReference::Inline(material) => Some(material.as_ref()),
+                MaterialReference::Named(_) => None,
+            });
+
+        for material in self
+            .materials
+            .iter()
+            .flat_map(|materials| materials.values())
+            .chain(inline)
+        {
+            for (path, color_space) in material.textures() {
+                if let Entry::Vacant(entry) = textures.entry((path.clone(), color_space)) {
+                    let texture = import_image::load_texture(Path::new(path), color_space)?;
+                    entry.insert(Arc::new(texture));
+                }
+            }
+        }
+
+        Ok(textures)
+    }
+
+    fn resolve_material<'a>(
+        &'a self,
+        reference: &'a MaterialReference,
+    ) -> Result<&'a MaterialDescription, Error> {
+        match reference {
+            MaterialReference::Inline(material) => Ok(material),
+            MaterialReference::Named(name) => self
+                .materials
+                .as_ref()
+                .and_then(|materials| materials.get(name))
+                .ok_or_else(|| Error::FormatError(format!("undefined material '{}'", name))),
+        }
+    }
+
+    /// Resolves materials assigned to given mesh.
+    pub fn mesh_materials<'a>(
+        &'a self,
+        mesh: &'a MeshDescription,
+        textures: &'a SceneTextures,
+    ) -> Result<MeshMaterials<'a>, Error> {
+        let resolve = |reference| self.resolve_material(reference);
+
+        let mut by_name = HashMap::new();
+        for (name, reference) in mesh.gltf_materials.iter().flatten() {
+            by_name.insert(name.as_str(), resolve(reference)?);
+        }
+
+        Ok(MeshMaterials {
+            mesh: mesh.material.as_ref().map(resolve).transpose()?,
+            by_name,
+            textures,
+        })
+    }
+
+    /// Creates material of given primitive, unset properties default to white double sided dielectric.
+    pub fn primitive_material(
+        &self,
+        primitive: &PrimitiveDescription,
+        textures: &SceneTextures,
+    ) -> Result<Material, Error> {
+        let mut material = Material::default();
+
+        if let Some(reference) = &primitive.material {
+            self.resolve_material(reference)?
+                .apply(&mut material, textures);
+        }
+        material.brdf = import_gltf::create_brdf(&material);
+
+        Ok(material)
+    }
+
+    /// Adds meshes, primitives and lights of included description in front of our own.
+    fn merge_included(&mut self, mut included: SceneDescription) {
+        included.meshes.append(&mut self.meshes);
+        self.meshes = included.meshes;
+
+        included.primitives.append(&mut self.primitives);
+        self.primitives = included.primitives;
+
+        included.volumes.append(&mut self.volumes);
+        self.volumes = included.volumes;
+
+        let mut dir_lights = included.dir_lights.unwrap_or_default();
+        dir_lights.append(&mut self.dir_lights.take().unwrap_or_default());
+        self.dir_lights = Some(dir_lights);
+
+        let mut point_lights = included.point_lights.unwrap_or_default();
+        point_lights.append(&mut self.point_lights.take().unwrap_or_default());
+        self.point_lights = Some(point_lights);
+
+        if self.env.is_none() {
+            self.env = included.env;
+        }
+        if self.fog.is_none() {
+            self.fog = included.fog;
+        }
+
+        if let Some(materials) = included.materials {
+            let own = self.materials.get_or_insert_with(HashMap::new);
+            for (name, material) in materials {
+                own.entry(name).or_insert(material);
+            }
+        }
+    }
+
+    /// Applies override to all meshes, primitives, volumes and lights of given name, returns false if none matched.
+    fn apply_override(&mut self, item: &OverrideDescription) -> bool {
+        let mut matched = false;
+
+        for mesh in self.meshes.iter_mut().filter(|mesh| mesh.name == item.name) {
+            if let Some(transformation) = &item.transformation {
+                mesh.transformation = Some(transformation.clone());
+            }
+            if let Some(material) = &item.material {
+                match &mut mesh.material {
+                    Some(current) => current.merge(material, &self.materials),
+                    None => mesh.material = Some(material.clone()),
+                }
+            }
+            matched = true;
+        }
+
+        for primitive in &mut self.primitives {
+            if primitive.name.as_deref() == Some(&item.name) {
+                if let Some(transformation) = &item.transformation {
+                    primitive.transformation = Some(transformation.clone());
+                }
+                if let Some(material) = &item.material {
+                    match &mut primitive.material {
+                        Some(current) => current.merge(material, &self.materials),
+                        None => primitive.material = Some(material.clone()),
+                    }
+                }
+                matched = true;
+            }
+        }
+
+        for volume in &mut self.volumes {
+            if volume.name.as_deref() == Some(&item.name) {
+                if let Some(transformation) = &item.transformation {
+                    volume.transformation = Some(transformation.clone());
+                }
+                matched = true;
+            }
+        }
+
+        for light in self.dir_lights.iter_mut().flatten() {
+            if light.name.as_deref() == Some(&item.name) {
+                light.color = item.color.unwrap_or(light.color);
+                light.intensity = item.intensity.unwrap_or(light.intensity);
+                matched = true;
+            }
+        }
+
+        for light in self.point_lights.iter_mut().flatten() {
+            if light.name.as_deref() == Some(&item.name) {
+                light.color = item.color.unwrap_or(light.color);
+                light.intensity = item.intensity.unwrap_or(light.intensity);
+                matched = true;
+            }
+        }
+
+        matched
+    }
+
+    fn load_composed(
+        filename: &Path,
+        base: &Path,
+        variables: &HashMap<String, serde_json::Value>,
+        stack: &mut Vec<PathBuf>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Result<Option<Self>, Error> {
+        let (document, description) =
+            validation::parse_document(filename, base, variables, diagnostics)?;
+        let mut description = match description {
+            Some(description) => description,
+            None => return Ok(None),
+        };
+
+        let canonical = filename.canonicalize()?;
+        let directory = canonical.parent().unwrap_or(&canonical).to_owned();
+        stack.push(canonical);
+        let own_meshes = description.meshes.len();
+        let own_primitives = description.primitives.len();
+
+        let includes = description.include.take().unwrap_or_default();
+        for (i, include) in includes.into_iter().enumerate() {
+            let path = Path::new(&include.path);
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+
+            if stack.contains(&canonical) {
+                document.report(diagnostics).error(
+                    &format!("include[{}].path", i),
+                    format!("'{}' is included recursively", include.path),
+                );
+                continue;
+            }
+
+            let given = include.variables.unwrap_or_default().into_iter().collect();
+            match Self::load_composed(path, &directory, &given, stack, diagnostics) {
+                Ok(Some(included)) => description.merge_included(included),
+                Ok(None) => {}
+                Err(err) => document.report(diagnostics).error(
+                    &format!("include[{}].path", i),
+                    format!("failed to read '{}': {:?}", include.path, err),
+                ),
+            }
+        }
+
+        stack.pop();
+
+        // Material names can refer to materials of this file and of all included files.
+        let first_own = description.meshes.len() - own_meshes;
+        for (i, mesh) in description.meshes[first_own..].iter().enumerate() {
+            let references: Vec<_> = mesh.material_references().collect();
+            if let Some(name) = description.undefined_material(&references) {
+                document.report(diagnostics).error(
+                    &format!("meshes[{}]", i),
+                    format!("undefined material '{}'", name),
+                );
+            }
+        }
+
+        let first_own = description.primitives.len() - own_primitives;
+        for (i, primitive) in description.primitives[first_own..].iter().enumerate() {
+            let references: Vec<_> = primitive.material.iter().collect();
+            if let Some(name) = description.undefined_material(&references) {
+                document.report(diagnostics).error(
+                    &format!("primitives[{}].material", i),
+                    format!("undefined material '{}'", name),
+                );
+            }
+        }
+
+        for (i, item) in description.overrides.take().iter().flatten().enumerate() {
+            let references: Vec<_> = item.material.iter().collect();
+            if let Some(name) = description.undefined_material(&references) {
+                document.report(diagnostics).error(
+                    &format!("overrides[{}].material", i),
+                    format!("undefined material '{}'", name),
+                );
+            }
+
+            if !description.apply_override(item) {
+                document.report(diagnostics).warning(
+                    &format!("overrides[{}].name", i),
+                    format!("no mesh, primitive, volume or light named '{}'", item.name),
+                );
+            }
+        }
+
+        Ok(Some(description))
+    }
+
+    /// Loads scene description with all its includes, collecting diagnostics of all files.
+    pub fn load(filename: &Path) -> Result<(Option<Self>, Vec<Diagnostic>), Error> {
+        let mut diagnostics = vec![];
+        let description = Self::load_composed(
+            filename,
+            &std::env::current_dir()?,
+            &HashMap::new(),
+            &mut vec![],
+            &mut diagnostics,
+        )?;
+
+        let failed = diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == validation::Severity::Error);
+
+        match failed {
+            true => Ok((None, diagnostics)),
+            false => Ok((description, diagnostics)),
+        }
+    }
+
+    /// Loads and validates scene description, warnings are printed and errors are returned.
     pub fn from_file(filename: &Path) -> Result<Self, Error> {
-        let file = File::open(filename)?;
-        let description: SceneDescription = serde_json::from_reader(file)?;
-        Ok(description)
+        let (description, diagnostics) = Self::load(filename)?;
+
+        let mut errors = vec![];
+        for diagnostic in diagnostics {
+            match diagnostic.severity {
+                validation::Severity::Warning => println!("{}", diagnostic),
+                validation::Severity::Error => errors.push(diagnostic.to_string()),
+            }
+        }
+
+        match description {
+            Some(description) if errors.is_empty() => Ok(description),
+            _ => Err(Error::FormatError(errors.join("\n"))),
+        }
     }
 
     pub fn meshes(&self) -> &Vec<MeshDescription> {
         &self.meshes
     }
 
-    pub fn lights(self) -> Vec<Light> {
+    pub fn primitives(&self) -> &Vec<PrimitiveDescription> {
+        &self.primitives
+    }
+
+    pub fn volumes(&self) -> &Vec<VolumeDescription> {
+        &self.volumes
+    }
+
+    pub fn lights(self) -> Result<Vec<Light>, Error> {
         self.dir_lights
             .unwrap_or_default()
             .into_iter()
-            .map(|desc| desc.to_light())
+            .map(|desc| Ok(desc.to_light()))
             .chain(
                 self.point_lights
                     .unwrap_or_default()
@@ -144,4 +1193,72 @@ impl SceneDescription {
             },
         }
     }
+
+    /// Returns medium of the global fog, none if the scene is in vacuum.
+    pub fn fog(&self) -> Option<Medium> {
+        self.fog
+            .as_ref()
+            .map(|fog| fog.to_medium())
+            .filter(|medium| medium.extinction() != Vector3::zero())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_version() {
+        let migrate_version = |version: serde_json::Value| {
+            migrate(serde_json::json!({ "version": version }), Path::new(""))
+                .map(|(_, version)| version)
+        };
+
+        assert_eq!(migrate_version(serde_json::json!(1)).ok(), Some(1));
+        assert!(migrate_version(serde_json::json!(SCENE_VERSION + 1)).is_err());
+        assert!(migrate_version(serde_json::json!(u64::from(u32::MAX) + 2)).is_err());
+        assert!(migrate_version(serde_json::json!("2")).is_err());
+    }
+
+    #[test]
+    fn test_migrate_mesh_paths() {
+        let scene = serde_json::json!({ "meshes": [{ "name": "a", "path": "a.gltf" }] });
+        let (scene, _) = migrate(scene, Path::new("/scenes")).unwrap();
+        assert_eq!(
+            Path::new(scene["meshes"][0]["path"].as_str().unwrap()),
+            Path::new("/scenes/a.gltf")
+        );
+    }
+
+    #[test]
+    fn test_merge_material_reference() {
+        let materials = Some(HashMap::from([(
+            "gold".to_string(),
+            MaterialDescription {
+                metalness: Some(1.),
+                roughness: Some(0.2),
+                ..Default::default()
+            },
+        )]));
+
+        // Inline override of a named material keeps its other fields.
+        let mut reference = MaterialReference::Named("gold".to_string());
+        reference.merge(
+            &MaterialReference::Inline(Box::new(MaterialDescription {
+                roughness: Some(0.5),
+                ..Default::default()
+            })),
+            &materials,
+        );
+        match &reference {
+            MaterialReference::Inline(material) => {
+                assert_eq!(material.metalness, Some(1.));
+                assert_eq!(material.roughness, Some(0.5));
+            }
+            MaterialReference::Named(_) => panic!("named material is not resolved"),
+        }
+
+        reference.merge(&MaterialReference::Named("gold".to_string()), &materials);
+        assert_eq!(reference.name(), Some("gold"));
+    }
 }
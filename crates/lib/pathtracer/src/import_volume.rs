@@ -0,0 +1,162 @@
+use std::path::Path;
+
+use crate::volume::Grid;
+use crate::Error;
+
+/// Magic bytes of sparse grid files.
+const SPARSE_MAGIC: &[u8; 4] = b"SPGR";
+const SPARSE_VERSION: u32 = 1;
+
+/// Loads voxel grid from a raw or sparse file.
+///
+/// Raw files are dense arrays of little-endian `f32` or `u8` values (normalized to [0; 1]) with
+/// X changing fastest, their resolution has to be given. Sparse files start with `SPGR` magic,
+/// followed by little-endian `u32` version, resolution X, Y, Z, brick size and brick count. Each
+/// brick is given by `u32` coordinates of its first voxel and `f32` values of brick size cubed
+/// voxels, voxels outside of all bricks are empty.
+pub fn load_grid(filename: &Path, resolution: Option<(u32, u32, u32)>) -> Result<Grid, Error> {
+    println!("Loading volume {:?}...", filename);
+
+    let data = std::fs::read(filename)?;
+    match data.starts_with(SPARSE_MAGIC) {
+        true => parse_sparse(&data),
+        false => match resolution {
+            Some((x, y, z)) => parse_raw(&data, [x as usize, y as usize, z as usize]),
+            None => Err(Error::FormatError(format!(
+                "resolution of raw volume {:?} is not given",
+                filename
+            ))),
+        },
+    }
+}
+
+/// Returns number of voxels of the grid, rejecting empty and overflowing resolutions.
+fn voxel_count(resolution: [usize; 3]) -> Result<usize, Error> {
+    if resolution.contains(&0) {
+        return Err(Error::FormatError(format!(
+            "volume resolution {:?} must not be zero",
+            resolution
+        )));
+    }
+
+    resolution
+        .iter()
+        .try_fold(1usize, |count, &n| count.checked_mul(n))
+        .ok_or_else(|| {
+            Error::FormatError(format!("volume resolution {:?} is too large", resolution))
+        })
+}
+
+fn parse_raw(data: &[u8], resolution: [usize; 3]) -> Result<Grid, Error> {
+    let count = voxel_count(resolution)?;
+
+    let values = if Some(data.len()) == count.checked_mul(4) {
+        data.chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect()
+    } else if data.len() == count {
+        data.iter().map(|&value| value as f32 / 255.).collect()
+    } else {
+        return Err(Error::FormatError(format!(
+            "raw volume of {} bytes does not match resolution {:?}",
+            data.len(),
+            resolution
+        )));
+    };
+
+    Ok(Grid { resolution, values })
+}
+
+/// Reads little-endian 4 bytes at given offset and advances it.
+fn read_bytes(data: &[u8], offset: &mut usize) -> Result<[u8; 4], Error> {
+    let bytes = data
+        .get(*offset..*offset + 4)
+        .ok_or_else(|| Error::FormatError("sparse volume is truncated".to_string()))?;
+    *offset += 4;
+    Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn parse_sparse(data: &[u8]) -> Result<Grid, Error> {
+    let mut offset = SPARSE_MAGIC.len();
+    let read_u32 =
+        |offset: &mut usize| read_bytes(data, offset).map(|b| u32::from_le_bytes(b) as usize);
+
+    let version = read_u32(&mut offset)?;
+    if version != SPARSE_VERSION as usize {
+        return Err(Error::FormatError(format!(
+            "unsupported sparse volume version {}",
+            version
+        )));
+    }
+
+    let resolution = [
+        read_u32(&mut offset)?,
+        read_u32(&mut offset)?,
+        read_u32(&mut offset)?,
+    ];
+    let brick_size = read_u32(&mut offset)?;
+    let brick_count = read_u32(&mut offset)?;
+
+    let mut values = vec![0.; voxel_count(resolution)?];
+    for _ in 0..brick_count {
+        let origin = [
+            read_u32(&mut offset)?,
+            read_u32(&mut offset)?,
+            read_u32(&mut offset)?,
+        ];
+
+        for z in 0..brick_size {
+            for y in 0..brick_size {
+                for x in 0..brick_size {
+                    let value = f32::from_le_bytes(read_bytes(data, &mut offset)?);
+                    let (x, y, z) = (origin[0] + x, origin[1] + y, origin[2] + z);
+
+                    // Bricks on the far edges may overlap the grid.
+                    if x < resolution[0] && y < resolution[1] && z < resolution[2] {
+                        values[x + resolution[0] * (y + resolution[1] * z)] = value;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(Grid { resolution, values })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparse() {
+        let mut data = SPARSE_MAGIC.to_vec();
+        for value in [SPARSE_VERSION, 3, 2, 2, 2, 1, 2, 0, 0] {
+            data.extend(value.to_le_bytes());
+        }
+        for i in 0..8 {
+            data.extend((i as f32).to_le_bytes());
+        }
+
+        let grid = parse_sparse(&data).unwrap();
+        assert_eq!(grid.resolution, [3, 2, 2]);
+        assert_eq!(
+            grid.values,
+            vec![0., 0., 0., 0., 0., 2., 0., 0., 4., 0., 0., 6.]
+        );
+
+        assert!(parse_sparse(&data[..data.len() - 1]).is_err());
+        assert!(parse_raw(&[0; 11], [3, 2, 2]).is_err());
+        assert_eq!(
+            parse_raw(&[255; 12], [3, 2, 2]).unwrap().values,
+            vec![1.; 12]
+        );
+
+        // Empty and overflowing resolutions are rejected before allocating voxels.
+        assert!(parse_raw(&[], [0, 2, 2]).is_err());
+        let mut data = SPARSE_MAGIC.to_vec();
+        for value in [SPARSE_VERSION, u32::MAX, u32::MAX, u32::MAX, 1, 0] {
+            data.extend(value.to_le_bytes());
+        }
+        assert!(parse_sparse(&data).is_err());
+    }
+}
@@ -0,0 +1,614 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::light_sampling::AliasTable;
+use crate::math::*;
+use crate::random::*;
+use crate::ray::Ray;
+use crate::Error;
+
+// Apertures shaping the bokeh of cameras with depth of field, and lenses of several elements.
+// Source: https://pbr-book.org/3ed-2018/Camera_Models/Realistic_Cameras
+
+/// Shape of the opening of a lens, points of the aperture lie in the unit disk.
+#[derive(Clone, Default)]
+pub enum Aperture {
+    #[default]
+    Disk,
+    /// Regular polygon formed by diaphragm blades, rotated by given angle in degrees.
+    Polygon { blades: u32, rotation: f32 },
+    /// Opening given by transmission of an image.
+    Mask(Arc<ApertureMask>),
+}
+
+impl Aperture {
+    /// Returns corner of the polygon of given index.
+    fn corner(blades: u32, rotation: f32, index: u32) -> Vector3 {
+        let angle = rotation.to_radians() + TWO_PI * index as f32 / blades as f32;
+        Vector3::new(angle.cos(), angle.sin(), 0.)
+    }
+
+    /// Samples a point of the aperture, returns it with its density per unit area.
+    pub(crate) fn sample(&self, sampler: &dyn Sampler) -> (Vector3, f32) {
+        match self {
+            Aperture::Disk => (unit_disk(sampler), std::f32::consts::FRAC_1_PI),
+            Aperture::Polygon { blades, rotation } => {
+                // Triangles between the center and the sides have equal areas.
+                let blades = (*blades).max(3);
+                let index = ((sampler.next_float() * blades as f32) as u32).min(blades - 1);
+                let a = Self::corner(blades, *rotation, index);
+                let b = Self::corner(blades, *rotation, index + 1);
+
+                let u = sampler.next_float().sqrt();
+                let v = sampler.next_float();
+
+                (a * (u * (1. - v)) + b * (u * v), 1. / self.area())
+            }
+            Aperture::Mask(mask) => mask.sample(sampler),
+        }
+    }
+
+    /// Returns transmission of the aperture integrated over its area.
+    pub(crate) fn area(&self) -> f32 {
+        match self {
+            Aperture::Disk => std::f32::consts::PI,
+            Aperture::Polygon { blades, .. } => {
+                let blades = (*blades).max(3);
+                0.5 * blades as f32 * (TWO_PI / blades as f32).sin()
+            }
+            Aperture::Mask(mask) => mask.area(),
+        }
+    }
+
+    /// Returns fraction of light passing through the aperture at given point.
+    pub(crate) fn transmission(&self, x: f32, y: f32) -> f32 {
+        match self {
+            Aperture::Disk => match x * x + y * y <= 1. {
+                true => 1.,
+                false => 0.,
+            },
+            Aperture::Polygon { blades, rotation } => {
+                let blades = (*blades).max(3);
+                let inside = (0..blades).all(|index| {
+                    let a = Self::corner(blades, *rotation, index);
+                    let b = Self::corner(blades, *rotation, index + 1);
+                    (b.x - a.x) * (y - a.y) - (b.y - a.y) * (x - a.x) >= 0.
+                });
+
+                match inside {
+                    true => 1.,
+                    false => 0.,
+                }
+            }
+            Aperture::Mask(mask) => mask.transmission(x, y),
+        }
+    }
+}
+
+/// Image of transmission of the aperture, stretched over the square around the unit disk.
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    /// Transmission of pixels from the top row, the brightest pixel transmits all light.
+    values: Vec<f32>,
+    table: AliasTable,
+}
+
+impl ApertureMask {
+    /// Creates mask of given transmissions, none if no light passes through.
+    pub fn new(width: usize, height: usize, values: Vec<f32>) -> Option<Self> {
+        let max = values.iter().copied().fold(0., f32::max);
+        if values.len() != width * height || max <= 0. {
+            return None;
+        }
+
+        let values: Vec<f32> = values.iter().map(|value| value.max(0.) / max).collect();
+        let table = AliasTable::new(&values)?;
+
+        Some(Self {
+            width,
+            height,
+            values,
+            table,
+        })
+    }
+
+    /// Loads mask from luminance of an image file.
+    pub fn load(filename: &Path) -> Result<Self, Error> {
+        println!("Loading aperture mask {:?}...", filename);
+
+        let image = image::open(filename)?.to_luma32f();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+
+        Self::new(width, height, image.into_raw())
+            .ok_or_else(|| Error::FormatError(format!("{:?}: aperture mask is black", filename)))
+    }
+
+    fn area(&self) -> f32 {
+        self.values.iter().sum::<f32>() * 4. / (self.width * self.height) as f32
+    }
+
+    fn sample(&self, sampler: &dyn Sampler) -> (Vector3, f32) {
+        let (index, probability) = self.table.sample(sampler.next_float());
+        let (column, row) = (index % self.width, index / self.width);
+
+        let x = (column as f32 + sampler.next_float()) / self.width as f32;
+        let y = (row as f32 + sampler.next_float()) / self.height as f32;
+        let pixel_area = 4. / (self.width * self.height) as f32;
+
+        (
+            Vector3::new(2. * x - 1., 1. - 2. * y, 0.),
+            probability / pixel_area,
+        )
+    }
+
+    fn transmission(&self, x: f32, y: f32) -> f32 {
+        let column = ((x + 1.) / 2. * self.width as f32).floor();
+        let row = ((1. - y) / 2. * self.height as f32).floor();
+
+        match (0. ..self.width as f32).contains(&column) && (0. ..self.height as f32).contains(&row)
+        {
+            true => self.values[row as usize * self.width + column as usize],
+            false => 0.,
+        }
+    }
+}
+
+/// Spherical surface of a lens, listed from the front of the lens, in millimetres.
+#[derive(Clone, Copy, Debug)]
+pub struct LensElement {
+    /// Radius of curvature, positive if the center lies behind the surface, zero for the
+    /// aperture stop.
+    pub curvature_radius: f32,
+    /// Distance to the next surface along the axis.
+    pub thickness: f32,
+    /// Index of refraction behind the surface, zero or one for air.
+    pub ior: f32,
+    pub diameter: f32,
+}
+
+const fn element(curvature_radius: f32, thickness: f32, ior: f32, diameter: f32) -> LensElement {
+    LensElement {
+        curvature_radius,
+        thickness,
+        ior,
+        diameter,
+    }
+}
+
+impl LensElement {
+    /// Loads lens prescription given by rows of curvature radius, thickness, index of refraction
+    /// and diameter of the surfaces, lines starting with `#` are comments.
+    pub fn load(filename: &Path) -> Result<Vec<LensElement>, Error> {
+        println!("Loading lens {:?}...", filename);
+
+        let elements = std::fs::read_to_string(filename)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let values = line
+                    .split_whitespace()
+                    .map(str::parse::<f32>)
+                    .collect::<Result<Vec<_>, _>>();
+
+                match values.as_deref() {
+                    Ok([curvature_radius, thickness, ior, diameter]) => {
+                        Ok(element(*curvature_radius, *thickness, *ior, *diameter))
+                    }
+                    _ => Err(Error::FormatError(format!(
+                        "{:?}: invalid lens element '{}'",
+                        filename, line
+                    ))),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match elements.is_empty() {
+            true => Err(Error::FormatError(format!(
+                "{:?}: lens has no elements",
+                filename
+            ))),
+            false => Ok(elements),
+        }
+    }
+}
+
+/// Double Gauss lens of 50 mm focal length at f/2.
+// Source: US patent 2,673,491 by Tronnier, scaled from 100 mm.
+pub const DOUBLE_GAUSS: [LensElement; 11] = [
+    element(29.475, 3.76, 1.67, 25.2),
+    element(84.83, 0.12, 1., 25.2),
+    element(19.275, 4.025, 1.67, 23.),
+    element(40.77, 3.275, 1.699, 23.),
+    element(12.75, 5.705, 1., 18.),
+    element(0., 4.5, 0., 17.1),
+    element(-14.495, 1.18, 1.603, 17.),
+    element(40.77, 6.065, 1.658, 20.),
+    element(-20.385, 0.19, 1., 20.),
+    element(437.065, 3.22, 1.717, 20.),
+    element(-39.73, 0., 1., 20.),
+];
+
+/// Lens of the realistic camera, lengths are in millimetres unless noted otherwise.
+#[derive(Clone)]
+pub struct Lens {
+    pub elements: Vec<LensElement>,
+    /// Diameter of the opening of the aperture stop, limited by the diameter of the stop element.
+    pub aperture_diameter: f32,
+    pub aperture: Aperture,
+    /// Distance from the film to the plane in focus in metres.
+    pub focus_distance: f32,
+    /// Diagonal of the film, 43.27 mm for full frame.
+    pub film_diagonal: f32,
+}
+
+impl Default for Lens {
+    fn default() -> Self {
+        Self {
+            elements: DOUBLE_GAUSS.to_vec(),
+            aperture_diameter: 17.1,
+            aperture: Aperture::Disk,
+            focus_distance: 1.,
+            film_diagonal: 43.27,
+        }
+    }
+}
+
+/// Surface of the lens system in metres, with radius of its opening.
+#[derive(Clone, Copy)]
+struct Interface {
+    curvature_radius: f32,
+    thickness: f32,
+    ior: f32,
+    radius: f32,
+}
+
+impl Interface {
+    fn ior(&self) -> f32 {
+        match self.ior != 0. {
+            true => self.ior,
+            false => 1.,
+        }
+    }
+}
+
+/// Rectangle on the plane of the rear element, empty if no light passes through the lens.
+#[derive(Clone, Copy)]
+pub(crate) struct PupilBounds {
+    min: (f32, f32),
+    max: (f32, f32),
+}
+
+impl PupilBounds {
+    pub(crate) fn area(&self) -> f32 {
+        (self.max.0 - self.min.0).max(0.) * (self.max.1 - self.min.1).max(0.)
+    }
+
+    /// Returns point of the rectangle at given fractions of its width and height.
+    pub(crate) fn point(&self, u: f32, v: f32) -> (f32, f32) {
+        (
+            lerp_scalar(self.min.0, self.max.0, u),
+            lerp_scalar(self.min.1, self.max.1, v),
+        )
+    }
+}
+
+/// Number of points along each axis of the rear element tested for light passing the lens.
+const PUPIL_GRID: usize = 64;
+
+/// Lens elements along the Z axis with the film at the origin, the lens lies towards negative Z.
+///
+/// Rays are given in the space of the camera, which looks towards positive Z.
+pub(crate) struct LensSystem {
+    interfaces: Vec<Interface>,
+    stop: Aperture,
+}
+
+impl LensSystem {
+    /// Creates lens focused at given distance in metres, with the aperture stop of given
+    /// diameter in millimetres.
+    pub(crate) fn new(
+        elements: &[LensElement],
+        aperture_diameter: f32,
+        stop: Aperture,
+        focus_distance: f32,
+    ) -> Self {
+        let interfaces = elements
+            .iter()
+            .map(|element| {
+                let diameter = match element.curvature_radius == 0. {
+                    true => element.diameter.min(aperture_diameter),
+                    false => element.diameter,
+                };
+
+                Interface {
+                    curvature_radius: element.curvature_radius * 0.001,
+                    thickness: element.thickness * 0.001,
+                    ior: element.ior,
+                    radius: diameter * 0.001 / 2.,
+                }
+            })
+            .collect();
+
+        let mut system = Self { interfaces, stop };
+        if let Some(thickness) = system.focus_thickness(focus_distance) {
+            system.interfaces.last_mut().unwrap().thickness = thickness;
+        }
+        system
+    }
+
+    /// Distance between the film and the rear element.
+    pub(crate) fn rear_z(&self) -> f32 {
+        self.interfaces.last().map_or(0., |rear| rear.thickness)
+    }
+
+    fn front_z(&self) -> f32 {
+        self.interfaces
+            .iter()
+            .map(|interface| interface.thickness)
+            .sum()
+    }
+
+    pub(crate) fn rear_radius(&self) -> f32 {
+        self.interfaces.last().map_or(0., |rear| rear.radius)
+    }
+
+    /// Returns bounds of points of the rear element through which light from given number of
+    /// segments of the film along the X axis leaves the lens.
+    // Source: https://pbr-book.org/3ed-2018/Camera_Models/Realistic_Cameras#TheExitPupil
+    pub(crate) fn exit_pupil(&self, film_radius: f32, segments: usize) -> Vec<PupilBounds> {
+        let radius = self.rear_radius();
+        let cell = 2. * radius / PUPIL_GRID as f32;
+        let coordinate = |i: usize| -radius + (i as f32 + 0.5) * cell;
+
+        (0..segments)
+            .map(|segment| {
+                let mut bounds = PupilBounds {
+                    min: (f32::MAX, f32::MAX),
+                    max: (f32::MIN, f32::MIN),
+                };
+
+                // Points along the segment including its ends.
+                for step in 0..=3 {
+                    let offset = (segment as f32 + step as f32 / 3.) / segments as f32;
+                    let film = Vector3::new(film_radius * offset, 0., 0.);
+
+                    for (i, j) in (0..PUPIL_GRID).flat_map(|i| (0..PUPIL_GRID).map(move |j| (i, j)))
+                    {
+                        let (x, y) = (coordinate(i), coordinate(j));
+                        let rear = Vector3::new(x, y, self.rear_z());
+
+                        if self.trace_from_film(&Ray::new(film, rear - film)).is_some() {
+                            bounds.min = (bounds.min.0.min(x), bounds.min.1.min(y));
+                            bounds.max = (bounds.max.0.max(x), bounds.max.1.max(y));
+                        }
+                    }
+                }
+
+                // Light may pass between the tested points as well.
+                bounds.min = (bounds.min.0 - cell, bounds.min.1 - cell);
+                bounds.max = (bounds.max.0 + cell, bounds.max.1 + cell);
+                bounds
+            })
+            .collect()
+    }
+
+    /// Returns distance along the ray to a spherical surface with given center on the axis and
+    /// normal of the surface facing the ray.
+    fn intersect(radius: f32, center: f32, ray: &Ray) -> Option<(f32, Vector3)> {
+        let origin = ray.origin - Vector3::new(0., 0., center);
+        let a = ray.direction.squared_length();
+        let b = 2. * cgmath::dot(ray.direction, origin);
+        let c = origin.squared_length() - radius * radius;
+
+        let discriminant = b * b - 4. * a * c;
+        if discriminant < 0. {
+            return None;
+        }
+        let root = discriminant.sqrt();
+        let (t0, t1) = ((-b - root) / (2. * a), (-b + root) / (2. * a));
+
+        // Convex surfaces facing the ray are hit first, concave ones from the inside.
+        let t = match (ray.direction.z > 0.) ^ (radius < 0.) {
+            true => t0.min(t1),
+            false => t0.max(t1),
+        };
+        if t < 0. {
+            return None;
+        }
+
+        let normal = (origin + ray.direction * t).unit();
+        match cgmath::dot(normal, ray.direction) < 0. {
+            true => Some((t, normal)),
+            false => Some((t, -normal)),
+        }
+    }
+
+    /// Passes the ray through the interface at given position, `ior` is the ratio of indices
+    /// of refraction, returns transmission of the aperture stop.
+    fn pass(&self, interface: &Interface, z: f32, ior: f32, ray: &mut Ray) -> Option<f32> {
+        let stop = interface.curvature_radius == 0.;
+        let (t, normal) = match stop {
+            true => {
+                // Rays refracted by the previous surface may turn away from the stop.
+                let t = (z - ray.origin.z) / ray.direction.z;
+                if ray.direction.z == 0. || t < 0. {
+                    return None;
+                }
+                (t, Vector3::zero())
+            }
+            false => Self::intersect(
+                interface.curvature_radius,
+                z + interface.curvature_radius,
+                ray,
+            )?,
+        };
+
+        let hit = ray.point_at(t);
+        if hit.x * hit.x + hit.y * hit.y > interface.radius * interface.radius {
+            return None;
+        }
+
+        ray.origin = hit;
+        match stop {
+            true => {
+                let transmission = self
+                    .stop
+                    .transmission(hit.x / interface.radius, hit.y / interface.radius);
+                (transmission > 0.).then_some(transmission)
+            }
+            false => {
+                ray.direction = refract(&ray.direction, &normal, ior)?;
+                Some(1.)
+            }
+        }
+    }
+
+    /// Traces ray leaving the film through the lens, returns the ray leaving the front element
+    /// and transmission of the aperture stop, none if the ray is blocked.
+    pub(crate) fn trace_from_film(&self, ray: &Ray) -> Option<(Ray, f32)> {
+        let mut ray = flip(ray);
+        let mut z = 0.;
+        let mut transmission = 1.;
+
+        for (i, interface) in self.interfaces.iter().enumerate().rev() {
+            z -= interface.thickness;
+            let outside = match i {
+                0 => 1.,
+                _ => self.interfaces[i - 1].ior(),
+            };
+            transmission *= self.pass(interface, z, interface.ior() / outside, &mut ray)?;
+        }
+
+        Some((flip(&ray), transmission))
+    }
+
+    /// Traces ray from the scene through the lens towards the film.
+    fn trace_from_scene(&self, ray: &Ray) -> Option<Ray> {
+        let mut ray = flip(ray);
+        let mut z = -self.front_z();
+
+        for (i, interface) in self.interfaces.iter().enumerate() {
+            let outside = match i {
+                0 => 1.,
+                _ => self.interfaces[i - 1].ior(),
+            };
+            self.pass(interface, z, outside / interface.ior(), &mut ray)?;
+            z += interface.thickness;
+        }
+
+        Some(flip(&ray))
+    }
+
+    /// Returns positions of the principal plane and the focal point of the ray parallel to the
+    /// axis leaving the lens system.
+    fn cardinal_points(input: &Ray, output: &Ray) -> (f32, f32) {
+        let focal = -output.origin.x / output.direction.x;
+        let principal = (input.origin.x - output.origin.x) / output.direction.x;
+
+        (-output.point_at(principal).z, -output.point_at(focal).z)
+    }
+
+    /// Returns distance between the rear element and the film focusing the lens at given
+    /// distance, by approximating the system with a thick lens.
+    fn focus_thickness(&self, focus_distance: f32) -> Option<f32> {
+        let x = self.rear_radius() * 0.01;
+        let from_scene = Ray::new(
+            Vector3::new(x, 0., self.front_z() + 1.),
+            Vector3::new(0., 0., -1.),
+        );
+        let to_film = self.trace_from_scene(&from_scene)?;
+        let (principal_0, focal_0) = Self::cardinal_points(&from_scene, &to_film);
+
+        let from_film = Ray::new(
+            Vector3::new(x, 0., self.rear_z() - 1.),
+            Vector3::new(0., 0., 1.),
+        );
+        let to_scene = self.trace_from_film(&from_film)?.0;
+        let (principal_1, _) = Self::cardinal_points(&from_film, &to_scene);
+
+        let focal_length = focal_0 - principal_0;
+        let z = -focus_distance;
+        let c =
+            (principal_1 - z - principal_0) * (principal_1 - z - 4. * focal_length - principal_0);
+        let delta = 0.5 * (principal_1 - z + principal_0 - c.max(0.).sqrt());
+
+        Some(self.rear_z() + delta)
+    }
+}
+
+/// Converts between the space of the camera and of the lens, which looks towards negative Z.
+fn flip(ray: &Ray) -> Ray {
+    Ray::new(
+        Vector3::new(ray.origin.x, ray.origin.y, -ray.origin.z),
+        Vector3::new(ray.direction.x, ray.direction.y, -ray.direction.z),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aperture() {
+        let sampler = UniformSampler::new();
+        let apertures = [
+            Aperture::Disk,
+            Aperture::Polygon {
+                blades: 6,
+                rotation: 15.,
+            },
+            Aperture::Mask(Arc::new(
+                ApertureMask::new(2, 2, vec![1., 0., 0.5, 0.25]).unwrap(),
+            )),
+        ];
+
+        for aperture in apertures {
+            // Points are sampled inside the opening with density proportional to transmission.
+            for _ in 0..1000 {
+                let (point, pdf) = aperture.sample(&sampler);
+                let transmission = aperture.transmission(point.x, point.y);
+                assert!(transmission > 0.);
+                assert!(pdf > 0.);
+            }
+        }
+
+        let hexagon = Aperture::Polygon {
+            blades: 6,
+            rotation: 0.,
+        };
+        assert_eq!(hexagon.transmission(0.95, 0.), 1.);
+        assert_eq!(hexagon.transmission(0., 0.95), 0.);
+
+        let mask = ApertureMask::new(2, 2, vec![1., 0., 0.5, 0.25]).unwrap();
+        assert_eq!(mask.transmission(-0.5, 0.5), 1.);
+        assert_eq!(mask.transmission(0.5, -0.5), 0.25);
+        assert!(ApertureMask::new(1, 1, vec![0.]).is_none());
+    }
+
+    #[test]
+    fn test_lens_system() {
+        let focus_distance = 2.;
+        let system = LensSystem::new(&DOUBLE_GAUSS, 17.1, Aperture::Disk, focus_distance);
+
+        // Rays from the center of the film converge at the focus distance, up to spherical
+        // aberration of the lens.
+        for x in [-0.4, -0.1, 0.2, 0.5] {
+            let rear = Vector3::new(x * system.rear_radius(), 0., system.rear_z());
+            let (ray, _) = system
+                .trace_from_film(&Ray::new(Vector3::zero(), rear))
+                .unwrap();
+
+            let t = -ray.origin.x / ray.direction.x;
+            assert!((ray.point_at(t).z - focus_distance).abs() < 0.1 * focus_distance);
+        }
+
+        // Rays near the rim of the rear element are blocked by the aperture stop.
+        let rear = Vector3::new(system.rear_radius() * 0.99, 0., system.rear_z());
+        let narrow = LensSystem::new(&DOUBLE_GAUSS, 5., Aperture::Disk, focus_distance);
+        assert!(narrow
+            .trace_from_film(&Ray::new(Vector3::zero(), rear))
+            .is_none());
+    }
+}
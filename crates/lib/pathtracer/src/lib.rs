@@ -5,18 +5,34 @@ pub mod pathtracer;
 pub mod random;
 pub mod scene;
 pub mod threadpool;
+pub mod validation;
 
+mod bidirectional;
 mod brdf;
+mod brdf_dielectric;
 mod brdf_lambert;
 mod brdf_microfacet;
 mod consts;
 mod env;
+mod guiding;
 mod import_gltf;
+mod import_ies;
+mod import_image;
 mod import_scene;
+mod import_volume;
+mod lens;
 mod light;
+mod light_sampling;
+mod medium;
 mod mesh;
+mod metropolis;
 mod microfacet;
+mod photon_mapping;
+mod primitive;
 mod ray;
+mod spectrum;
+mod variables;
+mod volume;
 
 #[derive(Debug)]
 pub enum Error {
@@ -42,3 +58,9 @@ impl From<serde_json::Error> for Error {
         Error::FormatError(format!("{:?}", e))
     }
 }
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        Error::ImportError(format!("{:?}", e))
+    }
+}
@@ -1,4 +1,13 @@
-use crate::math::{smoothstep, EnhancedVector, Vector3};
+use cgmath::dot;
+
+use crate::math::{
+    clamp, saturate, smoothstep, EnhancedVector, TangentFrame, Vector3, ONE_OVER_PI, TWO_PI,
+};
+use crate::random::{unit_disk, unit_sphere, Sampler};
+use crate::ray::Ray;
+
+use std::f32::consts::PI;
+use std::sync::Arc;
 
 pub trait Attenuable {
     fn intensity_at(&self, position: &Vector3) -> Vector3;
@@ -16,28 +25,202 @@ impl Attenuable for Directional {
     }
 }
 
+/// Attenuation of point lights with distance.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Falloff {
+    /// Intensity falls off with squared distance and is smoothly windowed to zero at the range,
+    /// as glTF recommends.
+    InverseSquare,
+    /// Intensity stays constant and fades out towards the range, lights of scenes before
+    /// version 3 behave this way.
+    Range,
+}
+
+impl Falloff {
+    /// Returns part of the intensity of a point light of given range arriving to given distance.
+    pub fn attenuation(&self, range: f32, distance: f32) -> f32 {
+        match self {
+            Falloff::InverseSquare => window(range, distance) / (distance * distance),
+            Falloff::Range => range_falloff(range, distance),
+        }
+    }
+}
+
+/// Returns window of inverse square falloff reaching zero at given range.
+// Source: https://github.com/KhronosGroup/glTF/tree/main/extensions/2.0/Khronos/KHR_lights_punctual#range-property
+pub(crate) fn window(range: f32, distance: f32) -> f32 {
+    let ratio = distance / range;
+    let window = saturate(1. - ratio * ratio * ratio * ratio);
+    window * window
+}
+
+/// Returns part of the intensity of a point light of range falloff arriving to given distance.
+pub(crate) fn range_falloff(range: f32, distance: f32) -> f32 {
+    1.0 - smoothstep(range * 0.75, range, distance)
+}
+
+/// Photometric profile scaling intensity of a light in directions around its axis, intensities
+/// are given for vertical angles from the axis and horizontal angles around it.
+pub struct Profile {
+    /// Vertical and horizontal angles in degrees, ascending.
+    pub vertical: Vec<f32>,
+    pub horizontal: Vec<f32>,
+    /// Intensities relative to the brightest direction, vertical angles change fastest.
+    pub values: Vec<f32>,
+}
+
+impl Profile {
+    /// Returns relative intensity in given unit direction of the frame whose Z axis is the axis
+    /// of the profile. Horizontal angles up to 0, 90 or 180 degrees are mirrored around the axis.
+    pub fn intensity(&self, direction: &Vector3) -> f32 {
+        let vertical = clamp(direction.z, -1., 1.).acos().to_degrees();
+        let horizontal = direction.y.atan2(direction.x).to_degrees().rem_euclid(360.);
+        let horizontal = match self.horizontal.last() {
+            Some(last) if *last <= 0. => 0.,
+            Some(last) if *last <= 90. => 90. - (90. - horizontal.rem_euclid(180.)).abs(),
+            Some(last) if *last <= 180. => 180. - (180. - horizontal).abs(),
+            _ => horizontal,
+        };
+
+        // Neighbouring angles with the position between them, None outside of the angles.
+        let locate = |angles: &[f32], angle: f32| -> Option<(usize, usize, f32)> {
+            let (first, last) = (*angles.first()?, *angles.last()?);
+            if angle < first || angle > last {
+                return None;
+            }
+
+            let i = angles
+                .partition_point(|a| *a <= angle)
+                .clamp(1, angles.len())
+                - 1;
+            let j = (i + 1).min(angles.len() - 1);
+            let span = angles[j] - angles[i];
+            Some((
+                i,
+                j,
+                if span > 0. {
+                    (angle - angles[i]) / span
+                } else {
+                    0.
+                },
+            ))
+        };
+
+        let (v0, v1, tv) = match locate(&self.vertical, vertical) {
+            Some(position) => position,
+            None => return 0.,
+        };
+        // Horizontal angles of a full circle wrap around from the last one to the first one.
+        let (h0, h1, th) = locate(&self.horizontal, horizontal).unwrap_or_else(|| {
+            let (first, last) = (
+                self.horizontal[0],
+                self.horizontal[self.horizontal.len() - 1],
+            );
+            let span = 360. - last + first;
+            let t = (horizontal - last).rem_euclid(360.) / span;
+            (
+                self.horizontal.len() - 1,
+                0,
+                if span > 0. { t.min(1.) } else { 0. },
+            )
+        });
+
+        let rows = self.vertical.len();
+        let row =
+            |h: usize| self.values[h * rows + v0] * (1. - tv) + self.values[h * rows + v1] * tv;
+        row(h0) * (1. - th) + row(h1) * th
+    }
+}
+
+/// Cone of a spot light, intensity fades out from its inner angle to the outer one.
+#[derive(Clone, Copy)]
+pub struct Cone {
+    /// Unit direction the light is pointing to.
+    pub axis: Vector3,
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+}
+
+impl Cone {
+    /// Creates cone of given angles from the axis in radians.
+    pub fn new(axis: Vector3, inner_angle: f32, outer_angle: f32) -> Self {
+        Self {
+            axis: axis.unit(),
+            cos_inner: inner_angle.cos(),
+            cos_outer: outer_angle.cos(),
+        }
+    }
+
+    /// Returns part of the intensity emitted in given unit direction.
+    // Source: https://github.com/KhronosGroup/glTF/tree/main/extensions/2.0/Khronos/KHR_lights_punctual#inner-and-outer-cone-angles
+    pub fn attenuation(&self, direction: &Vector3) -> f32 {
+        let scale = 1. / (self.cos_inner - self.cos_outer).max(0.001);
+        let attenuation = saturate((dot(*direction, self.axis) - self.cos_outer) * scale);
+        attenuation * attenuation
+    }
+
+    /// Solid angle within the outer angle.
+    pub fn solid_angle(&self) -> f32 {
+        TWO_PI * (1. - self.cos_outer)
+    }
+
+    /// Samples direction uniformly within the outer angle.
+    pub fn sample(&self, sampler: &impl Sampler) -> Vector3 {
+        let cos_theta = 1. - sampler.next_float() * (1. - self.cos_outer);
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        let phi = TWO_PI * sampler.next_float();
+
+        TangentFrame::new(self.axis, Vector3::zero()).to_world(Vector3::new(
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        ))
+    }
+}
+
 pub struct Point {
     pub position: Vector3,
     pub color: Vector3,
+    /// Intensity in candela, or constant intensity of lights of range falloff.
     pub intensity: f32,
+    /// Infinite if the light is not cut off.
     pub range: f32,
     pub range_squared: f32,
+    pub falloff: Falloff,
+    /// Profile with the frame whose Z axis is its axis.
+    pub profile: Option<(Arc<Profile>, TangentFrame)>,
+    /// Cone of spot lights.
+    pub cone: Option<Cone>,
+}
+
+impl Point {
+    /// Returns part of the intensity emitted in given unit direction.
+    fn angular_attenuation(&self, direction: &Vector3) -> f32 {
+        let profile = match &self.profile {
+            Some((profile, frame)) => profile.intensity(&frame.to_local(*direction)),
+            None => 1.,
+        };
+        let cone = self.cone.map_or(1., |cone| cone.attenuation(direction));
+        profile * cone
+    }
+
+    /// Solid angle of directions the light emits to.
+    pub fn solid_angle(&self) -> f32 {
+        self.cone.map_or(4. * PI, |cone| cone.solid_angle())
+    }
 }
 
 impl Attenuable for Point {
     fn intensity_at(&self, position: &Vector3) -> Vector3 {
-        // TODO: Exp. fallof
-
-        // TODO: Distance
-
-        self.color
-            * self.intensity
-            * (1.0
-                - smoothstep(
-                    self.range * 0.75,
-                    self.range,
-                    (self.position - *position).length(),
-                ))
+        let offset = *position - self.position;
+        let distance = offset.length();
+        // Direction is undefined at the light itself.
+        let angular = match distance > 0. {
+            true => self.angular_attenuation(&(offset / distance)),
+            false => 1.,
+        };
+
+        self.color * (self.intensity * angular * self.falloff.attenuation(self.range, distance))
     }
 }
 
@@ -46,6 +229,16 @@ pub enum Light {
     Point(Point),
 }
 
+/// Ray leaving a light.
+pub struct Emission {
+    pub ray: Ray,
+    /// Density of the origin of the ray on the light.
+    pub pdf_position: f32,
+    /// Density of the first point along the ray per solid angle, or per area of the disk of
+    /// parallel rays of directional lights.
+    pub pdf: f32,
+}
+
 impl Light {
     pub fn direction_distance_from(&self, position: &Vector3) -> (Vector3, f32) {
         match self {
@@ -56,6 +249,55 @@ impl Light {
             }
         }
     }
+
+    /// Samples ray emitted by the light into a scene enclosed by given bounding sphere.
+    pub fn sample_emission(
+        &self,
+        (center, radius): (Vector3, f32),
+        sampler: &impl Sampler,
+    ) -> Option<Emission> {
+        match self {
+            Light::Point(point) => {
+                let direction = match point.cone {
+                    Some(cone) => cone.sample(sampler),
+                    None => unit_sphere(sampler).unit(),
+                };
+
+                Some(Emission {
+                    ray: Ray::new(point.position, direction),
+                    pdf_position: 1.,
+                    pdf: 1. / point.solid_angle(),
+                })
+            }
+            Light::Directional(directional) => {
+                // Parallel rays from a disk covering the scene.
+                if radius <= 0. {
+                    return None;
+                }
+
+                let frame = TangentFrame::new(directional.dir, Vector3::zero());
+                let disk = unit_disk(sampler) * radius;
+                let origin = center + frame.to_world(Vector3::new(disk.x, disk.y, radius));
+                let pdf_position = ONE_OVER_PI / (radius * radius);
+
+                Some(Emission {
+                    ray: Ray::new(origin, -directional.dir),
+                    pdf_position,
+                    pdf: pdf_position,
+                })
+            }
+        }
+    }
+
+    /// Returns light emitted from given origin on the light to the position, per solid angle for
+    /// point lights. Point lights of range falloff do not fall off with squared distance, so they
+    /// emit more towards farther positions.
+    pub fn emitted(&self, origin: &Vector3, position: &Vector3) -> Vector3 {
+        match self {
+            Light::Point(_) => self.intensity_at(position) * (*position - *origin).squared_length(),
+            Light::Directional(_) => self.intensity_at(position),
+        }
+    }
 }
 
 impl Attenuable for Light {
@@ -66,3 +308,50 @@ impl Attenuable for Light {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::UniformSampler;
+
+    #[test]
+    fn test_spot_light() {
+        let sampler = UniformSampler::new();
+        let profile = Profile {
+            vertical: vec![0., 180.],
+            horizontal: vec![0.],
+            values: vec![1., 1.],
+        };
+        let spot = Light::Point(Point {
+            position: Vector3::zero(),
+            color: Vector3::one(),
+            intensity: 2.,
+            range: 10.,
+            range_squared: 100.,
+            falloff: Falloff::Range,
+            profile: Some((
+                Arc::new(profile),
+                TangentFrame::new(Vector3::new(0., -1., 0.), Vector3::zero()),
+            )),
+            cone: Some(Cone::new(
+                Vector3::new(0., -1., 0.),
+                20f32.to_radians(),
+                40f32.to_radians(),
+            )),
+        });
+
+        // Intensity at the light itself has no direction.
+        assert_eq!(spot.intensity_at(&Vector3::zero()).x, 2.);
+        assert_eq!(spot.intensity_at(&Vector3::new(0.1, -1., 0.)).x, 2.);
+        assert_eq!(spot.intensity_at(&Vector3::new(1., -1., 0.)).x, 0.);
+
+        let cos_outer = 40f32.to_radians().cos();
+        for _ in 0..1000 {
+            let emission = spot
+                .sample_emission((Vector3::zero(), 1.), &sampler)
+                .unwrap();
+            assert!(-emission.ray.direction.y >= cos_outer - 1e-5);
+            assert!((emission.pdf * TWO_PI * (1. - cos_outer) - 1.).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use crate::light::{range_falloff, window, Falloff, Light};
+use crate::math::*;
+use crate::pathtracer::LightSelection;
+
+use cgmath::{dot, InnerSpace};
+
+use std::f32::consts::PI;
+
+// Selection of a single light sampled at a point when there are too many of them to sample all.
+// Source: https://pbr-book.org/4ed/Light_Sources/Light_Sampling
+// Lights are selected uniformly, in proportion to their power by an alias table, or by a tree
+// bounding intensity, falloff and reach of its point lights, which favours lights close to the point.
+// Every selection comes with its probability, lights which cannot light the point are never selected.
+
+/// Item of an alias table, the item is kept if a uniform number is below the threshold,
+/// otherwise its alias is taken.
+struct Bin {
+    probability: f32,
+    threshold: f32,
+    alias: usize,
+}
+
+/// Table selecting items in proportion to their weights in constant time.
+// Source: https://www.keithschwarz.com/darts-dice-coins/
+pub(crate) struct AliasTable {
+    bins: Vec<Bin>,
+}
+
+impl AliasTable {
+    /// Returns table of given weights, None if none of them is positive.
+    pub(crate) fn new(weights: &[f32]) -> Option<Self> {
+        let total: f64 = weights.iter().map(|w| w.max(0.) as f64).sum();
+        if total <= 0. || !total.is_finite() {
+            return None;
+        }
+
+        let count = weights.len() as f64;
+        let mut scaled: Vec<f64> = weights
+            .iter()
+            .map(|w| w.max(0.) as f64 * count / total)
+            .collect();
+        let mut bins: Vec<Bin> = scaled
+            .iter()
+            .enumerate()
+            .map(|(i, p)| Bin {
+                probability: (p / count) as f32,
+                threshold: 1.,
+                alias: i,
+            })
+            .collect();
+
+        // Bins under the average are filled up by parts of bins over it.
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) =
+            (0..weights.len()).partition(|i| scaled[*i] < 1.);
+        while let (Some(s), Some(&l)) = (small.pop(), large.last()) {
+            bins[s].threshold = scaled[s] as f32;
+            bins[s].alias = l;
+
+            scaled[l] -= 1. - scaled[s];
+            if scaled[l] < 1. {
+                large.pop();
+                small.push(l);
+            }
+        }
+
+        Some(Self { bins })
+    }
+
+    /// Returns item selected by a uniform number with its probability.
+    pub(crate) fn sample(&self, u: f32) -> (usize, f32) {
+        let scaled = u * self.bins.len() as f32;
+        let index = (scaled as usize).min(self.bins.len() - 1);
+        let bin = &self.bins[index];
+
+        let selected = match scaled - (index as f32) < bin.threshold {
+            true => index,
+            false => bin.alias,
+        };
+        (selected, self.bins[selected].probability)
+    }
+}
+
+/// Bounds of lights in a node of the light tree.
+#[derive(Clone, Copy)]
+struct LightBounds {
+    bounds: [Vector3; 2],
+    /// Sum of intensities and the longest range of lights falling off with squared distance,
+    /// positions farther from the bounds than the range are not lit.
+    inverse_square: (f32, f32),
+    /// Sum of intensities and the longest range of lights of range falloff.
+    constant: (f32, f32),
+}
+
+impl LightBounds {
+    fn union(&self, other: &LightBounds) -> LightBounds {
+        let add = |a: (f32, f32), b: (f32, f32)| (a.0 + b.0, a.1.max(b.1));
+
+        LightBounds {
+            bounds: [
+                self.bounds[0].min(other.bounds[0]),
+                self.bounds[1].max(other.bounds[1]),
+            ],
+            inverse_square: add(self.inverse_square, other.inverse_square),
+            constant: add(self.constant, other.constant),
+        }
+    }
+
+    /// Returns estimate of light arriving from the lights to given position, scaled by the
+    /// cosine to the normal of the side lit by lights if there is one.
+    ///
+    /// It is zero only if none of the lights can light the position. Lights may be arbitrarily
+    /// close to positions inside of the bounds, their distance is estimated by the bounding sphere.
+    fn importance(&self, position: &Vector3, normal: Option<Vector3>) -> f32 {
+        let [min, max] = self.bounds;
+        let closest = position.max(min).min(max);
+        let near = (closest - *position).magnitude();
+
+        let center = (min + max) / 2.;
+        let to_center = center - *position;
+        let (distance, radius) = (to_center.magnitude(), (max - center).magnitude());
+        let squared = distance.max(radius).max(MIN_DISTANCE).powi(2);
+
+        let (power, range) = self.inverse_square;
+        let (constant_power, constant_range) = self.constant;
+        let arriving = power * window(range, near) / squared
+            + constant_power * range_falloff(constant_range, near);
+        if arriving <= 0. {
+            return 0.;
+        }
+
+        let cosine = match normal {
+            Some(normal) => cone_cosine(&normal, &to_center, distance, radius),
+            None => 1.,
+        };
+
+        arriving * cosine
+    }
+}
+
+/// Distance to lights below which their importance stops growing.
+const MIN_DISTANCE: f32 = 1e-4;
+
+/// Returns upper bound of the cosine between the normal and directions into a sphere.
+fn cone_cosine(normal: &Vector3, to_center: &Vector3, distance: f32, radius: f32) -> f32 {
+    if distance <= radius {
+        return 1.;
+    }
+
+    let sin_bound = radius / distance;
+    let cos_bound = (1. - sin_bound * sin_bound).max(0.).sqrt();
+    let cos_center = dot(*normal, *to_center) / distance;
+    if cos_center >= cos_bound {
+        return 1.;
+    }
+
+    // Cosine of the angle to the center reduced by the angle of the sphere.
+    let sin_center = (1. - cos_center * cos_center).max(0.).sqrt();
+    (cos_center * cos_bound + sin_center * sin_bound).max(0.)
+}
+
+enum LightNode {
+    /// Second child follows the subtree of the first child, which follows the node.
+    Inner {
+        bounds: LightBounds,
+        second: usize,
+    },
+    Leaf {
+        bounds: LightBounds,
+        light: usize,
+    },
+}
+
+impl LightNode {
+    fn bounds(&self) -> &LightBounds {
+        match self {
+            LightNode::Inner { bounds, .. } | LightNode::Leaf { bounds, .. } => bounds,
+        }
+    }
+}
+
+/// Bounding volume hierarchy of point lights, directional lights lie outside of it.
+struct LightTree {
+    nodes: Vec<LightNode>,
+    /// Index, direction towards the light and power of directional lights.
+    directional: Vec<(usize, Vector3, f32)>,
+}
+
+impl LightTree {
+    fn new(lights: &[Light]) -> Self {
+        let mut directional = Vec::new();
+        let mut leaves = Vec::new();
+        for (index, light) in lights.iter().enumerate() {
+            let intensity = intensity(light);
+            if intensity <= 0. {
+                continue;
+            }
+
+            match light {
+                Light::Directional(_) => {
+                    let (direction, _) = light.direction_distance_from(&Vector3::zero());
+                    directional.push((index, direction, intensity));
+                }
+                Light::Point(point) => {
+                    let (inverse_square, constant) = match point.falloff {
+                        Falloff::InverseSquare => ((intensity, point.range), (0., 0.)),
+                        Falloff::Range => ((0., 0.), (intensity, point.range)),
+                    };
+
+                    leaves.push(LightNode::Leaf {
+                        bounds: LightBounds {
+                            bounds: [point.position, point.position],
+                            inverse_square,
+                            constant,
+                        },
+                        light: index,
+                    });
+                }
+            }
+        }
+
+        let mut nodes = Vec::with_capacity(2 * leaves.len());
+        if !leaves.is_empty() {
+            Self::build(&mut leaves, &mut nodes);
+        }
+
+        Self { nodes, directional }
+    }
+
+    /// Appends subtree of given leaves split at the median of the longest axis of their bounds.
+    fn build(leaves: &mut [LightNode], nodes: &mut Vec<LightNode>) {
+        if leaves.len() == 1 {
+            let LightNode::Leaf { bounds, light } = &leaves[0] else {
+                unreachable!("only leaves are built into the tree")
+            };
+            nodes.push(LightNode::Leaf {
+                bounds: *bounds,
+                light: *light,
+            });
+            return;
+        }
+
+        let bounds = leaves
+            .iter()
+            .map(|leaf| *leaf.bounds())
+            .reduce(|a, b| a.union(&b))
+            .expect("leaves should not be empty");
+        let extent = bounds.bounds[1] - bounds.bounds[0];
+        let axis = match (extent.x, extent.y, extent.z) {
+            (x, y, z) if x >= y && x >= z => 0,
+            (_, y, z) if y >= z => 1,
+            _ => 2,
+        };
+
+        let middle = leaves.len() / 2;
+        leaves.select_nth_unstable_by(middle, |a, b| {
+            a.bounds().bounds[0][axis].total_cmp(&b.bounds().bounds[0][axis])
+        });
+
+        let node = nodes.len();
+        nodes.push(LightNode::Inner { bounds, second: 0 });
+        let (first, second) = leaves.split_at_mut(middle);
+        Self::build(first, nodes);
+        let second_index = nodes.len();
+        Self::build(second, nodes);
+
+        if let LightNode::Inner { second, .. } = &mut nodes[node] {
+            *second = second_index;
+        }
+    }
+
+    /// Selects light in proportion to upper bounds of light arriving from it, descending the
+    /// tree by remapping the uniform number at every node.
+    fn sample(&self, position: &Vector3, normal: Option<Vector3>, u: f32) -> Option<(usize, f32)> {
+        let cosine = |direction: &Vector3| match normal {
+            Some(normal) => dot(normal, *direction).max(0.),
+            None => 1.,
+        };
+        let root = self
+            .nodes
+            .first()
+            .map_or(0., |root| root.bounds().importance(position, normal));
+
+        let total = root
+            + self
+                .directional
+                .iter()
+                .map(|(_, direction, power)| power * cosine(direction))
+                .sum::<f32>();
+        if total <= 0. {
+            return None;
+        }
+
+        let mut u = u * total;
+        for (index, direction, power) in &self.directional {
+            let importance = power * cosine(direction);
+            if u < importance {
+                return Some((*index, importance / total));
+            }
+            u -= importance;
+        }
+        if root <= 0. {
+            return None;
+        }
+
+        let (mut u, mut probability) = ((u / root).min(ONE_MINUS_EPSILON), root / total);
+        let mut node = 0;
+        loop {
+            match &self.nodes[node] {
+                LightNode::Leaf { light, .. } => return Some((*light, probability)),
+                LightNode::Inner { second, .. } => {
+                    let importance =
+                        |node: usize| self.nodes[node].bounds().importance(position, normal);
+                    let (first, second) = (node + 1, *second);
+                    let (first_importance, second_importance) =
+                        (importance(first), importance(second));
+                    if first_importance + second_importance <= 0. {
+                        return None;
+                    }
+
+                    let p = first_importance / (first_importance + second_importance);
+                    (node, u, probability) = match u < p {
+                        true => (first, u / p, probability * p),
+                        false => (second, (u - p) / (1. - p), probability * (1. - p)),
+                    };
+                    u = u.min(ONE_MINUS_EPSILON);
+                }
+            }
+        }
+    }
+}
+
+/// Largest float below one.
+const ONE_MINUS_EPSILON: f32 = 1. - f32::EPSILON / 2.;
+
+/// Returns luminance of intensity of the light, of illuminance of directional lights.
+fn intensity(light: &Light) -> f32 {
+    match light {
+        Light::Directional(directional) => luminance(directional.color) * directional.intensity,
+        Light::Point(point) => luminance(point.color) * point.intensity,
+    }
+}
+
+/// Returns estimate of luminous power of light leaving the light into a scene of given radius.
+///
+/// Directional lights light a disk covering the scene, point lights of range falloff emit more
+/// towards farther positions up to their range. Spot lights emit into their outer cone.
+fn power(light: &Light, radius: f32) -> f32 {
+    let area = match light {
+        Light::Directional(_) => PI * radius * radius,
+        Light::Point(point) => match point.falloff {
+            Falloff::InverseSquare => point.solid_angle(),
+            Falloff::Range => point.solid_angle() * point.range.min(radius).powi(2),
+        },
+    };
+    intensity(light) * area
+}
+
+/// Distributions selecting lights of a scene.
+pub(crate) struct LightSampler {
+    count: usize,
+    power: Option<AliasTable>,
+    tree: LightTree,
+}
+
+impl LightSampler {
+    /// Builds distributions of lights of a scene enclosed by a sphere of given radius.
+    pub(crate) fn new(lights: &[Light], radius: f32) -> Self {
+        let powers: Vec<f32> = lights.iter().map(|light| power(light, radius)).collect();
+
+        Self {
+            count: lights.len(),
+            power: AliasTable::new(&powers),
+            tree: LightTree::new(lights),
+        }
+    }
+
+    /// Selects light sampled at given position, with normal of the side lit by lights if there is
+    /// one, returning its index and probability. None if no light can light the position.
+    pub(crate) fn sample(
+        &self,
+        selection: LightSelection,
+        position: &Vector3,
+        normal: Option<Vector3>,
+        u: f32,
+    ) -> Option<(usize, f32)> {
+        match selection {
+            LightSelection::Uniform => self.sample_uniform(u),
+            LightSelection::Power => self.power.as_ref().map(|table| table.sample(u)),
+            LightSelection::Tree => self.tree.sample(position, normal, u),
+        }
+    }
+
+    /// Selects light emitting a path into the scene, returning its index and probability.
+    pub(crate) fn sample_emitting(
+        &self,
+        selection: LightSelection,
+        u: f32,
+    ) -> Option<(usize, f32)> {
+        match selection {
+            LightSelection::Uniform => self.sample_uniform(u),
+            LightSelection::Power | LightSelection::Tree => {
+                self.power.as_ref().map(|table| table.sample(u))
+            }
+        }
+    }
+
+    fn sample_uniform(&self, u: f32) -> Option<(usize, f32)> {
+        match self.count {
+            0 => None,
+            count => Some((
+                ((u * count as f32) as usize).min(count - 1),
+                1. / count as f32,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::Point;
+
+    fn point(position: Vector3, intensity: f32, range: f32) -> Light {
+        Light::Point(Point {
+            position,
+            color: Vector3::new(1., 1., 1.),
+            intensity,
+            range,
+            range_squared: range * range,
+            falloff: Falloff::InverseSquare,
+            profile: None,
+            cone: None,
+        })
+    }
+
+    #[test]
+    fn test_alias_table() {
+        let weights = [1., 0., 3., 4.];
+        let table = AliasTable::new(&weights).unwrap();
+
+        let mut counts = [0; 4];
+        let samples = 10000;
+        for i in 0..samples {
+            let (index, probability) = table.sample((i as f32 + 0.5) / samples as f32);
+            assert_eq!(probability, weights[index] / 8.);
+            counts[index] += 1;
+        }
+        for (count, weight) in counts.iter().zip(weights) {
+            assert!((*count as f32 / samples as f32 - weight / 8.).abs() < 1e-3);
+        }
+
+        assert!(AliasTable::new(&[0., 0.]).is_none());
+    }
+
+    #[test]
+    fn test_light_tree() {
+        let lights: Vec<Light> = (0..64)
+            .map(|i| point(Vector3::new(i as f32, 0., 0.), 1. + (i % 3) as f32, 4.))
+            .collect();
+        let tree = LightTree::new(&lights);
+        let position = Vector3::new(10., 1., 0.);
+
+        // Lights out of range are never selected and probabilities of selections sum up to one.
+        let mut probabilities = vec![0.; lights.len()];
+        let samples = 10000;
+        for i in 0..samples {
+            let u = (i as f32 + 0.5) / samples as f32;
+            let (index, probability) = tree.sample(&position, None, u).unwrap();
+            assert!((lights[index].direction_distance_from(&position).1) < 4.);
+            probabilities[index] = probability;
+        }
+        assert!((probabilities.iter().sum::<f32>() - 1.).abs() < 1e-4);
+
+        // Lights behind the lit side are never selected.
+        let below = tree.sample(&position, Some(Vector3::new(0., 1., 0.)), 0.5);
+        assert!(below.is_none());
+    }
+}
@@ -1,7 +1,11 @@
 use crate::brdf::Brdf;
+use crate::brdf_microfacet::MicrofacetBrdf;
 use crate::math::*;
+use crate::medium::Medium;
+use crate::spectrum::ComplexIor;
 
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::sync::{Arc, OnceLock};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Filtering {
@@ -9,6 +13,17 @@ pub enum Filtering {
     Linear,
 }
 
+/// Filtering between levels of the mip pyramid when the texture is minified.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MipFiltering {
+    /// Always samples the full resolution level.
+    Disabled,
+    /// Isotropic, blends two levels selected by the longer axis of the footprint.
+    Trilinear,
+    /// Anisotropic, elliptically weighted average over the footprint.
+    Ewa,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum WrapMode {
     Clamp,
@@ -16,6 +31,25 @@ pub enum WrapMode {
     MirroredRepeat,
 }
 
+impl WrapMode {
+    // Maps texel coordinate into [0; size).
+    #[inline]
+    fn wrap(&self, i: i32, size: u32) -> u32 {
+        let size = size as i32;
+        let wrapped = match self {
+            WrapMode::Clamp => i.clamp(0, size - 1),
+            WrapMode::Repeat => i.rem_euclid(size),
+            WrapMode::MirroredRepeat => match i.rem_euclid(2 * size) {
+                i if i < size => i,
+                i => 2 * size - 1 - i,
+            },
+        };
+        wrapped as u32
+    }
+}
+
+type Rgba = (f32, f32, f32, f32);
+
 #[derive(Debug, PartialEq)]
 pub enum AlphaMode {
     Opaque,
@@ -23,42 +57,284 @@ pub enum AlphaMode {
     Blend,
 }
 
-pub struct Texture {
+/// Number of texture coordinate sets of vertices, TEXCOORD_0 and TEXCOORD_1 of glTF.
+pub const UV_SETS: usize = 2;
+
+/// Texture coordinates with their differentials with respect to pixels of the image.
+///
+/// Zero differentials sample the full resolution level of textures.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UvCoord {
+    pub uv: (f32, f32),
+    pub duv_dx: (f32, f32),
+    pub duv_dy: (f32, f32),
+}
+
+impl UvCoord {
+    pub fn new(uv: (f32, f32)) -> Self {
+        UvCoord {
+            uv,
+            ..Default::default()
+        }
+    }
+}
+
+/// Texture coordinates of all UV sets at a point of a surface.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TexCoord {
+    pub sets: [UvCoord; UV_SETS],
+}
+
+impl TexCoord {
+    pub fn new(uv: [(f32, f32); UV_SETS]) -> Self {
+        TexCoord {
+            sets: uv.map(UvCoord::new),
+        }
+    }
+}
+
+/// Offset, rotation and scale of texture coordinates, KHR_texture_transform.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TextureTransform {
+    pub offset: (f32, f32),
+    /// Counter-clockwise rotation in UV space, in radians.
+    pub rotation: f32,
+    pub scale: (f32, f32),
+}
+
+impl Default for TextureTransform {
+    fn default() -> Self {
+        TextureTransform {
+            offset: (0., 0.),
+            rotation: 0.,
+            scale: (1., 1.),
+        }
+    }
+}
+
+impl TextureTransform {
+    // Scale, then rotation and offset, differentials are transformed by the linear part only.
+    fn apply(&self, coord: &UvCoord) -> UvCoord {
+        if *self == TextureTransform::default() {
+            return *coord;
+        }
+
+        let (sin, cos) = self.rotation.sin_cos();
+        let linear = |(u, v): (f32, f32)| {
+            let (u, v) = (u * self.scale.0, v * self.scale.1);
+            (cos * u + sin * v, -sin * u + cos * v)
+        };
+
+        let (u, v) = linear(coord.uv);
+        UvCoord {
+            uv: (u + self.offset.0, v + self.offset.1),
+            duv_dx: linear(coord.duv_dx),
+            duv_dy: linear(coord.duv_dy),
+        }
+    }
+}
+
+/// Encoding of color channels of a texture, alpha is always linear.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    Linear,
+    Srgb,
+}
+
+/// RGBA channels of texels, integers are normalized to [0; 1].
+pub enum Texels {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    F32(Vec<f32>),
+}
+
+impl Texels {
+    #[inline]
+    fn get(&self, index: usize) -> f32 {
+        match self {
+            Texels::U8(values) => values[index] as f32 / 255.,
+            Texels::U16(values) => values[index] as f32 / 65535.,
+            Texels::F32(values) => values[index],
+        }
+    }
+
+    // Encodes linear values in the same format.
+    fn encode(&self, texels: &[Rgba], color_space: ColorSpace) -> Texels {
+        let channels = texels.iter().flat_map(|texel| {
+            let (r, g, b, a) = match color_space {
+                ColorSpace::Linear => *texel,
+                ColorSpace::Srgb => (
+                    linear_to_srgb(texel.0),
+                    linear_to_srgb(texel.1),
+                    linear_to_srgb(texel.2),
+                    texel.3,
+                ),
+            };
+            [r, g, b, a]
+        });
+
+        match self {
+            Texels::U8(_) => Texels::U8(
+                channels
+                    .map(|c| (saturate(c) * 255.).round() as u8)
+                    .collect(),
+            ),
+            Texels::U16(_) => Texels::U16(
+                channels
+                    .map(|c| (saturate(c) * 65535.).round() as u16)
+                    .collect(),
+            ),
+            Texels::F32(_) => Texels::F32(channels.collect()),
+        }
+    }
+}
+
+// Decoded values of all 8-bit sRGB values.
+fn srgb_u8_table() -> &'static [f32; 256] {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| std::array::from_fn(|i| srgb_to_linear(i as f32 / 255.)))
+}
+
+pub struct MipLevel {
     pub width: u32,
     pub height: u32,
-    pub rgba: Vec<u8>,
-    pub pixel_size: u8,
+    pub texels: Texels,
+}
+
+impl MipLevel {
+    /// Returns texels converted to 8-bit channels, without decoding.
+    pub fn to_rgba8(&self) -> Cow<'_, [u8]> {
+        match &self.texels {
+            Texels::U8(values) => Cow::Borrowed(values),
+            Texels::U16(values) => Cow::Owned(values.iter().map(|v| (v >> 8) as u8).collect()),
+            Texels::F32(values) => Cow::Owned(
+                values
+                    .iter()
+                    .map(|v| (saturate(*v) * 255.).round() as u8)
+                    .collect(),
+            ),
+        }
+    }
+
+    // Returns linear value of texel at given position.
+    #[inline]
+    fn texel(&self, color_space: ColorSpace, x: u32, y: u32) -> Rgba {
+        let index = ((y * self.width + x) * 4) as usize;
+        let alpha = self.texels.get(index + 3);
+
+        match (color_space, &self.texels) {
+            (ColorSpace::Linear, texels) => (
+                texels.get(index),
+                texels.get(index + 1),
+                texels.get(index + 2),
+                alpha,
+            ),
+            (ColorSpace::Srgb, Texels::U8(values)) => {
+                let table = srgb_u8_table();
+                (
+                    table[values[index] as usize],
+                    table[values[index + 1] as usize],
+                    table[values[index + 2] as usize],
+                    alpha,
+                )
+            }
+            (ColorSpace::Srgb, texels) => (
+                srgb_to_linear(texels.get(index)),
+                srgb_to_linear(texels.get(index + 1)),
+                srgb_to_linear(texels.get(index + 2)),
+                alpha,
+            ),
+        }
+    }
+
+    // Box filter of 2x2 texels in linear space, the last row or column of odd sizes is repeated.
+    fn downsample(&self, color_space: ColorSpace) -> MipLevel {
+        let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+        let mut texels = Vec::with_capacity((width * height) as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                let sum = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .map(|(dx, dy)| {
+                        let tx = (2 * x + dx).min(self.width - 1);
+                        let ty = (2 * y + dy).min(self.height - 1);
+                        self.texel(color_space, tx, ty)
+                    })
+                    .into_iter()
+                    .fold((0., 0., 0., 0.), |s, t| {
+                        (s.0 + t.0, s.1 + t.1, s.2 + t.2, s.3 + t.3)
+                    });
+                texels.push((sum.0 / 4., sum.1 / 4., sum.2 / 4., sum.3 / 4.));
+            }
+        }
+
+        MipLevel {
+            width,
+            height,
+            texels: self.texels.encode(&texels, color_space),
+        }
+    }
 }
 
+pub struct Texture {
+    pub color_space: ColorSpace,
+    /// Mip pyramid, the first level has full resolution and each next one half the size of the previous.
+    pub levels: Vec<MipLevel>,
+}
+
+impl Texture {
+    /// Creates texture from RGBA texels and builds its mip pyramid.
+    pub fn new(width: u32, height: u32, texels: Texels, color_space: ColorSpace) -> Self {
+        let mut levels = vec![MipLevel {
+            width,
+            height,
+            texels,
+        }];
+
+        while let Some(level) = levels.last().filter(|l| l.width > 1 || l.height > 1) {
+            let next = level.downsample(color_space);
+            levels.push(next);
+        }
+
+        Texture {
+            color_space,
+            levels,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.levels[0].height
+    }
+}
+
+// Longest allowed ratio of the footprint axes, the minor axis of longer ones is stretched to bound the number of texels.
+const MAX_ANISOTROPY: f32 = 8.;
+// Falloff of the Gaussian EWA filter.
+const EWA_ALPHA: f32 = 2.;
+
 pub struct Sampler {
     pub filtering: Filtering,
+    pub mip_filtering: MipFiltering,
     pub wrap_s: WrapMode,
     pub wrap_t: WrapMode,
 }
 
 impl Sampler {
     #[inline]
-    fn nearest(&self, texture: &Texture, x: f32, y: f32) -> (f32, f32, f32, f32) {
-        let (tx, ty) = (
-            ((x + 0.5) as u32).min(texture.width - 1),
-            ((y + 0.5) as u32).min(texture.height - 1),
-        );
-
-        assert!(tx < texture.width);
-        assert!(ty < texture.height);
-
-        let index = ((ty * texture.width + tx) * texture.pixel_size as u32) as usize;
-        // TODO: floating point.
-        (
-            texture.rgba[index] as f32 / 255.,
-            texture.rgba[index + 1] as f32 / 255.,
-            texture.rgba[index + 2] as f32 / 255.,
-            texture.rgba[index + 3] as f32 / 255.,
+    fn texel(&self, texture: &Texture, level: &MipLevel, x: i32, y: i32) -> Rgba {
+        level.texel(
+            texture.color_space,
+            self.wrap_s.wrap(x, level.width),
+            self.wrap_t.wrap(y, level.height),
         )
     }
 
     #[inline]
-    fn lerp(p1: (f32, f32, f32, f32), p2: (f32, f32, f32, f32), t: f32) -> (f32, f32, f32, f32) {
+    fn lerp(p1: Rgba, p2: Rgba, t: f32) -> Rgba {
         (
             p1.0 * (1. - t) + p2.0 * t,
             p1.1 * (1. - t) + p2.1 * t,
@@ -67,41 +343,175 @@ impl Sampler {
         )
     }
 
-    fn linear(&self, texture: &Texture, x: f32, y: f32) -> (f32, f32, f32, f32) {
-        let sx = x.floor();
-        let sy = y.floor();
+    fn nearest(&self, texture: &Texture, level: &MipLevel, uv: (f32, f32)) -> Rgba {
+        let (x, y) = (uv.0 * level.width as f32, uv.1 * level.height as f32);
+        self.texel(texture, level, x.floor() as i32, y.floor() as i32)
+    }
+
+    // Texel centers lie at half-integer coordinates.
+    fn linear(&self, texture: &Texture, level: &MipLevel, uv: (f32, f32)) -> Rgba {
+        let (x, y) = (
+            uv.0 * level.width as f32 - 0.5,
+            uv.1 * level.height as f32 - 0.5,
+        );
+        let (sx, sy) = (x.floor(), y.floor());
+        let (ix, iy) = (sx as i32, sy as i32);
 
-        let p1 = self.nearest(texture, sx, sy);
-        let p2 = self.nearest(texture, sx + 1., sy);
-        let p3 = self.nearest(texture, sx, sy + 1.);
-        let p4 = self.nearest(texture, sx + 1., sy + 1.);
+        let p1 = self.texel(texture, level, ix, iy);
+        let p2 = self.texel(texture, level, ix + 1, iy);
+        let p3 = self.texel(texture, level, ix, iy + 1);
+        let p4 = self.texel(texture, level, ix + 1, iy + 1);
 
-        let x_frac = x.fract();
+        let x_frac = x - sx;
         let p12 = Self::lerp(p1, p2, x_frac);
-        let p23 = Self::lerp(p3, p4, x_frac);
+        let p34 = Self::lerp(p3, p4, x_frac);
 
-        Self::lerp(p12, p23, y.fract())
+        Self::lerp(p12, p34, y - sy)
     }
 
-    pub fn sample(&self, texture: &Texture, uv: (f32, f32)) -> (f32, f32, f32, f32) {
-        // TODO: Wrap mode.
-        assert_eq!(self.wrap_s, WrapMode::Repeat);
-        assert_eq!(self.wrap_t, WrapMode::Repeat);
+    fn sample_level(&self, texture: &Texture, level: usize, uv: (f32, f32)) -> Rgba {
+        let level = &texture.levels[level];
+        match self.filtering {
+            Filtering::Linear => self.linear(texture, level, uv),
+            Filtering::Nearest => self.nearest(texture, level, uv),
+        }
+    }
 
-        let add = (
-            if uv.0 < 0. { 1. } else { 0. },
-            if uv.1 < 0. { 1. } else { 0. },
-        );
-        let (u, v) = (add.0 + uv.0.fract(), add.1 + uv.1.fract());
+    // Interpolates between two levels given continuous level of detail, evaluated by 'sample'.
+    fn sample_lod<F: Fn(usize) -> Rgba>(texture: &Texture, lod: f32, sample: F) -> Rgba {
+        let last = texture.levels.len() - 1;
 
-        let (x, y) = (
-            u * (texture.width - 1) as f32,
-            v * (texture.height - 1) as f32,
+        if lod.is_nan() || lod <= 0. {
+            return sample(0);
+        }
+        if lod >= last as f32 {
+            return sample(last);
+        }
+
+        let level = lod.floor();
+        Self::lerp(
+            sample(level as usize),
+            sample(level as usize + 1),
+            lod - level,
+        )
+    }
+
+    fn trilinear(&self, texture: &Texture, coord: &UvCoord) -> Rgba {
+        let (width, height) = (texture.width() as f32, texture.height() as f32);
+        let length = |d: (f32, f32)| ((d.0 * width).powi(2) + (d.1 * height).powi(2)).sqrt();
+        let lod = length(coord.duv_dx).max(length(coord.duv_dy)).log2();
+
+        Self::sample_lod(texture, lod, |level| {
+            self.sample_level(texture, level, coord.uv)
+        })
+    }
+
+    // https://www.pbr-book.org/3ed-2018/Texture/Image_Texture#EllipticallyWeightedAverage
+    fn ewa(&self, texture: &Texture, coord: &UvCoord) -> Rgba {
+        let (width, height) = (texture.width() as f32, texture.height() as f32);
+        let length = |a: (f32, f32)| (a.0 * a.0 + a.1 * a.1).sqrt();
+
+        // Axes of the footprint in texels of the full resolution level.
+        let mut major = (coord.duv_dx.0 * width, coord.duv_dx.1 * height);
+        let mut minor = (coord.duv_dy.0 * width, coord.duv_dy.1 * height);
+        if length(major) < length(minor) {
+            std::mem::swap(&mut major, &mut minor);
+        }
+
+        let (major_length, minor_length) = (length(major), length(minor));
+        if !major_length.is_normal() {
+            return self.sample_level(texture, 0, coord.uv);
+        }
+
+        if minor_length * MAX_ANISOTROPY < major_length {
+            minor = match minor_length > 0. {
+                true => {
+                    let scale = major_length / (minor_length * MAX_ANISOTROPY);
+                    (minor.0 * scale, minor.1 * scale)
+                }
+                false => (-major.1 / MAX_ANISOTROPY, major.0 / MAX_ANISOTROPY),
+            };
+        }
+
+        // Level where the minor axis spans about a single texel.
+        let lod = length(minor).log2();
+        Self::sample_lod(texture, lod, |level| {
+            self.ewa_level(texture, level, coord.uv, major, minor)
+        })
+    }
+
+    fn ewa_level(
+        &self,
+        texture: &Texture,
+        level: usize,
+        uv: (f32, f32),
+        major: (f32, f32),
+        minor: (f32, f32),
+    ) -> Rgba {
+        let data = &texture.levels[level];
+        let scale = (
+            data.width as f32 / texture.width() as f32,
+            data.height as f32 / texture.height() as f32,
+        );
+        let (a0, a1) = (
+            (major.0 * scale.0, major.1 * scale.1),
+            (minor.0 * scale.0, minor.1 * scale.1),
+        );
+        let (s, t) = (
+            uv.0 * data.width as f32 - 0.5,
+            uv.1 * data.height as f32 - 0.5,
         );
 
-        match self.filtering {
-            Filtering::Linear => self.linear(texture, x, y),
-            Filtering::Nearest => self.nearest(texture, x, y),
+        // Implicit ellipse a*s^2 + b*s*t + c*t^2 = 1, enlarged by a texel so that it always covers some.
+        let a = a0.1 * a0.1 + a1.1 * a1.1 + 1.;
+        let b = -2. * (a0.0 * a0.1 + a1.0 * a1.1);
+        let c = a0.0 * a0.0 + a1.0 * a1.0 + 1.;
+        let inv_f = 1. / (a * c - b * b * 0.25);
+        let (a, b, c) = (a * inv_f, b * inv_f, c * inv_f);
+
+        // Bounding box of the ellipse.
+        let det = 4. * a * c - b * b;
+        let (s_radius, t_radius) = (2. * (det * c).sqrt() / det, 2. * (det * a).sqrt() / det);
+        let (s0, s1) = ((s - s_radius).ceil() as i32, (s + s_radius).floor() as i32);
+        let (t0, t1) = ((t - t_radius).ceil() as i32, (t + t_radius).floor() as i32);
+
+        let mut sum = (0., 0., 0., 0.);
+        let mut weights = 0.;
+        for it in t0..=t1 {
+            let tt = it as f32 - t;
+            for is in s0..=s1 {
+                let ss = is as f32 - s;
+                let r2 = a * ss * ss + b * ss * tt + c * tt * tt;
+                if r2 < 1. {
+                    let weight = (-EWA_ALPHA * r2).exp() - (-EWA_ALPHA).exp();
+                    let texel = self.texel(texture, data, is, it);
+                    sum = (
+                        sum.0 + texel.0 * weight,
+                        sum.1 + texel.1 * weight,
+                        sum.2 + texel.2 * weight,
+                        sum.3 + texel.3 * weight,
+                    );
+                    weights += weight;
+                }
+            }
+        }
+
+        match weights > 0. {
+            true => (
+                sum.0 / weights,
+                sum.1 / weights,
+                sum.2 / weights,
+                sum.3 / weights,
+            ),
+            false => self.sample_level(texture, level, uv),
+        }
+    }
+
+    pub fn sample(&self, texture: &Texture, coord: &UvCoord) -> Rgba {
+        match self.mip_filtering {
+            MipFiltering::Disabled => self.sample_level(texture, 0, coord.uv),
+            MipFiltering::Trilinear => self.trilinear(texture, coord),
+            MipFiltering::Ewa => self.ewa(texture, coord),
         }
     }
 }
@@ -109,11 +519,142 @@ impl Sampler {
 pub struct TextureSampler {
     pub texture: Arc<Texture>,
     pub sampler: Sampler,
+    /// Index of the UV set.
+    pub tex_coord: usize,
+    pub transform: TextureTransform,
 }
 
 impl TextureSampler {
-    pub fn sample(&self, uv: (f32, f32)) -> (f32, f32, f32, f32) {
-        self.sampler.sample(&self.texture, uv)
+    pub fn sample(&self, coord: &TexCoord) -> (f32, f32, f32, f32) {
+        let coord = self.transform.apply(&coord.sets[self.tex_coord]);
+        self.sampler.sample(&self.texture, &coord)
+    }
+}
+
+/// Dielectric specular reflectance, KHR_materials_specular.
+pub struct Specular {
+    pub factor: f32,
+    pub texture: Option<TextureSampler>,
+    pub color: Vector3,
+    pub color_texture: Option<TextureSampler>,
+}
+
+impl Default for Specular {
+    fn default() -> Self {
+        Specular {
+            factor: 1.,
+            texture: None,
+            color: Vector3::one(),
+            color_texture: None,
+        }
+    }
+}
+
+/// Clear dielectric layer on top of the material, KHR_materials_clearcoat.
+#[derive(Default)]
+pub struct Clearcoat {
+    pub factor: f32,
+    pub texture: Option<TextureSampler>,
+    pub roughness: f32,
+    pub roughness_texture: Option<TextureSampler>,
+}
+
+/// Back-scattering layer of cloth fibers, KHR_materials_sheen.
+pub struct Sheen {
+    pub color: Vector3,
+    pub color_texture: Option<TextureSampler>,
+    pub roughness: f32,
+    pub roughness_texture: Option<TextureSampler>,
+}
+
+impl Default for Sheen {
+    fn default() -> Self {
+        Sheen {
+            color: Vector3::zero(),
+            color_texture: None,
+            roughness: 0.,
+            roughness_texture: None,
+        }
+    }
+}
+
+/// Direction dependent roughness, KHR_materials_anisotropy.
+#[derive(Default)]
+pub struct Anisotropy {
+    pub strength: f32,
+    /// Counter-clockwise rotation of the direction from tangent, in radians.
+    pub rotation: f32,
+    pub texture: Option<TextureSampler>,
+}
+
+/// Thin-film interference on top of the specular layer, KHR_materials_iridescence.
+pub struct Iridescence {
+    pub factor: f32,
+    pub texture: Option<TextureSampler>,
+    pub ior: f32,
+    /// Thickness of the film in nanometers, texture interpolates between minimum and maximum.
+    pub thickness_minimum: f32,
+    pub thickness_maximum: f32,
+    pub thickness_texture: Option<TextureSampler>,
+}
+
+impl Default for Iridescence {
+    fn default() -> Self {
+        Iridescence {
+            factor: 0.,
+            texture: None,
+            ior: 1.3,
+            thickness_minimum: 100.,
+            thickness_maximum: 400.,
+            thickness_texture: None,
+        }
+    }
+}
+
+/// Scattering inside of a volume, absorption is given by the attenuation of the material.
+pub struct Scattering {
+    /// Scattering coefficient per unit of distance.
+    pub coefficient: Vector3,
+    /// Asymmetry of the Henyey-Greenstein phase function.
+    pub asymmetry: f32,
+}
+
+impl Default for Scattering {
+    fn default() -> Self {
+        Scattering {
+            coefficient: Vector3::zero(),
+            asymmetry: 0.,
+        }
+    }
+}
+
+/// Random walk subsurface scattering inside of a closed mesh, KHR_materials_diffuse_transmission.
+///
+/// Light which is not reflected by the surface enters the volume, where it scatters until it
+/// leaves the mesh or gets absorbed. The medium is given by the albedo of the multiple scattering
+/// and the mean free path, its color is taken where the light entered.
+pub struct Subsurface {
+    /// Fraction of the diffuse base replaced by the subsurface scattering.
+    pub factor: f32,
+    pub texture: Option<TextureSampler>,
+    pub color: Vector3,
+    pub color_texture: Option<TextureSampler>,
+    /// Average distance between scattering events per color channel, in scene units.
+    pub mean_free_path: Vector3,
+    /// Asymmetry of the Henyey-Greenstein phase function.
+    pub asymmetry: f32,
+}
+
+impl Default for Subsurface {
+    fn default() -> Self {
+        Subsurface {
+            factor: 0.,
+            texture: None,
+            color: Vector3::one(),
+            color_texture: None,
+            mean_free_path: Vector3::zero(),
+            asymmetry: 0.,
+        }
     }
 }
 
@@ -129,19 +670,71 @@ pub struct Material {
     pub metalic: f32,
     pub roughness: f32,
     pub metalic_roughness_texture: Option<TextureSampler>,
+    pub ior: f32,
+    /// Dispersion of the IOR as 20 over Abbe number, KHR_materials_dispersion.
+    pub dispersion: f32,
+    /// Measured complex IOR replacing the base color and metalness by a conductor.
+    pub conductor: Option<ComplexIor>,
+    pub transmission: f32,
+    pub transmission_texture: Option<TextureSampler>,
+    /// Zero thickness makes the material thin-walled, otherwise it is a boundary of a volume.
+    pub thickness: f32,
+    pub attenuation_color: Vector3,
+    pub attenuation_distance: f32,
+    pub scattering: Scattering,
+    pub subsurface: Subsurface,
+    pub specular: Specular,
+    pub clearcoat: Clearcoat,
+    pub sheen: Sheen,
+    pub anisotropy: Anisotropy,
+    pub iridescence: Iridescence,
     pub single_sided: bool,
 
     pub brdf: Box<dyn Brdf + Sync + Send + 'static>,
 }
 
+impl Default for Material {
+    /// White double sided dielectric.
+    fn default() -> Self {
+        Material {
+            alpha_mode: AlphaMode::Opaque,
+            albedo_factor: Vector3::one(),
+            albedo_texture: None,
+            emitted_factor: Vector3::zero(),
+            emitted_texture: None,
+            normal_texture: None,
+            metalic: 0.,
+            roughness: 1.,
+            metalic_roughness_texture: None,
+            ior: 1.5,
+            dispersion: 0.,
+            conductor: None,
+            transmission: 0.,
+            transmission_texture: None,
+            thickness: 0.,
+            attenuation_color: Vector3::one(),
+            attenuation_distance: f32::INFINITY,
+            scattering: Scattering::default(),
+            subsurface: Subsurface::default(),
+            specular: Specular::default(),
+            clearcoat: Clearcoat::default(),
+            sheen: Sheen::default(),
+            anisotropy: Anisotropy::default(),
+            iridescence: Iridescence::default(),
+            single_sided: false,
+            brdf: Box::new(MicrofacetBrdf::new()),
+        }
+    }
+}
+
 impl Material {
     fn sample_texture(
         factor: &Vector3,
         texture: &Option<TextureSampler>,
-        uv: (f32, f32),
+        coord: &TexCoord,
     ) -> (f32, f32, f32, f32) {
         let color = match texture {
-            Some(tex) => tex.sample(uv),
+            Some(tex) => tex.sample(coord),
             None => (1., 1., 1., 1.),
         };
 
@@ -153,13 +746,25 @@ impl Material {
         )
     }
 
-    pub fn discard(&self, uv: (f32, f32)) -> bool {
+    /// Returns the fraction of light stopped by the surface, a masked surface is either fully
+    /// opaque or fully transparent while a blended one is partially covering.
+    pub fn opacity(&self, coord: &TexCoord) -> f32 {
         match self.alpha_mode {
-            AlphaMode::Opaque => false,
-            AlphaMode::Mask(alpha) => {
-                Self::sample_texture(&self.albedo_factor, &self.albedo_texture, uv).3 <= alpha
+            AlphaMode::Opaque => 1.,
+            AlphaMode::Mask(cutoff) => {
+                let alpha =
+                    Self::sample_texture(&self.albedo_factor, &self.albedo_texture, coord).3;
+                if alpha <= cutoff {
+                    0.
+                } else {
+                    1.
+                }
+            }
+            AlphaMode::Blend => {
+                Self::sample_texture(&self.albedo_factor, &self.albedo_texture, coord)
+                    .3
+                    .clamp(0., 1.)
             }
-            AlphaMode::Blend => false,
         }
     }
 
@@ -167,30 +772,470 @@ impl Material {
         self.normal_texture.is_some()
     }
 
-    pub fn base_color(&self, uv: (f32, f32)) -> Vector3 {
-        let color = Self::sample_texture(&self.albedo_factor, &self.albedo_texture, uv);
+    pub fn base_color(&self, coord: &TexCoord) -> Vector3 {
+        let color = Self::sample_texture(&self.albedo_factor, &self.albedo_texture, coord);
         Vector3::new(color.0, color.1, color.2)
     }
 
-    pub fn emissive_color(&self, uv: (f32, f32)) -> Vector3 {
-        let color = Self::sample_texture(&self.emitted_factor, &self.emitted_texture, uv);
+    pub fn emissive_color(&self, coord: &TexCoord) -> Vector3 {
+        let color = Self::sample_texture(&self.emitted_factor, &self.emitted_texture, coord);
         Vector3::new(color.0, color.1, color.2)
     }
 
-    pub fn normal(&self, uv: (f32, f32)) -> Vector3 {
-        let (x, y, z, _) = Self::sample_texture(&Vector3::one(), &self.normal_texture, uv);
+    pub fn normal(&self, coord: &TexCoord) -> Vector3 {
+        let (x, y, z, _) = Self::sample_texture(&Vector3::one(), &self.normal_texture, coord);
         Vector3::new(x * 2. - 1., y * 2. - 1., z * 2. - 1.)
     }
 
-    pub fn metalness(&self, uv: (f32, f32)) -> f32 {
+    pub fn metalness(&self, coord: &TexCoord) -> f32 {
         let (m, _, _, _) =
-            Self::sample_texture(&Vector3::one(), &self.metalic_roughness_texture, uv);
+            Self::sample_texture(&Vector3::one(), &self.metalic_roughness_texture, coord);
         self.metalic * m
     }
 
-    pub fn roughness(&self, uv: (f32, f32)) -> f32 {
+    pub fn roughness(&self, coord: &TexCoord) -> f32 {
         let (_, r, _, _) =
-            Self::sample_texture(&Vector3::one(), &self.metalic_roughness_texture, uv);
+            Self::sample_texture(&Vector3::one(), &self.metalic_roughness_texture, coord);
         self.roughness * r
     }
+
+    pub fn transmission(&self, coord: &TexCoord) -> f32 {
+        let (t, _, _, _) = Self::sample_texture(&Vector3::one(), &self.transmission_texture, coord);
+        self.transmission * t
+    }
+
+    /// Returns strength and albedo of the subsurface scattering, zero if the material has none.
+    pub fn subsurface(&self, coord: &TexCoord) -> (f32, Vector3) {
+        if !self.has_subsurface() {
+            return (0., Vector3::zero());
+        }
+
+        let (_, _, _, s) = Self::sample_texture(&Vector3::one(), &self.subsurface.texture, coord);
+        let (r, g, b, _) = Self::sample_texture(
+            &self.subsurface.color,
+            &self.subsurface.color_texture,
+            coord,
+        );
+        (self.subsurface.factor * s, Vector3::new(r, g, b))
+    }
+
+    /// Returns strength and color of dielectric specular reflection.
+    pub fn specular(&self, coord: &TexCoord) -> (f32, Vector3) {
+        let (_, _, _, s) = Self::sample_texture(&Vector3::one(), &self.specular.texture, coord);
+        let (r, g, b, _) =
+            Self::sample_texture(&self.specular.color, &self.specular.color_texture, coord);
+        (self.specular.factor * s, Vector3::new(r, g, b))
+    }
+
+    /// Returns strength and roughness of the clearcoat layer.
+    pub fn clearcoat(&self, coord: &TexCoord) -> (f32, f32) {
+        let (c, _, _, _) = Self::sample_texture(&Vector3::one(), &self.clearcoat.texture, coord);
+        let (_, r, _, _) =
+            Self::sample_texture(&Vector3::one(), &self.clearcoat.roughness_texture, coord);
+        (self.clearcoat.factor * c, self.clearcoat.roughness * r)
+    }
+
+    /// Returns color and roughness of the sheen layer.
+    pub fn sheen(&self, coord: &TexCoord) -> (Vector3, f32) {
+        let (r, g, b, _) =
+            Self::sample_texture(&self.sheen.color, &self.sheen.color_texture, coord);
+        let (_, _, _, a) =
+            Self::sample_texture(&Vector3::one(), &self.sheen.roughness_texture, coord);
+        (Vector3::new(r, g, b), self.sheen.roughness * a)
+    }
+
+    /// Returns strength and direction of anisotropy in tangent space.
+    pub fn anisotropy(&self, coord: &TexCoord) -> (f32, (f32, f32)) {
+        let (strength, (x, y)) = match &self.anisotropy.texture {
+            Some(texture) => {
+                let (r, g, b, _) = texture.sample(coord);
+                let (x, y) = (r * 2. - 1., g * 2. - 1.);
+                let length = (x * x + y * y).sqrt();
+                match length > 0. {
+                    true => (self.anisotropy.strength * b, (x / length, y / length)),
+                    false => (0., (1., 0.)),
+                }
+            }
+            None => (self.anisotropy.strength, (1., 0.)),
+        };
+
+        let (sin, cos) = self.anisotropy.rotation.sin_cos();
+        (strength, (cos * x - sin * y, sin * x + cos * y))
+    }
+
+    /// Returns strength of iridescence and thickness of the film in nanometers.
+    pub fn iridescence(&self, coord: &TexCoord) -> (f32, f32) {
+        let (i, _, _, _) = Self::sample_texture(&Vector3::one(), &self.iridescence.texture, coord);
+        let thickness = match &self.iridescence.thickness_texture {
+            Some(texture) => lerp_scalar(
+                self.iridescence.thickness_minimum,
+                self.iridescence.thickness_maximum,
+                texture.sample(coord).1,
+            ),
+            None => self.iridescence.thickness_maximum,
+        };
+
+        (self.iridescence.factor * i, thickness)
+    }
+
+    /// Returns IOR at given wavelength in nanometers, the IOR of the material is given at the
+    /// Fraunhofer d line (Source: KHR_materials_dispersion).
+    pub fn ior_at(&self, lambda: f32) -> f32 {
+        match self.dispersion > 0. {
+            true => {
+                let abbe = 20. / self.dispersion;
+                self.ior + (self.ior - 1.) / abbe * (523655. / (lambda * lambda) - 1.5168)
+            }
+            false => self.ior,
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        self.dispersion > 0. && self.is_transmissive()
+    }
+
+    pub fn is_transmissive(&self) -> bool {
+        self.transmission > 0. || self.has_subsurface()
+    }
+
+    /// Subsurface scattering needs a volume to walk in.
+    pub fn has_subsurface(&self) -> bool {
+        !self.is_thin_walled()
+            && self.subsurface.factor > 0.
+            && self.subsurface.mean_free_path != Vector3::zero()
+    }
+
+    pub fn is_thin_walled(&self) -> bool {
+        self.thickness == 0.
+    }
+
+    /// Smooth fully transmissive boundary of a volume with unit IOR does not change direction of
+    /// light, so it can be connected through, e.g. by a boundary of a fog volume.
+    pub fn is_index_matched(&self) -> bool {
+        !self.is_thin_walled()
+            && self.transmission >= 1.
+            && self.transmission_texture.is_none()
+            && self.ior == 1.
+            && self.roughness == 0.
+    }
+
+    /// Back faces of volume boundaries are always visible, light travels inside of them.
+    pub fn is_single_sided(&self) -> bool {
+        self.single_sided && self.is_thin_walled()
+    }
+
+    /// Returns medium filling the volume bounded by this material entered at given coordinates,
+    /// none for thin-walled materials and clear volumes.
+    pub fn medium(&self, coord: &TexCoord) -> Option<Medium> {
+        if self.is_thin_walled() {
+            return None;
+        }
+
+        if self.has_subsurface() {
+            let (_, albedo) = self.subsurface(coord);
+            return Some(self.subsurface_medium(albedo));
+        }
+
+        let absorption = match self.attenuation_distance.is_finite() {
+            true => {
+                let channel =
+                    |color: f32| -color.max(f32::MIN_POSITIVE).ln() / self.attenuation_distance;
+                Vector3::new(
+                    channel(self.attenuation_color.x),
+                    channel(self.attenuation_color.y),
+                    channel(self.attenuation_color.z),
+                )
+            }
+            false => Vector3::zero(),
+        };
+
+        let medium = Medium {
+            absorption,
+            scattering: self.scattering.coefficient,
+            asymmetry: self.scattering.asymmetry,
+        };
+
+        match medium.extinction() == Vector3::zero() {
+            true => None,
+            false => Some(medium),
+        }
+    }
+
+    /// Converts multiple scattering albedo to single scattering one, so that a semi-infinite slab
+    /// reflects light of the albedo (Source: "Approximate Reflectance Profiles for Efficient
+    /// Subsurface Scattering" by Christensen and Burley, as used by Cycles).
+    fn subsurface_medium(&self, albedo: Vector3) -> Medium {
+        let channel = |albedo: f32, mean_free_path: f32| {
+            let albedo = albedo.clamp(0., 0.999);
+            let s = 4.09712 + 4.20863 * albedo
+                - (9.59217 + 41.6808 * albedo + 17.7126 * albedo * albedo).sqrt();
+            let extinction = 1. / mean_free_path.max(1e-6);
+            let scattering = (1. - s * s) * extinction;
+            (extinction - scattering, scattering)
+        };
+
+        let mean_free_path = self.subsurface.mean_free_path;
+        let (r, g, b) = (
+            channel(albedo.x, mean_free_path.x),
+            channel(albedo.y, mean_free_path.y),
+            channel(albedo.z, mean_free_path.z),
+        );
+
+        Medium {
+            absorption: Vector3::new(r.0, g.0, b.0),
+            scattering: Vector3::new(r.1, g.1, b.1),
+            asymmetry: self.subsurface.asymmetry,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::medium::MediumEvent;
+
+    fn checkerboard(size: u32) -> Texture {
+        let rgba = (0..size * size)
+            .flat_map(|i| {
+                let value = match (i % size + i / size) % 2 {
+                    0 => 0,
+                    _ => 255,
+                };
+                [value, value, value, 255]
+            })
+            .collect();
+        Texture::new(size, size, Texels::U8(rgba), ColorSpace::Linear)
+    }
+
+    fn sampler(filtering: Filtering, mip_filtering: MipFiltering, wrap: WrapMode) -> Sampler {
+        Sampler {
+            filtering,
+            mip_filtering,
+            wrap_s: wrap,
+            wrap_t: WrapMode::Repeat,
+        }
+    }
+
+    #[test]
+    fn test_wrap_modes() {
+        let cases = [
+            (WrapMode::Clamp, [0, 0, 3, 3, 3]),
+            (WrapMode::Repeat, [3, 0, 3, 0, 1]),
+            (WrapMode::MirroredRepeat, [0, 0, 3, 3, 2]),
+        ];
+
+        for (mode, expected) in cases {
+            let wrapped = [-1, 0, 3, 4, 5].map(|i| mode.wrap(i, 4));
+            assert_eq!(wrapped, expected, "{:?}", mode);
+        }
+
+        // Texel just outside of the right edge.
+        let texture = checkerboard(4);
+        let uv = UvCoord::new((1.1, 0.1));
+        let nearest = |wrap| sampler(Filtering::Nearest, MipFiltering::Disabled, wrap);
+        assert_eq!(nearest(WrapMode::Clamp).sample(&texture, &uv).0, 1.);
+        assert_eq!(nearest(WrapMode::Repeat).sample(&texture, &uv).0, 0.);
+        assert_eq!(
+            nearest(WrapMode::MirroredRepeat).sample(&texture, &uv).0,
+            1.
+        );
+    }
+
+    #[test]
+    fn test_mip_pyramid() {
+        let texels = Texels::U8([255u8; 4 * 2 * 4].to_vec());
+        let texture = Texture::new(4, 2, texels, ColorSpace::Linear);
+        let sizes: Vec<_> = texture.levels.iter().map(|l| (l.width, l.height)).collect();
+        assert_eq!(sizes, [(4, 2), (2, 1), (1, 1)]);
+
+        let texture = checkerboard(8);
+        assert_eq!(texture.levels.len(), 4);
+        for level in &texture.levels[1..] {
+            assert!(
+                matches!(&level.texels, Texels::U8(v) if v.iter().step_by(4).all(|v| *v == 128))
+            );
+        }
+
+        // Levels of sRGB textures are averaged in linear space.
+        let texels = Texels::U8(vec![0, 0, 0, 255, 255, 255, 255, 255]);
+        let texture = Texture::new(2, 1, texels, ColorSpace::Srgb);
+        let (r, _, _, a) = texture.levels[1].texel(ColorSpace::Srgb, 0, 0);
+        assert!((r - 0.5).abs() < 0.01 && a == 1.);
+    }
+
+    #[test]
+    fn test_texel_formats() {
+        let cases = [
+            (
+                Texels::U8(vec![128, 255, 0, 255]),
+                ColorSpace::Linear,
+                128. / 255.,
+            ),
+            (Texels::U8(vec![128, 255, 0, 255]), ColorSpace::Srgb, 0.2158),
+            (
+                Texels::U16(vec![32768, 65535, 0, 65535]),
+                ColorSpace::Srgb,
+                0.2140,
+            ),
+            (Texels::F32(vec![4., 1., 0., 1.]), ColorSpace::Linear, 4.),
+        ];
+
+        for (texels, color_space, red) in cases {
+            let texture = Texture::new(1, 1, texels, color_space);
+            let (r, g, b, a) = texture.levels[0].texel(color_space, 0, 0);
+            assert!((r - red).abs() < 0.001, "{:?} {}", color_space, r);
+            assert_eq!((g, b, a), (1., 0., 1.));
+        }
+    }
+
+    #[test]
+    fn test_minification() {
+        let texture = checkerboard(64);
+
+        // Isotropic footprint covering many texels, and a long footprint across the checker.
+        let footprints = [((0.1, 0.), (0., 0.1)), ((0.2, 0.2), (0.005, -0.005))];
+
+        for mip_filtering in [MipFiltering::Trilinear, MipFiltering::Ewa] {
+            let sampler = sampler(Filtering::Linear, mip_filtering, WrapMode::Repeat);
+            for (duv_dx, duv_dy) in footprints {
+                let coord = UvCoord {
+                    uv: (0.3, 0.6),
+                    duv_dx,
+                    duv_dy,
+                };
+                let value = sampler.sample(&texture, &coord).0;
+                assert!(
+                    (value - 0.5).abs() < 0.05,
+                    "{:?} {}",
+                    sampler.mip_filtering,
+                    value
+                );
+            }
+        }
+
+        // Without footprint the full resolution level is sampled.
+        let sampler = sampler(Filtering::Nearest, MipFiltering::Ewa, WrapMode::Repeat);
+        let value = sampler.sample(&texture, &UvCoord::new((0.3, 0.6))).0;
+        assert!(value == 0. || value == 1.);
+    }
+
+    #[test]
+    fn test_texture_transform() {
+        let transform = TextureTransform {
+            offset: (0.5, 0.5),
+            rotation: std::f32::consts::FRAC_PI_2,
+            scale: (2., 1.),
+        };
+        let coord = transform.apply(&UvCoord {
+            uv: (0.25, 0.1),
+            duv_dx: (1., 0.),
+            duv_dy: (0., 1.),
+        });
+
+        let close =
+            |a: (f32, f32), b: (f32, f32)| (a.0 - b.0).abs() < 1e-5 && (a.1 - b.1).abs() < 1e-5;
+        assert!(close(coord.uv, (0.6, 0.)), "{:?}", coord.uv);
+        assert!(close(coord.duv_dx, (0., -2.)), "{:?}", coord.duv_dx);
+        assert!(close(coord.duv_dy, (1., 0.)), "{:?}", coord.duv_dy);
+
+        // Second UV set selects the white texel.
+        let texels = Texels::U8(vec![0, 0, 0, 255, 255, 255, 255, 255]);
+        let texture_sampler = TextureSampler {
+            texture: Arc::new(Texture::new(2, 1, texels, ColorSpace::Linear)),
+            sampler: sampler(Filtering::Nearest, MipFiltering::Disabled, WrapMode::Repeat),
+            tex_coord: 1,
+            transform: TextureTransform::default(),
+        };
+        let coord = TexCoord::new([(0.25, 0.5), (0.75, 0.5)]);
+        assert_eq!(texture_sampler.sample(&coord).0, 1.);
+    }
+
+    #[test]
+    fn test_opacity() {
+        // Alpha of 0.2 on the left texel and 0.8 on the right one.
+        let texels = || Texels::U8(vec![255, 255, 255, 51, 255, 255, 255, 204]);
+        let material = |alpha_mode| Material {
+            alpha_mode,
+            albedo_texture: Some(TextureSampler {
+                texture: Arc::new(Texture::new(2, 1, texels(), ColorSpace::Linear)),
+                sampler: sampler(Filtering::Nearest, MipFiltering::Disabled, WrapMode::Clamp),
+                tex_coord: 0,
+                transform: TextureTransform::default(),
+            }),
+            ..Material::default()
+        };
+        let left = TexCoord::new([(0.25, 0.5); UV_SETS]);
+        let right = TexCoord::new([(0.75, 0.5); UV_SETS]);
+
+        let opaque = material(AlphaMode::Opaque);
+        assert_eq!(opaque.opacity(&left), 1.);
+
+        let mask = material(AlphaMode::Mask(0.5));
+        assert_eq!(mask.opacity(&left), 0.);
+        assert_eq!(mask.opacity(&right), 1.);
+
+        let blend = material(AlphaMode::Blend);
+        assert!((blend.opacity(&left) - 0.2).abs() < 1e-6);
+        assert!((blend.opacity(&right) - 0.8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_subsurface_medium() {
+        let material = |thickness| Material {
+            thickness,
+            subsurface: Subsurface {
+                factor: 1.,
+                color: Vector3::new(0., 0.5, 0.8),
+                mean_free_path: Vector3::new(0.5, 0.5, 0.5),
+                ..Default::default()
+            },
+            ..Material::default()
+        };
+        let coord = TexCoord::default();
+
+        assert!(material(0.).medium(&coord).is_none());
+        assert!(material(0.).subsurface(&coord).0 == 0.);
+
+        let medium = material(1.).medium(&coord).unwrap();
+        let extinction = medium.extinction();
+        assert!((extinction - Vector3::new(2., 2., 2.)).length() < 1e-4);
+        assert!(medium.scattering.x < 1e-4);
+
+        // Random walks entering a half-space from diffuse illumination leave it with about the
+        // probability of the multiple scattering albedo.
+        let sampler = crate::random::UniformSampler::new();
+        let count = 20000;
+        let mut reflected = Vector3::zero();
+        for _ in 0..count {
+            let (mut position, mut direction) =
+                (Vector3::zero(), -crate::math::sample_hemisphere(&sampler).0);
+            let mut weight = Vector3::one();
+            for _ in 0..10000 {
+                let length = match direction.z > 0. {
+                    true => -position.z / direction.z,
+                    false => f32::INFINITY,
+                };
+                match medium.event(medium.free_flight(&sampler), length) {
+                    MediumEvent::Pass { weight: pass } => {
+                        reflected += weight.mul(pass);
+                        break;
+                    }
+                    MediumEvent::Scatter {
+                        distance,
+                        weight: scatter,
+                    } => {
+                        weight = weight.mul(scatter);
+                        position += direction * distance;
+                        direction =
+                            crate::medium::sample_henyey_greenstein(&direction, 0., &sampler);
+                    }
+                }
+            }
+        }
+        reflected /= count as f32;
+
+        assert!(reflected.x < 1e-3);
+        assert!((reflected.y - 0.5).abs() < 0.05, "{:?}", reflected);
+        assert!((reflected.z - 0.8).abs() < 0.05, "{:?}", reflected);
+    }
 }
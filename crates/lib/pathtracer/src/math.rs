@@ -1,9 +1,7 @@
 use crate::consts::*;
-use crate::material::Material;
-use crate::random::{Sampler, UniformSampler};
+use crate::random::Sampler;
 
 use std::ops::{Add, Mul};
-use std::sync::Arc;
 
 use cgmath::*;
 
@@ -55,6 +53,22 @@ pub fn luminance(rgb: Vector3) -> f32 {
     rgb.dot(LUMINANCE)
 }
 
+/// Decodes value of sRGB transfer function.
+pub fn srgb_to_linear(value: f32) -> f32 {
+    match value <= 0.04045 {
+        true => value / 12.92,
+        false => ((value + 0.055) / 1.055).powf(2.4),
+    }
+}
+
+/// Encodes linear value with sRGB transfer function.
+pub fn linear_to_srgb(value: f32) -> f32 {
+    match value <= 0.003_130_8 {
+        true => value * 12.92,
+        false => 1.055 * value.powf(1. / 2.4) - 0.055,
+    }
+}
+
 #[inline]
 pub fn lerp_scalar(v1: f32, v2: f32, f: f32) -> f32 {
     (1. - f) * v1 + f * v2
@@ -151,27 +165,6 @@ impl EnhancedVector<f32> for Vector3 {
     }
 }
 
-// Sphere
-pub struct Sphere {
-    pub center: Vector3,
-    pub radius: f32,
-    pub material: Arc<Material>,
-}
-
-impl Sphere {
-    pub fn position_radius(x: f32, y: f32, z: f32, radius: f32, material: Arc<Material>) -> Sphere {
-        Sphere {
-            center: Vector3 { x, y, z },
-            radius,
-            material,
-        }
-    }
-
-    pub fn normal(&self, position: Vector3) -> Vector3 {
-        (position - self.center).unit()
-    }
-}
-
 pub struct Average {
     spp: i32,
     prev_pass_add: f32,
@@ -222,12 +215,86 @@ impl Average {
     }
 }
 
+/// Orthonormal basis around a normal.
+pub struct TangentFrame {
+    pub t: Vector3,
+    pub b: Vector3,
+    pub n: Vector3,
+}
+
+impl TangentFrame {
+    /// Creates frame with tangent closest to given direction, arbitrary one if it is degenerate.
+    pub fn new(n: Vector3, tangent: Vector3) -> TangentFrame {
+        let projected = tangent - n * n.dot(tangent);
+
+        let t = match projected.squared_length() > 1e-8 {
+            true => projected.unit(),
+            false => {
+                // Source: "Building an Orthonormal Basis, Revisited" by Duff et al.
+                let sign = 1f32.copysign(n.z);
+                let a = -1. / (sign + n.z);
+                Vector3::new(1. + sign * n.x * n.x * a, sign * n.x * n.y * a, -sign * n.x)
+            }
+        };
+
+        TangentFrame {
+            t,
+            b: n.cross(t),
+            n,
+        }
+    }
+
+    pub fn to_local(&self, v: Vector3) -> Vector3 {
+        Vector3::new(self.t.dot(v), self.b.dot(v), self.n.dot(v))
+    }
+
+    pub fn to_world(&self, v: Vector3) -> Vector3 {
+        self.t * v.x + self.b * v.y + self.n * v.z
+    }
+}
+
 pub fn schlick(cosine: f32, ref_index: f32) -> f32 {
     let r0 = (1. - ref_index) / (1. + ref_index);
     let r0 = r0 * r0;
     r0 + (1. - r0) * (1. - cosine).powf(5.)
 }
 
+// Exact Fresnel reflectance of unpolarized light, eta is ratio of IOR behind and in front of the surface.
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin_t_squared = (1. - cos_i * cos_i) / (eta * eta);
+    if sin_t_squared >= 1. {
+        // Total internal reflection.
+        return 1.;
+    }
+
+    let cos_t = (1. - sin_t_squared).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+
+    0.5 * (rs * rs + rp * rp)
+}
+
+// Exact Fresnel reflectance of unpolarized light on a conductor of complex IOR eta + ik.
+// Source: PBRT v3, 8.2.1.
+pub fn fresnel_conductor(cos_i: f32, eta: f32, k: f32) -> f32 {
+    let cos_squared = cos_i * cos_i;
+    let sin_squared = 1. - cos_squared;
+    let (eta_squared, k_squared) = (eta * eta, k * k);
+
+    let t0 = eta_squared - k_squared - sin_squared;
+    let a2_plus_b2 = (t0 * t0 + 4. * eta_squared * k_squared).sqrt();
+    let t1 = a2_plus_b2 + cos_squared;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.).sqrt();
+    let t2 = 2. * cos_i * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos_squared * a2_plus_b2 + sin_squared * sin_squared;
+    let t4 = t2 * sin_squared;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
+
 pub fn reflect(dir: Vector3, normal: Vector3) -> Vector3 {
     dir - 2.0 * cgmath::dot(dir, normal) * normal
 }
@@ -250,6 +317,12 @@ pub fn base_color_to_specular_f0(base_color: Vector3, metalness: f32) -> Vector3
     lerp(MIN_DIELECTRICS_F0_VEC, base_color, metalness)
 }
 
+// Reflectance at normal incidence of dielectric with given index of refraction, in air.
+pub fn ior_to_f0(ior: f32) -> f32 {
+    let f0 = (ior - 1.) / (ior + 1.);
+    f0 * f0
+}
+
 pub fn base_color_to_diffuse_reflectance(base_color: Vector3, metalness: f32) -> Vector3 {
     base_color * (1. - metalness)
 }
@@ -310,7 +383,7 @@ pub fn rotate_point(q: Quaternion, v: Vector3) -> Vector3 {
 
 // Samples a direction within a hemisphere oriented along +Z axis with a cosine-weighted distribution
 // Source: "Sampling Transformations Zoo" in Ray Tracing Gems by Shirley et al.
-pub fn sample_hemisphere(sampler: &UniformSampler) -> (Vector3, f32) {
+pub fn sample_hemisphere(sampler: &dyn Sampler) -> (Vector3, f32) {
     let (ux, uy) = (sampler.next_float(), sampler.next_float());
 
     let a = ux.sqrt();
@@ -321,3 +394,39 @@ pub fn sample_hemisphere(sampler: &UniformSampler) -> (Vector3, f32) {
 
     (result, result.z * ONE_OVER_PI)
 }
+
+// Inverse of the error function, maps uniform numbers to normally distributed ones.
+// Source: "Approximating the erfinv function" by Giles
+pub fn erf_inv(x: f32) -> f32 {
+    const CENTRAL: [f32; 9] = [
+        2.810_226_4e-8,
+        3.432_739_4e-7,
+        -3.523_387_7e-6,
+        -4.391_506_5e-6,
+        2.185_808_7e-4,
+        -1.253_725e-3,
+        -4.177_681_6e-3,
+        0.246_640_73,
+        1.501_409_4,
+    ];
+    const TAIL: [f32; 9] = [
+        -2.002_142_6e-4,
+        1.009_505_6e-4,
+        1.349_343_2e-3,
+        -3.673_428_4e-3,
+        5.739_507_7e-3,
+        -7.622_461e-3,
+        9.438_870_5e-3,
+        1.001_674,
+        2.832_976_8,
+    ];
+
+    let x = clamp(x, -0.999_999, 0.999_999);
+    let w = -((1. - x) * (1. + x)).ln();
+    let (coefficients, w) = match w < 5. {
+        true => (&CENTRAL, w - 2.5),
+        false => (&TAIL, w.sqrt() - 3.),
+    };
+
+    coefficients.iter().fold(0., |p, c| c + p * w) * x
+}
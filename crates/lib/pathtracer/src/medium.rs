@@ -0,0 +1,205 @@
+use crate::math::*;
+use crate::random::Sampler;
+
+use std::f32::consts::PI;
+
+/// Homogeneous participating medium, coefficients are given per unit of scene distance.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Medium {
+    pub absorption: Vector3,
+    pub scattering: Vector3,
+    /// Asymmetry of the Henyey-Greenstein phase function, positive values scatter forward.
+    pub asymmetry: f32,
+}
+
+/// Result of free-flight sampling along a segment of a ray.
+#[derive(Debug, Copy, Clone)]
+pub enum MediumEvent {
+    /// Light is scattered at given distance, weight includes the albedo.
+    Scatter { distance: f32, weight: Vector3 },
+    /// The whole segment is passed through.
+    Pass { weight: Vector3 },
+}
+
+impl Medium {
+    pub fn extinction(&self) -> Vector3 {
+        self.absorption + self.scattering
+    }
+
+    pub fn is_scattering(&self) -> bool {
+        self.scattering != Vector3::zero()
+    }
+
+    /// Returns Beer-Lambert transmittance of given distance.
+    pub fn transmittance(&self, distance: f32) -> Vector3 {
+        let extinction = self.extinction();
+        let channel = |sigma: f32| match sigma {
+            sigma if sigma <= 0. => 1.,
+            sigma => (-sigma * distance).exp(),
+        };
+
+        Vector3::new(
+            channel(extinction.x),
+            channel(extinction.y),
+            channel(extinction.z),
+        )
+    }
+
+    /// Samples distance to the next scattering event within segment of given length.
+    pub fn sample_distance(&self, length: f32, sampler: &impl Sampler) -> MediumEvent {
+        self.event(self.free_flight(sampler), length)
+    }
+
+    /// Samples free-flight distance, infinite in media without scattering which only attenuate.
+    ///
+    /// A color channel is picked uniformly and the distance follows its transmittance, weights
+    /// of events are divided by pdf averaged over all channels (Source: PBRT v3, 15.2.2).
+    pub fn free_flight(&self, sampler: &impl Sampler) -> f32 {
+        if !self.is_scattering() {
+            return f32::INFINITY;
+        }
+
+        let channel = ((sampler.next_float() * 3.) as usize).min(2);
+        -(1. - sampler.next_float()).ln() / self.extinction()[channel]
+    }
+
+    /// Returns event of given free-flight distance within segment of given length.
+    pub fn event(&self, distance: f32, length: f32) -> MediumEvent {
+        if distance >= length {
+            return MediumEvent::Pass {
+                weight: self.pass_weight(length),
+            };
+        }
+
+        let transmittance = self.transmittance(distance);
+        let pdf = average(self.extinction().mul(transmittance));
+        MediumEvent::Scatter {
+            distance,
+            weight: divide(self.scattering.mul(transmittance), pdf),
+        }
+    }
+
+    /// Returns weight of free flights passing through segment of given length.
+    pub fn pass_weight(&self, length: f32) -> Vector3 {
+        let transmittance = self.transmittance(length);
+        match self.is_scattering() {
+            true => divide(transmittance, average(transmittance)),
+            false => transmittance,
+        }
+    }
+}
+
+fn average(v: Vector3) -> f32 {
+    (v.x + v.y + v.z) / 3.
+}
+
+fn divide(v: Vector3, pdf: f32) -> Vector3 {
+    match pdf > 0. {
+        true => v / pdf,
+        false => Vector3::zero(),
+    }
+}
+
+/// Henyey-Greenstein phase function of cosine between directions of incoming and scattered light.
+pub fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denominator = 1. + g * g - 2. * g * cos_theta;
+    (1. - g * g) / (4. * PI * denominator * denominator.max(1e-8).sqrt())
+}
+
+/// Samples direction of light travelling in `direction` after scattering with Henyey-Greenstein
+/// phase function, which is sampled exactly so the weight is one.
+pub fn sample_henyey_greenstein(direction: &Vector3, g: f32, sampler: &impl Sampler) -> Vector3 {
+    let u = sampler.next_float();
+
+    let cos_theta = match g.abs() < 1e-3 {
+        true => 1. - 2. * u,
+        false => {
+            let square = (1. - g * g) / (1. - g + 2. * g * u);
+            (1. + g * g - square * square) / (2. * g)
+        }
+    }
+    .clamp(-1., 1.);
+
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let phi = 2. * PI * sampler.next_float();
+
+    let frame = TangentFrame::new(*direction, Vector3::zero());
+    frame.to_world(Vector3::new(
+        sin_theta * phi.cos(),
+        sin_theta * phi.sin(),
+        cos_theta,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::UniformSampler;
+
+    #[test]
+    fn test_phase_function() {
+        let sampler = UniformSampler::new();
+
+        for g in [-0.7, 0., 0.3, 0.9] {
+            // Integral over the sphere is one.
+            let steps = 20000;
+            let integral: f32 = (0..steps)
+                .map(|i| {
+                    let cos_theta = -1. + 2. * (i as f32 + 0.5) / steps as f32;
+                    henyey_greenstein(cos_theta, g) * 2. * PI * 2. / steps as f32
+                })
+                .sum();
+            assert!(
+                (integral - 1.).abs() < 1e-2,
+                "g {} integral {}",
+                g,
+                integral
+            );
+
+            // Mean cosine of sampled directions equals asymmetry.
+            let direction = Vector3::new(0.48, 0.6, 0.64);
+            let count = 50000;
+            let mean = (0..count)
+                .map(|_| cgmath::dot(direction, sample_henyey_greenstein(&direction, g, &sampler)))
+                .sum::<f32>()
+                / count as f32;
+            assert!((mean - g).abs() < 2e-2, "g {} mean cosine {}", g, mean);
+        }
+    }
+
+    #[test]
+    fn test_free_flight() {
+        let sampler = UniformSampler::new();
+        let medium = Medium {
+            absorption: Vector3::new(0.1, 0.2, 0.),
+            scattering: Vector3::new(0.5, 0.1, 1.),
+            asymmetry: 0.,
+        };
+
+        // Passed through weights estimate transmittance of the segment, scattered ones estimate
+        // its complement weighted by single scattering albedo.
+        let length = 1.5;
+        let count = 100000;
+        let (mut passed, mut scattered) = (Vector3::zero(), Vector3::zero());
+        for _ in 0..count {
+            match medium.sample_distance(length, &sampler) {
+                MediumEvent::Pass { weight } => passed += weight,
+                MediumEvent::Scatter { distance, weight } => {
+                    assert!(distance < length);
+                    scattered += weight;
+                }
+            }
+        }
+        passed /= count as f32;
+        scattered /= count as f32;
+
+        let transmittance = medium.transmittance(length);
+        let extinction = medium.extinction();
+        for channel in 0..3 {
+            let albedo = medium.scattering[channel] / extinction[channel];
+            let expected = albedo * (1. - transmittance[channel]);
+            assert!((passed[channel] - transmittance[channel]).abs() < 1e-2);
+            assert!((scattered[channel] - expected).abs() < 1e-2);
+        }
+    }
+}
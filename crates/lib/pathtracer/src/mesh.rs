@@ -1,4 +1,4 @@
-use crate::material::Material;
+use crate::material::{Material, UV_SETS};
 use crate::math::*;
 
 use kdtree_ray::*;
@@ -7,14 +7,14 @@ use std::{ops::Mul, sync::Arc};
 #[derive(Copy, Clone, Debug)]
 pub struct Vertex {
     pub pos: Vector3,
-    pub uv: (f32, f32),
+    pub uv: [(f32, f32); UV_SETS],
 }
 
 impl Vertex {
     pub fn new() -> Vertex {
         Vertex {
             pos: Vector3::zero(),
-            uv: (0., 0.),
+            uv: [(0., 0.); UV_SETS],
         }
     }
 }
@@ -0,0 +1,351 @@
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+
+use crate::math::*;
+use crate::pathtracer::{Integrator, Splat, Tracer};
+use crate::random::Sampler;
+use crate::threadpool::ThreadPool;
+
+// Primary sample space Metropolis light transport.
+// Source: "A Simple and Robust Mutation Strategy for the Metropolis Light Transport Algorithm" by Kelemen et al.
+// Source: https://pbr-book.org/3ed-2018/Light_Transport_III_Bidirectional_Methods/Metropolis_Light_Transport
+// Markov chains mutate random numbers drawn by the path tracer, including the point of the image,
+// and visit paths in proportion to their luminance. Large steps draw all numbers anew, small steps
+// perturb them. A bootstrap phase of independent paths estimates the integral of luminance, which
+// scales contributions, and selects starting states of the chains. Every call advances one of the
+// chains by a mutation and splats both the proposed and the current path weighted by the
+// probability of acceptance, so light arrives at the image only through splats.
+pub struct Metropolis<'a> {
+    tracer: &'a Tracer,
+    chains: Option<&'a MarkovChains>,
+}
+
+/// Standard deviation of small step perturbations.
+const SIGMA: f32 = 0.01;
+
+struct PrimarySample {
+    value: f32,
+    /// Iteration of the last change of the value.
+    modified: u64,
+    backup: (f32, u64),
+}
+
+/// Sampler of random numbers which are mutated from iteration to iteration.
+///
+/// Numbers are mutated lazily when they are drawn, numbers not drawn for several iterations
+/// catch up with the large steps and the perturbations they missed.
+struct PrimarySampler {
+    rng: RefCell<StdRng>,
+    samples: RefCell<Vec<PrimarySample>>,
+    /// Index of the next number drawn in the current iteration.
+    index: Cell<usize>,
+    iteration: u64,
+    last_large_step: u64,
+    large_step: bool,
+    large_step_probability: f32,
+}
+
+impl PrimarySampler {
+    /// Creates sampler whose first iteration draws independent numbers determined by the seed.
+    fn new(seed: u64, large_step_probability: f32) -> Self {
+        Self {
+            rng: RefCell::new(StdRng::seed_from_u64(seed)),
+            samples: RefCell::new(Vec::new()),
+            index: Cell::new(0),
+            iteration: 0,
+            last_large_step: 0,
+            large_step: true,
+            large_step_probability,
+        }
+    }
+
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.get_mut().gen::<f32>() < self.large_step_probability;
+        self.index.set(0);
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for sample in self.samples.get_mut() {
+            if sample.modified == self.iteration {
+                (sample.value, sample.modified) = sample.backup;
+            }
+        }
+        self.iteration -= 1;
+    }
+}
+
+impl Sampler for PrimarySampler {
+    fn next_float(&self) -> f32 {
+        let mut rng = self.rng.borrow_mut();
+        let mut samples = self.samples.borrow_mut();
+        let index = self.index.replace(self.index.get() + 1);
+
+        // Numbers not drawn before are as independent as those drawn by the last large step.
+        if index == samples.len() {
+            samples.push(PrimarySample {
+                value: rng.gen(),
+                modified: self.last_large_step,
+                backup: (0., 0),
+            });
+        }
+
+        let sample = &mut samples[index];
+        if sample.modified < self.last_large_step {
+            sample.value = rng.gen();
+            sample.modified = self.last_large_step;
+        }
+        sample.backup = (sample.value, sample.modified);
+
+        if self.large_step {
+            sample.value = rng.gen();
+        } else {
+            // Perturbations of missed iterations add up to a wider normal distribution.
+            let small_steps = (self.iteration - sample.modified) as f32;
+            let normal = std::f32::consts::SQRT_2 * erf_inv(2. * rng.gen::<f32>() - 1.);
+            sample.value += normal * SIGMA * small_steps.sqrt();
+            sample.value -= sample.value.floor();
+        }
+        sample.modified = self.iteration;
+
+        sample.value
+    }
+
+    fn next_float_norm(&self) -> f32 {
+        2. * self.next_float() - 1.
+    }
+}
+
+/// Path through a point of the image with its radiance.
+struct State {
+    x: f32,
+    y: f32,
+    color: Vector3,
+    luminance: f32,
+}
+
+impl State {
+    /// Returns splat of the path contributing given part of the integral of luminance.
+    fn splat(&self, weight: f32) -> Splat {
+        Splat {
+            x: self.x,
+            y: self.y,
+            color: self.color * (weight / self.luminance),
+        }
+    }
+}
+
+struct Chain {
+    sampler: PrimarySampler,
+    /// None until the starting state found by the bootstrap is traced again.
+    current: Option<State>,
+}
+
+/// Markov chains which continue from pass to pass, so that the image keeps accumulating.
+pub(crate) struct MarkovChains {
+    chains: Vec<Mutex<Chain>>,
+    /// Integral of luminance over the primary sample space estimated by the bootstrap.
+    normalization: f32,
+    /// Chain advanced by the next call, calls take the chains in turn.
+    next: AtomicUsize,
+}
+
+/// Traces path for the random numbers of the sampler.
+fn evaluate(tracer: &Tracer, sampler: &PrimarySampler, pixel_size: (f32, f32)) -> State {
+    let (x, y) = (sampler.next_float(), sampler.next_float());
+    let color = tracer.trace_path(x, y, pixel_size, sampler);
+
+    // Paths of invalid radiance are never visited.
+    match luminance(color) {
+        luminance if luminance.is_finite() && luminance > 0. => State {
+            x,
+            y,
+            color,
+            luminance,
+        },
+        _ => State {
+            x,
+            y,
+            color: Vector3::zero(),
+            luminance: 0.,
+        },
+    }
+}
+
+impl MarkovChains {
+    /// Traces independent bootstrap paths in parallel and starts chains at paths selected in
+    /// proportion to their luminance.
+    ///
+    /// Bootstrap paths are traced without texture filtering, since only their luminance matters.
+    pub(crate) fn bootstrap(tracer: &Tracer) -> Self {
+        let settings = tracer.settings();
+        let count = settings.bootstrap_paths as u64;
+        let large_step_probability = settings.large_step_probability;
+        let seed: u64 = thread_rng().gen();
+        let threads = ThreadPool::thread_count() as u64;
+
+        let luminances: Vec<f32> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|thread| {
+                    let paths = (count * thread / threads)..(count * (thread + 1) / threads);
+                    scope.spawn(move || {
+                        paths
+                            .map(|path| {
+                                let sampler = PrimarySampler::new(
+                                    seed.wrapping_add(path),
+                                    large_step_probability,
+                                );
+                                evaluate(tracer, &sampler, (0., 0.)).luminance
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("bootstrap should not panic"))
+                .collect()
+        });
+
+        let cumulative: Vec<f64> = luminances
+            .iter()
+            .scan(0., |sum, luminance| {
+                *sum += *luminance as f64;
+                Some(*sum)
+            })
+            .collect();
+        let total = cumulative.last().copied().unwrap_or(0.);
+
+        let chains = match total > 0. {
+            true => (0..settings.markov_chains)
+                .map(|_| {
+                    let u = thread_rng().gen::<f64>() * total;
+                    let path = cumulative.partition_point(|sum| *sum <= u);
+                    let path = path.min(cumulative.len() - 1) as u64;
+
+                    Mutex::new(Chain {
+                        sampler: PrimarySampler::new(
+                            seed.wrapping_add(path),
+                            large_step_probability,
+                        ),
+                        current: None,
+                    })
+                })
+                .collect(),
+            false => Vec::new(),
+        };
+
+        Self {
+            chains,
+            normalization: (total / count.max(1) as f64) as f32,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<'a> Metropolis<'a> {
+    /// Creates integrator advancing given chains, without them it finds no light.
+    pub fn new(tracer: &'a Tracer, chains: Option<&'a MarkovChains>) -> Self {
+        Self { tracer, chains }
+    }
+}
+
+impl Integrator for Metropolis<'_> {
+    fn radiance(
+        &self,
+        _x: f32,
+        _y: f32,
+        pixel_size: (f32, f32),
+        splats: &mut Vec<Splat>,
+    ) -> Vector3 {
+        let chains = match self.chains {
+            Some(chains) if !chains.chains.is_empty() => chains,
+            _ => return Vector3::zero(),
+        };
+
+        let index = chains.next.fetch_add(1, Ordering::Relaxed) % chains.chains.len();
+        let mut chain = chains.chains[index]
+            .lock()
+            .expect("chain should not be poisoned");
+        let chain = &mut *chain;
+
+        // Sampler replays the numbers of the bootstrap path in its first iteration.
+        let current = match chain.current.take() {
+            Some(current) => current,
+            None => evaluate(self.tracer, &chain.sampler, pixel_size),
+        };
+
+        chain.sampler.start_iteration();
+        let proposed = evaluate(self.tracer, &chain.sampler, pixel_size);
+
+        let acceptance = match current.luminance > 0. {
+            true => (proposed.luminance / current.luminance).min(1.),
+            false => 1.,
+        };
+
+        // Expected contributions of both states.
+        if proposed.luminance > 0. {
+            splats.push(proposed.splat(acceptance * chains.normalization));
+        }
+        if current.luminance > 0. && acceptance < 1. {
+            splats.push(current.splat((1. - acceptance) * chains.normalization));
+        }
+
+        chain.current = match thread_rng().gen::<f32>() < acceptance {
+            true => {
+                chain.sampler.accept();
+                Some(proposed)
+            }
+            false => {
+                chain.sampler.reject();
+                Some(current)
+            }
+        };
+
+        Vector3::zero()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_primary_sampler() {
+        // Seed determines numbers of the first iteration.
+        let mut sampler = PrimarySampler::new(7, 0.);
+        let replay = PrimarySampler::new(7, 0.);
+        let first: Vec<f32> = (0..4).map(|_| sampler.next_float()).collect();
+        let replayed: Vec<f32> = (0..4).map(|_| replay.next_float()).collect();
+        assert_eq!(first, replayed);
+
+        // Small steps stay close and rejection restores the numbers.
+        sampler.start_iteration();
+        for value in &first {
+            let distance = (sampler.next_float() - value).abs();
+            assert!(distance.min(1. - distance) < 10. * SIGMA);
+        }
+        sampler.reject();
+
+        let restored: Vec<f32> = sampler.samples.borrow().iter().map(|s| s.value).collect();
+        assert_eq!(restored, first);
+    }
+
+    #[test]
+    fn test_erf_inv() {
+        for (x, expected) in [(0., 0.), (0.5, 0.476_936_3), (-0.9, -1.163_087_1)] {
+            assert!((erf_inv(x) - expected).abs() < 1e-5);
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use crate::brdf::ResolvedMaterial;
 use crate::math::*;
 
-use cgmath::dot;
+use std::sync::OnceLock;
+
+// Smooth surfaces are rendered as very narrow GGX lobes, so that eval and pdf stay finite.
+pub const MIN_ALPHA: f32 = 0.001;
 
 pub struct BrdfData {
     // Material properties
@@ -9,9 +12,10 @@ pub struct BrdfData {
     pub diffuse_reflectance: Vector3,
 
     // Roughnesses
-    pub roughness: f32,     //< perceptively linear roughness (artist's input)
-    pub alpha: f32,         //< linear roughness - often 'alpha' in specular BRDF equations
+    pub roughness: f32,       //< perceptively linear roughness (artist's input)
+    pub alpha: f32,           //< linear roughness - often 'alpha' in specular BRDF equations
     pub alpha_squared: f32, //< alpha squared - pre-calculated value commonly used in BRDF equations
+    pub alpha_2d: (f32, f32), //< alpha along tangent and bitangent of anisotropic material
 
     // Commonly used terms for BRDF evaluation
     pub f: Vector3, //< Fresnel term
@@ -19,6 +23,7 @@ pub struct BrdfData {
     // Vectors
     pub v: Vector3, //< Direction to viewer (or opposite direction of incident ray)
     pub n: Vector3, //< Shading normal
+    pub frame: TangentFrame, //< Shading normal with tangent along direction of anisotropy
     pub h: Vector3, //< Half vector (microfacet normal)
     pub l: Vector3, //< Direction to light (or direction of reflecting ray)
 
@@ -55,18 +60,26 @@ pub fn prepare_brdf_data(
     let n_dot_h = saturate(cgmath::dot(n, h));
     let v_dot_h = saturate(cgmath::dot(v, h));
 
-    // Unpack material properties
-    let specular_f0 = base_color_to_specular_f0(material.base_color, material.metalness);
-    let diffuse_reflectance =
-        base_color_to_diffuse_reflectance(material.base_color, material.metalness);
+    // Unpack material properties, reflectance of dielectrics is given by IOR and KHR_materials_specular
+    let dielectric_f0 = (to_v3(ior_to_f0(material.ior)).mul(material.specular_color))
+        .min(Vector3::one())
+        * material.specular;
+    let (specular_f0, diffuse_reflectance) = match material.conductor {
+        Some((eta, k)) => (conductor_fresnel(eta, k, 1.), Vector3::zero()),
+        None => (
+            lerp(dielectric_f0, material.base_color, material.metalness),
+            base_color_to_diffuse_reflectance(material.base_color, material.metalness),
+        ),
+    };
 
     // Unpack 'perceptively linear' -> 'linear' -> 'squared' roughness
     let roughness = material.roughness;
-    let alpha = material.roughness * material.roughness;
+    let alpha = (material.roughness * material.roughness).max(MIN_ALPHA);
     let alpha_squared = alpha * alpha;
+    let alpha_2d = anisotropic_alpha(alpha, material.anisotropy);
 
     // Pre-calculate some more BRDF terms
-    let f = eval_fresnel(specular_f0, to_v3(shadowed_f90(specular_f0)), l_dot_h);
+    let f = eval_specular_fresnel(material, specular_f0, l_dot_h);
 
     BrdfData {
         specular_f0,
@@ -74,9 +87,11 @@ pub fn prepare_brdf_data(
         roughness,
         alpha,
         alpha_squared,
+        alpha_2d,
         f,
         v,
         n,
+        frame: TangentFrame::new(n, material.anisotropy_direction),
         h,
         l,
         n_dot_l,
@@ -89,6 +104,166 @@ pub fn prepare_brdf_data(
     }
 }
 
+// Exact Fresnel term of a conductor per channel
+fn conductor_fresnel(eta: Vector3, k: Vector3, n_dot_s: f32) -> Vector3 {
+    Vector3::new(
+        fresnel_conductor(n_dot_s, eta.x, k.x),
+        fresnel_conductor(n_dot_s, eta.y, k.y),
+        fresnel_conductor(n_dot_s, eta.z, k.z),
+    )
+}
+
+// Fresnel term of the specular layer, thin-film interference replaces it by strength of iridescence
+// Conductors of measured complex IOR use the exact Fresnel equations instead of Schlick's approximation
+pub fn eval_specular_fresnel(
+    material: &ResolvedMaterial,
+    specular_f0: Vector3,
+    n_dot_s: f32,
+) -> Vector3 {
+    let f = match material.conductor {
+        Some((eta, k)) => conductor_fresnel(eta, k, n_dot_s),
+        None => eval_fresnel(specular_f0, to_v3(shadowed_f90(specular_f0)), n_dot_s),
+    };
+
+    match material.iridescence > 0. {
+        true => lerp(
+            f,
+            eval_iridescence(
+                1.,
+                material.iridescence_ior,
+                n_dot_s,
+                material.iridescence_thickness,
+                specular_f0,
+            ),
+            material.iridescence,
+        ),
+        false => f,
+    }
+}
+
+// -------------------------------------------------------------------------
+//    Thin-film iridescence
+// -------------------------------------------------------------------------
+
+#[inline]
+fn ior_to_fresnel0(transmitted_ior: f32, incident_ior: f32) -> f32 {
+    let f0 = (transmitted_ior - incident_ior) / (transmitted_ior + incident_ior);
+    f0 * f0
+}
+
+#[inline]
+fn fresnel0_to_ior(f0: f32) -> f32 {
+    let sqrt_f0 = f0.sqrt();
+    (1. + sqrt_f0) / (1. - sqrt_f0)
+}
+
+#[inline]
+fn schlick_scalar(f0: f32, n_dot_s: f32) -> f32 {
+    f0 + (1. - f0) * (1. - n_dot_s).powf(5.)
+}
+
+// Fourier transform of CIE color matching functions for given optical path difference (nm) and phase shift, in linear sRGB
+fn eval_sensitivity(opd: f32, shift: Vector3) -> Vector3 {
+    let phase = TWO_PI * opd * 1.0e-9;
+    let val = [5.4856e-13f32, 4.4201e-13, 5.2481e-13];
+    let pos = [1.6810e+06f32, 1.7953e+06, 2.2084e+06];
+    let var = [4.3278e+09f32, 9.3046e+09, 6.6121e+09];
+    let shift = [shift.x, shift.y, shift.z];
+
+    let mut xyz = [0f32; 3];
+    for i in 0..3 {
+        xyz[i] = val[i]
+            * (TWO_PI * var[i]).sqrt()
+            * (pos[i] * phase + shift[i]).cos()
+            * (-phase * phase * var[i]).exp();
+    }
+    xyz[0] += 9.7470e-14
+        * (TWO_PI * 4.5282e+09f32).sqrt()
+        * (2.2399e+06 * phase + shift[0]).cos()
+        * (-4.5282e+09 * phase * phase).exp();
+
+    let (x, y, z) = (xyz[0] / 1.0685e-7, xyz[1] / 1.0685e-7, xyz[2] / 1.0685e-7);
+    Vector3::new(
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.969_266 * x + 1.8760108 * y + 0.0415560 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    )
+}
+
+// Reflectance of the base covered by thin film of given IOR and thickness (nm), as in KHR_materials_iridescence
+// Source: "A Practical Extension to Microfacet Theory for the Modeling of Varying Iridescence" by Belcour and Barla
+pub fn eval_iridescence(
+    outside_ior: f32,
+    film_ior: f32,
+    cos_theta1: f32,
+    thickness: f32,
+    base_f0: Vector3,
+) -> Vector3 {
+    // Film vanishes as its thickness goes to zero
+    let t = saturate(thickness / 30.);
+    let film_ior = lerp_scalar(outside_ior, film_ior, t * t * (3. - 2. * t));
+
+    // Angle of refraction inside of the film
+    let sin_theta2_squared =
+        (outside_ior / film_ior) * (outside_ior / film_ior) * (1. - cos_theta1 * cos_theta1);
+    let cos_theta2_squared = 1. - sin_theta2_squared;
+    if cos_theta2_squared < 0. {
+        // Total internal reflection
+        return Vector3::one();
+    }
+    let cos_theta2 = cos_theta2_squared.sqrt();
+
+    // First interface
+    let r12 = schlick_scalar(ior_to_fresnel0(film_ior, outside_ior), cos_theta1);
+    let t121 = 1. - r12;
+    let phi12 = match film_ior < outside_ior {
+        true => std::f32::consts::PI,
+        false => 0.,
+    };
+    let phi21 = std::f32::consts::PI - phi12;
+
+    // Second interface, IOR of the base is derived from its reflectance
+    let base = |f0: f32| {
+        let base_ior = fresnel0_to_ior(clamp(f0, 0., 0.9999));
+        let r23 = schlick_scalar(ior_to_fresnel0(base_ior, film_ior), cos_theta2);
+        let phi23 = match base_ior < film_ior {
+            true => std::f32::consts::PI,
+            false => 0.,
+        };
+        (r23, phi23)
+    };
+    let (r23, phi23) = {
+        let (x, y, z) = (base(base_f0.x), base(base_f0.y), base(base_f0.z));
+        (Vector3::new(x.0, y.0, z.0), Vector3::new(x.1, y.1, z.1))
+    };
+
+    // Phase shift
+    let opd = 2. * film_ior * thickness * cos_theta2;
+    let phi = to_v3(phi21) + phi23;
+
+    // Compound terms
+    let r123 = (r23 * r12).clamp(1e-5, 0.9999);
+    let sqrt_r123 = Vector3::new(r123.x.sqrt(), r123.y.sqrt(), r123.z.sqrt());
+    let rs = Vector3::new(
+        t121 * t121 * r23.x / (1. - r123.x),
+        t121 * t121 * r23.y / (1. - r123.y),
+        t121 * t121 * r23.z / (1. - r123.z),
+    );
+
+    // Reflectance term for m = 0 (DC term amplitude)
+    let mut reflectance = to_v3(r12) + rs;
+
+    // Reflectance terms for m > 0 (pairs of diracs)
+    let mut cm = rs - to_v3(t121);
+    for m in 1..=2 {
+        cm = cm.mul(sqrt_r123);
+        let sm = 2. * eval_sensitivity(m as f32 * opd, phi * m as f32);
+        reflectance += cm.mul(sm);
+    }
+
+    reflectance.max(Vector3::zero())
+}
+
 // -------------------------------------------------------------------------
 //    Microfacet model
 // -------------------------------------------------------------------------
@@ -127,84 +302,55 @@ pub fn sample_ggx_vndf(ve: Vector3, alpha_2d: (f32, f32), u: (f32, f32)) -> Vect
 }
 
 pub fn smith_g1_ggx(alpha_squared: f32, n_dot_s_squared: f32) -> f32 {
-    2. / ((((alpha_squared * (1. - n_dot_s_squared)) + n_dot_s_squared).sqrt() / n_dot_s_squared)
+    2. / ((((alpha_squared * (1. - n_dot_s_squared)) + n_dot_s_squared) / n_dot_s_squared).sqrt()
         + 1.)
 }
 
-pub fn smith_g2_over_g1_height_correlated(
-    _alpha: f32,
-    alpha_squared: f32,
-    n_dot_l: f32,
-    n_dot_v: f32,
-) -> f32 {
-    let g1v = smith_g1_ggx(alpha_squared, n_dot_v * n_dot_v);
-    let g1l = smith_g1_ggx(alpha_squared, n_dot_l * n_dot_l);
-    g1l / (g1v + g1l - g1v * g1l)
+pub fn ggx_d(alpha_squared: f32, n_dot_h: f32) -> f32 {
+    let b = (alpha_squared - 1.) * n_dot_h * n_dot_h + 1.;
+    alpha_squared / (std::f32::consts::PI * b * b)
 }
 
-// Weight for the reflection ray sampled from GGX distribution using VNDF method
-pub fn specular_sample_weight_ggx_vndf(
-    alpha: f32,
-    alpha_squared: f32,
-    n_dot_l: f32,
-    n_dot_v: f32,
-    _h_dot_l: f32,
-    _n_dot_h: f32,
-) -> f32 {
-    // #if USE_HEIGHT_CORRELATED_G2
-    smith_g2_over_g1_height_correlated(alpha, alpha_squared, n_dot_l, n_dot_v)
-    // #else
-    //     return Smith_G1_GGX(alpha, NdotL, alphaSquared, NdotL * NdotL);
-    // #endif
-}
-
-// Samples a reflection ray from the rough surface using selected microfacet distribution and sampling method
-// Resulting weight includes multiplication by cosine (NdotL) term
-pub fn sample_specular_microfacet(
-    v_local: Vector3,
-    alpha: f32,
-    alpha_squared: f32,
-    specular_f0: Vector3,
-    u: (f32, f32),
-) -> (Vector3, Vector3) {
-    // Sample a microfacet normal (H) in local space
-    let h_local = if alpha == 0. {
-        // Fast path for zero roughness (perfect reflection), also prevents NaNs appearing due to divisions by zeroes
-        Vector3::new(0., 0., 1.)
-    } else {
-        // For non-zero roughness, this calls VNDF sampling for GG-X distribution or Walter's sampling for Beckmann distribution
-        sample_ggx_vndf(v_local, (alpha, alpha), u)
-    };
+// Stretches alpha along the tangent by anisotropy strength, as in KHR_materials_anisotropy
+pub fn anisotropic_alpha(alpha: f32, anisotropy: f32) -> (f32, f32) {
+    (lerp_scalar(alpha, 1., anisotropy * anisotropy), alpha)
+}
 
-    // Reflect view direction to obtain light vector
-    let l_local = reflect(-v_local, h_local);
-
-    // Note: HdotL is same as HdotV here
-    // Clamp dot products here to small value to prevent numerical instability. Assume that rays incident from below the hemisphere have been filtered
-    let h_dot_l = 0.00001f32.max(1f32.min(dot(h_local, l_local)));
-    let n_local = Vector3::new(0., 0., 1.);
-    let n_dot_l = 0.00001f32.max(1f32.min(dot(n_local, l_local)));
-    let n_dot_v = 0.00001f32.max(1f32.min(dot(n_local, v_local)));
-    let n_dot_h = 0.00001f32.max(1f32.min(dot(n_local, h_local)));
-    let f = eval_fresnel(specular_f0, to_v3(shadowed_f90(specular_f0)), h_dot_l);
-
-    // Calculate weight of the sample specific for selected sampling method
-    // (this is microfacet BRDF divided by PDF of sampling method - notice how most terms cancel out)
-    let weight = f * specular_sample_weight_ggx_vndf(
-        alpha,
-        alpha_squared,
-        n_dot_l,
-        n_dot_v,
-        h_dot_l,
-        n_dot_h,
-    );
+// Anisotropic GGX distribution and its Smith masking function, vectors are in tangent space.
+// Source: "Understanding the Masking-Shadowing Function in Microfacet-Based BRDFs" by Heitz
+pub fn ggx_d_anisotropic(alpha_2d: (f32, f32), h: Vector3) -> f32 {
+    if h.z <= 0. {
+        return 0.;
+    }
 
-    (l_local, weight)
+    let (x, y) = (h.x / alpha_2d.0, h.y / alpha_2d.1);
+    let b = x * x + y * y + h.z * h.z;
+    1. / (std::f32::consts::PI * alpha_2d.0 * alpha_2d.1 * b * b)
 }
 
-pub fn ggx_d(alpha_squared: f32, n_dot_h: f32) -> f32 {
-    let b = (alpha_squared - 1.) * n_dot_h * n_dot_h + 1.;
-    alpha_squared / (std::f32::consts::PI * b * b)
+pub fn smith_lambda_ggx_anisotropic(alpha_2d: (f32, f32), s: Vector3) -> f32 {
+    let (x, y) = (s.x * alpha_2d.0, s.y * alpha_2d.1);
+    let a_squared = (x * x + y * y) / (s.z * s.z);
+    0.5 * ((1. + a_squared).sqrt() - 1.)
+}
+
+pub fn smith_g1_ggx_anisotropic(alpha_2d: (f32, f32), s: Vector3) -> f32 {
+    1. / (1. + smith_lambda_ggx_anisotropic(alpha_2d, s))
+}
+
+pub fn smith_g2_height_correlated_ggx_anisotropic(
+    alpha_2d: (f32, f32),
+    v: Vector3,
+    l: Vector3,
+) -> f32 {
+    1. / (1.
+        + smith_lambda_ggx_anisotropic(alpha_2d, v)
+        + smith_lambda_ggx_anisotropic(alpha_2d, l))
+}
+
+// PDF of reflecting the view direction about normal sampled by 'sample_ggx_vndf', vectors are in tangent space
+pub fn ggx_vndf_reflection_pdf(alpha_2d: (f32, f32), v: Vector3, h: Vector3) -> f32 {
+    smith_g1_ggx_anisotropic(alpha_2d, v) * ggx_d_anisotropic(alpha_2d, h) / (4. * v.z)
 }
 
 pub fn smith_g2_height_correlated_ggx_lagarde(
@@ -218,17 +364,235 @@ pub fn smith_g2_height_correlated_ggx_lagarde(
 }
 
 pub fn eval_microfacet(data: &BrdfData) -> Vector3 {
-    let d = ggx_d(0.00001f32.max(data.alpha_squared), data.n_dot_h);
-    let g2 = smith_g2_height_correlated_ggx_lagarde(data.alpha_squared, data.n_dot_l, data.n_dot_v);
-    //float3 F = evalFresnel(data.specularF0, shadowedF90(data.specularF0), data.VdotH); //< Unused, F is precomputed already
+    let (v, l, h) = (
+        data.frame.to_local(data.v),
+        data.frame.to_local(data.l),
+        data.frame.to_local(data.h),
+    );
 
-    // #if G2_DIVIDED_BY_DENOMINATOR
-    data.f * (g2 * d * data.n_dot_l)
-    // #else
-    // 	return ((data.F * G2 * D) / (4.0f * data.NdotL * data.NdotV)) * data.NdotL;
-    // #endif
+    let d = ggx_d_anisotropic(data.alpha_2d, h);
+    let g2 = smith_g2_height_correlated_ggx_anisotropic(data.alpha_2d, v, l);
+
+    // G2 is not divided by denominator, NdotL of the denominator cancels with the cosine term
+    data.f * (g2 * d / (4. * data.n_dot_v))
 }
 
 pub fn eval_lambertian(data: &BrdfData) -> Vector3 {
     data.diffuse_reflectance * (ONE_OVER_PI * data.n_dot_l)
 }
+
+// -------------------------------------------------------------------------
+//    Sheen
+// -------------------------------------------------------------------------
+
+// Lower roughness makes the distribution too sharp to be tabulated.
+const MIN_SHEEN_ROUGHNESS: f32 = 0.07;
+
+// "Charlie" distribution of sheen fibers.
+// Source: "Production Friendly Microfacet Sheen BRDF" by Estevez and Kulla
+pub fn charlie_d(roughness: f32, n_dot_h: f32) -> f32 {
+    let alpha = roughness.max(MIN_SHEEN_ROUGHNESS).powi(2);
+    let inv_alpha = 1. / alpha;
+    let sin_squared = (1. - n_dot_h * n_dot_h).max(0.);
+    (2. + inv_alpha) * sin_squared.powf(0.5 * inv_alpha) / TWO_PI
+}
+
+// Visibility term of sheen, predivided by '4 * NdotL * NdotV'.
+// Source: "Velvet" in "Crafting a Next-Gen Material Pipeline for The Order: 1886" by Neubelt and Pettineo
+pub fn sheen_v(n_dot_l: f32, n_dot_v: f32) -> f32 {
+    1. / (4. * (n_dot_l + n_dot_v - n_dot_l * n_dot_v))
+}
+
+// Integrates albedo of white sheen by midpoint rule over the hemisphere, the lobe is symmetric in phi.
+fn integrate_sheen_albedo(n_dot_v: f32, roughness: f32) -> f32 {
+    let v = Vector3::new((1. - n_dot_v * n_dot_v).sqrt(), 0., n_dot_v);
+    let (rows, columns) = (64, 32);
+
+    let mut albedo = 0.;
+    for row in 0..rows {
+        let theta = (row as f32 + 0.5) / rows as f32 * 0.5 * std::f32::consts::PI;
+        let solid_angle = theta.sin() * 0.5 * std::f32::consts::PI / rows as f32
+            * std::f32::consts::PI
+            / columns as f32;
+
+        for column in 0..columns {
+            let phi = (column as f32 + 0.5) / columns as f32 * std::f32::consts::PI;
+            let l = Vector3::new(
+                theta.sin() * phi.cos(),
+                theta.sin() * phi.sin(),
+                theta.cos(),
+            );
+            let h = (v + l).unit();
+
+            albedo += 2. * charlie_d(roughness, h.z) * sheen_v(l.z, n_dot_v) * l.z * solid_angle;
+        }
+    }
+
+    albedo
+}
+
+// Fraction of light reflected by white sheen, the rest reaches underlying layers.
+pub fn sheen_albedo(n_dot_v: f32, roughness: f32) -> f32 {
+    static TABLE: OnceLock<AlbedoTable> = OnceLock::new();
+
+    TABLE
+        .get_or_init(|| AlbedoTable::new(integrate_sheen_albedo))
+        .albedo(n_dot_v, roughness)
+}
+
+// -------------------------------------------------------------------------
+//    Multiple scattering
+// -------------------------------------------------------------------------
+
+const ALBEDO_TABLE_SIZE: usize = 32;
+
+// Directional albedo tabulated by NdotV (rows) and roughness (columns), generated on first use.
+struct AlbedoTable {
+    albedo: Vec<f32>,
+    average: Vec<f32>, //< Cosine weighted average of albedo over all directions, per roughness
+}
+
+impl AlbedoTable {
+    fn new(integrate: impl Fn(f32, f32) -> f32) -> AlbedoTable {
+        let last = (ALBEDO_TABLE_SIZE - 1) as f32;
+        let n_dot_v = |i: usize| (i as f32 / last).max(0.001);
+
+        let mut albedo = Vec::with_capacity(ALBEDO_TABLE_SIZE * ALBEDO_TABLE_SIZE);
+        for i in 0..ALBEDO_TABLE_SIZE {
+            for j in 0..ALBEDO_TABLE_SIZE {
+                albedo.push(saturate(integrate(n_dot_v(i), j as f32 / last)));
+            }
+        }
+
+        // Average is '2 * integral of albedo * NdotV', by trapezoidal rule.
+        let average = (0..ALBEDO_TABLE_SIZE)
+            .map(|j| {
+                let f = |i: usize| 2. * albedo[i * ALBEDO_TABLE_SIZE + j] * i as f32 / last;
+                let inner: f32 = (1..ALBEDO_TABLE_SIZE - 1).map(f).sum();
+                (inner + 0.5 * (f(0) + f(ALBEDO_TABLE_SIZE - 1))) / last
+            })
+            .collect();
+
+        AlbedoTable { albedo, average }
+    }
+
+    fn albedo(&self, n_dot_v: f32, roughness: f32) -> f32 {
+        let last = (ALBEDO_TABLE_SIZE - 1) as f32;
+        let (x, y) = (saturate(n_dot_v) * last, saturate(roughness) * last);
+        let (i, j) = (
+            (x as usize).min(ALBEDO_TABLE_SIZE - 2),
+            (y as usize).min(ALBEDO_TABLE_SIZE - 2),
+        );
+        let (tx, ty) = (x - i as f32, y - j as f32);
+
+        let at = |i: usize, j: usize| self.albedo[i * ALBEDO_TABLE_SIZE + j];
+        lerp_scalar(
+            lerp_scalar(at(i, j), at(i, j + 1), ty),
+            lerp_scalar(at(i + 1, j), at(i + 1, j + 1), ty),
+            tx,
+        )
+    }
+
+    fn average(&self, roughness: f32) -> f32 {
+        let y = saturate(roughness) * (ALBEDO_TABLE_SIZE - 1) as f32;
+        let j = (y as usize).min(ALBEDO_TABLE_SIZE - 2);
+        lerp_scalar(self.average[j], self.average[j + 1], y - j as f32)
+    }
+}
+
+// Integrates albedo of single scattering GGX with Fresnel equal to one, VNDF sampled with stratified samples.
+fn integrate_ggx_albedo(n_dot_v: f32, roughness: f32) -> f32 {
+    let alpha = (roughness * roughness).max(MIN_ALPHA);
+    let alpha_2d = (alpha, alpha);
+    let v = Vector3::new((1. - n_dot_v * n_dot_v).sqrt(), 0., n_dot_v);
+    let count = 32;
+
+    let mut albedo = 0.;
+    for i in 0..count {
+        for j in 0..count {
+            let u = (
+                (i as f32 + 0.5) / count as f32,
+                (j as f32 + 0.5) / count as f32,
+            );
+            let h = sample_ggx_vndf(v, alpha_2d, u);
+            let l = reflect(-v, h);
+
+            // Weight of VNDF sample is 'G2 / G1(NdotV)'
+            if l.z > 0. {
+                albedo += smith_g2_height_correlated_ggx_anisotropic(alpha_2d, v, l)
+                    / smith_g1_ggx_anisotropic(alpha_2d, v);
+            }
+        }
+    }
+
+    albedo / (count * count) as f32
+}
+
+fn ggx_albedo_table() -> &'static AlbedoTable {
+    static TABLE: OnceLock<AlbedoTable> = OnceLock::new();
+    TABLE.get_or_init(|| AlbedoTable::new(integrate_ggx_albedo))
+}
+
+// Roughness of isotropic lobe with the same albedo as the anisotropic one, approximately.
+#[inline]
+fn effective_roughness(alpha_2d: (f32, f32)) -> f32 {
+    (alpha_2d.0 * alpha_2d.1).sqrt().sqrt()
+}
+
+// Fresnel term of multiply scattered light, it is reflected once per bounce between microfacets
+// Source: "Revisiting Physically Based Shading at Imageworks" by Kulla and Conty
+fn multiple_scattering_fresnel(data: &BrdfData, average_albedo: f32) -> Vector3 {
+    let f0 = data.specular_f0;
+    let f_avg = f0 + (to_v3(shadowed_f90(f0)) - f0) / 21.;
+    let f = |f_avg: f32| f_avg * f_avg * average_albedo / (1. - f_avg * (1. - average_albedo));
+    Vector3::new(f(f_avg.x), f(f_avg.y), f(f_avg.z))
+}
+
+// Energy which single scattering GGX loses for view direction, it is returned by 'eval_multiple_scattering'.
+pub fn multiple_scattering_albedo(data: &BrdfData) -> Vector3 {
+    let table = ggx_albedo_table();
+    let roughness = effective_roughness(data.alpha_2d);
+
+    multiple_scattering_fresnel(data, table.average(roughness))
+        * (1. - table.albedo(data.n_dot_v, roughness))
+}
+
+// Energy compensation lobe of GGX, multiplied by cosine term.
+// Source: "Revisiting Physically Based Shading at Imageworks" by Kulla and Conty
+pub fn eval_multiple_scattering(data: &BrdfData) -> Vector3 {
+    let table = ggx_albedo_table();
+    let roughness = effective_roughness(data.alpha_2d);
+    let average = table.average(roughness);
+    if average >= 1. {
+        return Vector3::zero();
+    }
+
+    let e_v = table.albedo(data.n_dot_v, roughness);
+    let e_l = table.albedo(data.n_dot_l, roughness);
+
+    multiple_scattering_fresnel(data, average)
+        * ((1. - e_v) * (1. - e_l) * ONE_OVER_PI / (1. - average) * data.n_dot_l)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::eval_iridescence;
+    use crate::math::*;
+
+    #[test]
+    fn test_iridescence() {
+        let base_f0 = Vector3::new(0.04, 0.5, 0.9);
+
+        // Film without thickness does not change reflectance of the base.
+        let bare = eval_iridescence(1., 1.3, 1., 0., base_f0);
+        assert!((bare - base_f0).length() < 0.01);
+
+        // Interference colors reflection of gray base.
+        for thickness in [200., 300., 400.] {
+            let film = eval_iridescence(1., 1.3, 0.8, thickness, to_v3(0.04));
+            assert!(film.x >= 0. && film.y >= 0. && film.z >= 0.);
+            assert!(film.x <= 1. && film.y <= 1. && film.z <= 1.);
+            assert!((film.x - film.y).abs() + (film.y - film.z).abs() > 0.01);
+        }
+    }
+}
@@ -2,27 +2,166 @@ use serde::Deserialize;
 use serde::Serialize;
 use serde_json;
 
+use cgmath::ElementWise;
+
+use crate::bidirectional::Bidirectional;
 use crate::brdf::*;
 use crate::camera;
+use crate::guiding::{GuidedVertex, GuidingField, GUIDED_FRACTION};
 use crate::light::Attenuable;
 use crate::light::Light;
 use crate::math::*;
+use crate::medium::{henyey_greenstein, sample_henyey_greenstein, Medium, MediumEvent};
+use crate::metropolis::{MarkovChains, Metropolis};
+use crate::photon_mapping::{PhotonMap, PhotonMapping};
 use crate::random::{Sampler, UniformSampler};
 use crate::ray::Ray;
 use crate::scene;
+use crate::spectrum::{upsample, Spectrum, Wavelengths, WAVELENGTHS};
 use crate::Error;
 
-#[derive(Default, Copy, Clone, Serialize, Deserialize)]
+/// Light transport algorithm estimating radiance arriving through points of the image.
+pub trait Integrator {
+    /// Returns radiance arriving through given point of the image, pixel size drives texture filtering.
+    ///
+    /// Light found arriving through other points of the image is added to splats, splats of a
+    /// single sample of every pixel estimate the image together.
+    fn radiance(&self, x: f32, y: f32, pixel_size: (f32, f32), splats: &mut Vec<Splat>) -> Vector3;
+}
+
+/// Radiance arriving through a point of the image in normalized coordinates.
+pub struct Splat {
+    pub x: f32,
+    pub y: f32,
+    pub color: Vector3,
+}
+
+#[derive(Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum IntegratorType {
+    #[default]
+    Path,
+    /// Connects camera paths with paths traced from lights, suited for indirectly lit scenes.
+    Bidirectional,
+    /// Gathers photons traced from lights at the first rough surface, suited for caustics.
+    PhotonMapping,
+    /// Mutates paths of the path tracer in proportion to their luminance, suited for scenes where
+    /// little light finds its way to the camera.
+    Metropolis,
+}
+
+/// Strategy selecting a single light sampled at a point, lights emitting paths of bidirectional
+/// path tracing and photon mapping are selected in proportion to their power unless uniformly.
+#[derive(Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LightSelection {
+    #[default]
+    Uniform,
+    /// In proportion to power of lights.
+    Power,
+    /// By a tree of lights bounding light arriving from them, suited for scenes of many lights
+    /// lighting parts of the scene.
+    Tree,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct TracerSettings {
     pub max_scatter_depth: u32,
     pub shadow_rays: bool,
     pub random_light_sample: bool,
+    #[serde(default)]
+    pub light_selection: LightSelection,
     pub t_min: f32,
     pub t_max: f32,
     pub min_bounces: u32,
+    /// Number of alpha cut out, blended or thin transmissive surfaces a ray passes through before
+    /// the next one blocks it.
+    #[serde(default = "TracerSettings::default_max_transparent_hits")]
+    pub max_transparent_hits: u32,
+    /// Number of scattering events inside of a volume bounded by a surface, they do not count
+    /// as bounces since random walks of subsurface scattering take hundreds of them.
+    #[serde(default = "TracerSettings::default_max_walk_steps")]
+    pub max_walk_steps: u32,
+    /// Paths carry radiance at sampled wavelengths instead of RGB, which renders dispersion and
+    /// measured conductors.
+    #[serde(default)]
+    pub spectral: bool,
+    #[serde(default)]
+    pub integrator: IntegratorType,
+    /// Path tracer samples directions also from light learned to arrive during previous passes,
+    /// the spectral mode is not guided.
+    #[serde(default)]
+    pub path_guiding: bool,
+    /// Number of photons traced in every pass of photon mapping.
+    #[serde(default = "TracerSettings::default_photons")]
+    pub photons: u32,
+    /// Photon lookup radius of the first pass relative to the radius of the scene, it shrinks
+    /// with every pass.
+    #[serde(default = "TracerSettings::default_photon_radius")]
+    pub photon_radius: f32,
+    /// Number of independent paths estimating brightness of the image for Metropolis.
+    #[serde(default = "TracerSettings::default_bootstrap_paths")]
+    pub bootstrap_paths: u32,
+    #[serde(default = "TracerSettings::default_markov_chains")]
+    pub markov_chains: u32,
+    /// Probability of mutations drawing new paths instead of perturbing the current ones.
+    #[serde(default = "TracerSettings::default_large_step_probability")]
+    pub large_step_probability: f32,
+}
+
+impl Default for TracerSettings {
+    /// Settings used when none are saved, fields optional in saved settings share their serde
+    /// defaults.
+    fn default() -> Self {
+        TracerSettings {
+            max_scatter_depth: 5,
+            shadow_rays: true,
+            random_light_sample: false,
+            light_selection: LightSelection::default(),
+            t_min: 0.001,
+            t_max: 100000.0,
+            min_bounces: 3,
+            max_transparent_hits: TracerSettings::default_max_transparent_hits(),
+            max_walk_steps: TracerSettings::default_max_walk_steps(),
+            spectral: false,
+            integrator: IntegratorType::default(),
+            path_guiding: false,
+            photons: TracerSettings::default_photons(),
+            photon_radius: TracerSettings::default_photon_radius(),
+            bootstrap_paths: TracerSettings::default_bootstrap_paths(),
+            markov_chains: TracerSettings::default_markov_chains(),
+            large_step_probability: TracerSettings::default_large_step_probability(),
+        }
+    }
 }
 
 impl TracerSettings {
+    fn default_max_transparent_hits() -> u32 {
+        16
+    }
+
+    fn default_max_walk_steps() -> u32 {
+        1024
+    }
+
+    fn default_photons() -> u32 {
+        100000
+    }
+
+    fn default_photon_radius() -> f32 {
+        0.005
+    }
+
+    fn default_bootstrap_paths() -> u32 {
+        100000
+    }
+
+    fn default_markov_chains() -> u32 {
+        1000
+    }
+
+    fn default_large_step_probability() -> f32 {
+        0.3
+    }
+
     pub fn from_file(path: &str) -> Result<Self, Error> {
         let mut settings = TracerSettings::default();
         settings.load(path).map(|_| settings)
@@ -44,6 +183,9 @@ pub struct Tracer {
     settings: TracerSettings,
     camera: Box<dyn camera::Camera + Send + Sync>,
     scene: scene::Scene,
+    photon_map: Option<PhotonMap>,
+    markov_chains: Option<MarkovChains>,
+    guiding: Option<GuidingField>,
 }
 
 impl Drop for Tracer {
@@ -63,6 +205,9 @@ impl Tracer {
             settings,
             camera,
             scene,
+            photon_map: None,
+            markov_chains: None,
+            guiding: None,
         }
     }
 
@@ -82,8 +227,80 @@ impl Tracer {
         self.settings = settings;
     }
 
-    /// Returns direction to light from given position if the light is visible from that position.
-    fn trace_light(&self, position: &Vector3, normal: &Vector3, light: &Light) -> Option<Vector3> {
+    /// Prepares given pass of progressive rendering numbered from one, photon mapping traces
+    /// photons of the pass, Metropolis starts its chains in the first pass and path guiding
+    /// learns from the passes before.
+    pub fn begin_pass(&mut self, pass: u32) {
+        self.photon_map = match self.settings.integrator {
+            IntegratorType::PhotonMapping => Some(PhotonMap::trace(self, pass)),
+            _ => None,
+        };
+        self.markov_chains = match (self.settings.integrator, self.markov_chains.take()) {
+            (IntegratorType::Metropolis, Some(chains)) if pass > 1 => Some(chains),
+            (IntegratorType::Metropolis, _) => Some(MarkovChains::bootstrap(self)),
+            _ => None,
+        };
+        let guided = self.settings.path_guiding && self.settings.integrator == IntegratorType::Path;
+        self.guiding = match (guided, self.guiding.take()) {
+            (true, Some(mut guiding)) if pass > 1 => {
+                // Learned light is sampled whenever the number of passes doubles.
+                if pass.is_power_of_two() {
+                    guiding.refine();
+                }
+                Some(guiding)
+            }
+            (true, _) => Some(GuidingField::new(&self.scene)),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn settings(&self) -> &TracerSettings {
+        &self.settings
+    }
+
+    pub(crate) fn camera(&self) -> &(dyn camera::Camera + Send + Sync) {
+        self.camera.as_ref()
+    }
+
+    /// Samples ray of the camera through given point of the image with its weight, see
+    /// `Camera::sample_ray`.
+    ///
+    /// Cameras do not normalize their rays, while media and volumes measure distances by the ray
+    /// parameter, so the direction is normalized.
+    pub(crate) fn camera_ray(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        sampler: &impl Sampler,
+    ) -> (Ray, f32) {
+        let (ray, weight) = self.camera.sample_ray(x, y, pixel_size, sampler);
+        (
+            Ray {
+                direction: ray.direction.unit(),
+                ..ray
+            },
+            weight,
+        )
+    }
+
+    /// Returns medium of given region along the ray up to `t_max`, with the part of the ray
+    /// inside of it.
+    fn medium(&self, region: Region, ray: &Ray, t_max: f32) -> Option<(Medium, f32, f32)> {
+        match region {
+            Region::Outside => self.scene.fog(ray, t_max),
+            Region::Inside(medium) => medium.map(|medium| (medium, 0., t_max)),
+        }
+    }
+
+    /// Returns direction to light and its transmittance if the light is visible from given position.
+    fn trace_light(
+        &self,
+        position: &Vector3,
+        region: Region,
+        light: &Light,
+        sampler: &impl Sampler,
+    ) -> Option<(Vector3, Vector3)> {
         // Shortcut for point lights too far away.
         if let Light::Point(point) = light {
             if (point.position - *position).squared_length() > point.range_squared {
@@ -92,95 +309,420 @@ impl Tracer {
         }
 
         let (direction, distance) = light.direction_distance_from(position);
+        self.segment_transmittance(*position, direction, distance, Some(region), sampler)
+            .map(|transmittance| (direction, transmittance))
+    }
 
-        if cgmath::dot(*normal, direction) <= 0. {
-            return None;
-        }
+    /// Returns transmittance of surfaces along the segment of given length, if they do not block
+    /// it, for integrators which do not sample media.
+    pub(crate) fn surface_transmittance(
+        &self,
+        origin: Vector3,
+        direction: Vector3,
+        distance: f32,
+        sampler: &impl Sampler,
+    ) -> Option<Vector3> {
+        self.segment_transmittance(origin, direction, distance, None, sampler)
+    }
 
-        // TODO: Hitting dielectric material...
-        let hit = self.scene.hit(
-            &Ray::new(*position, direction),
-            self.settings.t_min,
-            self.settings.t_max,
-        );
+    /// Returns transmittance of the segment of given length, if it is not blocked.
+    ///
+    /// Segments pass through thin-walled transmissive surfaces and index matched volume
+    /// boundaries, other volume boundaries block them since refracted light cannot be connected
+    /// through them. Media and heterogeneous volumes along the way attenuate the light, unless
+    /// no region is given.
+    fn segment_transmittance(
+        &self,
+        origin: Vector3,
+        direction: Vector3,
+        distance: f32,
+        region: Option<Region>,
+        sampler: &impl Sampler,
+    ) -> Option<Vector3> {
+        let mut origin = origin;
+        let mut distance = distance;
+        let mut region = region;
+        let mut transmittance = Vector3::one();
+        let mut transparent_hits = 0;
 
-        match hit {
-            Some(hit) if hit.t >= distance => Some(direction),
-            None => Some(direction),
-            _ => None,
+        loop {
+            let ray = Ray::new(origin, direction);
+            let hit = self
+                .scene
+                .hit_surface(&ray, self.settings.t_min, self.settings.t_max)
+                .filter(|hit| hit.t < distance);
+
+            let segment = hit.as_ref().map_or(distance, |hit| hit.t);
+            if let Some(region) = region {
+                if let Some((medium, start, end)) = self.medium(region, &ray, segment) {
+                    transmittance = transmittance.mul(medium.transmittance(end - start));
+                }
+                if let Region::Outside = region {
+                    transmittance *= self.scene.volume_transmittance(&ray, segment, sampler);
+                }
+            }
+
+            let hit = match hit {
+                Some(hit) => hit,
+                None => {
+                    return match transmittance == Vector3::zero() {
+                        true => None,
+                        false => Some(transmittance),
+                    }
+                }
+            };
+
+            if transparent_hits >= self.settings.max_transparent_hits {
+                return None;
+            }
+            transparent_hits += 1;
+
+            // Uncovered part of the surface lets light through, covered part only when it is
+            // a thin transmissive sheet or an index matched boundary.
+            let opacity = hit.material.opacity(&hit.tex_coord);
+            let mut surface_transmittance = Vector3::one() * (1. - opacity);
+
+            if opacity > 0.
+                && hit.material.is_transmissive()
+                && (hit.material.is_thin_walled() || hit.material.is_index_matched())
+            {
+                let surface = hit.resolve_material();
+                let cosine = cgmath::dot(direction, surface.shading_normal).abs();
+                let fresnel = fresnel_dielectric(cosine, surface.ior);
+
+                surface_transmittance +=
+                    surface.base_color * (opacity * surface.transmission * (1. - fresnel));
+                region = region.map(|region| region.behind(&hit, &direction));
+            }
+
+            transmittance = transmittance.mul(surface_transmittance);
+            if transmittance == Vector3::zero() {
+                return None;
+            }
+
+            origin = hit.position;
+            distance -= hit.t;
+        }
+    }
+
+    /// Returns direction to the light and radiance arriving from it to the hit, if it is visible.
+    fn light_arriving(
+        &self,
+        light: &Light,
+        hit: &Hit,
+        region: Region,
+        material: &ResolvedMaterial,
+        sampler: &impl Sampler,
+    ) -> Option<(Vector3, Vector3)> {
+        let (direction, _) = light.direction_distance_from(&hit.position);
+        if lit_side(material).is_some_and(|normal| cgmath::dot(normal, direction) <= 0.) {
+            return None;
         }
+
+        self.trace_light(
+            &hit.position,
+            region.behind(hit, &direction),
+            light,
+            sampler,
+        )
+        .map(|(light_dir, transmittance)| {
+            (
+                light_dir,
+                light.intensity_at(&hit.position).mul(transmittance),
+            )
+        })
     }
 
     fn sample_light(
         &self,
         light: &Light,
-        position: &Vector3,
+        hit: &Hit,
+        region: Region,
         material: &ResolvedMaterial,
-        brdf: &(dyn Brdf + Send + Sync),
         wo: &Vector3,
+        sampler: &impl Sampler,
     ) -> Vector3 {
-        match self.trace_light(position, &material.shading_normal, light) {
-            Some(light_dir) => {
-                brdf.eval(&light_dir, wo, material)
-                    .mul(light.intensity_at(position))
-                    / brdf.pdf(&light_dir, &material.shading_normal)
-            }
+        match self.light_arriving(light, hit, region, material, sampler) {
+            Some((light_dir, radiance)) => hit
+                .material
+                .brdf
+                .eval(&light_dir, wo, material)
+                .mul(radiance),
             _ => Vector3::zero(),
         }
     }
 
-    fn sample_lights(
+    /// Returns light arriving from lights to the hit outside of volumes and scattered into the ray
+    /// of given direction.
+    pub(crate) fn direct_light(
         &self,
-        position: &Vector3,
+        hit: &Hit,
         material: &ResolvedMaterial,
-        brdf: &(dyn Brdf + Send + Sync),
-        sampler: &UniformSampler,
         wo: &Vector3,
+        sampler: &impl Sampler,
     ) -> Vector3 {
+        self.sample_lights(&hit.position, lit_side(material), sampler, |light| {
+            self.sample_light(light, hit, Region::Outside, material, wo, sampler)
+        })
+    }
+
+    /// Returns light scattered by a medium with given phase function asymmetry at given position
+    /// into the ray of given direction.
+    fn sample_medium_light(
+        &self,
+        light: &Light,
+        position: &Vector3,
+        region: Region,
+        asymmetry: f32,
+        direction: &Vector3,
+        sampler: &impl Sampler,
+    ) -> Vector3 {
+        match self.trace_light(position, region, light, sampler) {
+            Some((light_dir, transmittance)) => {
+                let phase = henyey_greenstein(cgmath::dot(*direction, light_dir), asymmetry);
+                light.intensity_at(position).mul(transmittance) * phase
+            }
+            _ => Vector3::zero(),
+        }
+    }
+
+    /// Returns light arriving from lights to given position, which is lit only from the side of
+    /// given normal if there is one, sampled by given function.
+    ///
+    /// Contributions of lights are averaged, a single selected light is divided by the number of
+    /// lights and the probability of its selection.
+    fn sample_lights<T>(
+        &self,
+        position: &Vector3,
+        normal: Option<Vector3>,
+        sampler: &impl Sampler,
+        sample_light: impl Fn(&Light) -> T,
+    ) -> T
+    where
+        T: cgmath::Zero + std::ops::Div<f32, Output = T>,
+    {
         // https://computergraphics.stackexchange.com/questions/5152/progressive-path-tracing-with-explicit-light-sampling
         // For each light:
         optick::event!("lights");
 
         let num_lights = self.scene.lights().len();
         if num_lights == 0 {
-            return Vector3::zero();
+            return T::zero();
         }
 
         if self.settings.random_light_sample {
-            let random_light = (sampler.next_float() * num_lights as f32) as usize;
-            let light = &self.scene.lights()[random_light];
+            let selected = self.scene.light_sampler().sample(
+                self.settings.light_selection,
+                position,
+                normal,
+                sampler.next_float(),
+            );
 
-            self.sample_light(light, position, material, brdf, wo) / (num_lights as f32)
+            match selected {
+                Some((index, probability)) => {
+                    let light = &self.scene.lights()[index];
+                    sample_light(light) / (probability * num_lights as f32)
+                }
+                None => T::zero(),
+            }
         } else {
-            let mut color = Vector3::zero();
+            let mut color = T::zero();
 
             for light in self.scene.lights() {
-                color += self.sample_light(light, position, material, brdf, wo);
+                color = color + sample_light(light);
             }
 
             color / (num_lights as f32)
         }
     }
 
-    pub fn trace(&self, x: f32, y: f32) -> Vector3 {
+    /// Samples scattering in the homogeneous medium of given region and in heterogeneous volumes
+    /// along the ray up to the hit, returning phase function asymmetry and position of the event.
+    ///
+    /// Throughput is updated by the weight of the sampled event, emission of volumes in front of
+    /// it is added to the color.
+    fn sample_media(
+        &self,
+        region: Region,
+        ray: &Ray,
+        hit: &Option<Hit>,
+        throughput: &mut Vector3,
+        color: &mut Vector3,
+        sampler: &impl Sampler,
+    ) -> Option<(f32, Vector3)> {
+        let t_surface = hit.as_ref().map_or(f32::INFINITY, |hit| hit.t);
+        let medium = self.medium(region, ray, t_surface);
+
+        // Collision in the homogeneous medium limits tracking of volumes, it is accepted only if
+        // no volume collision precedes it.
+        let t_medium = match medium {
+            Some((medium, start, end)) => match start + medium.free_flight(sampler) {
+                t if t < end => t,
+                _ => f32::INFINITY,
+            },
+            None => f32::INFINITY,
+        };
+        let pass_weight = |t: f32| match medium {
+            Some((medium, start, end)) => medium.pass_weight(t.min(end).max(start) - start),
+            None => Vector3::one(),
+        };
+
+        // Heterogeneous volumes are placed outside of volumes bounded by surfaces.
+        if let Region::Outside = region {
+            let volumes = self
+                .scene
+                .sample_volumes(ray, t_surface.min(t_medium), sampler);
+
+            for (t, emission) in volumes.emission {
+                *color += throughput.mul(pass_weight(t)).mul(emission);
+            }
+
+            if let Some(collision) = volumes.collision {
+                *throughput = throughput
+                    .mul(pass_weight(collision.distance))
+                    .mul(collision.albedo);
+                return Some((collision.asymmetry, ray.point_at(collision.distance)));
+            }
+        }
+
+        let (medium, start, end) = medium?;
+        match medium.event(t_medium - start, end - start) {
+            MediumEvent::Pass { weight } => {
+                *throughput = throughput.mul(weight);
+                None
+            }
+            MediumEvent::Scatter { distance, weight } => {
+                *throughput = throughput.mul(weight);
+                Some((medium.asymmetry, ray.point_at(start + distance)))
+            }
+        }
+    }
+
+    /// Returns none if the path of given throughput luminance is terminated, otherwise the
+    /// probability of its survival, which compensates its throughput.
+    pub(crate) fn russian_roulette(
+        &self,
+        bounce: u32,
+        throughput: f32,
+        sampler: &impl Sampler,
+    ) -> Option<f32> {
+        // Russian rulette: https://pbr-book.org/3ed-2018/Monte_Carlo_Integration/Russian_Roulette_and_Splitting
+        if bounce > self.settings.min_bounces {
+            let prob = throughput.min(0.95);
+            if prob < sampler.next_float() {
+                return None;
+            }
+            return Some(prob);
+        }
+
+        Some(1.)
+    }
+
+    /// Returns radiance arriving through given point of the image by the selected integrator,
+    /// light arriving through other points of the image is added to splats.
+    pub fn trace(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        splats: &mut Vec<Splat>,
+    ) -> Vector3 {
         optick::event!("trace");
-        let sampler = UniformSampler::new();
+        match self.settings.integrator {
+            IntegratorType::Path => self.radiance(x, y, pixel_size, splats),
+            IntegratorType::Bidirectional => {
+                Bidirectional::new(self).radiance(x, y, pixel_size, splats)
+            }
+            IntegratorType::PhotonMapping => PhotonMapping::new(self, self.photon_map.as_ref())
+                .radiance(x, y, pixel_size, splats),
+            IntegratorType::Metropolis => Metropolis::new(self, self.markov_chains.as_ref())
+                .radiance(x, y, pixel_size, splats),
+        }
+    }
+
+    /// Unidirectional path tracing with next event estimation, random numbers are drawn from the
+    /// sampler.
+    pub(crate) fn trace_path(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        sampler: &impl Sampler,
+    ) -> Vector3 {
+        if self.settings.spectral {
+            return self.trace_spectral(x, y, pixel_size, sampler);
+        }
+
+        let (mut ray, weight) = self.camera_ray(x, y, pixel_size, sampler);
+        if weight <= 0. {
+            return Vector3::zero();
+        }
 
-        let mut ray = self.camera.ray(x, y, &sampler);
         let mut color = Vector3::zero();
-        let mut throughput = Vector3::one();
+        let mut throughput = Vector3::one() * weight;
         let mut bounce = 0;
+        let mut walk_steps = 0;
+        // Camera is expected to be outside of all volumes.
+        let mut region = Region::Outside;
+        let mut guided_vertices = Vec::new();
 
         while bounce < self.settings.max_scatter_depth {
             optick::event!("bounce");
 
             bounce += 1;
 
-            // No hit.
-            let hit = match self
-                .scene
-                .hit(&ray, self.settings.t_min, self.settings.t_max)
+            let hit = self.scene.hit(
+                &ray,
+                self.settings.t_min,
+                self.settings.t_max,
+                self.settings.max_transparent_hits,
+                sampler,
+            );
+
+            // Free flight through media in front of the surface, scattering in a medium replaces
+            // interaction with the surface.
+            if let Some((asymmetry, position)) =
+                self.sample_media(region, &ray, &hit, &mut throughput, &mut color, sampler)
             {
+                // Steps of a random walk inside of a volume are limited on their own.
+                if let Region::Inside(_) = region {
+                    walk_steps += 1;
+                    if walk_steps > self.settings.max_walk_steps {
+                        break;
+                    }
+                    bounce -= 1;
+                }
+
+                if self.settings.shadow_rays {
+                    color +=
+                        throughput.mul(self.sample_lights(&position, None, sampler, |light| {
+                            self.sample_medium_light(
+                                light,
+                                &position,
+                                region,
+                                asymmetry,
+                                &ray.direction,
+                                sampler,
+                            )
+                        }));
+                }
+
+                if bounce == self.settings.max_scatter_depth {
+                    break;
+                }
+                match self.russian_roulette(bounce, luminance(throughput), sampler) {
+                    Some(survival) => throughput /= survival,
+                    None => break,
+                }
+
+                // Phase function is sampled exactly, its value cancels out with pdf.
+                let wi = sample_henyey_greenstein(&ray.direction, asymmetry, sampler);
+                ray = Ray::new(position, wi);
+                continue;
+            }
+
+            // No hit.
+            let hit = match hit {
                 None => {
                     color += throughput.mul(self.scene.environment(&ray));
                     break;
@@ -196,54 +738,79 @@ impl Tracer {
 
             // Direct light sampling.
             if self.settings.shadow_rays {
-                color += throughput.mul(self.sample_lights(
-                    &hit.position,
-                    &material,
-                    brdf,
-                    &sampler,
-                    &ray.direction,
-                ));
+                let normal = lit_side(&material);
+                color +=
+                    throughput.mul(self.sample_lights(&hit.position, normal, sampler, |light| {
+                        self.sample_light(light, &hit, region, &material, &ray.direction, sampler)
+                    }));
             }
 
             if bounce == self.settings.max_scatter_depth {
                 break;
             }
-
-            // Russian rulette: https://pbr-book.org/3ed-2018/Monte_Carlo_Integration/Russian_Roulette_and_Splitting
-            if bounce > self.settings.min_bounces {
-                let prob = luminance(throughput).min(0.95);
-                if prob < sampler.next_float() {
-                    break;
-                }
-                throughput /= prob; // TODO: why?
+            match self.russian_roulette(bounce, luminance(throughput), sampler) {
+                Some(survival) => throughput /= survival,
+                None => break,
             }
 
-            let brdf_type = if material.metalness == 1. && material.roughness == 0. {
-                BrdfType::Specular
-            } else {
-                let prob = brdf.probability(&v, &material);
+            // Smooth surfaces scatter into too narrow lobes to be guided.
+            let guiding = self.guiding.as_ref().filter(|_| !material.is_smooth());
+            let guide = guiding.and_then(|guiding| guiding.guide(&hit.position));
 
-                if sampler.next_float() < prob {
-                    throughput /= prob;
-                    BrdfType::Specular
-                } else {
-                    throughput /= 1. - prob;
-                    BrdfType::Diffuse
+            let wi = match guide {
+                Some(guide) if sampler.next_float() < GUIDED_FRACTION => guide.sample(sampler),
+                _ => {
+                    // Probability of the selected BRDF is included in its pdf.
+                    let brdf_type = match sampler.next_float() < brdf.probability(&v, &material) {
+                        true => BrdfType::Specular,
+                        false => BrdfType::Diffuse,
+                    };
+
+                    match brdf.sample(brdf_type, &ray.direction, &material, sampler) {
+                        Some(wi) => wi,
+                        None => break,
+                    }
                 }
             };
 
-            let wi = match brdf.sample(brdf_type, &ray.direction, &material, &sampler) {
-                Some(wi) => wi,
-                None => break,
+            // One-sample MIS of guided sampling and sampling of the BRDF.
+            let pdf = match guide {
+                Some(guide) => lerp_scalar(
+                    brdf.pdf(&wi, &ray.direction, &material),
+                    guide.pdf(&wi),
+                    GUIDED_FRACTION,
+                ),
+                None => brdf.pdf(&wi, &ray.direction, &material),
             };
-
-            let pdf = brdf.pdf(&wi, &material.shading_normal);
+            if pdf <= 0. {
+                break;
+            }
 
             // Eval.
             let mat_color = brdf.eval(&wi, &ray.direction, &material);
             throughput = throughput.mul(mat_color) / pdf;
 
-            ray = Ray::new(hit.position, wi);
+            if guiding.is_some() {
+                guided_vertices.push(GuidedVertex {
+                    position: hit.position,
+                    direction: wi,
+                    pdf,
+                    color,
+                    throughput,
+                });
+            }
+
+            // Light refracted into a volume travels through its medium.
+            region = region.behind(&hit, &wi);
+
+            ray = Ray {
+                differentials: hit.scatter_differentials(&ray, &material, wi),
+                ..Ray::new(hit.position, wi)
+            };
+        }
+
+        if let Some(guiding) = &self.guiding {
+            guiding.record(&guided_vertices, color);
         }
 
         match self.settings.max_scatter_depth {
@@ -251,4 +818,344 @@ impl Tracer {
             _ => color,
         }
     }
+
+    /// Spectral variant of `trace`, the path carries radiance at hero and secondary wavelengths.
+    ///
+    /// Materials are evaluated for each wavelength with their colors upsampled and their IOR
+    /// taken at it, while directions are sampled for the hero wavelength. Refraction by dispersive
+    /// materials is valid only for the hero, so it drops the secondary wavelengths. Lights, media
+    /// and the environment are given in RGB and upsampled.
+    fn trace_spectral(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        sampler: &impl Sampler,
+    ) -> Vector3 {
+        let mut wavelengths = Wavelengths::sample(sampler);
+
+        let (mut ray, weight) = self.camera_ray(x, y, pixel_size, sampler);
+        if weight <= 0. {
+            return Vector3::zero();
+        }
+
+        let mut color = Spectrum::new(0., 0., 0., 0.);
+        let mut throughput = Spectrum::new(weight, weight, weight, weight);
+        let mut bounce = 0;
+        let mut walk_steps = 0;
+        let mut region = Region::Outside;
+
+        while bounce < self.settings.max_scatter_depth {
+            optick::event!("bounce");
+
+            bounce += 1;
+
+            let hit = self.scene.hit(
+                &ray,
+                self.settings.t_min,
+                self.settings.t_max,
+                self.settings.max_transparent_hits,
+                sampler,
+            );
+
+            // Media are sampled in RGB, their weight and emission scale the spectral throughput.
+            let (mut weight, mut emission) = (Vector3::one(), Vector3::zero());
+            let scatter =
+                self.sample_media(region, &ray, &hit, &mut weight, &mut emission, sampler);
+            color += throughput.mul_element_wise(wavelengths.upsample(emission));
+            throughput.mul_assign_element_wise(wavelengths.upsample(weight));
+
+            if let Some((asymmetry, position)) = scatter {
+                if let Region::Inside(_) = region {
+                    walk_steps += 1;
+                    if walk_steps > self.settings.max_walk_steps {
+                        break;
+                    }
+                    bounce -= 1;
+                }
+
+                if self.settings.shadow_rays {
+                    let light = self.sample_lights(&position, None, sampler, |light| {
+                        self.sample_medium_light(
+                            light,
+                            &position,
+                            region,
+                            asymmetry,
+                            &ray.direction,
+                            sampler,
+                        )
+                    });
+                    color += throughput.mul_element_wise(wavelengths.upsample(light));
+                }
+
+                if bounce == self.settings.max_scatter_depth {
+                    break;
+                }
+                match self.russian_roulette(bounce, max_value(throughput), sampler) {
+                    Some(survival) => throughput /= survival,
+                    None => break,
+                }
+
+                let wi = sample_henyey_greenstein(&ray.direction, asymmetry, sampler);
+                ray = Ray::new(position, wi);
+                continue;
+            }
+
+            let hit = match hit {
+                None => {
+                    let environment = wavelengths.upsample(self.scene.environment(&ray));
+                    color += throughput.mul_element_wise(environment);
+                    break;
+                }
+                Some(hit) => hit,
+            };
+
+            let lambda = wavelengths.lambda;
+            let material = hit.resolve_material();
+            let materials: [ResolvedMaterial; WAVELENGTHS] =
+                std::array::from_fn(|i| material.at_wavelength(&hit.material, lambda[i]));
+            let brdf = &(*hit.material.brdf);
+            let eval = |wi: &Vector3| {
+                Spectrum::from(std::array::from_fn(|i| {
+                    upsample(brdf.eval(wi, &ray.direction, &materials[i]), lambda[i])
+                }))
+            };
+            let hero = &materials[0];
+            let v = -ray.direction;
+
+            color += throughput.mul_element_wise(wavelengths.upsample(material.emissive));
+
+            if self.settings.shadow_rays {
+                let light =
+                    self.sample_lights(&hit.position, lit_side(hero), sampler, |light| match self
+                        .light_arriving(light, &hit, region, hero, sampler)
+                    {
+                        Some((light_dir, radiance)) => {
+                            eval(&light_dir).mul_element_wise(wavelengths.upsample(radiance))
+                        }
+                        None => Spectrum::new(0., 0., 0., 0.),
+                    });
+                color += throughput.mul_element_wise(light);
+            }
+
+            if bounce == self.settings.max_scatter_depth {
+                break;
+            }
+            match self.russian_roulette(bounce, max_value(throughput), sampler) {
+                Some(survival) => throughput /= survival,
+                None => break,
+            }
+
+            let brdf_type = match sampler.next_float() < brdf.probability(&v, hero) {
+                true => BrdfType::Specular,
+                false => BrdfType::Diffuse,
+            };
+
+            let wi = match brdf.sample(brdf_type, &ray.direction, hero, sampler) {
+                Some(wi) => wi,
+                None => break,
+            };
+
+            let pdf = brdf.pdf(&wi, &ray.direction, hero);
+            if pdf <= 0. {
+                break;
+            }
+
+            let refracted = cgmath::dot(wi, hit.normal) * cgmath::dot(v, hit.normal) < 0.;
+            if refracted && hit.material.is_dispersive() {
+                wavelengths.terminate_secondary(&mut throughput);
+            }
+
+            throughput = throughput.mul_element_wise(eval(&wi)) / pdf;
+
+            region = region.behind(&hit, &wi);
+
+            ray = Ray {
+                differentials: hit.scatter_differentials(&ray, hero, wi),
+                ..Ray::new(hit.position, wi)
+            };
+        }
+
+        let color = match self.settings.max_scatter_depth {
+            1 => color + throughput,
+            _ => color,
+        };
+        wavelengths.to_rgb(color)
+    }
+}
+
+impl Integrator for Tracer {
+    fn radiance(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        _splats: &mut Vec<Splat>,
+    ) -> Vector3 {
+        self.trace_path(x, y, pixel_size, &UniformSampler::new())
+    }
+}
+
+/// Returns normal of the side of the surface lit by lights, transmissive materials are lit from
+/// both sides.
+fn lit_side(material: &ResolvedMaterial) -> Option<Vector3> {
+    match material.transmission == 0. && material.subsurface == 0. {
+        true => Some(material.shading_normal),
+        false => None,
+    }
+}
+
+fn max_value(spectrum: Spectrum) -> f32 {
+    spectrum.x.max(spectrum.y).max(spectrum.z).max(spectrum.w)
+}
+
+/// Part of the scene a ray travels through, interiors of volumes are not filled by the fog.
+#[derive(Copy, Clone)]
+enum Region {
+    Outside,
+    Inside(Option<Medium>),
+}
+
+impl Region {
+    /// Returns region on the side of the surface given direction points to.
+    fn behind(self, hit: &Hit, direction: &Vector3) -> Region {
+        match hit.material.is_thin_walled() {
+            true => self,
+            false if cgmath::dot(*direction, hit.normal) < 0. => {
+                Region::Inside(hit.material.medium(&hit.tex_coord))
+            }
+            false => Region::Outside,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{ApertureCamera, SimpleCamera};
+    use crate::material::{AlphaMode, Material};
+    use crate::primitive::{Primitive, Shape};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn test_fog_of_camera_rays() {
+        let sampler = UniformSampler::new();
+        let absorption = 0.3;
+        let fog = Medium {
+            absorption: Vector3::new(absorption, absorption, absorption),
+            scattering: Vector3::zero(),
+            asymmetry: 0.,
+        };
+        let bounds = [Vector3::new(-2., -2., -2.), Vector3::new(2., 2., 2.)];
+
+        let (position, look_at, up) = (
+            Vector3::zero(),
+            Vector3::new(0., 0., -1.),
+            Vector3::new(0., 1., 0.),
+        );
+        let cameras: [Box<dyn camera::Camera + Send + Sync>; 2] = [
+            Box::new(SimpleCamera::look_at(position, look_at, up, 90., 1.5)),
+            Box::new(ApertureCamera::look_at(
+                position, look_at, up, 60., 1.5, 0.2, 5.,
+            )),
+        ];
+
+        for camera in cameras {
+            let scene = scene::Scene::with_fog(fog, bounds, vec![]);
+            let tracer = Tracer::new(camera, scene, TracerSettings::default());
+
+            // Off-axis ray, its direction through the image plane is far from unit length.
+            let (ray, _) = tracer.camera_ray(0.95, 0.9, (0.001, 0.001), &sampler);
+            let direction = ray.direction.unit();
+            let distance = (0..3)
+                .map(|axis| {
+                    (bounds[1][axis].copysign(direction[axis]) - ray.origin[axis]) / direction[axis]
+                })
+                .fold(f32::INFINITY, f32::min);
+
+            let mut throughput = Vector3::one();
+            let mut color = Vector3::zero();
+            let scatter = tracer.sample_media(
+                Region::Outside,
+                &ray,
+                &None,
+                &mut throughput,
+                &mut color,
+                &sampler,
+            );
+
+            assert!(scatter.is_none());
+            let expected = (-absorption * distance).exp();
+            assert!(
+                (throughput.x - expected).abs() < 1e-4,
+                "{} != {}",
+                throughput.x,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_fog_behind_transparent_surface() {
+        let sampler = UniformSampler::new();
+        let absorption = 0.3;
+        let fog = Medium {
+            absorption: Vector3::new(absorption, absorption, absorption),
+            scattering: Vector3::zero(),
+            asymmetry: 0.,
+        };
+        let bounds = [Vector3::new(-2., -2., -2.), Vector3::new(2., 2., 2.)];
+
+        // Alpha never exceeds the cutoff of 1, the nearer plane is cut out entirely.
+        let plane = |height: f32, alpha_mode| {
+            let material = Material {
+                alpha_mode,
+                ..Material::default()
+            };
+            Primitive::new(
+                Shape::Plane,
+                Matrix4::from_translation(Vector3::new(0., height, 0.)),
+                Arc::new(material),
+            )
+        };
+        let planes = vec![
+            plane(-0.5, AlphaMode::Mask(1.)),
+            plane(-1.5, AlphaMode::Opaque),
+        ];
+
+        let camera = SimpleCamera::look_at(
+            Vector3::zero(),
+            Vector3::new(0., -1., 0.),
+            Vector3::new(0., 0., 1.),
+            90.,
+            1.,
+        );
+        let scene = scene::Scene::with_fog(fog, bounds, planes);
+        let tracer = Tracer::new(Box::new(camera), scene, TracerSettings::default());
+
+        let ray = Ray::new(Vector3::zero(), Vector3::new(0., -1., 0.));
+        let hit = tracer.scene.hit(&ray, 0.001, f32::MAX, 8, &sampler);
+        assert!((hit.as_ref().unwrap().t - 1.5).abs() < 1e-4);
+
+        let mut throughput = Vector3::one();
+        let mut color = Vector3::zero();
+        let scatter = tracer.sample_media(
+            Region::Outside,
+            &ray,
+            &hit,
+            &mut throughput,
+            &mut color,
+            &sampler,
+        );
+
+        assert!(scatter.is_none());
+        let expected = (-absorption * 1.5).exp();
+        assert!(
+            (throughput.x - expected).abs() < 1e-4,
+            "{} != {}",
+            throughput.x,
+            expected
+        );
+    }
 }
@@ -0,0 +1,398 @@
+use cgmath::dot;
+
+use crate::bidirectional::shading_correction;
+use crate::brdf::*;
+use crate::math::*;
+use crate::pathtracer::{Integrator, Splat, Tracer};
+use crate::random::{Sampler, UniformSampler};
+use crate::ray::Ray;
+use crate::threadpool::ThreadPool;
+
+// Progressive photon mapping.
+// Source: "Progressive Photon Mapping: A Probabilistic Approach" by Knaus and Zwicker
+// Source: https://pbr-book.org/3ed-2018/Light_Transport_III_Bidirectional_Methods/Stochastic_Progressive_Photon_Mapping
+// Every pass traces new photons from lights and camera paths gather them around the first rough
+// surface they hit. Passes are independent estimates with the lookup radius shrinking from pass to
+// pass, so their average converges. Direct light comes from shadow rays as in the path tracer and
+// photons carry only light which bounced at least once, without shadow rays photons carry direct
+// light as well. Emissive surfaces and the environment do not emit photons, they are found by
+// camera paths through smooth surfaces alone. Media are not sampled.
+pub struct PhotonMapping<'a> {
+    tracer: &'a Tracer,
+    photons: Option<&'a PhotonMap>,
+}
+
+/// Ratio of photons kept from pass to pass by the radius reduction.
+const ALPHA: f32 = 2. / 3.;
+
+/// Returns lookup radius of given pass numbered from one.
+fn pass_radius(initial: f32, pass: u32) -> f32 {
+    let squared = (1..pass).fold(initial * initial, |squared, i| {
+        squared * (i as f32 + ALPHA) / (i as f32 + 1.)
+    });
+    squared.sqrt()
+}
+
+struct Photon {
+    position: Vector3,
+    direction: Vector3, //< Direction of travel
+    power: Vector3,
+}
+
+/// Photons of a single pass in a hash grid with cells of the size of the lookup radius.
+pub(crate) struct PhotonMap {
+    /// Photons ordered by hash bucket.
+    photons: Vec<Photon>,
+    /// Index of the first photon of every bucket, followed by the number of photons.
+    buckets: Vec<usize>,
+    radius: f32,
+    /// Number of photon paths including those which stored no photon.
+    emitted: usize,
+}
+
+impl PhotonMap {
+    /// Traces photons of given pass numbered from one, in parallel.
+    pub(crate) fn trace(tracer: &Tracer, pass: u32) -> Self {
+        let settings = tracer.settings();
+        let (_, scene_radius) = tracer.scene().bounding_sphere();
+        let radius = pass_radius(settings.photon_radius * scene_radius, pass);
+        let emitted = settings.photons as usize;
+
+        if radius <= 0. {
+            return Self::new(Vec::new(), 1., emitted);
+        }
+
+        let threads = ThreadPool::thread_count();
+
+        let photons = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|thread| {
+                    let count = emitted / threads + usize::from(thread < emitted % threads);
+                    scope.spawn(move || {
+                        let sampler = UniformSampler::new();
+                        let mut photons = Vec::new();
+                        for _ in 0..count {
+                            trace_photon(tracer, &sampler, &mut photons);
+                        }
+                        photons
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("photon tracing should not panic"))
+                .collect()
+        });
+
+        Self::new(photons, radius, emitted)
+    }
+
+    fn new(photons: Vec<Photon>, radius: f32, emitted: usize) -> Self {
+        let count = photons.len().max(1);
+
+        let mut photons: Vec<_> = photons
+            .into_iter()
+            .map(|photon| (bucket(cell(&photon.position, radius), count), photon))
+            .collect();
+        photons.sort_unstable_by_key(|(bucket, _)| *bucket);
+
+        // Counts of photons turned to indices of the first photon of every bucket.
+        let mut buckets = vec![0; count + 1];
+        for (bucket, _) in &photons {
+            buckets[*bucket] += 1;
+        }
+        let mut start = 0;
+        for bucket in buckets.iter_mut() {
+            let count = *bucket;
+            *bucket = start;
+            start += count;
+        }
+
+        Self {
+            photons: photons.into_iter().map(|(_, photon)| photon).collect(),
+            buckets,
+            radius,
+            emitted,
+        }
+    }
+
+    /// Calls given function for photons within the lookup radius around the position.
+    fn lookup(&self, position: &Vector3, mut f: impl FnMut(&Photon)) {
+        let center = cell(position, self.radius);
+        let count = self.buckets.len() - 1;
+        let radius_squared = self.radius * self.radius;
+
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    let cell = [center[0] + x, center[1] + y, center[2] + z];
+                    let bucket = bucket(cell, count);
+
+                    for photon in &self.photons[self.buckets[bucket]..self.buckets[bucket + 1]] {
+                        // Distinct cells may share a bucket.
+                        if self::cell(&photon.position, self.radius) == cell
+                            && (photon.position - *position).squared_length() < radius_squared
+                        {
+                            f(photon);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn cell(position: &Vector3, size: f32) -> [i32; 3] {
+    [
+        (position.x / size).floor() as i32,
+        (position.y / size).floor() as i32,
+        (position.z / size).floor() as i32,
+    ]
+}
+
+// Source: "Optimized Spatial Hashing for Collision Detection of Deformable Objects" by Teschner et al.
+fn bucket(cell: [i32; 3], count: usize) -> usize {
+    let hash = (cell[0] as u32).wrapping_mul(73856093)
+        ^ (cell[1] as u32).wrapping_mul(19349663)
+        ^ (cell[2] as u32).wrapping_mul(83492791);
+    hash as usize % count
+}
+
+/// Traces photon from a randomly selected light, storing it at rough surfaces after the first
+/// bounce, or from the first one when direct light is not estimated by shadow rays.
+fn trace_photon(tracer: &Tracer, sampler: &UniformSampler, photons: &mut Vec<Photon>) {
+    let settings = tracer.settings();
+    let scene = tracer.scene();
+    let lights = scene.lights();
+    let (index, probability) = match scene
+        .light_sampler()
+        .sample_emitting(settings.light_selection, sampler.next_float())
+    {
+        Some(selected) => selected,
+        None => return,
+    };
+    let light = &lights[index];
+    let emission = match light.sample_emission(scene.bounding_sphere(), sampler) {
+        Some(emission) => emission,
+        None => return,
+    };
+
+    let mut ray = emission.ray;
+    // Contributions of lights are averaged as in the path tracer.
+    let mut power = to_v3(1. / (probability * lights.len() as f32 * emission.pdf));
+    let mut throughput = Vector3::one();
+
+    for bounce in 1..=settings.max_scatter_depth {
+        let hit = match scene.hit(
+            &ray,
+            settings.t_min,
+            settings.t_max,
+            settings.max_transparent_hits,
+            sampler,
+        ) {
+            Some(hit) => hit,
+            None => break,
+        };
+
+        if bounce == 1 {
+            power = power.mul(light.emitted(&ray.origin, &hit.position));
+        }
+
+        let material = hit.resolve_material();
+        if (bounce > 1 || !settings.shadow_rays) && !material.is_smooth() {
+            photons.push(Photon {
+                position: hit.position,
+                direction: ray.direction,
+                power: power.mul(throughput),
+            });
+        }
+
+        if bounce == settings.max_scatter_depth {
+            break;
+        }
+        match tracer.russian_roulette(bounce, luminance(throughput), sampler) {
+            Some(survival) => throughput /= survival,
+            None => break,
+        }
+
+        let (wi, weight) = match scatter(&hit, &material, &ray, sampler) {
+            Some(scattered) => scattered,
+            None => break,
+        };
+
+        // Photons carry importance, which scatters with the adjoint BRDF.
+        throughput =
+            throughput.mul(weight) * shading_correction(&hit, &material, -ray.direction, wi);
+        ray = Ray::new(hit.position, wi);
+    }
+}
+
+/// Samples direction of the ray leaving the surface, returns it with weight of the throughput.
+fn scatter(
+    hit: &Hit,
+    material: &ResolvedMaterial,
+    ray: &Ray,
+    sampler: &UniformSampler,
+) -> Option<(Vector3, Vector3)> {
+    let brdf = &(*hit.material.brdf);
+    let wo = ray.direction;
+
+    // Probability of the selected BRDF is included in its pdf.
+    let brdf_type = match sampler.next_float() < brdf.probability(&-wo, material) {
+        true => BrdfType::Specular,
+        false => BrdfType::Diffuse,
+    };
+
+    let wi = brdf.sample(brdf_type, &wo, material, sampler)?.unit();
+    let pdf = brdf.pdf(&wi, &wo, material);
+    if pdf <= 0. {
+        return None;
+    }
+
+    Some((wi, brdf.eval(&wi, &wo, material) / pdf))
+}
+
+impl<'a> PhotonMapping<'a> {
+    /// Creates integrator gathering photons of the current pass, without them it estimates only
+    /// direct light and light found through smooth surfaces.
+    pub fn new(tracer: &'a Tracer, photons: Option<&'a PhotonMap>) -> Self {
+        Self { tracer, photons }
+    }
+
+    /// Returns light of photons around the hit scattered into the ray of given direction.
+    fn photon_light(&self, hit: &Hit, material: &ResolvedMaterial, wo: &Vector3) -> Vector3 {
+        let map = match self.photons {
+            Some(map) if map.emitted > 0 => map,
+            _ => return Vector3::zero(),
+        };
+
+        let brdf = &(*hit.material.brdf);
+        let mut color = Vector3::zero();
+        map.lookup(&hit.position, |photon| {
+            // BRDF includes cosine of the direction to light, while photon power is the flux.
+            let wi = -photon.direction;
+            let cosine = dot(wi, material.shading_normal).abs();
+            if cosine > 0. {
+                color += brdf.eval(&wi, wo, material).mul(photon.power) / cosine;
+            }
+        });
+
+        color * ONE_OVER_PI / (map.radius * map.radius * map.emitted as f32)
+    }
+}
+
+impl Integrator for PhotonMapping<'_> {
+    fn radiance(
+        &self,
+        x: f32,
+        y: f32,
+        pixel_size: (f32, f32),
+        _splats: &mut Vec<Splat>,
+    ) -> Vector3 {
+        let sampler = UniformSampler::new();
+        let settings = self.tracer.settings();
+        let scene = self.tracer.scene();
+
+        let (mut ray, weight) = self.tracer.camera_ray(x, y, pixel_size, &sampler);
+        if weight <= 0. {
+            return Vector3::zero();
+        }
+
+        let mut color = Vector3::zero();
+        let mut throughput = Vector3::one() * weight;
+
+        for bounce in 1..=settings.max_scatter_depth {
+            let hit = match scene.hit(
+                &ray,
+                settings.t_min,
+                settings.t_max,
+                settings.max_transparent_hits,
+                &sampler,
+            ) {
+                Some(hit) => hit,
+                None => {
+                    color += throughput.mul(scene.environment(&ray));
+                    break;
+                }
+            };
+
+            let material = hit.resolve_material();
+            color += throughput.mul(material.emissive);
+
+            if settings.shadow_rays {
+                color += throughput.mul(self.tracer.direct_light(
+                    &hit,
+                    &material,
+                    &ray.direction,
+                    &sampler,
+                ));
+            }
+
+            if !material.is_smooth() {
+                color += throughput.mul(self.photon_light(&hit, &material, &ray.direction));
+                break;
+            }
+
+            if bounce == settings.max_scatter_depth {
+                break;
+            }
+            match self
+                .tracer
+                .russian_roulette(bounce, luminance(throughput), &sampler)
+            {
+                Some(survival) => throughput /= survival,
+                None => break,
+            }
+
+            let (wi, weight) = match scatter(&hit, &material, &ray, &sampler) {
+                Some(scattered) => scattered,
+                None => break,
+            };
+
+            throughput = throughput.mul(weight);
+            ray = Ray {
+                differentials: hit.scatter_differentials(&ray, &material, wi),
+                ..Ray::new(hit.position, wi)
+            };
+        }
+
+        color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let photon = |x: f32| Photon {
+            position: Vector3::new(x, 0.5, -0.5),
+            direction: Vector3::new(0., -1., 0.),
+            power: Vector3::one(),
+        };
+        let map = PhotonMap::new(
+            vec![photon(0.), photon(0.09), photon(-0.1), photon(0.3)],
+            0.2,
+            4,
+        );
+
+        let mut found = 0;
+        map.lookup(&Vector3::new(0.05, 0.5, -0.5), |_| found += 1);
+        assert_eq!(found, 3);
+
+        let mut found = 0;
+        map.lookup(&Vector3::new(0., 0.8, -0.5), |_| found += 1);
+        assert_eq!(found, 0);
+    }
+
+    #[test]
+    fn test_pass_radius() {
+        assert_eq!(pass_radius(0.5, 1), 0.5);
+        assert!(pass_radius(0.5, 100) < pass_radius(0.5, 10));
+        // Squared radius shrinks roughly with pass^(alpha - 1).
+        let ratio = (pass_radius(1., 10000) / pass_radius(1., 1000)).powi(2);
+        assert!((ratio - 10f32.powf(ALPHA - 1.)).abs() < 0.01);
+    }
+}
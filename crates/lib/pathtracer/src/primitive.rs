@@ -0,0 +1,412 @@
+use crate::brdf::Hit;
+use crate::material::{Material, TexCoord, UV_SETS};
+use crate::math::*;
+use crate::ray::Ray;
+
+use cgmath::{Matrix, SquareMatrix};
+use kdtree_ray::*;
+
+use std::sync::Arc;
+
+/// Analytic shape in its local space.
+///
+/// Flat shapes lie in the XZ plane facing +Y, cylinder is open and aligned with the Y axis.
+pub enum Shape {
+    Sphere { radius: f32 },
+    Quad { half_width: f32, half_depth: f32 },
+    Disk { radius: f32 },
+    Cylinder { radius: f32, half_height: f32 },
+    Plane,
+}
+
+struct LocalHit {
+    t: f32,
+    uv: (f32, f32),
+    normal: Vector3,
+    dpdu: Vector3,
+    dpdv: Vector3,
+}
+
+// Maps angle around the Y axis to [0; 1] so that dp/du x dp/dv points outwards.
+#[inline]
+fn azimuth_u(x: f32, z: f32) -> f32 {
+    (std::f32::consts::PI - z.atan2(x)) / TWO_PI
+}
+
+fn hit_sphere(
+    origin: Vector3,
+    direction: Vector3,
+    radius: f32,
+    t_min: f32,
+    t_max: f32,
+) -> Option<LocalHit> {
+    let a = cgmath::dot(direction, direction);
+    let b = 2.0 * cgmath::dot(origin, direction);
+    let c = cgmath::dot(origin, origin) - radius * radius;
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant <= 0.0 {
+        return None;
+    }
+
+    let discriminant_sq = discriminant.sqrt();
+    let t = [
+        (-b - discriminant_sq) / (2.0 * a),
+        (-b + discriminant_sq) / (2.0 * a),
+    ]
+    .into_iter()
+    .find(|t| *t >= t_min && *t <= t_max)?;
+
+    let p = origin + direction * t;
+    let normal = p / radius;
+    let theta = clamp(normal.y, -1., 1.).acos();
+
+    // Derivatives are undefined at the poles, pick tangent frame aligned with the normal.
+    let rho = (p.x * p.x + p.z * p.z).sqrt();
+    let (dpdu, dpdv) = match rho > 0. {
+        true => (
+            Vector3::new(p.z, 0., -p.x) * TWO_PI,
+            Vector3::new(-p.y * p.x / rho, rho, -p.y * p.z / rho) * std::f32::consts::PI,
+        ),
+        false => (Vector3::new(1., 0., 0.), Vector3::new(0., 0., -normal.y)),
+    };
+
+    Some(LocalHit {
+        t,
+        uv: (azimuth_u(p.x, p.z), 1. - theta * ONE_OVER_PI),
+        normal,
+        dpdu,
+        dpdv,
+    })
+}
+
+fn hit_cylinder(
+    origin: Vector3,
+    direction: Vector3,
+    radius: f32,
+    half_height: f32,
+    t_min: f32,
+    t_max: f32,
+) -> Option<LocalHit> {
+    let a = direction.x * direction.x + direction.z * direction.z;
+    let b = 2.0 * (origin.x * direction.x + origin.z * direction.z);
+    let c = origin.x * origin.x + origin.z * origin.z - radius * radius;
+
+    let discriminant = b * b - 4.0 * a * c;
+    if a == 0. || discriminant <= 0.0 {
+        return None;
+    }
+
+    let discriminant_sq = discriminant.sqrt();
+    let (t, p) = [
+        (-b - discriminant_sq) / (2.0 * a),
+        (-b + discriminant_sq) / (2.0 * a),
+    ]
+    .into_iter()
+    .map(|t| (t, origin + direction * t))
+    .find(|(t, p)| *t >= t_min && *t <= t_max && p.y.abs() <= half_height)?;
+
+    Some(LocalHit {
+        t,
+        uv: (
+            azimuth_u(p.x, p.z),
+            (p.y + half_height) / (2. * half_height),
+        ),
+        normal: Vector3::new(p.x, 0., p.z) / radius,
+        dpdu: Vector3::new(p.z, 0., -p.x) * TWO_PI,
+        dpdv: Vector3::new(0., 2. * half_height, 0.),
+    })
+}
+
+// Intersection with the XZ plane, returns distance and local position.
+fn hit_xz_plane(
+    origin: Vector3,
+    direction: Vector3,
+    t_min: f32,
+    t_max: f32,
+) -> Option<(f32, Vector3)> {
+    if direction.y.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let t = -origin.y / direction.y;
+    if t < t_min || t > t_max {
+        return None;
+    }
+
+    Some((t, origin + direction * t))
+}
+
+fn planar_hit(t: f32, uv: (f32, f32), size: (f32, f32)) -> LocalHit {
+    LocalHit {
+        t,
+        uv,
+        normal: Vector3::new(0., 1., 0.),
+        dpdu: Vector3::new(size.0, 0., 0.),
+        dpdv: Vector3::new(0., 0., -size.1),
+    }
+}
+
+impl Shape {
+    fn bounds(&self) -> (Vector3, Vector3) {
+        // Flat shapes get a small thickness so that the box is never degenerate.
+        let thickness = 0.0001;
+
+        let extent = match *self {
+            Shape::Sphere { radius } => Vector3::new(radius, radius, radius),
+            Shape::Quad {
+                half_width,
+                half_depth,
+            } => Vector3::new(half_width, thickness, half_depth),
+            Shape::Disk { radius } => Vector3::new(radius, thickness, radius),
+            Shape::Cylinder {
+                radius,
+                half_height,
+            } => Vector3::new(radius, half_height, radius),
+            Shape::Plane => Vector3::new(f32::INFINITY, thickness, f32::INFINITY),
+        };
+
+        (-extent, extent)
+    }
+
+    fn intersect(
+        &self,
+        origin: Vector3,
+        direction: Vector3,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<LocalHit> {
+        match *self {
+            Shape::Sphere { radius } => hit_sphere(origin, direction, radius, t_min, t_max),
+            Shape::Cylinder {
+                radius,
+                half_height,
+            } => hit_cylinder(origin, direction, radius, half_height, t_min, t_max),
+            Shape::Quad {
+                half_width,
+                half_depth,
+            } => {
+                let (t, p) = hit_xz_plane(origin, direction, t_min, t_max)?;
+                if p.x.abs() > half_width || p.z.abs() > half_depth {
+                    return None;
+                }
+
+                let uv = (
+                    (p.x + half_width) / (2. * half_width),
+                    (half_depth - p.z) / (2. * half_depth),
+                );
+                Some(planar_hit(t, uv, (2. * half_width, 2. * half_depth)))
+            }
+            Shape::Disk { radius } => {
+                let (t, p) = hit_xz_plane(origin, direction, t_min, t_max)?;
+                if p.x * p.x + p.z * p.z > radius * radius {
+                    return None;
+                }
+
+                let uv = ((p.x / radius + 1.) / 2., (1. - p.z / radius) / 2.);
+                Some(planar_hit(t, uv, (2. * radius, 2. * radius)))
+            }
+            Shape::Plane => {
+                // Infinite plane is textured in local units.
+                let (t, p) = hit_xz_plane(origin, direction, t_min, t_max)?;
+                Some(planar_hit(t, (p.x, -p.z), (1., 1.)))
+            }
+        }
+    }
+}
+
+/// Transformed analytic shape with material.
+pub struct Primitive {
+    shape: Shape,
+    to_world: Matrix4,
+    to_local: Matrix4,
+    normal_matrix: Matrix3,
+    aabb: (Vector3, Vector3),
+    pub material: Arc<Material>,
+}
+
+impl Primitive {
+    pub fn new(shape: Shape, transformation: Matrix4, material: Arc<Material>) -> Primitive {
+        let to_local = transformation.invert().unwrap_or_else(Matrix4::identity);
+        let linear = Matrix3::from_cols(
+            transformation.x.truncate(),
+            transformation.y.truncate(),
+            transformation.z.truncate(),
+        );
+        let normal_matrix = linear
+            .invert()
+            .unwrap_or_else(Matrix3::identity)
+            .transpose();
+
+        let (min, max) = shape.bounds();
+        let mut aabb = (
+            Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            Vector3::new(-f32::INFINITY, -f32::INFINITY, -f32::INFINITY),
+        );
+
+        for corner in 0..8 {
+            let local = Vector3::new(
+                if corner & 1 == 0 { min.x } else { max.x },
+                if corner & 2 == 0 { min.y } else { max.y },
+                if corner & 4 == 0 { min.z } else { max.z },
+            );
+            let world = (transformation * local.extend(1.0)).truncate();
+            aabb = (aabb.0.min(world), aabb.1.max(world));
+        }
+
+        Primitive {
+            shape,
+            to_world: transformation,
+            to_local,
+            normal_matrix,
+            aabb,
+            material,
+        }
+    }
+
+    /// Returns true for shapes which cannot be stored in the KD tree.
+    pub fn is_unbounded(&self) -> bool {
+        matches!(self.shape, Shape::Plane)
+    }
+
+    pub fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        // Direction is not normalized so that distances are the same in both spaces.
+        let origin = (self.to_local * ray.origin.extend(1.0)).truncate();
+        let direction = (self.to_local * ray.direction.extend(0.0)).truncate();
+
+        let local = self.shape.intersect(origin, direction, t_min, t_max)?;
+
+        // Backface culling, sign of the dot product does not change with transformation.
+        if self.material.is_single_sided() && cgmath::dot(direction, local.normal) > 0. {
+            return None;
+        }
+
+        let to_world = |v: Vector3| (self.to_world * v.extend(0.0)).truncate();
+        let (dpdu, dpdv) = (to_world(local.dpdu), to_world(local.dpdv));
+
+        Some(Hit {
+            position: ray.point_at(local.t),
+            material: self.material.clone(),
+            t: local.t,
+            tex_coord: TexCoord::new([local.uv; UV_SETS]),
+            normal: (self.normal_matrix * local.normal).unit(),
+            tangent: dpdu.unit(),
+            bitangent: dpdv.unit(),
+            dpdu: [dpdu; UV_SETS],
+            dpdv: [dpdv; UV_SETS],
+            position_differentials: None,
+        })
+    }
+}
+
+impl BoundingBox for Primitive {
+    fn bounding_box(&self) -> AABB {
+        [self.aabb.0, self.aabb.1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Primitive, Shape};
+    use crate::material::Material;
+    use crate::math::*;
+    use crate::ray::{Ray, RayDifferentials};
+
+    use std::sync::Arc;
+
+    fn primitive(shape: Shape, transformation: Matrix4) -> Primitive {
+        let material = Material {
+            single_sided: true,
+            ..Material::default()
+        };
+
+        Primitive::new(shape, transformation, Arc::new(material))
+    }
+
+    #[test]
+    fn test_intersect() {
+        let transformation = Matrix4::from_translation(Vector3::new(0., -5., 0.))
+            * Matrix4::from_nonuniform_scale(2., 1., 3.);
+
+        let down = Ray::new(Vector3::new(0.1, 10., 0.2), Vector3::new(0., -1., 0.));
+        let side = Ray::new(Vector3::new(0., -5., 10.), Vector3::new(0., 0., -1.));
+
+        let cases = [
+            (Shape::Sphere { radius: 1. }, down, 14.0035),
+            (
+                Shape::Quad {
+                    half_width: 1.,
+                    half_depth: 1.,
+                },
+                down,
+                15.,
+            ),
+            (Shape::Disk { radius: 1. }, down, 15.),
+            (
+                Shape::Cylinder {
+                    radius: 1.,
+                    half_height: 1.,
+                },
+                side,
+                7.,
+            ),
+            (Shape::Plane, down, 15.),
+        ];
+
+        for (shape, ray, t) in cases {
+            let primitive = primitive(shape, transformation);
+            let hit = primitive.intersect(&ray, 0.001, f32::MAX).unwrap();
+
+            assert!((hit.t - t).abs() < 0.001);
+            assert!(cgmath::dot(hit.normal, ray.direction) < 0.);
+            assert!((hit.tangent.cross(hit.bitangent).unit() - hit.normal).length() < 0.01);
+
+            // Backfaces are culled for single sided materials.
+            let back = Ray::new(ray.point_at(t + 0.1), -ray.direction);
+            assert!(primitive.intersect(&back, 0.001, 0.2).is_none());
+        }
+    }
+
+    #[test]
+    fn test_differentials() {
+        let transformation = Matrix4::from_translation(Vector3::new(0., -5., 0.))
+            * Matrix4::from_nonuniform_scale(2., 1., 3.);
+        let quad = Shape::Quad {
+            half_width: 1.,
+            half_depth: 1.,
+        };
+        let primitive = primitive(quad, transformation);
+
+        let origin = Vector3::new(0.1, 10., 0.2);
+        let ray = Ray {
+            differentials: Some(RayDifferentials {
+                rx_origin: origin,
+                rx_direction: Vector3::new(0.01, -1., 0.),
+                ry_origin: origin,
+                ry_direction: Vector3::new(0., -1., 0.01),
+            }),
+            ..Ray::new(origin, Vector3::new(0., -1., 0.))
+        };
+
+        let mut hit = primitive.intersect(&ray, 0.001, f32::MAX).unwrap();
+        hit.compute_differentials(&ray);
+
+        // Offsets of 0.15 on the quad of size 4 x 6, v grows against Z.
+        let (duv_dx, duv_dy) = (hit.tex_coord.sets[0].duv_dx, hit.tex_coord.sets[0].duv_dy);
+        assert!((duv_dx.0 - 0.0375).abs() < 1e-4 && duv_dx.1.abs() < 1e-4);
+        assert!(duv_dy.0.abs() < 1e-4 && (duv_dy.1 + 0.025).abs() < 1e-4);
+
+        // Differentials follow mirror reflection, but not diffuse scattering.
+        let material = hit.resolve_material();
+        let reflected = hit
+            .scatter_differentials(&ray, &material, Vector3::new(0., 1., 0.))
+            .unwrap();
+        assert!((reflected.rx_origin - Vector3::new(0.25, -5., 0.2)).length() < 1e-4);
+        assert!((reflected.rx_direction - Vector3::new(0.01, 1., 0.).unit()).length() < 1e-4);
+
+        let diffuse = Vector3::new(0.5, 0.5, 0.5).unit();
+        assert!(hit
+            .scatter_differentials(&ray, &material, diffuse)
+            .is_none());
+    }
+}
@@ -8,7 +8,7 @@ pub trait Sampler {
     fn next_float_norm(&self) -> f32;
 }
 
-pub fn unit_sphere(sampler: &impl Sampler) -> Vector3 {
+pub fn unit_sphere(sampler: &(impl Sampler + ?Sized)) -> Vector3 {
     loop {
         let vector = Vector3::new(
             sampler.next_float_norm(),
@@ -21,7 +21,7 @@ pub fn unit_sphere(sampler: &impl Sampler) -> Vector3 {
     }
 }
 
-pub fn unit_disk(sampler: &impl Sampler) -> Vector3 {
+pub fn unit_disk(sampler: &(impl Sampler + ?Sized)) -> Vector3 {
     loop {
         let p = Vector3::new(sampler.next_float_norm(), sampler.next_float_norm(), 0.);
         if p.squared_length() < 1. {
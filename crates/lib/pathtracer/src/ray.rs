@@ -1,17 +1,32 @@
 use std::f32::EPSILON;
 
+use crate::material::UV_SETS;
 use crate::math::*;
 use crate::mesh::*;
 
+/// Rays through the neighbouring pixels, offset along the image plane axes.
+#[derive(Debug, Copy, Clone)]
+pub struct RayDifferentials {
+    pub rx_origin: Vector3,
+    pub rx_direction: Vector3,
+    pub ry_origin: Vector3,
+    pub ry_direction: Vector3,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Ray {
     pub origin: Vector3,
     pub direction: Vector3,
+    pub differentials: Option<RayDifferentials>,
 }
 
 impl Ray {
     pub fn new(origin: Vector3, direction: Vector3) -> Self {
-        Self { origin, direction }
+        Self {
+            origin,
+            direction,
+            differentials: None,
+        }
     }
 
     pub fn point_at(&self, distance: f32) -> Vector3 {
@@ -19,40 +34,14 @@ impl Ray {
     }
 }
 
-type Intersection = Option<f32>;
-
-pub fn ray_sphere_intersection(ray: &Ray, sphere: &Sphere, t_min: f32, t_max: f32) -> Intersection {
-    let ray_to_center = ray.origin - sphere.center;
-
-    let a = cgmath::dot(ray.direction, ray.direction);
-    let b = 2.0 * cgmath::dot(ray_to_center, ray.direction);
-    let c = cgmath::dot(ray_to_center, ray_to_center) - sphere.radius * sphere.radius;
-
-    let discriminant = b * b - 4.0 * a * c;
-
-    if discriminant > 0.0 {
-        let discriminant_sq = discriminant.sqrt();
-
-        let xt = (-b - discriminant_sq) / (2.0 * a);
-        if xt >= t_min && xt <= t_max {
-            return Some(xt);
-        }
-
-        let xt = (-b + discriminant_sq) / (2.0 * a);
-        if xt >= t_min && xt <= t_max {
-            return Some(xt);
-        }
-    }
-
-    None
-}
-
 pub struct TriangleIntersection {
     pub t: f32,
-    pub uv: (f32, f32),
+    pub uv: [(f32, f32); UV_SETS],
     pub normal: Vector3,
     pub tangent: Vector3,
     pub bitangent: Vector3,
+    pub dpdu: [Vector3; UV_SETS],
+    pub dpdv: [Vector3; UV_SETS],
 }
 
 pub fn ray_triangle_intersection(
@@ -101,25 +90,36 @@ pub fn ray_triangle_intersection(
     }
 
     // https://learnopengl.com/Advanced-Lighting/Normal-Mapping
-    let uv0v1_x = triangle.vertex[1].uv.0 - triangle.vertex[0].uv.0;
-    let uv0v1_y = triangle.vertex[1].uv.1 - triangle.vertex[0].uv.1;
-    let uv0v2_x = triangle.vertex[2].uv.0 - triangle.vertex[0].uv.0;
-    let uv0v2_y = triangle.vertex[2].uv.1 - triangle.vertex[0].uv.1;
-
-    let bu = triangle.vertex[0].uv.0 + u * uv0v1_x + v * uv0v2_x;
-    let bv = triangle.vertex[0].uv.1 + u * uv0v1_y + v * uv0v2_y;
-
-    // TODO: Make optional.
-    let f = 1. / (uv0v1_x * uv0v2_y - uv0v2_x * uv0v1_y);
-
-    let tangent = (f * (uv0v2_y * v0v1 - uv0v1_y * v0v2)).unit();
-    let bitangent = (f * (-uv0v2_x * v0v1 + uv0v1_x * v0v2)).unit();
+    let mut uv = [(0., 0.); UV_SETS];
+    let mut dpdu = [Vector3::zero(); UV_SETS];
+    let mut dpdv = [Vector3::zero(); UV_SETS];
+
+    for set in 0..UV_SETS {
+        let uv0 = triangle.vertex[0].uv[set];
+        let uv0v1_x = triangle.vertex[1].uv[set].0 - uv0.0;
+        let uv0v1_y = triangle.vertex[1].uv[set].1 - uv0.1;
+        let uv0v2_x = triangle.vertex[2].uv[set].0 - uv0.0;
+        let uv0v2_y = triangle.vertex[2].uv[set].1 - uv0.1;
+
+        uv[set] = (
+            uv0.0 + u * uv0v1_x + v * uv0v2_x,
+            uv0.1 + u * uv0v1_y + v * uv0v2_y,
+        );
+
+        // TODO: Make optional.
+        let f = 1. / (uv0v1_x * uv0v2_y - uv0v2_x * uv0v1_y);
+
+        dpdu[set] = f * (uv0v2_y * v0v1 - uv0v1_y * v0v2);
+        dpdv[set] = f * (-uv0v2_x * v0v1 + uv0v1_x * v0v2);
+    }
 
     Some(TriangleIntersection {
         t,
-        uv: (bu, bv),
+        uv,
         normal,
-        tangent,
-        bitangent,
+        tangent: dpdu[0].unit(),
+        bitangent: dpdv[0].unit(),
+        dpdu,
+        dpdv,
     })
 }
@@ -2,34 +2,83 @@ use crate::brdf::Hit;
 use crate::env;
 use crate::import_scene::*;
 use crate::light::Light;
+use crate::light_sampling::LightSampler;
+use crate::material::TexCoord;
 use crate::math::*;
+use crate::medium::Medium;
 use crate::mesh::*;
+use crate::primitive::Primitive;
+use crate::random::Sampler;
 use crate::ray::{self, ray_triangle_intersection};
+use crate::volume::Volume;
 use crate::{import_gltf, Error};
 
 use kdtree_ray::*;
 
 use std::path::Path;
+use std::sync::Arc;
+
+// Object stored in the scene KD tree.
+enum SceneObject {
+    Mesh(Mesh),
+    Primitive(Primitive),
+    Volume(Volume),
+}
+
+impl BoundingBox for SceneObject {
+    fn bounding_box(&self) -> AABB {
+        match self {
+            SceneObject::Mesh(mesh) => mesh.bounding_box(),
+            SceneObject::Primitive(primitive) => primitive.bounding_box(),
+            SceneObject::Volume(volume) => volume.bounding_box(),
+        }
+    }
+}
 
 pub struct Scene {
-    kd: KDtree<Mesh>,
+    kd: KDtree<SceneObject>,
+    // Infinite planes do not fit into the KD tree, they are tested for every ray.
+    unbounded: Vec<Primitive>,
 
     lights: Vec<Light>,
-    spheres: Vec<Sphere>,
+    light_sampler: LightSampler,
 
     env: Box<dyn env::Environment + Send + Sync>,
+
+    fog: Option<Medium>,
+    // Fog is limited to bounds of the scene, rays escaping to the environment leave it.
+    bounds: AABB,
+}
+
+/// Scattering event in a heterogeneous volume.
+pub struct VolumeCollision {
+    pub distance: f32,
+    pub albedo: Vector3,
+    pub asymmetry: f32,
+}
+
+pub struct VolumeSample {
+    pub collision: Option<VolumeCollision>,
+    /// Distances along the ray with estimates of radiance emitted there.
+    pub emission: Vec<(f32, Vector3)>,
 }
 
 pub trait SceneImportHandler {
     fn handle_material(&mut self, color: Vector3, texture: Option<(u32, u32, &[u8])>);
     fn handle_mesh(&mut self, vertices: &[f32], indices: &[u32], material_index: i32);
+    /// Orthographic camera given by half of the width and height of its image.
     fn handle_ortho_camera(&mut self, width: f32, height: f32, near: f32, far: f32);
+    /// Perspective camera with vertical field of view in radians.
     fn handle_perspective_camera(&mut self, v_fov: f32, aspect_ratio: f32, near: f32, far: f32);
+    /// Placement of the camera of given index in the scene, with unit forward and up directions.
+    ///
+    /// Cameras are indexed in the order they were reported, across all loaded files.
     fn handle_camera_transform(
         &mut self,
         camera_index: usize,
-        translate: &[f32; 3],
-        rotate: &[f32; 4],
+        position: Vector3,
+        forward: Vector3,
+        up: Vector3,
     );
 }
 
@@ -37,9 +86,12 @@ impl Scene {
     pub fn empty() -> Scene {
         Scene {
             kd: KDtree::new(vec![]),
+            unbounded: vec![],
             lights: vec![],
-            spheres: vec![],
+            light_sampler: LightSampler::new(&[], 0.),
             env: Box::new(env::Black {}),
+            fog: None,
+            bounds: [Vector3::zero(), Vector3::zero()],
         }
     }
 
@@ -49,98 +101,179 @@ impl Scene {
     {
         let description = SceneDescription::from_file(filename)?;
 
-        let mut meshes = vec![];
+        let textures = description.load_textures()?;
+
+        let mut objects = vec![];
+        let mut gltf_lights = vec![];
+        let mut cameras = 0;
         for mesh in description.meshes() {
-            meshes.append(&mut import_gltf::load(
+            let (meshes, lights) = import_gltf::load(
                 Path::new(mesh.path()),
                 mesh.transformation(),
+                &description.mesh_materials(mesh, &textures)?,
+                &mut cameras,
                 handler,
-            )?);
+            )?;
+            objects.extend(meshes.into_iter().map(SceneObject::Mesh));
+            gltf_lights.extend(lights);
+        }
+
+        let mut unbounded = vec![];
+        for description_primitive in description.primitives() {
+            let primitive = Primitive::new(
+                description_primitive.shape.to_shape(),
+                description_primitive.transformation(),
+                Arc::new(description.primitive_material(description_primitive, &textures)?),
+            );
+
+            match primitive.is_unbounded() {
+                true => unbounded.push(primitive),
+                false => objects.push(SceneObject::Primitive(primitive)),
+            }
+        }
+
+        for description_volume in description.volumes() {
+            objects.push(SceneObject::Volume(description_volume.to_volume()?));
         }
 
-        let kd = KDtree::new(meshes);
+        let bounds = objects
+            .iter()
+            .map(|object| object.bounding_box())
+            .reduce(|a, b| [a[0].min(b[0]), a[1].max(b[1])])
+            .unwrap_or([Vector3::zero(), Vector3::zero()]);
 
-        // self.spheres = vec![
-        //     Sphere::position_radius(0.0, 0.0, -1.0, 0.5, sphere_material.clone()),
-        //     Sphere::position_radius(0.0, 100.5, -1.0, 100.0, sphere_material.clone()),
-        // ];
+        let kd = KDtree::new(objects);
 
         let env = description.environment();
+        let fog = description.fog();
+
+        let mut lights = description.lights()?;
+        lights.append(&mut gltf_lights);
+        let radius = (bounds[1] - bounds[0]).length() / 2.;
+        let light_sampler = LightSampler::new(&lights, radius);
 
         Ok(Scene {
             kd,
-            lights: description.lights(),
-            spheres: vec![],
+            unbounded,
+            fog,
+            lights,
+            light_sampler,
             env,
+            bounds,
         })
     }
 
+    /// Returns scene of given infinite planes filled with the fog inside of given bounds.
+    #[cfg(test)]
+    pub(crate) fn with_fog(fog: Medium, bounds: AABB, unbounded: Vec<Primitive>) -> Scene {
+        Scene {
+            unbounded,
+            fog: Some(fog),
+            bounds,
+            ..Scene::empty()
+        }
+    }
+
     pub fn environment(&self, ray: &ray::Ray) -> Vector3 {
         self.env.color(ray)
     }
 
-    fn hit_triangles(&self, ray: &ray::Ray, t_min: f32, t_max: f32) -> Option<Hit> {
-        optick::event!("hit tris");
+    /// Returns the global fog with the part of the ray up to `t_max` which lies inside of it.
+    pub fn fog(&self, ray: &ray::Ray, t_max: f32) -> Option<(Medium, f32, f32)> {
+        let fog = self.fog?;
 
-        let mut result: Option<Hit> = None;
+        let (mut t_near, mut t_far) = (0., t_max);
+        for axis in 0..3 {
+            let inverse = 1. / ray.direction[axis];
+            let t0 = (self.bounds[0][axis] - ray.origin[axis]) * inverse;
+            let t1 = (self.bounds[1][axis] - ray.origin[axis]) * inverse;
 
-        let ray_origin = cgmath::Vector3::new(ray.origin.x, ray.origin.y, ray.origin.z);
-        let ray_direction = cgmath::Vector3::new(ray.direction.x, ray.direction.y, ray.direction.z);
+            // NaN of rays parallel to a slab through its plane keeps the current interval.
+            t_near = t0.min(t1).max(t_near);
+            t_far = t0.max(t1).min(t_far);
+        }
 
-        let meshes = {
-            optick::event!("kd_mesh");
-            self.kd.intersect(&ray_origin, &ray_direction)
-        };
-        optick::tag!("mesh_cnt", meshes.len() as i32);
+        match t_near < t_far {
+            true => Some((fog, t_near, t_far)),
+            false => None,
+        }
+    }
 
-        for mesh in &meshes {
-            let triangles = {
-                optick::event!("kd_tris");
-                mesh.kd.intersect(&ray_origin, &ray_direction)
-            };
-            optick::tag!("tris_cnt", triangles.len() as i32);
-
-            for triangle in &triangles {
-                let intersection = ray_triangle_intersection(
-                    ray,
-                    triangle,
-                    mesh.material.single_sided,
-                    t_min,
-                    t_max,
-                );
-
-                let prev_distance = match &result {
-                    Some(hit) => hit.t,
-                    None => f32::INFINITY,
-                };
-
-                result = match intersection {
-                    Some(tr_int) if prev_distance > tr_int.t => {
-                        let point = ray.point_at(tr_int.t);
-                        Some(Hit {
-                            position: point,
-                            material: mesh.material.clone(),
-                            t: tr_int.t,
-                            uv: tr_int.uv,
-                            normal: tr_int.normal,
-                            tangent: tr_int.tangent,
-                            bitangent: tr_int.bitangent,
-                        })
-                    }
-                    Some(_) => result,
-                    None => result,
-                }
+    /// Samples the first collision with heterogeneous volumes along the ray up to `t_max`.
+    pub fn sample_volumes(
+        &self,
+        ray: &ray::Ray,
+        t_max: f32,
+        sampler: &impl Sampler,
+    ) -> VolumeSample {
+        let mut sample = VolumeSample {
+            collision: None,
+            emission: vec![],
+        };
+        let mut limit = t_max;
+
+        for volume in self.volumes(ray) {
+            if let Some(distance) = volume.sample(ray, limit, sampler, &mut sample.emission) {
+                limit = distance;
+                sample.collision = Some(VolumeCollision {
+                    distance,
+                    albedo: volume.albedo,
+                    asymmetry: volume.asymmetry,
+                });
             }
         }
 
-        result
+        // Collision in one volume hides emission of others behind it.
+        sample.emission.retain(|(t, _)| *t < limit);
+        sample
     }
 
-    pub fn hit_spheres(&self, ray: &ray::Ray, t_min: f32, t_max: f32) -> Option<Hit> {
-        let mut result: Option<Hit> = None;
+    /// Returns transmittance of heterogeneous volumes along the ray up to `t_max`.
+    pub fn volume_transmittance(&self, ray: &ray::Ray, t_max: f32, sampler: &impl Sampler) -> f32 {
+        self.volumes(ray)
+            .map(|volume| volume.transmittance(ray, t_max, sampler))
+            .product()
+    }
+
+    fn volumes(&self, ray: &ray::Ray) -> impl Iterator<Item = &Volume> {
+        let ray_origin = cgmath::Vector3::new(ray.origin.x, ray.origin.y, ray.origin.z);
+        let ray_direction = cgmath::Vector3::new(ray.direction.x, ray.direction.y, ray.direction.z);
+
+        self.kd
+            .intersect(&ray_origin, &ray_direction)
+            .into_iter()
+            .filter_map(|object| match object {
+                SceneObject::Volume(volume) => Some(volume),
+                _ => None,
+            })
+    }
+
+    fn hit_mesh(
+        ray: &ray::Ray,
+        mesh: &Mesh,
+        t_min: f32,
+        t_max: f32,
+        result: Option<Hit>,
+    ) -> Option<Hit> {
+        let mut result = result;
+
+        let ray_origin = cgmath::Vector3::new(ray.origin.x, ray.origin.y, ray.origin.z);
+        let ray_direction = cgmath::Vector3::new(ray.direction.x, ray.direction.y, ray.direction.z);
+
+        let triangles = {
+            optick::event!("kd_tris");
+            mesh.kd.intersect(&ray_origin, &ray_direction)
+        };
+        optick::tag!("tris_cnt", triangles.len() as i32);
 
-        for sphere in &self.spheres {
-            let intersection = ray::ray_sphere_intersection(ray, sphere, t_min, t_max);
+        for triangle in &triangles {
+            let intersection = ray_triangle_intersection(
+                ray,
+                triangle,
+                mesh.material.is_single_sided(),
+                t_min,
+                t_max,
+            );
 
             let prev_distance = match &result {
                 Some(hit) => hit.t,
@@ -148,18 +281,19 @@ impl Scene {
             };
 
             result = match intersection {
-                Some(distance) if prev_distance > distance => {
-                    let point = ray.point_at(distance);
-                    let uv = (0.0, 0.0); // TODO:
-
+                Some(tr_int) if prev_distance > tr_int.t => {
+                    let point = ray.point_at(tr_int.t);
                     Some(Hit {
                         position: point,
-                        material: sphere.material.clone(),
-                        t: distance,
-                        uv,
-                        normal: sphere.normal(point),
-                        tangent: Vector3::zero(),
-                        bitangent: Vector3::zero(),
+                        material: mesh.material.clone(),
+                        t: tr_int.t,
+                        tex_coord: TexCoord::new(tr_int.uv),
+                        normal: tr_int.normal,
+                        tangent: tr_int.tangent,
+                        bitangent: tr_int.bitangent,
+                        dpdu: tr_int.dpdu,
+                        dpdv: tr_int.dpdv,
+                        position_differentials: None,
                     })
                 }
                 Some(_) => result,
@@ -170,26 +304,175 @@ impl Scene {
         result
     }
 
-    pub fn hit(&self, ray: &ray::Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+    fn hit_objects(&self, ray: &ray::Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        optick::event!("hit objects");
+
+        let mut result: Option<Hit> = None;
+
+        let ray_origin = cgmath::Vector3::new(ray.origin.x, ray.origin.y, ray.origin.z);
+        let ray_direction = cgmath::Vector3::new(ray.direction.x, ray.direction.y, ray.direction.z);
+
+        let objects = {
+            optick::event!("kd_mesh");
+            self.kd.intersect(&ray_origin, &ray_direction)
+        };
+        optick::tag!("mesh_cnt", objects.len() as i32);
+
+        let primitives = objects
+            .iter()
+            .filter_map(|object| match object {
+                SceneObject::Primitive(primitive) => Some(primitive),
+                _ => None,
+            })
+            .chain(self.unbounded.iter());
+
+        for primitive in primitives {
+            let prev_distance = result.as_ref().map_or(t_max, |hit| hit.t);
+            if let Some(hit) = primitive.intersect(ray, t_min, prev_distance) {
+                result = Some(hit);
+            }
+        }
+
+        for object in &objects {
+            if let SceneObject::Mesh(mesh) = object {
+                result = Self::hit_mesh(ray, mesh, t_min, t_max, result);
+            }
+        }
+
+        result
+    }
+
+    /// Returns the closest surface along the ray regardless of its opacity.
+    pub fn hit_surface(&self, ray: &ray::Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        let mut hit = self.hit_objects(ray, t_min, t_max)?;
+        hit.compute_differentials(ray);
+        Some(hit)
+    }
+
+    /// Returns the closest surface along the ray which is not cut out by its alpha. Blended
+    /// surfaces are stochastically passed through with probability of their transparency, after
+    /// `max_transparent_hits` skipped surfaces the next one is treated as opaque. Distance of the
+    /// hit is measured along the original ray.
+    pub fn hit(
+        &self,
+        ray: &ray::Ray,
+        t_min: f32,
+        t_max: f32,
+        max_transparent_hits: u32,
+        sampler: &impl Sampler,
+    ) -> Option<Hit> {
         let mut current_ray = *ray;
         let mut current_t_max = t_max;
+        let mut skipped_t = 0.;
+        let mut transparent_hits = 0;
 
         loop {
-            match self.hit_triangles(&current_ray, t_min, current_t_max) {
-                Some(hit) if hit.material.discard(hit.uv) => {
-                    current_ray = ray::Ray::new(hit.position, ray.direction);
-                    current_t_max -= hit.t;
-                }
-                Some(hit) => return Some(hit),
-                None => {
-                    return None;
-                }
+            let mut hit = self.hit_surface(&current_ray, t_min, current_t_max)?;
+
+            let opacity = hit.material.opacity(&hit.tex_coord);
+            if transparent_hits >= max_transparent_hits
+                || opacity >= 1.
+                || (opacity > 0. && sampler.next_float() < opacity)
+            {
+                hit.t += skipped_t;
+                return Some(hit);
             }
+
+            transparent_hits += 1;
+            current_ray = ray::Ray {
+                origin: hit.position,
+                ..current_ray
+            };
+            current_t_max -= hit.t;
+            skipped_t += hit.t;
         }
-        //self.hit_spheres(ray, t_min, t_max)
+    }
+
+    /// Returns center and radius of a sphere enclosing bounded objects of the scene.
+    pub fn bounding_sphere(&self) -> (Vector3, f32) {
+        let center = (self.bounds[0] + self.bounds[1]) / 2.;
+        (center, (self.bounds[1] - center).length())
     }
 
     pub fn lights(&self) -> &Vec<Light> {
         &self.lights
     }
+
+    pub(crate) fn light_sampler(&self) -> &LightSampler {
+        &self.light_sampler
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Cameras {
+        count: usize,
+        positions: Vec<(usize, Vector3)>,
+    }
+
+    impl SceneImportHandler for Cameras {
+        fn handle_material(&mut self, _color: Vector3, _texture: Option<(u32, u32, &[u8])>) {}
+        fn handle_mesh(&mut self, _vertices: &[f32], _indices: &[u32], _material_index: i32) {}
+        fn handle_ortho_camera(&mut self, _width: f32, _height: f32, _near: f32, _far: f32) {
+            self.count += 1;
+        }
+        fn handle_perspective_camera(
+            &mut self,
+            _v_fov: f32,
+            _aspect_ratio: f32,
+            _near: f32,
+            _far: f32,
+        ) {
+            self.count += 1;
+        }
+        fn handle_camera_transform(
+            &mut self,
+            camera_index: usize,
+            position: Vector3,
+            _forward: Vector3,
+            _up: Vector3,
+        ) {
+            self.positions.push((camera_index, position));
+        }
+    }
+
+    #[test]
+    fn test_cameras_of_several_files() {
+        let dir = std::env::temp_dir().join(format!("pathtracer-cameras-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let gltf = r#"{
+            "asset": {"version": "2.0"},
+            "cameras": [{"type": "perspective", "perspective": {"yfov": 1, "znear": 0.1}}],
+            "nodes": [{"camera": 0, "translation": [0, 0, 1]}],
+            "scenes": [{"nodes": [0]}],
+            "scene": 0
+        }"#;
+        std::fs::write(dir.join("camera.gltf"), gltf).unwrap();
+        std::fs::write(
+            dir.join("scene.json"),
+            r#"{
+                "version": 3,
+                "meshes": [
+                    {"name": "a", "path": "camera.gltf"},
+                    {"name": "b", "path": "camera.gltf", "transformation": {"translate": [5, 0, 0]}}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let mut cameras = Cameras::default();
+        let result = Scene::load(&dir.join("scene.json"), &mut Some(&mut cameras));
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+
+        assert_eq!(cameras.count, 2);
+        assert_eq!(
+            cameras.positions,
+            vec![(0, Vector3::new(0., 0., 1.)), (1, Vector3::new(5., 0., 1.))]
+        );
+    }
 }
@@ -0,0 +1,352 @@
+use crate::math::*;
+use crate::random::Sampler;
+
+use cgmath::ElementWise;
+
+use std::sync::OnceLock;
+
+/// Radiance or reflectance at the wavelengths carried by a path.
+pub type Spectrum = cgmath::Vector4<f32>;
+
+/// Number of wavelengths carried by a path.
+pub const WAVELENGTHS: usize = 4;
+
+/// Representative wavelengths of linear sRGB primaries in nanometers, used to evaluate spectral
+/// data when rendering in RGB.
+pub const RGB_WAVELENGTHS: [f32; 3] = [630., 532., 465.];
+
+const LAMBDA_MIN: f32 = 360.;
+const LAMBDA_MAX: f32 = 830.;
+
+/// Wavelengths in nanometers carried by a path with their probability densities.
+///
+/// The first one is the hero wavelength, it drives sampling of directions which depend on the
+/// wavelength, the others are stratified over the visible range (Source: "Hero Wavelength Spectral
+/// Sampling" by Wilkie et al. and PBRT v4, 4.5.4).
+#[derive(Debug, Copy, Clone)]
+pub struct Wavelengths {
+    pub lambda: [f32; WAVELENGTHS],
+    pub pdf: [f32; WAVELENGTHS],
+}
+
+impl Wavelengths {
+    pub fn sample(sampler: &impl Sampler) -> Self {
+        let u = sampler.next_float();
+
+        let mut lambda = [0.; WAVELENGTHS];
+        let mut pdf = [0.; WAVELENGTHS];
+        for i in 0..WAVELENGTHS {
+            let u = (u + i as f32 / WAVELENGTHS as f32).fract();
+            lambda[i] = sample_visible(u);
+            pdf[i] = visible_pdf(lambda[i]);
+        }
+
+        Wavelengths { lambda, pdf }
+    }
+
+    /// Drops all wavelengths except of the hero one, after the path took a direction valid only
+    /// for it, e.g. by dispersion.
+    pub fn terminate_secondary(&mut self, spectrum: &mut Spectrum) {
+        if self.pdf[1..].iter().all(|&pdf| pdf == 0.) {
+            return;
+        }
+
+        for i in 1..WAVELENGTHS {
+            self.pdf[i] = 0.;
+            spectrum[i] = 0.;
+        }
+        self.pdf[0] /= WAVELENGTHS as f32;
+    }
+
+    /// Returns values of RGB color upsampled at the wavelengths.
+    pub fn upsample(&self, rgb: Vector3) -> Spectrum {
+        Spectrum::new(
+            upsample(rgb, self.lambda[0]),
+            upsample(rgb, self.lambda[1]),
+            upsample(rgb, self.lambda[2]),
+            upsample(rgb, self.lambda[3]),
+        )
+    }
+
+    /// Converts radiance at the wavelengths to linear sRGB, white balanced so that the spectrum
+    /// upsampled from white maps back to white.
+    pub fn to_rgb(self, spectrum: Spectrum) -> Vector3 {
+        let mut xyz = Vector3::zero();
+        for i in 0..WAVELENGTHS {
+            if self.pdf[i] > 0. {
+                xyz += cie_xyz(self.lambda[i]) * (spectrum[i] / self.pdf[i]);
+            }
+        }
+
+        let rgb = xyz_to_rgb(xyz / (WAVELENGTHS as f32 * CIE_Y_INTEGRAL));
+        rgb.div_element_wise(*white_balance()).max(Vector3::zero())
+    }
+}
+
+// Density of wavelengths following sensitivity of the eye.
+// Source: "An Improved Technique for Full Spectral Rendering" by Radziszewski et al.
+fn visible_pdf(lambda: f32) -> f32 {
+    if !(LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+        return 0.;
+    }
+
+    let cosh = (0.0072 * (lambda - 538.)).cosh();
+    0.003_939_804 / (cosh * cosh)
+}
+
+fn sample_visible(u: f32) -> f32 {
+    538. - 138.888_89 * (0.856_910_6 - 1.827_502 * u).atanh()
+}
+
+/// Integral of the CIE Y matching function over wavelengths in nanometers.
+const CIE_Y_INTEGRAL: f32 = 106.856_895;
+
+/// CIE 1931 color matching functions.
+/// Source: "Simple Analytic Approximations to the CIE XYZ Color Matching Functions" by Wyman et al.
+pub fn cie_xyz(lambda: f32) -> Vector3 {
+    let g = |mu: f32, sigma1: f32, sigma2: f32| {
+        let t = (lambda - mu)
+            / match lambda < mu {
+                true => sigma1,
+                false => sigma2,
+            };
+        (-0.5 * t * t).exp()
+    };
+
+    Vector3::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+fn xyz_to_rgb(xyz: Vector3) -> Vector3 {
+    Vector3::new(
+        3.240_454_2 * xyz.x - 1.537_138_5 * xyz.y - 0.498_531_4 * xyz.z,
+        -0.969_266 * xyz.x + 1.876_010_8 * xyz.y + 0.041_556 * xyz.z,
+        0.055_643_4 * xyz.x - 0.204_025_9 * xyz.y + 1.057_225_2 * xyz.z,
+    )
+}
+
+// Linear sRGB of the equal energy spectrum, which the white is upsampled to.
+fn white_balance() -> &'static Vector3 {
+    static WHITE: OnceLock<Vector3> = OnceLock::new();
+    WHITE.get_or_init(|| {
+        let steps = 1000;
+        let step = (LAMBDA_MAX - LAMBDA_MIN) / steps as f32;
+        let xyz = (0..steps)
+            .map(|i| cie_xyz(LAMBDA_MIN + (i as f32 + 0.5) * step) * step)
+            .fold(Vector3::zero(), |sum, xyz| sum + xyz);
+        xyz_to_rgb(xyz / CIE_Y_INTEGRAL)
+    })
+}
+
+// Basis spectra of RGB upsampling in 10 bins between 380 and 720 nm.
+// Source: "An RGB to Spectrum Conversion for Reflectances" by Smits.
+const SMITS_WHITE: [f32; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f32; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f32; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f32; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f32; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f32; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f32; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+/// Returns value at given wavelength of a smooth spectrum matching linear RGB color.
+///
+/// Smallest component is covered by white, the middle one by one of the secondary colors and the
+/// rest by a primary, so the upsampling is linear in intensity of the color.
+pub fn upsample(rgb: Vector3, lambda: f32) -> f32 {
+    let (r, g, b) = (rgb.x.max(0.), rgb.y.max(0.), rgb.z.max(0.));
+
+    let bin = ((lambda - 380.) / 34.).clamp(0., 9.) as usize;
+    let value = |basis: &[f32; 10]| basis[bin];
+
+    if r <= g && r <= b {
+        let white = r * value(&SMITS_WHITE);
+        match g <= b {
+            true => white + (g - r) * value(&SMITS_CYAN) + (b - g) * value(&SMITS_BLUE),
+            false => white + (b - r) * value(&SMITS_CYAN) + (g - b) * value(&SMITS_GREEN),
+        }
+    } else if g <= r && g <= b {
+        let white = g * value(&SMITS_WHITE);
+        match r <= b {
+            true => white + (r - g) * value(&SMITS_MAGENTA) + (b - r) * value(&SMITS_BLUE),
+            false => white + (b - g) * value(&SMITS_MAGENTA) + (r - b) * value(&SMITS_RED),
+        }
+    } else {
+        let white = b * value(&SMITS_WHITE);
+        match r <= g {
+            true => white + (r - b) * value(&SMITS_YELLOW) + (g - r) * value(&SMITS_GREEN),
+            false => white + (g - b) * value(&SMITS_YELLOW) + (r - g) * value(&SMITS_RED),
+        }
+    }
+}
+
+/// Complex index of refraction of a conductor measured at a few wavelengths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComplexIor {
+    /// Wavelength in nanometers, real and imaginary part of the IOR, sorted by wavelength.
+    pub samples: Vec<(f32, f32, f32)>,
+}
+
+// Approximate measurements of common metals at 400, 450, ..., 700 nm.
+// Source: "Optical Constants of the Noble Metals" by Johnson and Christy, Rakic for aluminium.
+const METALS: &[(&str, [(f32, f32); 7])] = &[
+    (
+        "gold",
+        [
+            (1.66, 1.96),
+            (1.50, 1.88),
+            (0.97, 1.87),
+            (0.43, 2.46),
+            (0.25, 2.98),
+            (0.17, 3.38),
+            (0.16, 3.95),
+        ],
+    ),
+    (
+        "silver",
+        [
+            (0.05, 2.10),
+            (0.04, 2.65),
+            (0.05, 3.10),
+            (0.06, 3.55),
+            (0.06, 4.00),
+            (0.05, 4.40),
+            (0.04, 4.80),
+        ],
+    ),
+    (
+        "copper",
+        [
+            (1.17, 2.40),
+            (1.20, 2.40),
+            (1.12, 2.60),
+            (1.00, 2.58),
+            (0.30, 3.00),
+            (0.24, 3.60),
+            (0.21, 4.20),
+        ],
+    ),
+    (
+        "aluminium",
+        [
+            (0.49, 4.86),
+            (0.62, 5.47),
+            (0.77, 6.08),
+            (0.96, 6.69),
+            (1.20, 7.26),
+            (1.49, 7.79),
+            (1.83, 8.31),
+        ],
+    ),
+];
+
+impl ComplexIor {
+    /// Returns measured IOR of a metal of given name.
+    pub fn named(name: &str) -> Option<Self> {
+        METALS
+            .iter()
+            .find(|(metal, _)| *metal == name)
+            .map(|(_, samples)| ComplexIor {
+                samples: samples
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &(eta, k))| (400. + 50. * i as f32, eta, k))
+                    .collect(),
+            })
+    }
+
+    /// Returns names of the metals known by `named`.
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        METALS.iter().map(|(name, _)| *name)
+    }
+
+    /// Returns real and imaginary part of the IOR at given wavelength, interpolated linearly and
+    /// clamped outside of the measured range.
+    pub fn at(&self, lambda: f32) -> (f32, f32) {
+        let index = self.samples.partition_point(|sample| sample.0 < lambda);
+        match index {
+            0 => (self.samples[0].1, self.samples[0].2),
+            i if i == self.samples.len() => (self.samples[i - 1].1, self.samples[i - 1].2),
+            i => {
+                let (a, b) = (self.samples[i - 1], self.samples[i]);
+                let t = (lambda - a.0) / (b.0 - a.0);
+                (lerp_scalar(a.1, b.1, t), lerp_scalar(a.2, b.2, t))
+            }
+        }
+    }
+
+    /// Returns real and imaginary part of the IOR at the wavelengths of RGB primaries.
+    pub fn rgb(&self) -> (Vector3, Vector3) {
+        let [r, g, b] = RGB_WAVELENGTHS.map(|lambda| self.at(lambda));
+        (Vector3::new(r.0, g.0, b.0), Vector3::new(r.1, g.1, b.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::UniformSampler;
+
+    #[test]
+    fn test_upsampling() {
+        let sampler = UniformSampler::new();
+
+        // Colors survive the conversion to spectrum and back.
+        for rgb in [
+            Vector3::new(1., 1., 1.),
+            Vector3::new(0.8, 0.2, 0.1),
+            Vector3::new(0.1, 0.6, 0.3),
+            Vector3::new(0.2, 0.3, 0.9),
+            Vector3::new(0.5, 0.5, 0.5),
+        ] {
+            let count = 20000;
+            let mut sum = Vector3::zero();
+            for _ in 0..count {
+                let wavelengths = Wavelengths::sample(&sampler);
+                sum += wavelengths.to_rgb(wavelengths.upsample(rgb));
+            }
+            let result = sum / count as f32;
+
+            assert!(
+                (result - rgb).length() < 0.06,
+                "{:?} converted to {:?}",
+                rgb,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn test_complex_ior() {
+        let gold = ComplexIor::named("gold").unwrap();
+        assert_eq!(gold.at(300.), (1.66, 1.96));
+        assert_eq!(gold.at(800.), (0.16, 3.95));
+
+        let (eta, k) = gold.at(525.);
+        assert!((eta - 0.7).abs() < 1e-5 && (k - 2.165).abs() < 1e-5);
+
+        // Gold reflects more of red than of blue light.
+        let (eta, k) = gold.rgb();
+        let f0 = |eta: f32, k: f32| fresnel_conductor(1., eta, k);
+        assert!(f0(eta.x, k.x) > 0.9 && f0(eta.z, k.z) < 0.5);
+
+        assert!(ComplexIor::names().all(|name| ComplexIor::named(name).is_some()));
+        assert!(ComplexIor::named("unobtainium").is_none());
+    }
+}
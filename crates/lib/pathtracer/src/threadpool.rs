@@ -41,7 +41,7 @@ impl Worker {
 }
 
 impl ThreadPool {
-    fn thread_count() -> usize {
+    pub(crate) fn thread_count() -> usize {
         match std::thread::available_parallelism() {
             Ok(val) => val.get() as usize,
             _ => 4,
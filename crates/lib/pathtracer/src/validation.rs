@@ -0,0 +1,1193 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::import_ies;
+use crate::import_scene::{
+    self, FalloffDescription, MaterialDescription, MaterialReference, SceneDescription,
+    ShapeDescription, TransformationDescription,
+};
+use crate::variables::Variables;
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Single finding of the scene validation pass.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    /// Location of the offending value inside the document, e.g. `point_lights[1].range`.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+
+        write!(
+            f,
+            "{}:{}:{}: {}: ",
+            self.file.display(),
+            self.line,
+            self.column,
+            severity
+        )?;
+
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path)?;
+        }
+
+        write!(f, "{}", self.message)
+    }
+}
+
+// -------------------------------------------------------------------------
+//    Source positions
+// -------------------------------------------------------------------------
+
+/// Maps document paths (`meshes[0].path`) to line/column of the value in the source text.
+struct PositionIndex {
+    positions: HashMap<String, (usize, usize)>,
+}
+
+struct Scanner<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.next();
+        }
+    }
+
+    fn string(&mut self) -> String {
+        let mut result = String::new();
+        self.next(); // Opening quote.
+        while let Some(c) = self.next() {
+            match c {
+                '"' => break,
+                '\\' => {
+                    if let Some(escaped) = self.next() {
+                        result.push(escaped);
+                    }
+                }
+                c => result.push(c),
+            }
+        }
+        result
+    }
+
+    fn value(&mut self, path: &str, positions: &mut HashMap<String, (usize, usize)>) {
+        self.skip_whitespace();
+        positions.insert(path.to_string(), (self.line, self.column));
+
+        match self.chars.peek() {
+            Some('{') => {
+                self.next();
+                loop {
+                    self.skip_whitespace();
+                    match self.chars.peek() {
+                        Some('"') => {
+                            let key = self.string();
+                            self.skip_whitespace();
+                            self.next(); // Colon.
+                            let child = match path.is_empty() {
+                                true => key,
+                                false => format!("{}.{}", path, key),
+                            };
+                            self.value(&child, positions);
+                        }
+                        Some(',') => {
+                            self.next();
+                        }
+                        _ => {
+                            self.next();
+                            break;
+                        }
+                    }
+                }
+            }
+            Some('[') => {
+                self.next();
+                let mut index = 0;
+                loop {
+                    self.skip_whitespace();
+                    match self.chars.peek() {
+                        Some(']') | None => {
+                            self.next();
+                            break;
+                        }
+                        Some(',') => {
+                            self.next();
+                        }
+                        _ => {
+                            self.value(&format!("{}[{}]", path, index), positions);
+                            index += 1;
+                        }
+                    }
+                }
+            }
+            Some('"') => {
+                self.string();
+            }
+            _ => {
+                while let Some(c) = self.chars.peek() {
+                    if matches!(c, ',' | '}' | ']') || c.is_whitespace() {
+                        break;
+                    }
+                    self.next();
+                }
+            }
+        }
+    }
+}
+
+impl PositionIndex {
+    fn new(text: &str) -> Self {
+        let mut scanner = Scanner {
+            chars: text.chars().peekable(),
+            line: 1,
+            column: 1,
+        };
+
+        let mut positions = HashMap::new();
+        scanner.value("", &mut positions);
+
+        Self { positions }
+    }
+
+    /// Position of the value at given path, or of its closest ancestor present in the source.
+    fn find(&self, path: &str) -> (usize, usize) {
+        let mut path = path;
+
+        loop {
+            if let Some(position) = self.positions.get(path) {
+                return *position;
+            }
+
+            match path.rfind(['.', '[']) {
+                Some(index) => path = &path[..index],
+                None if path.is_empty() => return (1, 1),
+                None => path = "",
+            }
+        }
+    }
+}
+
+/// Source file of a scene description, used to locate diagnostics.
+pub(crate) struct Document {
+    file: PathBuf,
+    index: PositionIndex,
+}
+
+impl Document {
+    pub(crate) fn report<'a>(&'a self, diagnostics: &'a mut Vec<Diagnostic>) -> Report<'a> {
+        Report {
+            document: self,
+            first: diagnostics.len(),
+            diagnostics,
+        }
+    }
+}
+
+pub(crate) struct Report<'a> {
+    document: &'a Document,
+    first: usize,
+    diagnostics: &'a mut Vec<Diagnostic>,
+}
+
+impl<'a> Report<'a> {
+    fn add(&mut self, severity: Severity, path: &str, message: String) {
+        let (line, column) = self.document.index.find(path);
+
+        self.diagnostics.push(Diagnostic {
+            severity,
+            file: self.document.file.clone(),
+            line,
+            column,
+            path: path.to_string(),
+            message,
+        });
+    }
+
+    pub(crate) fn warning(&mut self, path: &str, message: String) {
+        self.add(Severity::Warning, path, message);
+    }
+
+    pub(crate) fn error(&mut self, path: &str, message: String) {
+        self.add(Severity::Error, path, message);
+    }
+
+    fn has_errors(&self) -> bool {
+        self.diagnostics[self.first..]
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+}
+
+// -------------------------------------------------------------------------
+//    Schema
+// -------------------------------------------------------------------------
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Number,
+    Integer,
+    Text,
+    Vec3,
+    Bool,
+    Object(&'static [Field]),
+    Array(&'static Kind),
+    /// Object with arbitrary keys, all values of given kind.
+    Map(&'static Kind),
+    /// Array of fixed length, items of given kinds.
+    Tuple(&'static [Kind]),
+    /// Value of either kind, chosen by the JSON type of the value.
+    Either(&'static Kind, &'static Kind),
+    /// Externally tagged enum, unit variants (without kind) are given as strings.
+    Enum(&'static [(&'static str, Option<Kind>)]),
+    Any,
+}
+
+struct Field {
+    name: &'static str,
+    kind: Kind,
+    required: bool,
+}
+
+const fn required(name: &'static str, kind: Kind) -> Field {
+    Field {
+        name,
+        kind,
+        required: true,
+    }
+}
+
+const fn optional(name: &'static str, kind: Kind) -> Field {
+    Field {
+        name,
+        kind,
+        required: false,
+    }
+}
+
+const TRANSFORMATION: &[Field] = &[
+    optional("translate", Kind::Vec3),
+    optional("scale", Kind::Vec3),
+    optional("rotate", Kind::Vec3),
+];
+
+const CONDUCTOR: Kind = Kind::Either(
+    &Kind::Array(&Kind::Vec3),
+    &Kind::Enum(&[
+        ("gold", None),
+        ("silver", None),
+        ("copper", None),
+        ("aluminium", None),
+    ]),
+);
+
+const MATERIAL: &[Field] = &[
+    optional("base_color", Kind::Vec3),
+    optional("base_color_texture", Kind::Text),
+    optional("metalness", Kind::Number),
+    optional("roughness", Kind::Number),
+    optional("metallic_roughness_texture", Kind::Text),
+    optional("emissive", Kind::Vec3),
+    optional("emissive_texture", Kind::Text),
+    optional("normal_texture", Kind::Text),
+    optional("ior", Kind::Number),
+    optional("dispersion", Kind::Number),
+    optional("conductor", CONDUCTOR),
+    optional("transmission", Kind::Number),
+    optional("thickness", Kind::Number),
+    optional("attenuation_color", Kind::Vec3),
+    optional("attenuation_distance", Kind::Number),
+    optional("scattering", Kind::Vec3),
+    optional("scattering_asymmetry", Kind::Number),
+    optional("subsurface", Kind::Number),
+    optional("subsurface_color", Kind::Vec3),
+    optional("subsurface_color_texture", Kind::Text),
+    optional("subsurface_radius", Kind::Vec3),
+    optional("subsurface_asymmetry", Kind::Number),
+    optional("specular", Kind::Number),
+    optional("specular_color", Kind::Vec3),
+    optional("clearcoat", Kind::Number),
+    optional("clearcoat_roughness", Kind::Number),
+    optional("sheen_color", Kind::Vec3),
+    optional("sheen_roughness", Kind::Number),
+    optional("anisotropy", Kind::Number),
+    optional("anisotropy_rotation", Kind::Number),
+    optional("iridescence", Kind::Number),
+    optional("iridescence_ior", Kind::Number),
+    optional("iridescence_thickness", Kind::Number),
+    optional("double_sided", Kind::Bool),
+];
+
+const MATERIAL_REFERENCE: Kind = Kind::Either(&Kind::Text, &Kind::Object(MATERIAL));
+
+const MESH: &[Field] = &[
+    required("name", Kind::Text),
+    required("path", Kind::Text),
+    optional("transformation", Kind::Object(TRANSFORMATION)),
+    optional("material", MATERIAL_REFERENCE),
+    optional("gltf_materials", Kind::Map(&MATERIAL_REFERENCE)),
+];
+
+const SHAPE: Kind = Kind::Enum(&[
+    (
+        "Sphere",
+        Some(Kind::Object(&[required("radius", Kind::Number)])),
+    ),
+    (
+        "Quad",
+        Some(Kind::Object(&[
+            required("width", Kind::Number),
+            required("depth", Kind::Number),
+        ])),
+    ),
+    (
+        "Disk",
+        Some(Kind::Object(&[required("radius", Kind::Number)])),
+    ),
+    (
+        "Cylinder",
+        Some(Kind::Object(&[
+            required("radius", Kind::Number),
+            required("height", Kind::Number),
+        ])),
+    ),
+    ("Plane", None),
+]);
+
+const PRIMITIVE: &[Field] = &[
+    optional("name", Kind::Text),
+    required("shape", SHAPE),
+    optional("transformation", Kind::Object(TRANSFORMATION)),
+    optional("material", MATERIAL_REFERENCE),
+];
+
+const BOUNDS: &[Field] = &[required("min", Kind::Vec3), required("max", Kind::Vec3)];
+
+const VOLUME: &[Field] = &[
+    optional("name", Kind::Text),
+    required("density", Kind::Text),
+    optional(
+        "resolution",
+        Kind::Tuple(&[Kind::Integer, Kind::Integer, Kind::Integer]),
+    ),
+    required("bounds", Kind::Object(BOUNDS)),
+    optional("transformation", Kind::Object(TRANSFORMATION)),
+    optional("density_scale", Kind::Number),
+    optional("albedo", Kind::Vec3),
+    optional("asymmetry", Kind::Number),
+    optional("emission", Kind::Text),
+    optional("emission_color", Kind::Vec3),
+];
+
+const DIR_LIGHT: &[Field] = &[
+    optional("name", Kind::Text),
+    required("dir", Kind::Vec3),
+    required("color", Kind::Vec3),
+    required("intensity", Kind::Number),
+    optional("unit", Kind::Enum(&[("Lux", None), ("Watts", None)])),
+];
+
+const POINT_LIGHT: &[Field] = &[
+    optional("name", Kind::Text),
+    required("position", Kind::Vec3),
+    required("color", Kind::Vec3),
+    required("intensity", Kind::Number),
+    optional(
+        "unit",
+        Kind::Enum(&[("Candela", None), ("Lumens", None), ("Watts", None)]),
+    ),
+    optional("range", Kind::Number),
+    optional(
+        "falloff",
+        Kind::Enum(&[("InverseSquare", None), ("Range", None)]),
+    ),
+    optional("profile", Kind::Text),
+    optional("profile_axis", Kind::Vec3),
+    optional("cone", Kind::Object(CONE)),
+];
+
+const CONE: &[Field] = &[
+    required("direction", Kind::Vec3),
+    optional("inner_angle", Kind::Number),
+    required("outer_angle", Kind::Number),
+];
+
+const FOG: &[Field] = &[
+    optional("absorption", Kind::Vec3),
+    optional("scattering", Kind::Vec3),
+    optional("asymmetry", Kind::Number),
+];
+
+const ENVIRONMENT: Kind = Kind::Enum(&[
+    ("Black", None),
+    ("Gradient", Some(Kind::Tuple(&[Kind::Vec3, Kind::Vec3]))),
+]);
+
+const INCLUDE: &[Field] = &[
+    required("path", Kind::Text),
+    optional("variables", Kind::Any),
+];
+
+const OVERRIDE: &[Field] = &[
+    required("name", Kind::Text),
+    optional("transformation", Kind::Object(TRANSFORMATION)),
+    optional("material", MATERIAL_REFERENCE),
+    optional("color", Kind::Vec3),
+    optional("intensity", Kind::Number),
+];
+
+const SCENE: &[Field] = &[
+    optional("version", Kind::Integer),
+    optional("variables", Kind::Any),
+    optional("include", Kind::Array(&Kind::Object(INCLUDE))),
+    optional("overrides", Kind::Array(&Kind::Object(OVERRIDE))),
+    optional("materials", Kind::Map(&Kind::Object(MATERIAL))),
+    optional("meshes", Kind::Array(&Kind::Object(MESH))),
+    optional("primitives", Kind::Array(&Kind::Object(PRIMITIVE))),
+    optional("volumes", Kind::Array(&Kind::Object(VOLUME))),
+    optional("dir_lights", Kind::Array(&Kind::Object(DIR_LIGHT))),
+    optional("point_lights", Kind::Array(&Kind::Object(POINT_LIGHT))),
+    optional("env", ENVIRONMENT),
+    optional("fog", Kind::Object(FOG)),
+];
+
+fn join(path: &str, name: &str) -> String {
+    match path.is_empty() {
+        true => name.to_string(),
+        false => format!("{}.{}", path, name),
+    }
+}
+
+fn check_value(report: &mut Report, value: &Value, kind: Kind, path: &str) {
+    match kind {
+        Kind::Any => {}
+        Kind::Number if value.is_number() => {}
+        Kind::Integer if value.is_u64() => {}
+        Kind::Text if value.is_string() => {}
+        Kind::Bool if value.is_boolean() => {}
+        Kind::Either(first, second) => {
+            let kind = match (first, value) {
+                (Kind::Text, Value::String(_)) => first,
+                (Kind::Object(_) | Kind::Map(_), Value::Object(_)) => first,
+                (Kind::Array(_) | Kind::Vec3, Value::Array(_)) => first,
+                (Kind::Number | Kind::Integer, Value::Number(_)) => first,
+                _ => second,
+            };
+            check_value(report, value, *kind, path);
+        }
+        Kind::Enum(variants) => {
+            let names = || {
+                variants
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            let (name, inner) = match value {
+                Value::String(name) => (name, None),
+                Value::Object(object) if object.len() == 1 => {
+                    let (name, inner) = object.iter().next().unwrap();
+                    (name, Some(inner))
+                }
+                _ => {
+                    report.error(
+                        path,
+                        format!("expected one of {} (string or single key object)", names()),
+                    );
+                    return;
+                }
+            };
+
+            match (variants.iter().find(|(n, _)| n == name), inner) {
+                (None, _) => report.error(
+                    path,
+                    format!("unknown variant '{}', expected one of {}", name, names()),
+                ),
+                (Some((_, None)), None) => {}
+                (Some((_, Some(_))), None) => {
+                    report.error(path, format!("variant '{}' requires parameters", name))
+                }
+                (Some((_, None)), Some(_)) => {
+                    report.error(path, format!("variant '{}' has no parameters", name))
+                }
+                (Some((_, Some(kind))), Some(inner)) => {
+                    check_value(report, inner, *kind, &join(path, name))
+                }
+            }
+        }
+        Kind::Map(item_kind) => match value.as_object() {
+            Some(object) => {
+                for (key, item) in object {
+                    check_value(report, item, *item_kind, &join(path, key));
+                }
+            }
+            None => report.error(path, "expected object".to_string()),
+        },
+        Kind::Vec3 => match value.as_array() {
+            Some(items) if items.len() == 3 && items.iter().all(Value::is_number) => {}
+            _ => report.error(path, "expected array of 3 numbers".to_string()),
+        },
+        Kind::Array(item_kind) => match value.as_array() {
+            Some(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    check_value(report, item, *item_kind, &format!("{}[{}]", path, i));
+                }
+            }
+            None => report.error(path, "expected array".to_string()),
+        },
+        Kind::Tuple(item_kinds) => match value.as_array() {
+            Some(items) if items.len() == item_kinds.len() => {
+                for (i, (item, item_kind)) in items.iter().zip(item_kinds).enumerate() {
+                    check_value(report, item, *item_kind, &format!("{}[{}]", path, i));
+                }
+            }
+            _ => report.error(
+                path,
+                format!("expected array of {} items", item_kinds.len()),
+            ),
+        },
+        Kind::Object(fields) => match value.as_object() {
+            Some(object) => {
+                for (key, child) in object {
+                    match fields.iter().find(|field| field.name == key) {
+                        Some(field) if child.is_null() && !field.required => {}
+                        Some(field) => check_value(report, child, field.kind, &join(path, key)),
+                        None => report.warning(
+                            &join(path, key),
+                            format!("unknown field '{}' is ignored", key),
+                        ),
+                    }
+                }
+
+                for field in fields.iter().filter(|field| field.required) {
+                    if !object.contains_key(field.name) {
+                        report.error(path, format!("missing required field '{}'", field.name));
+                    }
+                }
+            }
+            None => report.error(path, "expected object".to_string()),
+        },
+        Kind::Number => report.error(path, "expected number".to_string()),
+        Kind::Integer => report.error(path, "expected non-negative integer".to_string()),
+        Kind::Text => report.error(path, "expected string".to_string()),
+        Kind::Bool => report.error(path, "expected boolean".to_string()),
+    }
+}
+
+// -------------------------------------------------------------------------
+//    Semantic checks
+// -------------------------------------------------------------------------
+
+fn length(v: (f32, f32, f32)) -> f32 {
+    (v.0 * v.0 + v.1 * v.1 + v.2 * v.2).sqrt()
+}
+
+fn check_color(report: &mut Report, color: (f32, f32, f32), path: &str) {
+    if color.0 < 0. || color.1 < 0. || color.2 < 0. {
+        report.error(path, "color components must not be negative".to_string());
+    }
+}
+
+fn check_intensity(report: &mut Report, intensity: f32, path: &str) {
+    if intensity < 0. {
+        report.error(
+            path,
+            format!("intensity must not be negative, got {}", intensity),
+        );
+    } else if intensity == 0. {
+        report.warning(path, "light has zero intensity".to_string());
+    }
+}
+
+fn check_unit_interval(report: &mut Report, value: Option<f32>, path: &str) {
+    if let Some(value) = value {
+        if !(0. ..=1.).contains(&value) {
+            report.error(path, format!("value must be in [0, 1], got {}", value));
+        }
+    }
+}
+
+fn check_material(report: &mut Report, material: &MaterialDescription, path: &str) {
+    if let Some(color) = material.base_color {
+        check_color(report, color, &format!("{}.base_color", path));
+    }
+    if let Some(color) = material.emissive {
+        check_color(report, color, &format!("{}.emissive", path));
+    }
+    if let Some(color) = material.attenuation_color {
+        check_color(report, color, &format!("{}.attenuation_color", path));
+    }
+    if let Some(color) = material.specular_color {
+        check_color(report, color, &format!("{}.specular_color", path));
+    }
+    if let Some(color) = material.sheen_color {
+        check_color(report, color, &format!("{}.sheen_color", path));
+    }
+    for (name, value) in [
+        ("metalness", material.metalness),
+        ("roughness", material.roughness),
+        ("transmission", material.transmission),
+        ("specular", material.specular),
+        ("clearcoat", material.clearcoat),
+        ("clearcoat_roughness", material.clearcoat_roughness),
+        ("sheen_roughness", material.sheen_roughness),
+        ("anisotropy", material.anisotropy),
+        ("iridescence", material.iridescence),
+    ] {
+        check_unit_interval(report, value, &format!("{}.{}", path, name));
+    }
+
+    for (name, ior) in [
+        ("ior", material.ior),
+        ("iridescence_ior", material.iridescence_ior),
+    ] {
+        if let Some(ior) = ior {
+            if ior < 1. {
+                report.error(
+                    &format!("{}.{}", path, name),
+                    format!("index of refraction must be at least 1, got {}", ior),
+                );
+            }
+        }
+    }
+
+    if let Some(thickness) = material.thickness {
+        if thickness < 0. {
+            report.error(
+                &format!("{}.thickness", path),
+                format!("thickness must not be negative, got {}", thickness),
+            );
+        }
+    }
+
+    if let Some(thickness) = material.iridescence_thickness {
+        if thickness < 0. {
+            report.error(
+                &format!("{}.iridescence_thickness", path),
+                format!("thickness must not be negative, got {}", thickness),
+            );
+        }
+    }
+
+    if let Some(distance) = material.attenuation_distance {
+        if distance <= 0. {
+            report.error(
+                &format!("{}.attenuation_distance", path),
+                format!("attenuation distance must be positive, got {}", distance),
+            );
+        }
+    }
+
+    for (name, texture) in [
+        ("base_color_texture", &material.base_color_texture),
+        (
+            "metallic_roughness_texture",
+            &material.metallic_roughness_texture,
+        ),
+        ("emissive_texture", &material.emissive_texture),
+        ("normal_texture", &material.normal_texture),
+    ] {
+        if let Some(texture) = texture {
+            if !Path::new(texture).is_file() {
+                report.error(
+                    &format!("{}.{}", path, name),
+                    format!("texture file '{}' does not exist", texture),
+                );
+            }
+        }
+    }
+}
+
+fn check_material_reference(report: &mut Report, material: &MaterialReference, path: &str) {
+    if let MaterialReference::Inline(material) = material {
+        check_material(report, material, path);
+    }
+}
+
+fn check_scale(
+    report: &mut Report,
+    transformation: &Option<TransformationDescription>,
+    path: &str,
+) {
+    if let Some(scale) = transformation
+        .as_ref()
+        .and_then(|transformation| transformation.scale)
+    {
+        if scale.0 == 0. || scale.1 == 0. || scale.2 == 0. {
+            report.error(
+                &format!("{}.transformation.scale", path),
+                "scale must not be zero".to_string(),
+            );
+        }
+    }
+}
+
+fn check_description(report: &mut Report, description: &SceneDescription) {
+    let mut names = HashSet::new();
+
+    for (i, include) in description.include.iter().flatten().enumerate() {
+        if !Path::new(&include.path).is_file() {
+            report.error(
+                &format!("include[{}].path", i),
+                format!("included file '{}' does not exist", include.path),
+            );
+        }
+    }
+
+    for (i, mesh) in description.meshes.iter().enumerate() {
+        let path = format!("meshes[{}]", i);
+
+        if !names.insert(mesh.name.as_str()) {
+            report.warning(
+                &format!("{}.name", path),
+                format!("duplicate mesh name '{}'", mesh.name),
+            );
+        }
+
+        if !Path::new(&mesh.path).is_file() {
+            report.error(
+                &format!("{}.path", path),
+                format!("mesh file '{}' does not exist", mesh.path),
+            );
+        }
+
+        check_scale(report, &mesh.transformation, &path);
+
+        if let Some(material) = &mesh.material {
+            check_material_reference(report, material, &format!("{}.material", path));
+        }
+        for (name, material) in mesh.gltf_materials.iter().flatten() {
+            check_material_reference(
+                report,
+                material,
+                &format!("{}.gltf_materials.{}", path, name),
+            );
+        }
+    }
+
+    for (i, primitive) in description.primitives.iter().enumerate() {
+        let path = format!("primitives[{}]", i);
+
+        if let Some(name) = &primitive.name {
+            if !names.insert(name.as_str()) {
+                report.warning(
+                    &format!("{}.name", path),
+                    format!("duplicate mesh or primitive name '{}'", name),
+                );
+            }
+        }
+
+        let sizes: &[(&str, &str, f32)] = match primitive.shape {
+            ShapeDescription::Sphere { radius } => &[("Sphere", "radius", radius)],
+            ShapeDescription::Quad { width, depth } => {
+                &[("Quad", "width", width), ("Quad", "depth", depth)]
+            }
+            ShapeDescription::Disk { radius } => &[("Disk", "radius", radius)],
+            ShapeDescription::Cylinder { radius, height } => &[
+                ("Cylinder", "radius", radius),
+                ("Cylinder", "height", height),
+            ],
+            ShapeDescription::Plane => &[],
+        };
+        for (variant, name, size) in sizes {
+            if *size <= 0. {
+                report.error(
+                    &format!("{}.shape.{}.{}", path, variant, name),
+                    format!("{} must be positive, got {}", name, size),
+                );
+            }
+        }
+
+        check_scale(report, &primitive.transformation, &path);
+
+        if let Some(material) = &primitive.material {
+            check_material_reference(report, material, &format!("{}.material", path));
+        }
+    }
+
+    for (i, volume) in description.volumes.iter().enumerate() {
+        let path = format!("volumes[{}]", i);
+
+        if let Some(resolution) = volume.resolution {
+            if resolution.0 == 0 || resolution.1 == 0 || resolution.2 == 0 {
+                report.error(
+                    &format!("{}.resolution", path),
+                    format!("resolution must be positive, got {:?}", resolution),
+                );
+            }
+        }
+
+        let (min, max) = (volume.bounds.min, volume.bounds.max);
+        if min.0 >= max.0 || min.1 >= max.1 || min.2 >= max.2 {
+            report.error(
+                &format!("{}.bounds", path),
+                "bounds minimum must be less than maximum on every axis".to_string(),
+            );
+        }
+    }
+
+    for (name, material) in description.materials.iter().flatten() {
+        check_material(report, material, &format!("materials.{}", name));
+    }
+
+    for (i, item) in description.overrides.iter().flatten().enumerate() {
+        let path = format!("overrides[{}]", i);
+
+        check_scale(report, &item.transformation, &path);
+
+        if let Some(material) = &item.material {
+            check_material_reference(report, material, &format!("{}.material", path));
+        }
+        if let Some(color) = item.color {
+            check_color(report, color, &format!("{}.color", path));
+        }
+        if let Some(intensity) = item.intensity {
+            check_intensity(report, intensity, &format!("{}.intensity", path));
+        }
+    }
+
+    for (i, light) in description.dir_lights.iter().flatten().enumerate() {
+        let path = format!("dir_lights[{}]", i);
+
+        // Directions of any length are normalized when the light is created.
+        if length(light.dir) == 0. {
+            report.error(
+                &format!("{}.dir", path),
+                "direction must not be zero".to_string(),
+            );
+        }
+
+        check_color(report, light.color, &format!("{}.color", path));
+        check_intensity(report, light.intensity, &format!("{}.intensity", path));
+    }
+
+    for (i, light) in description.point_lights.iter().flatten().enumerate() {
+        let path = format!("point_lights[{}]", i);
+
+        match light.range {
+            Some(range) if range <= 0. => report.error(
+                &format!("{}.range", path),
+                format!("range must be positive, got {}", range),
+            ),
+            None if light.falloff == Some(FalloffDescription::Range) => report.error(
+                &format!("{}.falloff", path),
+                "range falloff requires range".to_string(),
+            ),
+            _ => {}
+        }
+        if light.profile_axis.is_some_and(|axis| length(axis) == 0.) {
+            report.error(
+                &format!("{}.profile_axis", path),
+                "axis must not be zero".to_string(),
+            );
+        }
+        if let Some(profile) = &light.profile {
+            let message = match Path::new(profile).is_file() {
+                true => match import_ies::read_profile(Path::new(profile)) {
+                    Ok(_) => None,
+                    Err(
+                        Error::FormatError(message)
+                        | Error::IoError(message)
+                        | Error::ImportError(message),
+                    ) => Some(message),
+                },
+                false => Some(format!("profile file '{}' does not exist", profile)),
+            };
+            if let Some(message) = message {
+                report.error(&format!("{}.profile", path), message);
+            }
+        }
+        if let Some(cone) = &light.cone {
+            if length(cone.direction) == 0. {
+                report.error(
+                    &format!("{}.cone.direction", path),
+                    "direction must not be zero".to_string(),
+                );
+            }
+            if !(cone.outer_angle > 0. && cone.outer_angle <= 180.) {
+                report.error(
+                    &format!("{}.cone.outer_angle", path),
+                    format!(
+                        "angle must be in (0, 180] degrees, got {}",
+                        cone.outer_angle
+                    ),
+                );
+            }
+            match cone.inner_angle {
+                Some(inner) if !(0. ..=cone.outer_angle).contains(&inner) => report.error(
+                    &format!("{}.cone.inner_angle", path),
+                    format!("angle must be between 0 and the outer angle, got {}", inner),
+                ),
+                _ => {}
+            }
+        }
+
+        check_color(report, light.color, &format!("{}.color", path));
+        check_intensity(report, light.intensity, &format!("{}.intensity", path));
+    }
+}
+
+/// Parses, migrates and validates single scene description file (without its includes).
+///
+/// `given` variables take precedence over variables defined by the document itself, `base` is
+/// the directory unversioned mesh paths are migrated against.
+pub(crate) fn parse_document(
+    filename: &Path,
+    base: &Path,
+    given: &HashMap<String, Value>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<(Document, Option<SceneDescription>), Error> {
+    let text = std::fs::read_to_string(filename)?;
+    let document = Document {
+        file: filename.to_owned(),
+        index: PositionIndex::new(&text),
+    };
+
+    let value: Value = match serde_json::from_str(&text) {
+        Ok(value) => value,
+        Err(err) => {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                file: filename.to_owned(),
+                line: err.line(),
+                column: err.column(),
+                path: String::new(),
+                message: err.to_string(),
+            });
+            return Ok((document, None));
+        }
+    };
+
+    let mut report = document.report(diagnostics);
+
+    let (value, version) = match import_scene::migrate(value, base) {
+        Ok(result) => result,
+        Err(Error::FormatError(message)) => {
+            report.error("version", message);
+            return Ok((document, None));
+        }
+        Err(err) => return Err(err),
+    };
+
+    if version < import_scene::SCENE_VERSION {
+        report.warning(
+            "version",
+            format!(
+                "scene uses schema version {}, migrated to version {}",
+                version,
+                import_scene::SCENE_VERSION
+            ),
+        );
+    }
+
+    let mut object = match value {
+        Value::Object(object) => object,
+        _ => {
+            report.error("", "expected object".to_string());
+            return Ok((document, None));
+        }
+    };
+
+    // Variables are resolved lazily, evaluate all of them to report broken definitions.
+    let definitions = match object.remove("variables") {
+        Some(Value::Object(definitions)) => definitions,
+        Some(_) => {
+            report.error("variables", "expected object".to_string());
+            return Ok((document, None));
+        }
+        None => Default::default(),
+    };
+
+    let mut variables = Variables::new(definitions, given);
+    for name in variables.names() {
+        if let Err(err) = variables.get(&name) {
+            report.error(&format!("variables.{}", name), err);
+        }
+    }
+
+    let mut errors = vec![];
+    let value = Value::Object(variables.substitute_members(&object, "", &mut errors));
+    for (path, err) in errors {
+        report.error(&path, err);
+    }
+
+    check_value(&mut report, &value, Kind::Object(SCENE), "");
+    if report.has_errors() {
+        return Ok((document, None));
+    }
+
+    let mut description: SceneDescription = match serde_json::from_value(value) {
+        Ok(description) => description,
+        Err(err) => {
+            report.error("", err.to_string());
+            return Ok((document, None));
+        }
+    };
+
+    description.resolve_paths(filename.parent().unwrap_or_else(|| Path::new("")));
+    check_description(&mut report, &description);
+
+    Ok((document, Some(description)))
+}
+
+/// Lints scene description file (including all included files) without loading meshes, textures or volumes it references.
+pub fn check_file(filename: &Path) -> Result<Vec<Diagnostic>, Error> {
+    let (_, mut diagnostics) = SceneDescription::load(filename)?;
+    diagnostics.sort_by(|a, b| (&a.file, a.line, a.column).cmp(&(&b.file, b.line, b.column)));
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_description, check_value, Document, Kind, PositionIndex, SceneDescription, Severity,
+        SCENE,
+    };
+    use std::path::PathBuf;
+
+    fn check(text: &str) -> Vec<(Severity, usize, usize, String)> {
+        let document = Document {
+            file: PathBuf::from("test.json"),
+            index: PositionIndex::new(text),
+        };
+        let mut diagnostics = vec![];
+
+        let value = serde_json::from_str(text).unwrap();
+        check_value(
+            &mut document.report(&mut diagnostics),
+            &value,
+            Kind::Object(SCENE),
+            "",
+        );
+
+        diagnostics
+            .into_iter()
+            .map(|d| (d.severity, d.line, d.column, d.path))
+            .collect()
+    }
+
+    #[test]
+    fn test_positions() {
+        let index = PositionIndex::new("{\n  \"a\": [1,\n    {\"b\": \"x\"}]\n}");
+        assert_eq!(index.find(""), (1, 1));
+        assert_eq!(index.find("a"), (2, 8));
+        assert_eq!(index.find("a[1].b"), (3, 11));
+        assert_eq!(index.find("a[1].c"), (3, 5));
+    }
+
+    #[test]
+    fn test_schema() {
+        assert_eq!(
+            check("{\"meshes\": [],\n \"haze\": 1}"),
+            vec![(Severity::Warning, 2, 10, "haze".to_string())]
+        );
+        assert_eq!(
+            check("{\"meshes\": [{\"name\": \"a\"}]}"),
+            vec![(Severity::Error, 1, 13, "meshes[0]".to_string())]
+        );
+        assert_eq!(
+            check("{\"meshes\": [], \"point_lights\": [{\"position\": [0, 0], \"color\": [1, 1, 1], \"intensity\": 1, \"range\": 1}]}"),
+            vec![(Severity::Error, 1, 46, "point_lights[0].position".to_string())]
+        );
+        assert_eq!(
+            check("{\"primitives\": [{\"shape\": \"Plane\"}, {\"shape\": {\"Disk\": {}}}]}"),
+            vec![(
+                Severity::Error,
+                1,
+                56,
+                "primitives[1].shape.Disk".to_string()
+            )]
+        );
+        assert_eq!(
+            check("{\"materials\": {\"a\": {\"conductor\": [[400, 1, 2]]}, \"b\": {\"conductor\": \"brass\"}}}"),
+            vec![(
+                Severity::Error,
+                1,
+                70,
+                "materials.b.conductor".to_string()
+            )]
+        );
+        assert_eq!(
+            check("{\"env\": {\"Gradient\": [[1, 1, 1]]}}"),
+            vec![(Severity::Error, 1, 22, "env.Gradient".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_point_lights() {
+        let document = Document {
+            file: PathBuf::from("test.json"),
+            index: PositionIndex::new("{}"),
+        };
+        let mut diagnostics = vec![];
+        let description: SceneDescription = serde_json::from_str(
+            "{\"point_lights\": [{\"position\": [0, 0, 0], \"color\": [1, 1, 1], \"intensity\": 1, \
+            \"profile\": \"missing.ies\", \"cone\": {\"direction\": [0, -1, 0], \"inner_angle\": 60, \"outer_angle\": 30}}]}",
+        )
+        .unwrap();
+        check_description(&mut document.report(&mut diagnostics), &description);
+
+        let paths: Vec<_> = diagnostics.into_iter().map(|d| d.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "point_lights[0].profile",
+                "point_lights[0].cone.inner_angle"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_volumes() {
+        let document = Document {
+            file: PathBuf::from("test.json"),
+            index: PositionIndex::new("{}"),
+        };
+        let mut diagnostics = vec![];
+        let description: SceneDescription = serde_json::from_str(
+            "{\"volumes\": [{\"density\": \"a.raw\", \"resolution\": [4, 0, 4], \
+            \"bounds\": {\"min\": [0, 0, 0], \"max\": [1, 0, 1]}}]}",
+        )
+        .unwrap();
+        check_description(&mut document.report(&mut diagnostics), &description);
+
+        let paths: Vec<_> = diagnostics.into_iter().map(|d| d.path).collect();
+        assert_eq!(paths, vec!["volumes[0].resolution", "volumes[0].bounds"]);
+    }
+}
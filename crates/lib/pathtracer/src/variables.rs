@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+
+// Variables and expressions of scene description files.
+//
+// Any string value of the document may refer to variables:
+//  - "$name" is replaced by the variable value (number, array, object...),
+//  - "=<expression>" is evaluated to a number, e.g. "=2 * $height + 0.5",
+//  - "${name}" inside of a string is replaced by the textual form of the variable,
+//  - "$$" at the start of a string escapes a literal '$'.
+
+pub struct Variables {
+    definitions: Map<String, Value>,
+    resolved: HashMap<String, Value>,
+    stack: Vec<String>,
+}
+
+impl Variables {
+    /// Creates variable scope from document definitions, `given` values take precedence over them.
+    pub fn new(definitions: Map<String, Value>, given: &HashMap<String, Value>) -> Self {
+        Self {
+            definitions,
+            resolved: given.clone(),
+            stack: vec![],
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.definitions.keys().cloned().collect()
+    }
+
+    pub fn get(&mut self, name: &str) -> Result<Value, String> {
+        if let Some(value) = self.resolved.get(name) {
+            return Ok(value.clone());
+        }
+
+        if self.stack.iter().any(|n| n == name) {
+            return Err(format!(
+                "cyclic variable definition: {} -> {}",
+                self.stack.join(" -> "),
+                name
+            ));
+        }
+
+        let definition = match self.definitions.get(name) {
+            Some(definition) => definition.clone(),
+            None => return Err(format!("undefined variable '{}'", name)),
+        };
+
+        self.stack.push(name.to_string());
+        let value = self.substitute(&definition);
+        self.stack.pop();
+
+        let value = value?;
+        self.resolved.insert(name.to_string(), value.clone());
+        Ok(value)
+    }
+
+    fn number(&mut self, name: &str) -> Result<f64, String> {
+        match self.get(name)? {
+            Value::Number(number) => Ok(number.as_f64().unwrap_or(0.)),
+            _ => Err(format!("variable '{}' is not a number", name)),
+        }
+    }
+
+    fn interpolate(&mut self, text: &str) -> Result<String, String> {
+        let mut result = String::new();
+        let mut rest = text;
+
+        while let Some(start) = rest.find("${") {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => return Err(format!("unterminated '${{' in '{}'", text)),
+            };
+
+            result.push_str(&rest[..start]);
+            match self.get(&rest[start + 2..end])? {
+                Value::String(s) => result.push_str(&s),
+                value => result.push_str(&value.to_string()),
+            }
+            rest = &rest[end + 1..];
+        }
+
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    fn substitute_string(&mut self, text: &str) -> Result<Value, String> {
+        if let Some(literal) = text.strip_prefix("$$") {
+            Ok(Value::String(format!("${}", literal)))
+        } else if let Some(expression) = text.strip_prefix('=') {
+            let number = Expression::new(expression, self).parse()?;
+            Ok(Value::from(number))
+        } else if text.starts_with('$') && !text.starts_with("${") {
+            self.get(&text[1..])
+        } else if text.contains("${") {
+            self.interpolate(text).map(Value::String)
+        } else {
+            Ok(Value::String(text.to_string()))
+        }
+    }
+
+    /// Returns copy of value with all variable references replaced.
+    pub fn substitute(&mut self, value: &Value) -> Result<Value, String> {
+        match value {
+            Value::String(text) => self.substitute_string(text),
+            Value::Array(items) => items
+                .iter()
+                .map(|item| self.substitute(item))
+                .collect::<Result<Vec<_>, _>>()
+                .map(Value::Array),
+            Value::Object(object) => {
+                let mut result = Map::new();
+                for (key, item) in object {
+                    result.insert(key.clone(), self.substitute(item)?);
+                }
+                Ok(Value::Object(result))
+            }
+            value => Ok(value.clone()),
+        }
+    }
+
+    /// Substitutes variables in every member of the object, reports failures with their document path.
+    pub fn substitute_members(
+        &mut self,
+        object: &Map<String, Value>,
+        path: &str,
+        errors: &mut Vec<(String, String)>,
+    ) -> Map<String, Value> {
+        let mut result = Map::new();
+
+        for (key, item) in object {
+            let item_path = match path.is_empty() {
+                true => key.clone(),
+                false => format!("{}.{}", path, key),
+            };
+
+            match item {
+                Value::Object(members) => {
+                    let members = self.substitute_members(members, &item_path, errors);
+                    result.insert(key.clone(), Value::Object(members));
+                }
+                Value::Array(items) => {
+                    let mut values = Vec::with_capacity(items.len());
+                    for (i, item) in items.iter().enumerate() {
+                        let item_path = format!("{}[{}]", item_path, i);
+                        let value = match item {
+                            Value::Object(members) => {
+                                Value::Object(self.substitute_members(members, &item_path, errors))
+                            }
+                            item => self.substitute(item).unwrap_or_else(|err| {
+                                errors.push((item_path, err));
+                                Value::Null
+                            }),
+                        };
+                        values.push(value);
+                    }
+                    result.insert(key.clone(), Value::Array(values));
+                }
+                item => {
+                    let value = self.substitute(item).unwrap_or_else(|err| {
+                        errors.push((item_path, err));
+                        Value::Null
+                    });
+                    result.insert(key.clone(), value);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+// Arithmetic expression with numbers, variables, parentheses and + - * / operators.
+struct Expression<'a, 'b> {
+    text: &'a str,
+    position: usize,
+    variables: &'b mut Variables,
+}
+
+impl<'a, 'b> Expression<'a, 'b> {
+    fn new(text: &'a str, variables: &'b mut Variables) -> Self {
+        Self {
+            text,
+            position: 0,
+            variables,
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.text[self.position..];
+        let trimmed = rest.trim_start();
+        self.position += rest.len() - trimmed.len();
+        trimmed.chars().next()
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let rest = &self.text[self.position..];
+        let length = rest.find(|c| !predicate(c)).unwrap_or(rest.len());
+        self.position += length;
+        &rest[..length]
+    }
+
+    fn parse(mut self) -> Result<f64, String> {
+        let value = self.sum()?;
+        match self.peek() {
+            None => Ok(value),
+            Some(c) => Err(format!("unexpected '{}' in expression '{}'", c, self.text)),
+        }
+    }
+
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut value = self.product()?;
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.position += 1;
+                    value += self.product()?;
+                }
+                Some('-') => {
+                    self.position += 1;
+                    value -= self.product()?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<f64, String> {
+        let mut value = self.factor()?;
+        loop {
+            match self.peek() {
+                Some('*') => {
+                    self.position += 1;
+                    value *= self.factor()?;
+                }
+                Some('/') => {
+                    self.position += 1;
+                    value /= self.factor()?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('-') => {
+                self.position += 1;
+                Ok(-self.factor()?)
+            }
+            Some('(') => {
+                self.position += 1;
+                let value = self.sum()?;
+                match self.peek() {
+                    Some(')') => {
+                        self.position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(format!("missing ')' in expression '{}'", self.text)),
+                }
+            }
+            Some('$') => {
+                self.position += 1;
+                let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+                self.variables.number(name)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                number
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid number '{}'", number))
+            }
+            Some(c) => Err(format!("unexpected '{}' in expression '{}'", c, self.text)),
+            None => Err(format!("unexpected end of expression '{}'", self.text)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Variables;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_substitute() {
+        let definitions = json!({
+            "h": "=$base * 2 - (1 + 1) / 4",
+            "base": 1.5,
+            "name": "lamp",
+            "tint": [1, "=$base", 0]
+        });
+        let given = HashMap::from([("base".to_string(), Value::from(2.))]);
+        let mut variables = Variables::new(definitions.as_object().unwrap().clone(), &given);
+
+        assert_eq!(variables.get("h"), Ok(Value::from(3.5)));
+        assert_eq!(
+            variables.substitute(&json!(["$tint", "${name}.gltf", "$$h"])),
+            Ok(json!([[1, 2., 0], "lamp.gltf", "$h"]))
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let definitions = json!({ "a": "=$b", "b": "=$a + 1", "c": "=2 +" });
+        let mut variables =
+            Variables::new(definitions.as_object().unwrap().clone(), &HashMap::new());
+
+        assert!(variables.get("a").is_err());
+        assert!(variables.get("c").is_err());
+        assert!(variables.get("d").is_err());
+    }
+}
@@ -0,0 +1,424 @@
+use crate::math::*;
+use crate::random::Sampler;
+use crate::ray::Ray;
+
+use cgmath::SquareMatrix;
+use kdtree_ray::*;
+
+/// Number of majorant cells along the longest axis of a volume.
+const MAJORANT_CELLS: usize = 16;
+
+/// Dense grid of scalar values at voxel centers, X changes fastest.
+pub struct Grid {
+    pub resolution: [usize; 3],
+    pub values: Vec<f32>,
+}
+
+impl Grid {
+    /// Returns value of given voxel, coordinates are clamped to the grid.
+    fn voxel(&self, x: i64, y: i64, z: i64) -> f32 {
+        let [nx, ny, nz] = self.resolution.map(|n| n as i64);
+        let (x, y, z) = (x.clamp(0, nx - 1), y.clamp(0, ny - 1), z.clamp(0, nz - 1));
+        self.values[(x + nx * (y + ny * z)) as usize]
+    }
+
+    /// Returns trilinearly interpolated value at given point of the unit cube covered by the grid.
+    pub fn lookup(&self, p: Vector3) -> f32 {
+        let coord = |v: f32, n: usize| v * n as f32 - 0.5;
+        let (x, y, z) = (
+            coord(p.x, self.resolution[0]),
+            coord(p.y, self.resolution[1]),
+            coord(p.z, self.resolution[2]),
+        );
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (fx, fy, fz) = (x - x0, y - y0, z - z0);
+        let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+
+        let lerp = |a: f32, b: f32, f: f32| a + (b - a) * f;
+        let row = |y: i64, z: i64| lerp(self.voxel(x0, y, z), self.voxel(x0 + 1, y, z), fx);
+        let slice = |z: i64| lerp(row(y0, z), row(y0 + 1, z), fy);
+        lerp(slice(z0), slice(z0 + 1), fz)
+    }
+
+    /// Returns maximum of voxels influencing interpolated values within given voxel coordinates.
+    fn maximum(&self, from: [f32; 3], to: [f32; 3]) -> f32 {
+        let range = |axis: usize| {
+            let n = self.resolution[axis] as i64;
+            let first = ((from[axis] - 0.5).floor() as i64).clamp(0, n - 1);
+            let last = ((to[axis] - 0.5).floor() as i64 + 1).clamp(0, n - 1);
+            first..=last
+        };
+
+        let mut maximum = 0f32;
+        for z in range(2) {
+            for y in range(1) {
+                for x in range(0) {
+                    maximum = maximum.max(self.voxel(x, y, z));
+                }
+            }
+        }
+        maximum
+    }
+}
+
+/// Coarse grid of maximal densities bounding the density grid, used for tracking.
+struct MajorantGrid {
+    resolution: [usize; 3],
+    values: Vec<f32>,
+}
+
+impl MajorantGrid {
+    fn new(grid: &Grid) -> Self {
+        let longest = *grid.resolution.iter().max().unwrap_or(&1);
+        let resolution = grid
+            .resolution
+            .map(|n| (n * MAJORANT_CELLS).div_ceil(longest).clamp(1, n.max(1)));
+
+        let mut values = Vec::with_capacity(resolution.iter().product());
+        for z in 0..resolution[2] {
+            for y in 0..resolution[1] {
+                for x in 0..resolution[0] {
+                    let cell = [x, y, z];
+                    let bound = |offset: usize| {
+                        [0, 1, 2].map(|axis| {
+                            (cell[axis] + offset) as f32 * grid.resolution[axis] as f32
+                                / resolution[axis] as f32
+                        })
+                    };
+                    values.push(grid.maximum(bound(0), bound(1)));
+                }
+            }
+        }
+
+        MajorantGrid { resolution, values }
+    }
+
+    /// Calls given function with start, end and majorant of cells the ray passes between `t0`
+    /// and `t1`, `origin` and `direction` are in the unit cube of the grid. Stops when the
+    /// function returns false.
+    fn traverse(
+        &self,
+        origin: Vector3,
+        direction: Vector3,
+        t0: f32,
+        t1: f32,
+        mut f: impl FnMut(f32, f32, f32) -> bool,
+    ) {
+        let resolution = self.resolution.map(|n| n as f32);
+        let start = origin + direction * t0;
+
+        let mut cell = [0i64; 3];
+        let mut next = [f32::INFINITY; 3];
+        let mut delta = [f32::INFINITY; 3];
+        let mut step = [0i64; 3];
+
+        for axis in 0..3 {
+            let position = start[axis] * resolution[axis];
+            let d = direction[axis] * resolution[axis];
+            cell[axis] = (position.floor() as i64).clamp(0, self.resolution[axis] as i64 - 1);
+
+            if d > 0. {
+                next[axis] = t0 + ((cell[axis] + 1) as f32 - position) / d;
+                delta[axis] = 1. / d;
+                step[axis] = 1;
+            } else if d < 0. {
+                next[axis] = t0 + (cell[axis] as f32 - position) / d;
+                delta[axis] = -1. / d;
+                step[axis] = -1;
+            }
+        }
+
+        let mut t = t0;
+        loop {
+            let axis = match (next[0] < next[1], next[0] < next[2], next[1] < next[2]) {
+                (true, true, _) => 0,
+                (false, _, true) => 1,
+                _ => 2,
+            };
+            let end = next[axis].min(t1);
+
+            let index = cell[0] as usize
+                + self.resolution[0] * (cell[1] as usize + self.resolution[1] * cell[2] as usize);
+            if !f(t, end, self.values[index]) || end >= t1 {
+                return;
+            }
+
+            t = end;
+            cell[axis] += step[axis];
+            next[axis] += delta[axis];
+            if cell[axis] < 0 || cell[axis] >= self.resolution[axis] as i64 {
+                return;
+            }
+        }
+    }
+}
+
+/// Heterogeneous medium given by a density grid filling a box in its local space.
+pub struct Volume {
+    density: Grid,
+    majorants: MajorantGrid,
+    /// Extinction coefficient of unit density per unit of distance.
+    density_scale: f32,
+    pub albedo: Vector3,
+    pub asymmetry: f32,
+    /// Emitted radiance of unit value of the emission grid.
+    emission: Option<(Grid, Vector3)>,
+    // Maps world space to the unit cube of the grids.
+    to_grid: Matrix4,
+    aabb: (Vector3, Vector3),
+}
+
+impl Volume {
+    pub fn new(
+        density: Grid,
+        density_scale: f32,
+        albedo: Vector3,
+        asymmetry: f32,
+        emission: Option<(Grid, Vector3)>,
+        bounds: (Vector3, Vector3),
+        transformation: Matrix4,
+    ) -> Volume {
+        let (min, max) = bounds;
+        let size = max - min;
+        let to_grid = Matrix4::from_nonuniform_scale(1. / size.x, 1. / size.y, 1. / size.z)
+            * Matrix4::from_translation(-min)
+            * transformation.invert().unwrap_or_else(Matrix4::identity);
+
+        let mut aabb = (
+            Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            Vector3::new(-f32::INFINITY, -f32::INFINITY, -f32::INFINITY),
+        );
+        for corner in 0..8 {
+            let local = Vector3::new(
+                if corner & 1 == 0 { min.x } else { max.x },
+                if corner & 2 == 0 { min.y } else { max.y },
+                if corner & 4 == 0 { min.z } else { max.z },
+            );
+            let world = (transformation * local.extend(1.0)).truncate();
+            aabb = (aabb.0.min(world), aabb.1.max(world));
+        }
+
+        Volume {
+            majorants: MajorantGrid::new(&density),
+            density,
+            density_scale,
+            albedo,
+            asymmetry,
+            emission,
+            to_grid,
+            aabb,
+        }
+    }
+
+    /// Returns the ray in the unit cube of the grids with its part up to `t_max` inside of it.
+    fn clip(&self, ray: &Ray, t_max: f32) -> Option<(Vector3, Vector3, f32, f32)> {
+        // Direction is not normalized so that the ray parameter is the same in both spaces.
+        let origin = (self.to_grid * ray.origin.extend(1.0)).truncate();
+        let direction = (self.to_grid * ray.direction.extend(0.0)).truncate();
+
+        let (mut t0, mut t1) = (0f32, t_max);
+        for axis in 0..3 {
+            let inverse = 1. / direction[axis];
+            let near = -origin[axis] * inverse;
+            let far = (1. - origin[axis]) * inverse;
+            t0 = near.min(far).max(t0);
+            t1 = near.max(far).min(t1);
+        }
+
+        match t0 < t1 {
+            true => Some((origin, direction, t0, t1)),
+            false => None,
+        }
+    }
+
+    /// Returns extinction of unit density per unit of the ray parameter, which is not a world
+    /// distance for rays whose direction is not normalized.
+    fn ray_scale(&self, ray: &Ray) -> f32 {
+        self.density_scale * ray.direction.length()
+    }
+
+    /// Delta tracking: returns distance of the first collision along the ray before `t_max`.
+    ///
+    /// Radiance emitted towards the ray origin is estimated at all tentative collisions and
+    /// pushed with their distances into `emission`.
+    pub fn sample(
+        &self,
+        ray: &Ray,
+        t_max: f32,
+        sampler: &impl Sampler,
+        emission: &mut Vec<(f32, Vector3)>,
+    ) -> Option<f32> {
+        let (origin, direction, t0, t1) = self.clip(ray, t_max)?;
+        let scale = self.ray_scale(ray);
+        let absorption = Vector3::one() - self.albedo;
+        let mut collision = None;
+
+        self.majorants
+            .traverse(origin, direction, t0, t1, |start, end, majorant| {
+                let majorant = majorant * scale;
+                if majorant <= 0. {
+                    return true;
+                }
+
+                let mut t = start;
+                loop {
+                    t -= (1. - sampler.next_float()).ln() / majorant;
+                    if t >= end {
+                        return true;
+                    }
+
+                    let p = origin + direction * t;
+                    let extinction = scale * self.density.lookup(p);
+
+                    if let Some((grid, color)) = &self.emission {
+                        let radiance = color * grid.lookup(p);
+                        emission.push((t, absorption.mul(radiance) * (extinction / majorant)));
+                    }
+
+                    if sampler.next_float() * majorant < extinction {
+                        collision = Some(t);
+                        return false;
+                    }
+                }
+            });
+
+        collision
+    }
+
+    /// Ratio tracking: returns estimate of transmittance along the ray up to `t_max`.
+    pub fn transmittance(&self, ray: &Ray, t_max: f32, sampler: &impl Sampler) -> f32 {
+        let (origin, direction, t0, t1) = match self.clip(ray, t_max) {
+            Some(clipped) => clipped,
+            None => return 1.,
+        };
+        let scale = self.ray_scale(ray);
+        let mut transmittance = 1.;
+
+        self.majorants
+            .traverse(origin, direction, t0, t1, |start, end, majorant| {
+                let majorant = majorant * scale;
+                if majorant <= 0. {
+                    return true;
+                }
+
+                let mut t = start;
+                loop {
+                    t -= (1. - sampler.next_float()).ln() / majorant;
+                    if t >= end {
+                        return true;
+                    }
+
+                    let extinction = scale * self.density.lookup(origin + direction * t);
+                    transmittance *= 1. - extinction / majorant;
+                    if transmittance <= 0. {
+                        return false;
+                    }
+                }
+            });
+
+        transmittance.max(0.)
+    }
+}
+
+impl BoundingBox for Volume {
+    fn bounding_box(&self) -> AABB {
+        [self.aabb.0, self.aabb.1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::UniformSampler;
+
+    fn volume(values: Vec<f32>, resolution: [usize; 3]) -> Volume {
+        Volume::new(
+            Grid { resolution, values },
+            2.,
+            Vector3::one(),
+            0.,
+            None,
+            (Vector3::new(-1., -1., -1.), Vector3::new(1., 1., 1.)),
+            Matrix4::from_translation(Vector3::new(0., 0., 5.)),
+        )
+    }
+
+    #[test]
+    fn test_majorants() {
+        let resolution = [40, 20, 3];
+        let values = (0..resolution.iter().product::<usize>())
+            .map(|i| ((i * 7919) % 101) as f32 / 100.)
+            .collect();
+        let grid = Grid { resolution, values };
+        let majorants = MajorantGrid::new(&grid);
+        assert_eq!(majorants.resolution, [16, 8, 2]);
+
+        // Every interpolated value is bounded by majorant of its cell.
+        for i in 0..2000 {
+            let p = Vector3::new(
+                (i as f32 * 0.618).fract(),
+                (i as f32 * 0.414).fract(),
+                (i as f32 * 0.732).fract(),
+            );
+            let cell = [0, 1, 2].map(|axis| {
+                ((p[axis] * majorants.resolution[axis] as f32) as usize)
+                    .min(majorants.resolution[axis] - 1)
+            });
+            let index =
+                cell[0] + majorants.resolution[0] * (cell[1] + majorants.resolution[1] * cell[2]);
+            assert!(grid.lookup(p) <= majorants.values[index] + 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_tracking() {
+        let sampler = UniformSampler::new();
+
+        // Constant density of a slab crossed along the Z axis, through 2 units of distance.
+        let resolution = [4, 4, 8];
+        let constant = volume(vec![0.5; 128], resolution);
+        let ray = Ray::new(Vector3::new(0., 0., 0.), Vector3::new(0., 0., 1.));
+        let expected = (-2. * 0.5 * 2f32).exp();
+
+        let count = 20000;
+        let ratio = (0..count)
+            .map(|_| constant.transmittance(&ray, f32::INFINITY, &sampler))
+            .sum::<f32>()
+            / count as f32;
+        assert!((ratio - expected).abs() < 1e-2, "{} {}", ratio, expected);
+
+        let passed = (0..count)
+            .filter(|_| {
+                let collision = constant.sample(&ray, f32::INFINITY, &sampler, &mut vec![]);
+                assert!(collision.is_none_or(|t| (4. ..6.).contains(&t)));
+                collision.is_none()
+            })
+            .count() as f32
+            / count as f32;
+        assert!((passed - expected).abs() < 2e-2, "{} {}", passed, expected);
+
+        // Density is given per unit of distance, the ray parameter of a longer direction
+        // covers the same slab in a shorter interval.
+        let scaled = Ray::new(Vector3::new(0., 0., 0.), Vector3::new(0., 0., 2.5));
+        let ratio = (0..count)
+            .map(|_| constant.transmittance(&scaled, f32::INFINITY, &sampler))
+            .sum::<f32>()
+            / count as f32;
+        assert!((ratio - expected).abs() < 1e-2, "{} {}", ratio, expected);
+
+        let passed = (0..count)
+            .filter(|_| {
+                let collision = constant.sample(&scaled, f32::INFINITY, &sampler, &mut vec![]);
+                assert!(collision.is_none_or(|t| (1.6..2.4).contains(&t)));
+                collision.is_none()
+            })
+            .count() as f32
+            / count as f32;
+        assert!((passed - expected).abs() < 2e-2, "{} {}", passed, expected);
+
+        // Density only in the back half does not attenuate rays ending in the front half.
+        let values = (0..128).map(|i| if i < 64 { 0. } else { 1. }).collect();
+        let back = volume(values, resolution);
+        assert_eq!(back.transmittance(&ray, 4.8, &sampler), 1.);
+    }
+}